@@ -1,11 +1,24 @@
 use anyhow::Result;
+use crate::{e2e::E2eConfig, websocket_client::ClientTlsConfig};
+use protocol::{chunking, compression::Compression, CodecType};
 
 #[derive(Debug, Clone)]
 pub struct Config {
     pub tenant_id: String,
     pub branch_id: String,
-    pub api_key: String,
+    /// Not needed when a client certificate authenticates the branch
+    pub api_key: Option<String>,
     pub hub_url: String,
+    pub tls: ClientTlsConfig,
+    /// Preferred wire encoding; the hub falls back to JSON if it does not support it
+    pub codec: CodecType,
+    /// zstd for large messages, when the hub supports it
+    pub compression: Compression,
+    /// Messages encoded larger than this are sent in chunks, when the hub supports it
+    pub chunk_size: usize,
+    /// Largest message sent or accepted, whole or reassembled
+    pub max_message_size: usize,
+    pub e2e: E2eConfig,
     pub local_database_url: String,
     pub database_schema: String,
     pub tracked_tables: Vec<String>,
@@ -14,15 +27,57 @@ pub struct Config {
 
 impl Config {
     pub fn from_env() -> Result<Self> {
+        let api_key = std::env::var("API_KEY").ok();
+        let tls = ClientTlsConfig {
+            ca_cert_path: std::env::var("HUB_CA_CERT").ok(),
+            client_cert_path: std::env::var("TLS_CLIENT_CERT").ok(),
+            client_key_path: std::env::var("TLS_CLIENT_KEY").ok(),
+        };
+
+        if tls.client_cert_path.is_some() != tls.client_key_path.is_some() {
+            anyhow::bail!("TLS_CLIENT_CERT and TLS_CLIENT_KEY must be set together");
+        }
+        if api_key.is_none() && tls.client_cert_path.is_none() {
+            anyhow::bail!("API_KEY or TLS_CLIENT_CERT must be set");
+        }
+
+        let e2e = match (std::env::var("E2E_TENANT_KEY").ok(), std::env::var("E2E_PRIVATE_KEY_PATH").ok()) {
+            (Some(_), Some(_)) => anyhow::bail!("Set only one of E2E_TENANT_KEY and E2E_PRIVATE_KEY_PATH"),
+            (Some(key), None) => E2eConfig::TenantKey(key),
+            (None, Some(path)) => E2eConfig::KeyPair { path },
+            (None, None) => E2eConfig::Disabled,
+        };
+
+        let max_message_size: usize = match std::env::var("HUB_MAX_MESSAGE_SIZE") {
+            Ok(size) => size.parse()?,
+            Err(_) => chunking::DEFAULT_MAX_MESSAGE_SIZE,
+        };
+        let chunk_size: usize = match std::env::var("HUB_CHUNK_SIZE") {
+            Ok(size) => size.parse()?,
+            Err(_) => chunking::DEFAULT_CHUNK_SIZE,
+        };
+        if chunk_size == 0 || chunk_size > max_message_size {
+            anyhow::bail!("HUB_CHUNK_SIZE must be between 1 and HUB_MAX_MESSAGE_SIZE");
+        }
+
         Ok(Config {
             tenant_id: std::env::var("TENANT_ID")
                 .expect("TENANT_ID must be set"),
             branch_id: std::env::var("BRANCH_ID")
                 .expect("BRANCH_ID must be set"),
-            api_key: std::env::var("API_KEY")
-                .expect("API_KEY must be set"),
+            api_key,
             hub_url: std::env::var("HUB_URL")
                 .unwrap_or_else(|_| "ws://localhost:8080/ws".to_string()),
+            tls,
+            codec: std::env::var("HUB_CODEC")
+                .unwrap_or_else(|_| "json".to_string())
+                .parse()?,
+            compression: std::env::var("HUB_COMPRESSION")
+                .unwrap_or_else(|_| "none".to_string())
+                .parse()?,
+            chunk_size,
+            max_message_size,
+            e2e,
             local_database_url: std::env::var("LOCAL_DATABASE_URL")
                 .expect("LOCAL_DATABASE_URL must be set"),
             database_schema: std::env::var("DATABASE_SCHEMA")
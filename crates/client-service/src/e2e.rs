@@ -0,0 +1,158 @@
+use anyhow::{Context, Result};
+use common::{BranchId, TenantId};
+use protocol::e2e::{self, BranchKeyPair, PayloadCipher, PublicKey, RecipientKeys, TenantKey};
+use protocol::{DatabaseChange, PublicKeyList, RouteMessage};
+use std::collections::HashMap;
+use std::sync::RwLock;
+use tracing::{info, warn};
+
+/// Where this branch's end-to-end encryption keys come from
+#[derive(Debug, Clone, Default)]
+pub enum E2eConfig {
+    #[default]
+    Disabled,
+    /// Base64 key shared by every branch of the tenant
+    TenantKey(String),
+    /// File holding this branch's X25519 private key; created on first start
+    KeyPair { path: String },
+}
+
+/// Keys this branch seals outgoing and opens incoming payloads with
+pub enum BranchKeys {
+    Tenant(Box<TenantKey>),
+    KeyPair {
+        keypair: BranchKeyPair,
+        /// Peer keys as first learned from the hub; a later, different key is refused
+        peers: RwLock<HashMap<BranchId, PublicKey>>,
+    },
+}
+
+impl BranchKeys {
+    pub fn load(config: &E2eConfig) -> Result<Option<Self>> {
+        match config {
+            E2eConfig::Disabled => Ok(None),
+            E2eConfig::TenantKey(encoded) => {
+                let key = TenantKey::from_base64(encoded)?;
+                info!("End-to-end encryption with tenant key {}", key.key_id());
+                Ok(Some(BranchKeys::Tenant(Box::new(key))))
+            }
+            E2eConfig::KeyPair { path } => {
+                let keypair = load_or_create_keypair(path)?;
+                info!(
+                    "End-to-end encryption with branch key {}",
+                    e2e::fingerprint(keypair.public_key())
+                );
+                Ok(Some(BranchKeys::KeyPair { keypair, peers: RwLock::new(HashMap::new()) }))
+            }
+        }
+    }
+
+    /// Public key to register with the hub, when peers seal for this branch individually
+    pub fn public_key_base64(&self) -> Option<String> {
+        match self {
+            BranchKeys::Tenant(_) => None,
+            BranchKeys::KeyPair { keypair, .. } => Some(keypair.public_key_base64()),
+        }
+    }
+
+    /// Remember peer keys published through the hub
+    pub fn learn_keys(&self, own_branch: &BranchId, list: PublicKeyList) {
+        let BranchKeys::KeyPair { peers, .. } = self else {
+            return;
+        };
+        let mut peers = peers.write().unwrap();
+
+        for entry in list.keys.into_iter().filter(|entry| entry.branch_id != *own_branch) {
+            let key = match e2e::parse_public_key(&entry.public_key) {
+                Ok(key) => key,
+                Err(e) => {
+                    warn!("Ignoring public key of {}: {}", entry.branch_id, e);
+                    continue;
+                }
+            };
+            match peers.get(&entry.branch_id) {
+                Some(known) if *known == key => {}
+                Some(known) => warn!(
+                    "Public key of {} changed from {} to {}; keeping the first one until restart",
+                    entry.branch_id,
+                    e2e::fingerprint(known),
+                    e2e::fingerprint(&key)
+                ),
+                None => {
+                    info!("Learned public key of {}: {}", entry.branch_id, e2e::fingerprint(&key));
+                    peers.insert(entry.branch_id, key);
+                }
+            }
+        }
+    }
+
+    /// Run `f` with the cipher as seen by `branch_id`; per-branch keys seal for every known peer
+    fn with_cipher<R>(&self, branch_id: &BranchId, f: impl FnOnce(&dyn PayloadCipher) -> R) -> R {
+        match self {
+            BranchKeys::Tenant(key) => f(key.as_ref()),
+            BranchKeys::KeyPair { keypair, peers } => f(&RecipientKeys {
+                branch_id,
+                keypair,
+                recipients: peers.read().unwrap().iter().map(|(id, key)| (id.clone(), *key)).collect(),
+            }),
+        }
+    }
+
+    pub fn seal_changes(&self, tenant_id: &TenantId, branch_id: &BranchId, changes: &mut [DatabaseChange]) -> Result<()> {
+        self.with_cipher(branch_id, |cipher| {
+            changes
+                .iter_mut()
+                .try_for_each(|change| e2e::seal_change(cipher, tenant_id, change))
+        })?;
+        Ok(())
+    }
+
+    /// Open every sealed change in place; returns how many could not be opened
+    pub fn open_changes(&self, tenant_id: &TenantId, branch_id: &BranchId, changes: &mut [DatabaseChange]) -> usize {
+        self.with_cipher(branch_id, |cipher| {
+            changes
+                .iter_mut()
+                .filter_map(|change| e2e::open_change(cipher, tenant_id, change).err())
+                .inspect(|e| warn!("Failed to open sealed change: {}", e))
+                .count()
+        })
+    }
+
+    pub fn open_route(&self, tenant_id: &TenantId, branch_id: &BranchId, from: &BranchId, route: &mut RouteMessage) -> Result<()> {
+        self.with_cipher(branch_id, |cipher| e2e::open_route(cipher, tenant_id, from, route))?;
+        Ok(())
+    }
+}
+
+/// Read the branch key pair, generating and saving one if the file does not exist
+fn load_or_create_keypair(path: &str) -> Result<BranchKeyPair> {
+    match std::fs::read_to_string(path) {
+        Ok(encoded) => Ok(BranchKeyPair::from_base64(&encoded)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let keypair = BranchKeyPair::generate();
+            write_private(path, &keypair.secret_base64())
+                .with_context(|| format!("Failed to save branch key to {}", path))?;
+            info!("Generated branch key pair at {}", path);
+            Ok(keypair)
+        }
+        Err(e) => Err(e).with_context(|| format!("Failed to read branch key from {}", path)),
+    }
+}
+
+#[cfg(unix)]
+fn write_private(path: &str, contents: &str) -> std::io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(contents.as_bytes())
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &str, contents: &str) -> std::io::Result<()> {
+    std::fs::write(path, contents)
+}
@@ -1,8 +1,11 @@
 mod config;
+mod e2e;
 mod websocket_client;
 mod sync_loop;
 
 use anyhow::Result;
+use std::sync::Arc;
+use tokio::sync::mpsc;
 use tracing::{info, error};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
@@ -47,16 +50,26 @@ async fn main() -> Result<()> {
     }
 
     // Create WebSocket client
-    let ws_client = websocket_client::WebSocketClient::new(
+    let ws_client = Arc::new(websocket_client::WebSocketClient::new(
         config.hub_url.clone(),
         config.tenant_id.clone(),
         config.branch_id.clone(),
         config.api_key.clone(),
-    );
+    )
+    .with_tls(config.tls.clone())
+    .with_codec(config.codec)
+    .with_compression(config.compression)
+    .with_chunking(config.chunk_size, config.max_message_size)
+    .with_e2e(e2e::BranchKeys::load(&config.e2e)?));
+
+    // Keep the hub connection up; the sync loop hands it one batch at a time
+    let (batches, outgoing) = mpsc::channel(1);
+    let connection = ws_client.clone();
+    tokio::spawn(async move { connection.run(outgoing).await });
 
     // Start sync loop
     let sync_task = tokio::spawn(async move {
-        sync_loop::run_sync_loop(ws_client, cdc_engine, pg_pool, config).await
+        sync_loop::run_sync_loop(ws_client, batches, cdc_engine, pg_pool, config).await
     });
 
     // Wait for completion
@@ -1,12 +1,17 @@
-use crate::{config::Config, websocket_client::WebSocketClient};
+use crate::{config::Config, websocket_client::{Outgoing, WebSocketClient}};
+use common::{utils::generate_transaction_id, VectorClock};
+use protocol::{DatabaseChange, Message, MessagePayload, SyncBatch};
 use sync_engine::CdcEngine;
 use sqlx::PgPool;
 use anyhow::Result;
+use std::sync::Arc;
 use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
 use tracing::info;
 
 pub async fn run_sync_loop(
-    _ws_client: WebSocketClient,
+    ws_client: Arc<WebSocketClient>,
+    batches: mpsc::Sender<Outgoing>,
     cdc_engine: CdcEngine,
     _pg_pool: PgPool,
     config: Config,
@@ -14,6 +19,7 @@ pub async fn run_sync_loop(
     info!("Starting sync loop...");
 
     let mut interval = tokio::time::interval(Duration::from_secs(config.sync_interval_secs));
+    let mut clock = VectorClock::new();
 
     loop {
         interval.tick().await;
@@ -23,10 +29,40 @@ pub async fn run_sync_loop(
             .fetch_pending_changes(&config.database_schema, 100)
             .await
         {
-            Ok(changes) => {
-                if !changes.is_empty() {
-                    info!("Found {} pending changes", changes.len());
-                    // TODO: Send changes to hub via WebSocket
+            Ok(pending) => {
+                if !pending.is_empty() {
+                    info!("Found {} pending changes", pending.len());
+                    let (ids, mut changes): (Vec<i64>, Vec<DatabaseChange>) = pending.into_iter().unzip();
+                    // Row data leaves the branch sealed; unsent changes stay in the change log
+                    if let Some(keys) = ws_client.e2e() {
+                        if let Err(e) = keys.seal_changes(ws_client.tenant_id(), ws_client.branch_id(), &mut changes) {
+                            tracing::error!("Failed to seal changes: {}", e);
+                            continue;
+                        }
+                    }
+
+                    clock.increment(ws_client.branch_id());
+                    let batch = Message::new(
+                        ws_client.branch_id().clone(),
+                        None,
+                        MessagePayload::SyncBatch(SyncBatch {
+                            transaction_id: generate_transaction_id(),
+                            vector_clock: clock.clone(),
+                            changes,
+                            is_final: true,
+                        }),
+                    );
+
+                    // Waits out a disconnect; changes are only marked synced once written to the hub
+                    let (sent, written) = oneshot::channel();
+                    batches.send(Outgoing { message: batch, sent }).await?;
+                    if written.await.is_ok() {
+                        if let Err(e) = cdc_engine.mark_synced(&config.database_schema, &ids).await {
+                            tracing::error!("Failed to mark changes synced: {}", e);
+                        } else {
+                            info!("Sent {} changes to the hub", ids.len());
+                        }
+                    }
                 }
             }
             Err(e) => {
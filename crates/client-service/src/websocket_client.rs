@@ -1,35 +1,300 @@
-use tokio_tungstenite::{connect_async, tungstenite::Message as WsMessage};
-use protocol::{Message, MessagePayload, ConnectRequest, JsonCodec, MessageCodec};
-use common::{BranchId, TenantId};
+use tokio_tungstenite::{
+    connect_async_tls_with_config,
+    tungstenite::{client::IntoClientRequest, http::HeaderValue, protocol::WebSocketConfig, Message as WsMessage},
+    Connector,
+};
+use protocol::{
+    compression::{self, Compression}, Message, MessagePayload, CodecType, ConnectRequest, JsonCodec, MessageCodec, NotificationLevel, PublicKeyQuery, WireFormat,
+    PublicKeyRegistration, DisconnectReason,
+};
+use protocol::chunking::{self, Outbox, Reassembler, Transfer, CHUNKED_CAPABILITY, RESUME_WINDOW};
+use protocol::delivery::{self, SeenMessages, ACK_CAPABILITY};
+use common::{utils::calculate_backoff_duration, BranchId, TenantId};
 use futures::{StreamExt, SinkExt};
+use anyhow::Context;
 use std::collections::HashMap;
-use tracing::{info, error};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{Instant, Interval, MissedTickBehavior};
+use protocol::signing::SigningKey;
+use protocol::version::Negotiated;
+use crate::e2e::BranchKeys;
+use tracing::{debug, info, warn, error};
+
+/// Routed message IDs remembered to skip the hub's resends
+const SEEN_MESSAGES: usize = 4096;
+
+/// Heartbeat period until the hub's ConnectAck names its own
+const DEFAULT_HEARTBEAT_SECS: u64 = 30;
+
+const RECONNECT_BASE_MS: u64 = 1_000;
+const RECONNECT_MAX_MS: u64 = 60_000;
+
+/// A message for the hub connection to send; `sent` fires once it is written to the socket
+pub struct Outgoing {
+    pub message: Message,
+    pub sent: oneshot::Sender<()>,
+}
+
+/// TLS settings for reaching the hub over `wss://`
+#[derive(Debug, Clone, Default)]
+pub struct ClientTlsConfig {
+    /// Trust only this CA (PEM) for the hub's certificate instead of the system roots
+    pub ca_cert_path: Option<String>,
+    /// Client certificate (PEM) naming the branch as `hub-broker://<tenant_id>/<branch_id>`
+    pub client_cert_path: Option<String>,
+    /// PKCS#8 private key (PEM) for the client certificate
+    pub client_key_path: Option<String>,
+}
 
 pub struct WebSocketClient {
     hub_url: String,
     tenant_id: TenantId,
     branch_id: BranchId,
-    api_key: String,
+    api_key: Option<String>,
+    tls: ClientTlsConfig,
+    format: WireFormat,
+    e2e: Option<Arc<BranchKeys>>,
+    /// Derived from the API key; certificate-only branches rely on TLS instead
+    signing_key: Option<SigningKey>,
+    chunk_size: usize,
+    max_message_size: usize,
+    /// Whether the hub accepted chunked transfer on the current connection
+    chunked: AtomicBool,
+    /// Kept across reconnects so unfinished transfers can resume
+    reassembler: Mutex<Reassembler>,
+    outbox: Mutex<Outbox>,
+    /// Whether the hub expects routed messages to be acknowledged on the current connection
+    acks: AtomicBool,
+    /// Kept across reconnects, as the hub resends whatever was not acknowledged
+    seen: Mutex<SeenMessages>,
 }
 
 impl WebSocketClient {
-    pub fn new(hub_url: String, tenant_id: String, branch_id: String, api_key: String) -> Self {
+    pub fn new(hub_url: String, tenant_id: String, branch_id: String, api_key: Option<String>) -> Self {
         Self {
             hub_url,
             tenant_id: TenantId::new(tenant_id),
             branch_id: BranchId::new(branch_id),
+            signing_key: api_key.as_deref().map(SigningKey::derive),
             api_key,
+            tls: ClientTlsConfig::default(),
+            format: WireFormat::default(),
+            e2e: None,
+            chunk_size: chunking::DEFAULT_CHUNK_SIZE,
+            max_message_size: chunking::DEFAULT_MAX_MESSAGE_SIZE,
+            chunked: AtomicBool::new(false),
+            reassembler: Mutex::new(Reassembler::new(chunking::DEFAULT_MAX_MESSAGE_SIZE, RESUME_WINDOW)),
+            outbox: Mutex::new(Outbox::default()),
+            acks: AtomicBool::new(false),
+            seen: Mutex::new(SeenMessages::new(SEEN_MESSAGES)),
+        }
+    }
+
+    pub fn with_tls(mut self, tls: ClientTlsConfig) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    pub fn with_codec(mut self, codec: CodecType) -> Self {
+        self.format.codec = codec;
+        self
+    }
+
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.format.compression = compression;
+        self
+    }
+
+    /// Send messages larger than `chunk_size` in chunks, and accept none over `max_message_size`
+    pub fn with_chunking(mut self, chunk_size: usize, max_message_size: usize) -> Self {
+        self.chunk_size = chunk_size;
+        self.max_message_size = max_message_size;
+        self.reassembler = Mutex::new(Reassembler::new(max_message_size, RESUME_WINDOW));
+        self
+    }
+
+    pub fn with_e2e(mut self, keys: Option<BranchKeys>) -> Self {
+        self.e2e = keys.map(Arc::new);
+        self
+    }
+
+    /// End-to-end encryption keys, when configured
+    pub fn e2e(&self) -> Option<&BranchKeys> {
+        self.e2e.as_deref()
+    }
+
+    pub fn tenant_id(&self) -> &TenantId {
+        &self.tenant_id
+    }
+
+    pub fn branch_id(&self) -> &BranchId {
+        &self.branch_id
+    }
+
+    /// Subprotocols to offer, preferred format first, falling back to plain JSON
+    fn offered_subprotocols(&self) -> String {
+        let mut offered = vec![self.format];
+        for fallback in [
+            WireFormat::new(self.format.codec, Compression::None),
+            WireFormat::default(),
+        ] {
+            if !offered.contains(&fallback) {
+                offered.push(fallback);
+            }
+        }
+        offered.iter().map(WireFormat::subprotocol).collect::<Vec<_>>().join(", ")
+    }
+
+    /// Sign and encode an outgoing message in the frames its format calls for: one, or a
+    /// signed chunk per frame when it is too large and the hub accepted chunking
+    fn encode(&self, format: WireFormat, mut message: Message) -> anyhow::Result<Vec<WsMessage>> {
+        if let Some(key) = &self.signing_key {
+            key.sign(&mut message)?;
+        }
+        let codec = format.codec.create();
+        let encoded = codec.encode(&message)?;
+
+        let chunk = self.chunked.load(Ordering::Relaxed)
+            && encoded.len() > self.chunk_size
+            && !matches!(message.payload, MessagePayload::Chunk(_));
+        if !chunk {
+            anyhow::ensure!(
+                encoded.len() <= self.max_message_size,
+                "Message {} is {} bytes, over the {} byte limit",
+                message.id,
+                encoded.len(),
+                self.max_message_size
+            );
+            return Ok(vec![frame(format, encoded)?]);
+        }
+
+        let transfer = Transfer::split(&message, encoded, self.chunk_size, self.max_message_size)?
+            .with_context(|| format!("Message {} did not split into chunks", message.id))?;
+        info!("Sending message {} in {} chunks", message.id, transfer.total());
+        self.outbox.lock().unwrap().insert(&transfer);
+        self.encode_chunks(format, transfer)
+    }
+
+    /// Sign and frame the remaining chunks of a transfer
+    fn encode_chunks(&self, format: WireFormat, mut transfer: Transfer) -> anyhow::Result<Vec<WsMessage>> {
+        let codec = format.codec.create();
+        let mut frames = Vec::with_capacity(transfer.total() as usize);
+        while let Some(mut chunk) = transfer.next_chunk() {
+            if let Some(key) = &self.signing_key {
+                key.sign(&mut chunk)?;
+            }
+            frames.push(frame(format, codec.encode(&chunk)?)?);
         }
+        Ok(frames)
+    }
+
+    /// Add an incoming chunk to its transfer; the message it completes, or any other message as is
+    fn reassemble(&self, message: Message, format: WireFormat) -> anyhow::Result<Option<Message>> {
+        let MessagePayload::Chunk(chunk) = message.payload else {
+            return Ok(Some(message));
+        };
+
+        let message_id = chunk.message_id.clone();
+        let Some(encoded) = self.reassembler.lock().unwrap().push(chunk)? else {
+            return Ok(None);
+        };
+        let message = format.codec.create().decode(&encoded)?;
+        anyhow::ensure!(
+            message.id == message_id && !matches!(message.payload, MessagePayload::Chunk(_)),
+            "Chunks of {} do not reassemble into that message",
+            message_id
+        );
+        info!("Reassembled message {} from {} bytes of chunks", message.id, encoded.len());
+        Ok(Some(message))
     }
 
-    pub async fn connect(&self) -> anyhow::Result<()> {
+    /// Custom connector for a pinned CA and/or client certificate; None keeps the defaults
+    fn tls_connector(&self) -> anyhow::Result<Option<Connector>> {
+        let tls = &self.tls;
+        if tls.ca_cert_path.is_none() && tls.client_cert_path.is_none() {
+            return Ok(None);
+        }
+
+        let mut builder = native_tls::TlsConnector::builder();
+
+        if let Some(ca_path) = &tls.ca_cert_path {
+            let ca = native_tls::Certificate::from_pem(&std::fs::read(ca_path)?)?;
+            builder.add_root_certificate(ca).disable_built_in_roots(true);
+        }
+
+        if let (Some(cert_path), Some(key_path)) = (&tls.client_cert_path, &tls.client_key_path) {
+            let identity = native_tls::Identity::from_pkcs8(
+                &std::fs::read(cert_path)?,
+                &std::fs::read(key_path)?,
+            )?;
+            builder.identity(identity);
+        }
+
+        Ok(Some(Connector::NativeTls(builder.build()?)))
+    }
+
+    /// Stay connected to the hub, reconnecting with backoff; `outgoing` is sent while connected
+    pub async fn run(&self, mut outgoing: mpsc::Receiver<Outgoing>) {
+        let mut attempt: u32 = 0;
+        loop {
+            match self.connect(&mut outgoing).await {
+                Ok(()) => attempt = 0,
+                Err(e) => {
+                    error!("Hub connection failed: {}", e);
+                    attempt = attempt.saturating_add(1);
+                }
+            }
+            let delay = calculate_backoff_duration(attempt, RECONNECT_BASE_MS, RECONNECT_MAX_MS);
+            info!("Reconnecting to hub in {:?}", delay);
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    pub async fn connect(&self, outgoing: &mut mpsc::Receiver<Outgoing>) -> anyhow::Result<()> {
         info!("Connecting to hub: {}", self.hub_url);
 
-        let (ws_stream, _) = connect_async(&self.hub_url).await?;
-        info!("WebSocket connected");
+        let connector = self.tls_connector()?;
+        let mut request = self.hub_url.as_str().into_client_request()?;
+        request
+            .headers_mut()
+            .insert("Sec-WebSocket-Protocol", HeaderValue::from_str(&self.offered_subprotocols())?);
+        // Hubs without chunking send large messages whole, up to the same limit
+        let ws_config = WebSocketConfig {
+            max_message_size: Some(self.max_message_size),
+            max_frame_size: Some(self.max_message_size),
+            ..Default::default()
+        };
+        let (ws_stream, response) =
+            connect_async_tls_with_config(request, Some(ws_config), false, connector).await?;
+        self.chunked.store(false, Ordering::Relaxed);
+        self.acks.store(false, Ordering::Relaxed);
+
+        // Hubs without codec negotiation answer with no subprotocol and speak JSON
+        let format = response
+            .headers()
+            .get("Sec-WebSocket-Protocol")
+            .and_then(|value| value.to_str().ok())
+            .and_then(WireFormat::from_subprotocol)
+            .unwrap_or_default();
+        info!("WebSocket connected ({:?})", format);
 
         let (mut write, mut read) = ws_stream.split();
-        let codec = JsonCodec;
+        let codec = format.create(compression::DEFAULT_THRESHOLD);
+
+        let mut capabilities = vec![
+            protocol::version::SYNC_CAPABILITY.to_string(),
+            CHUNKED_CAPABILITY.to_string(),
+            ACK_CAPABILITY.to_string(),
+        ];
+        if self.e2e.is_some() {
+            capabilities.push(protocol::e2e::E2E_CAPABILITY.to_string());
+        }
+        if self.signing_key.is_some() {
+            capabilities.push(protocol::signing::SIGNING_CAPABILITY.to_string());
+        }
 
         // Send Connect message
         let connect_msg = Message::new(
@@ -39,55 +304,330 @@ impl WebSocketClient {
                 tenant_id: self.tenant_id.clone(),
                 branch_id: self.branch_id.clone(),
                 api_key: self.api_key.clone(),
-                version: env!("CARGO_PKG_VERSION").to_string(),
-                capabilities: vec!["sync_v1".to_string()],
+                version: protocol::version::CURRENT.to_string(),
+                capabilities: capabilities.clone(),
                 metadata: HashMap::new(),
             }),
         );
 
-        let encoded = codec.encode(&connect_msg)?;
-        write
-            .send(WsMessage::Text(String::from_utf8(encoded)?))
-            .await?;
+        for frame in self.encode(format, connect_msg)? {
+            write.send(frame).await?;
+        }
 
         info!("Sent Connect message");
 
-        // Handle incoming messages
-        while let Some(msg) = read.next().await {
-            match msg {
-                Ok(WsMessage::Text(text)) => {
-                    if let Ok(message) = serde_json::from_str::<Message>(&text) {
-                        self.handle_message(message).await;
+        // Publish our key and fetch the peers' so they can seal for us and we for them
+        if let Some(public_key) = self.e2e.as_ref().and_then(|keys| keys.public_key_base64()) {
+            for payload in [
+                MessagePayload::PublicKeyRegister(PublicKeyRegistration { public_key }),
+                MessagePayload::PublicKeyRequest(PublicKeyQuery { branch_ids: vec![] }),
+            ] {
+                let message = Message::new(self.branch_id.clone(), None, payload);
+                for frame in self.encode(format, message)? {
+                    write.send(frame).await?;
+                }
+            }
+        }
+
+        let mut heartbeat = heartbeat_interval(DEFAULT_HEARTBEAT_SECS);
+        // Outgoing messages wait for the ConnectAck, so the hub has a session to take them from
+        let mut connected = false;
+
+        loop {
+            tokio::select! {
+                msg = read.next() => {
+                    let decoded = match msg {
+                        Some(Ok(WsMessage::Text(text))) => JsonCodec.decode(text.as_bytes()),
+                        Some(Ok(WsMessage::Binary(data))) => codec.decode(&data),
+                        Some(Ok(WsMessage::Close(_))) | None => {
+                            info!("Connection closed by server");
+                            break;
+                        }
+                        Some(Err(e)) => {
+                            error!("WebSocket error: {}", e);
+                            break;
+                        }
+                        Some(Ok(_)) => continue,
+                    };
+
+                    let message = match decoded.map_err(anyhow::Error::from).and_then(|m| self.reassemble(m, format)) {
+                        Ok(Some(message)) => message,
+                        Ok(None) => continue,
+                        Err(e) => {
+                            warn!("Failed to decode message: {}", e);
+                            continue;
+                        }
+                    };
+
+                    if let MessagePayload::ConnectAck(ack) = &message.payload {
+                        heartbeat = heartbeat_interval(ack.heartbeat_interval_secs);
+                        connected = true;
+                    }
+
+                    for reply in self.handle_message(message, &capabilities).await {
+                        for frame in self.encode(format, reply)? {
+                            write.send(frame).await?;
+                        }
                     }
                 }
-                Ok(WsMessage::Close(_)) => {
-                    info!("Connection closed by server");
-                    break;
+                _ = heartbeat.tick() => {
+                    let message = Message::new(self.branch_id.clone(), None, MessagePayload::Heartbeat);
+                    for frame in self.encode(format, message)? {
+                        write.send(frame).await?;
+                    }
                 }
-                Err(e) => {
-                    error!("WebSocket error: {}", e);
-                    break;
+                Some(outgoing) = outgoing.recv(), if connected => {
+                    // Dropping `sent` unsent tells the sender to try again later
+                    let frames = match self.encode(format, outgoing.message) {
+                        Ok(frames) => frames,
+                        Err(e) => {
+                            warn!("Failed to encode outgoing message: {}", e);
+                            continue;
+                        }
+                    };
+                    for frame in frames {
+                        write.send(frame).await?;
+                    }
+                    let _ = outgoing.sent.send(());
                 }
-                _ => {}
             }
         }
 
         Ok(())
     }
 
-    async fn handle_message(&self, message: Message) {
+    /// Act on a message from the hub; returns the messages to send in reply
+    async fn handle_message(&self, message: Message, offered: &[String]) -> Vec<Message> {
+        // Acknowledged before it is acted on; a resend is acknowledged again but not handled twice
+        let mut replies = Vec::new();
+        if delivery::needs_ack(&message.payload) {
+            if self.acks.load(Ordering::Relaxed) {
+                replies.push(delivery::acknowledge(&message, self.branch_id.clone()));
+            }
+            if !self.seen.lock().unwrap().first_sighting(&message.id) {
+                debug!("Skipping message {}, which was already handled", message.id);
+                return replies;
+            }
+        }
+
         match message.payload {
             MessagePayload::ConnectAck(ack) => {
-                info!("Connected! Session ID: {}", ack.session_id);
+                let negotiated = Negotiated::from_ack(&ack, offered);
+                info!(
+                    "Connected! Session ID: {} (protocol {}, {})",
+                    ack.session_id,
+                    negotiated.version,
+                    negotiated.capabilities.join(",")
+                );
+                for capability in offered.iter().filter(|c| !negotiated.has(c)) {
+                    warn!("Hub did not accept capability {}", capability);
+                }
+                let e2e_required = ack
+                    .assigned_config
+                    .get(protocol::e2e::E2E_REQUIRED_CONFIG)
+                    .is_some_and(|value| value == "true");
+                if e2e_required && self.e2e.is_none() {
+                    error!("Tenant requires end-to-end encryption; set E2E_TENANT_KEY or E2E_PRIVATE_KEY_PATH");
+                }
+
+                self.acks.store(negotiated.has(ACK_CAPABILITY), Ordering::Relaxed);
+
+                // Ask the hub to pick up the transfers the last connection cut short
+                let chunked = negotiated.has(CHUNKED_CAPABILITY);
+                self.chunked.store(chunked, Ordering::Relaxed);
+                if chunked {
+                    return self
+                        .reassembler
+                        .lock()
+                        .unwrap()
+                        .resume_requests()
+                        .into_iter()
+                        .map(|request| Message::new(self.branch_id.clone(), None, MessagePayload::ChunkResume(request)))
+                        .collect();
+                }
+            }
+            MessagePayload::ChunkResume(request) => {
+                match self.outbox.lock().unwrap().resume(&request) {
+                    Some(mut transfer) => {
+                        info!("Resuming message {} at chunk {}", request.message_id, request.next_index);
+                        return std::iter::from_fn(|| transfer.next_chunk()).collect();
+                    }
+                    None => warn!("Hub asked to resume message {}, which is no longer kept", request.message_id),
+                }
+            }
+            MessagePayload::Disconnect(reason) if reason.code == DisconnectReason::UNSUPPORTED_VERSION => {
+                error!("Hub refused protocol {}: {}", protocol::version::CURRENT, reason.reason);
+            }
+            MessagePayload::Disconnect(reason) => {
+                warn!("Disconnected by hub: {} ({})", reason.reason, reason.code);
+            }
+            MessagePayload::PublicKeys(list) => {
+                if let Some(keys) = &self.e2e {
+                    keys.learn_keys(&self.branch_id, list);
+                }
             }
             MessagePayload::HeartbeatAck => {
                 // Heartbeat acknowledged
             }
-            MessagePayload::SyncBatch(batch) => {
+            MessagePayload::SyncBatch(mut batch) => {
                 info!("Received sync batch: {} changes", batch.changes.len());
+                if let Some(keys) = &self.e2e {
+                    let failed = keys.open_changes(&self.tenant_id, &self.branch_id, &mut batch.changes);
+                    if failed > 0 {
+                        warn!("{} changes in batch {} could not be opened", failed, batch.transaction_id);
+                    }
+                } else if batch.changes.iter().any(|change| change.sealed.is_some()) {
+                    warn!("Batch {} has sealed changes but no end-to-end keys are configured", batch.transaction_id);
+                }
                 // TODO: Apply changes to local database
             }
+            MessagePayload::RouteMessage(mut route) => {
+                match &self.e2e {
+                    Some(keys) => {
+                        if let Err(e) = keys.open_route(&self.tenant_id, &self.branch_id, &message.from, &mut route) {
+                            warn!("Failed to open message from {}: {}", message.from, e);
+                            return replies;
+                        }
+                    }
+                    None if route.sealed.is_some() => {
+                        warn!("Sealed message from {} but no end-to-end keys are configured", message.from);
+                        return replies;
+                    }
+                    None => {}
+                }
+                info!("Received {} bytes from {}", route.payload.len(), message.from);
+            }
+            MessagePayload::MessageDelivered(delivered) => {
+                info!("Message {} delivered at {}", delivered.message_id, delivered.delivered_at);
+            }
+            MessagePayload::MessageFailed(failed) => {
+                warn!("Message {} was not delivered: {}", failed.message_id, failed.reason);
+            }
+            MessagePayload::SystemNotification(notification) => match notification.level {
+                NotificationLevel::Info => info!("Hub notification: {}", notification.message),
+                NotificationLevel::Warning => warn!("Hub notification: {}", notification.message),
+                NotificationLevel::Error | NotificationLevel::Critical => {
+                    error!("Hub notification: {}", notification.message)
+                }
+            },
             _ => {}
         }
+
+        replies
+    }
+}
+
+/// Ticks every `secs` seconds, the first one a full period from now
+fn heartbeat_interval(secs: u64) -> Interval {
+    let period = Duration::from_secs(secs.max(1));
+    let mut interval = tokio::time::interval_at(Instant::now() + period, period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    interval
+}
+
+/// Frame an encoded message the way its format calls for
+fn frame(format: WireFormat, encoded: Vec<u8>) -> anyhow::Result<WsMessage> {
+    let encoded = match format.compression {
+        Compression::Zstd => compression::compress(encoded, compression::DEFAULT_THRESHOLD)?,
+        Compression::None => encoded,
+    };
+    if format.is_binary() {
+        Ok(WsMessage::Binary(encoded))
+    } else {
+        Ok(WsMessage::Text(String::from_utf8(encoded)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::ConnectAck;
+    use tokio::net::TcpListener;
+
+    /// Next message from the client that is not a heartbeat, counting the heartbeats skipped
+    async fn next_message<S>(socket: &mut S, heartbeats: &mut usize) -> Message
+    where
+        S: futures::Stream<Item = Result<WsMessage, tokio_tungstenite::tungstenite::Error>> + Unpin,
+    {
+        loop {
+            let frame = socket.next().await.unwrap().unwrap();
+            let message = JsonCodec.decode(frame.into_data().as_slice()).unwrap();
+            match message.payload {
+                MessagePayload::Heartbeat => *heartbeats += 1,
+                _ => return message,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connection_sends_heartbeats_and_outgoing_batches() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/ws", listener.local_addr().unwrap());
+        let client = Arc::new(WebSocketClient::new(url, "tenant_1".into(), "store_1".into(), Some("key".into())));
+
+        let (batches, mut outgoing) = mpsc::channel(1);
+        let connection = client.clone();
+        tokio::spawn(async move { connection.connect(&mut outgoing).await });
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut hub = tokio_tungstenite::accept_async(stream).await.unwrap();
+        let mut heartbeats = 0;
+        assert!(matches!(next_message(&mut hub, &mut heartbeats).await.payload, MessagePayload::Connect(_)));
+
+        // Queued before the ConnectAck, so it is held back until then
+        let (sent, written) = oneshot::channel();
+        let batch = Message::new(client.branch_id().clone(), None, MessagePayload::Heartbeat);
+        let batch_id = batch.id.clone();
+        batches.send(Outgoing { message: batch, sent }).await.unwrap();
+
+        let ack = Message::new(
+            BranchId::new("hub"),
+            Some(client.branch_id().clone()),
+            MessagePayload::ConnectAck(ConnectAck {
+                session_id: "session_1".into(),
+                server_version: "test".into(),
+                heartbeat_interval_secs: 1,
+                assigned_config: HashMap::new(),
+            }),
+        );
+        hub.send(WsMessage::Text(String::from_utf8(JsonCodec.encode(&ack).unwrap()).unwrap()))
+            .await
+            .unwrap();
+
+        // The queued message is sent as is, then heartbeats follow at the hub's interval
+        let frame = hub.next().await.unwrap().unwrap();
+        let message = JsonCodec.decode(frame.into_data().as_slice()).unwrap();
+        assert_eq!(message.id, batch_id);
+        written.await.unwrap();
+
+        let frame = tokio::time::timeout(Duration::from_secs(3), hub.next()).await.unwrap().unwrap().unwrap();
+        let message = JsonCodec.decode(frame.into_data().as_slice()).unwrap();
+        assert!(matches!(message.payload, MessagePayload::Heartbeat));
+        assert_ne!(message.id, batch_id);
+        assert_eq!(heartbeats, 0);
+    }
+
+    #[test]
+    fn test_large_messages_are_chunked_and_oversized_ones_refused() {
+        let client = WebSocketClient::new("ws://hub".into(), "tenant_1".into(), "store_1".into(), None)
+            .with_chunking(256, 4096);
+        let route = |size: usize| {
+            Message::new(
+                BranchId::new("store_1"),
+                None,
+                MessagePayload::RouteMessage(protocol::RouteMessage {
+                    target_branch: BranchId::new("store_2"),
+                    payload: vec![0; size],
+                    sealed: None,
+                }),
+            )
+        };
+
+        // Whole until the hub accepts chunking
+        assert_eq!(client.encode(WireFormat::default(), route(512)).unwrap().len(), 1);
+        client.chunked.store(true, Ordering::Relaxed);
+        assert!(client.encode(WireFormat::default(), route(512)).unwrap().len() > 1);
+
+        assert!(client.encode(WireFormat::default(), route(8192)).is_err());
     }
 }
@@ -16,10 +16,76 @@ pub struct RedisConfig {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SecurityConfig {
-    pub jwt_secret: String,
     pub jwt_expiry_secs: i64,
+    /// How long each signing key signs before its successor takes over
+    pub jwt_key_rotation_secs: i64,
+    pub refresh_token_expiry_secs: i64,
+    /// How long a rotated-out API key keeps working alongside its replacement
+    pub api_key_rotation_overlap_secs: i64,
+    /// Refuse WebSocket upgrades that did not arrive over TLS
     pub require_tls: bool,
+    /// Accept `X-Forwarded-Proto: https` as TLS; only safe behind a terminating proxy
+    pub trust_forwarded_proto: bool,
+    pub tls: Option<TlsConfig>,
+    /// Inbound messages per second allowed for each branch, on top of the tenant's own limit
     pub rate_limit_per_sec: u32,
+    /// `/auth/token` attempts per minute allowed for each branch
+    pub auth_rate_limit_per_min: u32,
+    /// Operator credential for `/admin`, also used to mint admin JWTs
+    pub admin_token: Option<String>,
+    /// Lifetime of admin JWTs issued through `/admin/tokens`
+    pub admin_token_expiry_secs: i64,
+    /// Refuse unsigned messages from sessions that connected with an API key or token
+    pub require_message_signatures: bool,
+    /// How far a message timestamp may stray from the hub clock; message IDs are
+    /// remembered for twice this long to catch replays
+    pub max_clock_skew_secs: i64,
+}
+
+/// Native TLS termination; without it the hub listens on plain TCP
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TlsConfig {
+    pub cert_path: String,
+    pub key_path: String,
+    /// CA that issues branch client certificates; enables mTLS
+    pub client_ca_path: Option<String>,
+    /// Refuse handshakes without a valid client certificate
+    pub require_client_cert: bool,
+}
+
+/// Reduced limits for `Trial` tenants; each applies only where it is lower than the tenant's own
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrialConfig {
+    /// Trial length when a tenant is put on trial without an explicit expiry
+    pub duration_secs: i64,
+    pub max_branches: usize,
+    pub max_connections_per_branch: usize,
+    pub rate_limit_per_sec: u32,
+}
+
+/// How a message for a branch with several live sessions is delivered
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionDelivery {
+    /// Every session of the branch receives a copy
+    FanOut,
+    /// Only the oldest live session receives it
+    Primary,
+}
+
+impl std::str::FromStr for SessionDelivery {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fan_out" | "fanout" => Ok(SessionDelivery::FanOut),
+            "primary" => Ok(SessionDelivery::Primary),
+            other => Err(crate::Error::Internal(format!(
+                "Unknown session delivery mode: {}",
+                other
+            ))),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -28,7 +94,25 @@ pub struct ServerConfig {
     pub port: u16,
     pub max_connections: usize,
     pub heartbeat_interval_secs: u64,
+    pub heartbeat_timeout_secs: u64,
+    /// How long a routed message waits for its acknowledgement before the first resend
     pub message_timeout_secs: u64,
+    pub offline_message_ttl_secs: i64,
+    pub session_delivery: SessionDelivery,
+    /// Offer zstd-compressed WebSocket subprotocols to branches
+    pub ws_compression: bool,
+    /// Encoded messages below this size are sent uncompressed
+    pub ws_compression_threshold: usize,
+    /// Branches speaking an older protocol revision are refused at Connect
+    pub min_protocol_version: crate::ProtocolVersion,
+    /// Largest message accepted, whole or reassembled from chunks
+    pub max_message_size: usize,
+    /// Encoded messages larger than this go to chunk-capable branches in chunks
+    pub chunk_size: usize,
+    /// Sends of a routed message without an acknowledgement before the sender is told it failed
+    pub delivery_max_attempts: u32,
+    /// Cap on the doubling wait between resends
+    pub delivery_retry_max_secs: u64,
 }
 
 impl Default for ServerConfig {
@@ -38,7 +122,17 @@ impl Default for ServerConfig {
             port: 8080,
             max_connections: 10000,
             heartbeat_interval_secs: 30,
+            heartbeat_timeout_secs: 90,
             message_timeout_secs: 60,
+            offline_message_ttl_secs: 86400,
+            session_delivery: SessionDelivery::Primary,
+            ws_compression: true,
+            ws_compression_threshold: 1024,
+            min_protocol_version: crate::ProtocolVersion::new(0, 1),
+            max_message_size: 64 * 1024 * 1024,
+            chunk_size: 256 * 1024,
+            delivery_max_attempts: 5,
+            delivery_retry_max_secs: 900,
         }
     }
 }
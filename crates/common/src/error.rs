@@ -32,8 +32,17 @@ pub enum Error {
     #[error("Invalid message format: {0}")]
     InvalidMessage(String),
 
-    #[error("Rate limit exceeded")]
-    RateLimitExceeded,
+    #[error("Connection limit reached: {0}")]
+    ConnectionLimitReached(String),
+
+    #[error("Quota exceeded: {0}")]
+    QuotaExceeded(String),
+
+    #[error("Rate limit exceeded, retry after {retry_after_ms}ms")]
+    RateLimitExceeded { retry_after_ms: u64 },
+
+    #[error("Encryption error: {0}")]
+    Encryption(String),
 
     #[error("Internal error: {0}")]
     Internal(String),
@@ -44,6 +44,12 @@ pub struct Tenant {
     pub max_connections_per_branch: usize,
     pub rate_limit_per_sec: u32,
     pub database_schema: String,  // PostgreSQL schema for this tenant
+    /// Branches may only exchange row data and route payloads sealed end to end
+    #[serde(default)]
+    pub e2e_required: bool,
+    /// End of a `Trial`; the tenant is deactivated once it passes
+    #[serde(default)]
+    pub trial_expires_at: Option<chrono::DateTime<chrono::Utc>>,
     pub created_at: chrono::DateTime<chrono::Utc>,
     pub updated_at: chrono::DateTime<chrono::Utc>,
 }
@@ -57,6 +63,56 @@ pub enum TenantStatus {
     Trial,
 }
 
+impl TenantStatus {
+    /// Value stored in the `tenants.status` column
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TenantStatus::Active => "active",
+            TenantStatus::Suspended => "suspended",
+            TenantStatus::Inactive => "inactive",
+            TenantStatus::Trial => "trial",
+        }
+    }
+}
+
+/// Limits in force for a tenant once its plan is taken into account
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct TenantLimits {
+    pub max_branches: usize,
+    pub max_connections_per_branch: usize,
+    pub rate_limit_per_sec: u32,
+}
+
+impl Tenant {
+    /// Whether the tenant's branches may authenticate and stay connected at `now`
+    pub fn is_usable(&self, now: chrono::DateTime<chrono::Utc>) -> bool {
+        match self.status {
+            TenantStatus::Active => true,
+            TenantStatus::Trial => !matches!(self.trial_expires_at, Some(expires_at) if expires_at <= now),
+            TenantStatus::Suspended | TenantStatus::Inactive => false,
+        }
+    }
+
+    /// The tenant's own limits, capped by the trial limits while on `Trial`
+    pub fn limits(&self, trial: &crate::config::TrialConfig) -> TenantLimits {
+        let own = TenantLimits {
+            max_branches: self.max_branches,
+            max_connections_per_branch: self.max_connections_per_branch,
+            rate_limit_per_sec: self.rate_limit_per_sec,
+        };
+
+        if self.status != TenantStatus::Trial {
+            return own;
+        }
+
+        TenantLimits {
+            max_branches: own.max_branches.min(trial.max_branches),
+            max_connections_per_branch: own.max_connections_per_branch.min(trial.max_connections_per_branch),
+            rate_limit_per_sec: own.rate_limit_per_sec.min(trial.rate_limit_per_sec),
+        }
+    }
+}
+
 /// Full qualified branch identifier with tenant isolation
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct QualifiedBranchId {
@@ -69,11 +125,6 @@ impl QualifiedBranchId {
         Self { tenant_id, branch_id }
     }
 
-    /// Format: tenant_id:branch_id
-    pub fn to_string(&self) -> String {
-        format!("{}:{}", self.tenant_id.0, self.branch_id.0)
-    }
-
     /// Parse from string format
     pub fn from_string(s: &str) -> Option<Self> {
         let parts: Vec<&str> = s.split(':').collect();
@@ -88,6 +139,13 @@ impl QualifiedBranchId {
     }
 }
 
+/// Format: tenant_id:branch_id
+impl std::fmt::Display for QualifiedBranchId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.tenant_id.0, self.branch_id.0)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -95,7 +153,7 @@ mod tests {
     #[test]
     fn test_qualified_branch_id() {
         let tenant_id = TenantId::new("tenant_123");
-        let branch_id = super::BranchId::new("branch_456");
+        let branch_id = crate::BranchId::new("branch_456");
         let qid = QualifiedBranchId::new(tenant_id, branch_id);
 
         let serialized = qid.to_string();
@@ -104,4 +162,53 @@ mod tests {
         let parsed = QualifiedBranchId::from_string(&serialized).unwrap();
         assert_eq!(parsed, qid);
     }
+
+    fn tenant(status: TenantStatus, trial_expires_at: Option<chrono::DateTime<chrono::Utc>>) -> Tenant {
+        let now = chrono::Utc::now();
+        Tenant {
+            id: TenantId::new("tenant_a"),
+            name: "a".to_string(),
+            company_name: "A".to_string(),
+            contact_email: "ops@a.example".to_string(),
+            status,
+            max_branches: 10,
+            max_connections_per_branch: 5,
+            rate_limit_per_sec: 100,
+            database_schema: "tenant_a".to_string(),
+            e2e_required: false,
+            trial_expires_at,
+            created_at: now,
+            updated_at: now,
+        }
+    }
+
+    #[test]
+    fn test_tenant_usable_by_status_and_trial_expiry() {
+        let now = chrono::Utc::now();
+        let hour = chrono::Duration::hours(1);
+
+        assert!(tenant(TenantStatus::Active, None).is_usable(now));
+        assert!(!tenant(TenantStatus::Suspended, None).is_usable(now));
+        assert!(!tenant(TenantStatus::Inactive, None).is_usable(now));
+        assert!(tenant(TenantStatus::Trial, Some(now + hour)).is_usable(now));
+        assert!(!tenant(TenantStatus::Trial, Some(now - hour)).is_usable(now));
+    }
+
+    #[test]
+    fn test_trial_limits_only_reduce() {
+        let trial = crate::config::TrialConfig {
+            duration_secs: 86400,
+            max_branches: 2,
+            max_connections_per_branch: 10,
+            rate_limit_per_sec: 10,
+        };
+
+        let active = tenant(TenantStatus::Active, None).limits(&trial);
+        assert_eq!(active.max_branches, 10);
+
+        let limits = tenant(TenantStatus::Trial, None).limits(&trial);
+        assert_eq!(limits.max_branches, 2);
+        assert_eq!(limits.max_connections_per_branch, 5);
+        assert_eq!(limits.rate_limit_per_sec, 10);
+    }
 }
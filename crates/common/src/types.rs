@@ -40,6 +40,7 @@ pub struct BranchInfo {
     pub name: String,
     pub location: String,
     pub status: BranchStatus,
+    pub status_message: Option<String>,
     pub last_seen: DateTime<Utc>,
     pub metadata: HashMap<String, String>,
 }
@@ -53,6 +54,18 @@ pub enum BranchStatus {
     Error,
 }
 
+impl BranchStatus {
+    /// Value stored in the `branches.status` column
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BranchStatus::Online => "online",
+            BranchStatus::Offline => "offline",
+            BranchStatus::Syncing => "syncing",
+            BranchStatus::Error => "error",
+        }
+    }
+}
+
 /// Vector clock for distributed conflict resolution
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct VectorClock {
@@ -77,6 +90,13 @@ impl VectorClock {
 
     /// Returns true if self happened before other
     pub fn happens_before(&self, other: &VectorClock) -> bool {
+        // Entries missing from other count as 0
+        for (branch_id, &self_clock) in &self.clocks {
+            if self_clock > other.clocks.get(branch_id).copied().unwrap_or(0) {
+                return false;
+            }
+        }
+
         let mut less_than = false;
         for (branch_id, &other_clock) in &other.clocks {
             let self_clock = self.clocks.get(branch_id).copied().unwrap_or(0);
@@ -96,6 +116,58 @@ impl VectorClock {
     }
 }
 
+/// Revision of the branch-hub wire protocol, ordered by major then minor
+/// Written as "major.minor"; a trailing patch number (a crate version) is ignored
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ProtocolVersion {
+    pub major: u16,
+    pub minor: u16,
+}
+
+impl ProtocolVersion {
+    pub const fn new(major: u16, minor: u16) -> Self {
+        Self { major, minor }
+    }
+}
+
+impl std::fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+impl std::str::FromStr for ProtocolVersion {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || crate::Error::InvalidMessage(format!("Invalid protocol version: {:?}", s));
+        let mut parts = s.trim().split('.');
+        let major = parts.next().and_then(|p| p.parse().ok()).ok_or_else(invalid)?;
+        let minor = parts.next().and_then(|p| p.parse().ok()).ok_or_else(invalid)?;
+        match (parts.next(), parts.next()) {
+            (None, _) => {}
+            (Some(patch), None) if patch.parse::<u32>().is_ok() => {}
+            _ => return Err(invalid()),
+        }
+        Ok(Self { major, minor })
+    }
+}
+
+impl TryFrom<String> for ProtocolVersion {
+    type Error = crate::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<ProtocolVersion> for String {
+    fn from(version: ProtocolVersion) -> Self {
+        version.to_string()
+    }
+}
+
 /// Authentication token
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AuthToken {
@@ -108,7 +180,9 @@ pub struct AuthToken {
 /// Connection metadata
 #[derive(Debug, Clone)]
 pub struct ConnectionMetadata {
+    pub tenant_id: crate::TenantId,
     pub branch_id: BranchId,
+    pub session_id: String,
     pub connected_at: DateTime<Utc>,
     pub last_heartbeat: DateTime<Utc>,
     pub message_count: u64,
@@ -148,4 +222,51 @@ mod tests {
         assert!(clock1.is_concurrent(&clock2));
         assert!(clock2.is_concurrent(&clock1));
     }
+
+    #[test]
+    fn test_vector_clock_entries_missing_from_other() {
+        let mut clock1 = VectorClock::new();
+        let mut clock2 = VectorClock::new();
+
+        let branch_a = BranchId::new("branch_a");
+        let branch_b = BranchId::new("branch_b");
+
+        // clock1 has seen an event on branch_a that clock2 has not
+        clock1.increment(&branch_a);
+        clock1.increment(&branch_b);
+        clock2.increment(&branch_b);
+        clock2.increment(&branch_b);
+
+        assert!(!clock1.happens_before(&clock2));
+        assert!(!clock2.happens_before(&clock1));
+        assert!(clock1.is_concurrent(&clock2));
+    }
+
+    #[test]
+    fn test_protocol_version_parse_and_order() {
+        let v: ProtocolVersion = "1.2".parse().unwrap();
+        assert_eq!(v, ProtocolVersion::new(1, 2));
+        assert_eq!("0.1.0".parse::<ProtocolVersion>().unwrap(), ProtocolVersion::new(0, 1));
+        assert_eq!(v.to_string(), "1.2");
+        for bad in ["", "1", "1.x", "1.2.3.4", "1.2.beta", "test"] {
+            assert!(bad.parse::<ProtocolVersion>().is_err(), "{:?} parsed", bad);
+        }
+
+        assert!(ProtocolVersion::new(0, 9) < ProtocolVersion::new(1, 0));
+        assert!(ProtocolVersion::new(1, 10) > ProtocolVersion::new(1, 2));
+        assert_eq!(serde_json::to_string(&v).unwrap(), "\"1.2\"");
+    }
+
+    #[test]
+    fn test_branch_status_str_matches_serde() {
+        for status in [
+            BranchStatus::Online,
+            BranchStatus::Offline,
+            BranchStatus::Syncing,
+            BranchStatus::Error,
+        ] {
+            let json = serde_json::to_string(&status).unwrap();
+            assert_eq!(json, format!("\"{}\"", status.as_str()));
+        }
+    }
 }
@@ -20,7 +20,7 @@ pub fn calculate_hash(data: &[u8]) -> String {
 
 /// Exponential backoff calculation
 pub fn calculate_backoff_duration(attempt: u32, base_ms: u64, max_ms: u64) -> std::time::Duration {
-    let backoff_ms = base_ms * 2u64.pow(attempt);
+    let backoff_ms = base_ms.saturating_mul(2u64.saturating_pow(attempt));
     let capped_ms = backoff_ms.min(max_ms);
     std::time::Duration::from_millis(capped_ms)
 }
@@ -35,5 +35,6 @@ mod tests {
         assert_eq!(calculate_backoff_duration(1, 1000, 60000).as_millis(), 2000);
         assert_eq!(calculate_backoff_duration(2, 1000, 60000).as_millis(), 4000);
         assert_eq!(calculate_backoff_duration(10, 1000, 60000).as_millis(), 60000); // capped
+        assert_eq!(calculate_backoff_duration(80, 1000, 60000).as_millis(), 60000); // no overflow
     }
 }
@@ -0,0 +1,4 @@
+-- Track when a branch was last heard from, independent of row edits
+ALTER TABLE branches ADD COLUMN IF NOT EXISTS last_seen_at TIMESTAMP WITH TIME ZONE;
+
+CREATE INDEX IF NOT EXISTS idx_branches_last_seen_at ON branches(last_seen_at);
@@ -0,0 +1,2 @@
+-- Free-form detail reported alongside a branch status (e.g. last sync error)
+ALTER TABLE branches ADD COLUMN IF NOT EXISTS status_message TEXT;
@@ -0,0 +1,19 @@
+-- Server-side refresh tokens; only a SHA-256 of the token is stored.
+-- Each use rotates the token within its family; presenting a rotated token
+-- again revokes the whole family.
+CREATE TABLE IF NOT EXISTS refresh_tokens (
+    id VARCHAR(255) PRIMARY KEY,
+    family_id VARCHAR(255) NOT NULL,
+    tenant_id VARCHAR(255) NOT NULL REFERENCES tenants(id) ON DELETE CASCADE,
+    branch_id VARCHAR(255) NOT NULL,
+    token_hash VARCHAR(64) NOT NULL UNIQUE,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    revoked_at TIMESTAMP WITH TIME ZONE,
+    replaced_by VARCHAR(255),
+    FOREIGN KEY (tenant_id, branch_id) REFERENCES branches(tenant_id, id) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_refresh_tokens_family ON refresh_tokens(family_id);
+CREATE INDEX idx_refresh_tokens_tenant_branch ON refresh_tokens(tenant_id, branch_id);
+CREATE INDEX idx_refresh_tokens_expires_at ON refresh_tokens(expires_at);
@@ -0,0 +1,14 @@
+-- Ed25519 keys for signing access tokens, shared by all hub instances.
+-- A key signs between activates_at and retires_at and is published in the
+-- JWKS until expires_at, when the last token it signed has expired.
+CREATE TABLE IF NOT EXISTS signing_keys (
+    kid VARCHAR(255) PRIMARY KEY,
+    private_key BYTEA NOT NULL,
+    public_key BYTEA NOT NULL,
+    activates_at TIMESTAMP WITH TIME ZONE NOT NULL UNIQUE,
+    retires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_signing_keys_expires_at ON signing_keys(expires_at);
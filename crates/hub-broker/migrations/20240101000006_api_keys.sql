@@ -0,0 +1,28 @@
+-- Branch API keys: several per branch so keys can be rotated with overlap.
+-- Keys are presented as "<id>.<secret>"; only an argon2 hash of the whole key is stored.
+CREATE TABLE IF NOT EXISTS api_keys (
+    id VARCHAR(255) PRIMARY KEY,
+    tenant_id VARCHAR(255) NOT NULL REFERENCES tenants(id) ON DELETE CASCADE,
+    branch_id VARCHAR(255) NOT NULL,
+    label VARCHAR(255) NOT NULL,
+    key_hash TEXT NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    expires_at TIMESTAMP WITH TIME ZONE,
+    last_used_at TIMESTAMP WITH TIME ZONE,
+    revoked_at TIMESTAMP WITH TIME ZONE,
+    FOREIGN KEY (tenant_id, branch_id) REFERENCES branches(tenant_id, id) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_api_keys_tenant_branch ON api_keys(tenant_id, branch_id);
+
+-- Existing keys predate the id prefix and keep working until rotated
+INSERT INTO api_keys (id, tenant_id, branch_id, label, key_hash, created_at)
+SELECT 'legacy-' || tenant_id || '-' || id, tenant_id, id, 'legacy', api_key_hash, created_at
+FROM branches;
+
+ALTER TABLE branches DROP COLUMN api_key_hash;
+
+-- Lets revoking a key also end the token families minted from it
+ALTER TABLE refresh_tokens ADD COLUMN api_key_id VARCHAR(255) REFERENCES api_keys(id) ON DELETE CASCADE;
+
+CREATE INDEX idx_refresh_tokens_api_key ON refresh_tokens(api_key_id);
@@ -0,0 +1,7 @@
+-- Trial expiry for tenants on the `trial` plan
+ALTER TABLE tenants ADD COLUMN trial_expires_at TIMESTAMP WITH TIME ZONE;
+
+CREATE INDEX idx_tenants_trial_expires_at ON tenants(trial_expires_at) WHERE status = 'trial';
+
+-- create_tenant used to store the Debug form of the status ("Active")
+UPDATE tenants SET status = LOWER(status) WHERE status <> LOWER(status);
@@ -0,0 +1,3 @@
+-- Admin audit queries filter by tenant and time range, optionally by branch
+CREATE INDEX idx_audit_log_tenant_created ON audit_log(tenant_id, created_at DESC);
+CREATE INDEX idx_audit_log_tenant_branch ON audit_log(tenant_id, branch_id, created_at DESC);
@@ -0,0 +1,49 @@
+-- Row-level security on tenant data, as defense in depth behind the tenant_id
+-- filters in Storage. Tenant-scoped transactions run as hub_tenant with
+-- app.current_tenant set; the owning role (migrations, admin and maintenance
+-- queries) is not subject to these policies.
+
+DO $$
+BEGIN
+    IF NOT EXISTS (SELECT 1 FROM pg_roles WHERE rolname = 'hub_tenant') THEN
+        CREATE ROLE hub_tenant NOLOGIN;
+    END IF;
+END
+$$;
+
+-- Lets the application role SET ROLE hub_tenant; a superuser gives up its RLS bypass by doing so.
+-- A role without CREATEROLE needs hub_tenant created and granted to it (WITH ADMIN OPTION) beforehand.
+GRANT hub_tenant TO CURRENT_USER;
+
+GRANT USAGE ON SCHEMA public TO hub_tenant;
+GRANT SELECT, INSERT, UPDATE, DELETE ON ALL TABLES IN SCHEMA public TO hub_tenant;
+GRANT USAGE, SELECT ON ALL SEQUENCES IN SCHEMA public TO hub_tenant;
+ALTER DEFAULT PRIVILEGES IN SCHEMA public GRANT SELECT, INSERT, UPDATE, DELETE ON TABLES TO hub_tenant;
+ALTER DEFAULT PRIVILEGES IN SCHEMA public GRANT USAGE, SELECT ON SEQUENCES TO hub_tenant;
+
+-- Without app.current_tenant, current_setting(..., true) is NULL and no row matches
+
+ALTER TABLE branches ENABLE ROW LEVEL SECURITY;
+CREATE POLICY tenant_isolation ON branches TO hub_tenant
+    USING (tenant_id = current_setting('app.current_tenant', true))
+    WITH CHECK (tenant_id = current_setting('app.current_tenant', true));
+
+ALTER TABLE sync_transactions ENABLE ROW LEVEL SECURITY;
+CREATE POLICY tenant_isolation ON sync_transactions TO hub_tenant
+    USING (tenant_id = current_setting('app.current_tenant', true))
+    WITH CHECK (tenant_id = current_setting('app.current_tenant', true));
+
+ALTER TABLE offline_messages ENABLE ROW LEVEL SECURITY;
+CREATE POLICY tenant_isolation ON offline_messages TO hub_tenant
+    USING (tenant_id = current_setting('app.current_tenant', true))
+    WITH CHECK (tenant_id = current_setting('app.current_tenant', true));
+
+ALTER TABLE conflict_resolutions ENABLE ROW LEVEL SECURITY;
+CREATE POLICY tenant_isolation ON conflict_resolutions TO hub_tenant
+    USING (tenant_id = current_setting('app.current_tenant', true))
+    WITH CHECK (tenant_id = current_setting('app.current_tenant', true));
+
+ALTER TABLE audit_log ENABLE ROW LEVEL SECURITY;
+CREATE POLICY tenant_isolation ON audit_log TO hub_tenant
+    USING (tenant_id = current_setting('app.current_tenant', true))
+    WITH CHECK (tenant_id = current_setting('app.current_tenant', true));
@@ -0,0 +1,19 @@
+-- End-to-end payload encryption: a per-tenant requirement and the X25519 public
+-- keys branches register so peers can seal payloads for them. The hub only ever
+-- stores public keys.
+ALTER TABLE tenants ADD COLUMN e2e_required BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE TABLE IF NOT EXISTS branch_public_keys (
+    tenant_id VARCHAR(255) NOT NULL REFERENCES tenants(id) ON DELETE CASCADE,
+    branch_id VARCHAR(255) NOT NULL,
+    -- Base64 of the raw 32-byte key
+    public_key TEXT NOT NULL,
+    registered_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (tenant_id, branch_id),
+    FOREIGN KEY (tenant_id, branch_id) REFERENCES branches(tenant_id, id) ON DELETE CASCADE
+);
+
+ALTER TABLE branch_public_keys ENABLE ROW LEVEL SECURITY;
+CREATE POLICY tenant_isolation ON branch_public_keys TO hub_tenant
+    USING (tenant_id = current_setting('app.current_tenant', true))
+    WITH CHECK (tenant_id = current_setting('app.current_tenant', true));
@@ -0,0 +1,11 @@
+-- At-least-once delivery of routed messages: they stay queued until the target branch
+-- acknowledges them, are resent with backoff in the meantime, and are marked failed once
+-- attempts or the TTL run out so the sender can be told.
+ALTER TABLE offline_messages ADD COLUMN sender_branch_id VARCHAR(255);
+ALTER TABLE offline_messages ADD COLUMN attempts INTEGER NOT NULL DEFAULT 0;
+-- NULL while the target is offline; it is resent when the branch reconnects
+ALTER TABLE offline_messages ADD COLUMN next_attempt_at TIMESTAMP WITH TIME ZONE;
+ALTER TABLE offline_messages ADD COLUMN failed_at TIMESTAMP WITH TIME ZONE;
+
+CREATE INDEX idx_offline_messages_awaiting_ack ON offline_messages(tenant_id, next_attempt_at)
+    WHERE sender_branch_id IS NOT NULL AND delivered_at IS NULL AND failed_at IS NULL;
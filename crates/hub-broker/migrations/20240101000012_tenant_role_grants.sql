@@ -0,0 +1,36 @@
+-- hub_tenant only gets the tables row-level security confines it to. The schema-wide
+-- grants of the first RLS migration also reached signing_keys (private keys),
+-- api_keys, refresh_tokens, tenants and _sqlx_migrations, none of which had a policy.
+
+REVOKE ALL ON ALL TABLES IN SCHEMA public FROM hub_tenant;
+-- New tables get no access until the migration adding them grants it with a policy
+ALTER DEFAULT PRIVILEGES IN SCHEMA public REVOKE ALL ON TABLES FROM hub_tenant;
+
+ALTER TABLE api_keys ENABLE ROW LEVEL SECURITY;
+CREATE POLICY tenant_isolation ON api_keys TO hub_tenant
+    USING (tenant_id = current_setting('app.current_tenant', true))
+    WITH CHECK (tenant_id = current_setting('app.current_tenant', true));
+
+ALTER TABLE refresh_tokens ENABLE ROW LEVEL SECURITY;
+CREATE POLICY tenant_isolation ON refresh_tokens TO hub_tenant
+    USING (tenant_id = current_setting('app.current_tenant', true))
+    WITH CHECK (tenant_id = current_setting('app.current_tenant', true));
+
+-- Tenant-scoped queries check their own tenant row and lock it for quota checks
+ALTER TABLE tenants ENABLE ROW LEVEL SECURITY;
+CREATE POLICY tenant_isolation ON tenants TO hub_tenant
+    USING (id = current_setting('app.current_tenant', true));
+
+GRANT SELECT, INSERT, UPDATE, DELETE ON
+    branches,
+    sync_transactions,
+    offline_messages,
+    conflict_resolutions,
+    audit_log,
+    branch_public_keys,
+    api_keys,
+    refresh_tokens
+TO hub_tenant;
+
+-- SELECT ... FOR UPDATE needs UPDATE on some column; the policy pins id to the current tenant
+GRANT SELECT, UPDATE (id) ON tenants TO hub_tenant;
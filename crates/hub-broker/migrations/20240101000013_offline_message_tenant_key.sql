@@ -0,0 +1,5 @@
+-- Message IDs are chosen by the sending branch, so they are only unique within a tenant.
+-- Keyed by id alone, a message of one tenant kept another tenant's with the same ID out
+-- of the queue without either sender being told.
+ALTER TABLE offline_messages DROP CONSTRAINT offline_messages_pkey;
+ALTER TABLE offline_messages ADD PRIMARY KEY (tenant_id, id);
@@ -0,0 +1,3 @@
+-- A tenant's audit trail, and the record of who deleted the tenant, outlive the tenant.
+-- Deleted tenants' IDs stay in use by these rows and are not handed out again.
+ALTER TABLE audit_log DROP CONSTRAINT audit_log_tenant_id_fkey;
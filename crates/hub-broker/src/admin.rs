@@ -0,0 +1,1412 @@
+use axum::{
+    extract::{ConnectInfo, MatchedPath, Path, Query, RawPathParams, Request, State},
+    http::{header, HeaderName, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Json, Response},
+    routing::{delete, get, post, put},
+    Extension, Router,
+};
+use common::{BranchId, BranchStatus, QualifiedBranchId, Tenant, TenantId, TenantStatus};
+use protocol::{DisconnectReason, NotificationLevel, SystemNotification};
+use serde::Deserialize;
+use std::net::SocketAddr;
+use tracing::{info, warn};
+
+use crate::{
+    admin_auth::{self, AdminIdentity, AdminRole},
+    audit::{self, AuditEvent},
+    auth, routing,
+    server::AppState,
+    storage::{AuditFilter, NewApiKey, TenantFilter, TenantUpdate},
+    tenants,
+};
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 500;
+/// Most audit entries one export returns
+const MAX_EXPORT_ROWS: i64 = 50_000;
+/// Leaves room for the `tenant_` schema prefix within PostgreSQL's 63-byte identifiers
+const MAX_ID_LEN: usize = 48;
+/// Longest lifetime an API key can be issued with
+const MAX_API_KEY_LIFETIME_SECS: i64 = 10 * 365 * 86_400;
+/// Longest a rotated API key may keep working next to its replacement
+const MAX_API_KEY_OVERLAP_SECS: i64 = 30 * 86_400;
+
+/// Admin routes, mounted under `/admin`
+pub fn router(state: AppState) -> Router<AppState> {
+    Router::new()
+        .route("/branches", get(list_branches))
+        .route("/tenants", get(list_tenants).post(create_tenant))
+        .route(
+            "/tenants/:tenant_id",
+            get(get_tenant).patch(update_tenant).delete(delete_tenant),
+        )
+        .route("/tenants/:tenant_id/status", put(set_tenant_status))
+        .route(
+            "/tenants/:tenant_id/branches",
+            get(list_tenant_branches).post(create_branch),
+        )
+        .route(
+            "/tenants/:tenant_id/branches/:branch_id",
+            get(get_branch).patch(update_branch).delete(delete_branch),
+        )
+        .route("/tenants/:tenant_id/branches/:branch_id/status", get(branch_status))
+        .route("/tenants/:tenant_id/public-keys", get(list_public_keys))
+        .route(
+            "/tenants/:tenant_id/branches/:branch_id/public-key",
+            delete(delete_public_key),
+        )
+        .route("/tenants/:tenant_id/conflicts", get(list_conflicts))
+        .route("/tenants/:tenant_id/metrics", get(tenant_metrics))
+        .route("/tenants/:tenant_id/audit", get(list_audit_events))
+        .route("/tenants/:tenant_id/audit/export", get(export_audit_events))
+        .route("/notifications", post(send_notification))
+        .route("/tokens", post(issue_admin_token))
+        .route(
+            "/tenants/:tenant_id/branches/:branch_id/api-keys",
+            get(list_api_keys).post(issue_api_key),
+        )
+        .route(
+            "/tenants/:tenant_id/branches/:branch_id/api-keys/:key_id",
+            delete(revoke_api_key),
+        )
+        .route(
+            "/tenants/:tenant_id/branches/:branch_id/api-keys/:key_id/rotate",
+            post(rotate_api_key),
+        )
+        .route_layer(middleware::from_fn_with_state(state, require_admin))
+}
+
+/// Requires `Authorization: Bearer` with `ADMIN_API_TOKEN` or an admin JWT whose role
+/// allows the route; hands the caller to handlers as an `AdminIdentity` extension
+/// CRITICAL: A scoped identity only reaches routes of its own tenant
+async fn require_admin(
+    State(state): State<AppState>,
+    matched_path: MatchedPath,
+    params: RawPathParams,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let mut identity = match admin_auth::authenticate(
+        request.headers(),
+        state.config.security.admin_token.as_deref(),
+        &state.keys,
+    ) {
+        Ok(identity) => identity,
+        Err(e) => {
+            warn!("Refused admin request to {}: {}", request.uri().path(), e);
+            return Rejection::from(StatusCode::UNAUTHORIZED).into_response();
+        }
+    };
+
+    let path_tenant = params
+        .iter()
+        .find(|(name, _)| *name == "tenant_id")
+        .map(|(_, value)| TenantId::new(value));
+    if let Err(e) = admin_auth::authorize(
+        &identity,
+        request.method(),
+        matched_path.as_str(),
+        path_tenant.as_ref(),
+    ) {
+        warn!(
+            "Refused {} {} for {}: {}",
+            request.method(),
+            request.uri().path(),
+            identity.subject,
+            e
+        );
+        let message = match e {
+            common::Error::AuthorizationFailed(reason) => reason,
+            e => e.to_string(),
+        };
+        return Rejection::new(StatusCode::FORBIDDEN, message).into_response();
+    }
+
+    identity.remote_ip = audit::client_ip(
+        request.headers(),
+        peer.map(|ConnectInfo(addr)| addr),
+        state.config.security.trust_forwarded_proto,
+    );
+    request.extensions_mut().insert(identity);
+    next.run(request).await
+}
+
+/// Error response with a JSON `{"error": ...}` body
+#[derive(Debug)]
+pub struct Rejection {
+    status: StatusCode,
+    message: String,
+}
+
+impl Rejection {
+    fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+
+    fn invalid(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, message)
+    }
+}
+
+impl From<StatusCode> for Rejection {
+    fn from(status: StatusCode) -> Self {
+        Self::new(status, status.canonical_reason().unwrap_or("Error"))
+    }
+}
+
+impl IntoResponse for Rejection {
+    fn into_response(self) -> Response {
+        (self.status, Json(serde_json::json!({ "error": self.message }))).into_response()
+    }
+}
+
+/// Map storage failures to responses: missing rows, duplicates, quotas and references
+fn storage_error(context: &str) -> impl FnOnce(common::Error) -> Rejection + '_ {
+    move |e| match e {
+        common::Error::DatabaseError(sqlx::Error::RowNotFound) => StatusCode::NOT_FOUND.into(),
+        common::Error::QuotaExceeded(reason) => Rejection::new(StatusCode::CONFLICT, reason),
+        common::Error::DatabaseError(sqlx::Error::Database(db)) if db.is_unique_violation() => {
+            Rejection::new(StatusCode::CONFLICT, "Already exists")
+        }
+        common::Error::DatabaseError(sqlx::Error::Database(db)) if db.is_foreign_key_violation() => {
+            Rejection::new(StatusCode::CONFLICT, "Still referenced by sync history")
+        }
+        e => {
+            warn!("{}: {}", context, e);
+            StatusCode::INTERNAL_SERVER_ERROR.into()
+        }
+    }
+}
+
+/// Resolve `limit`/`offset` query parameters
+fn page(limit: Option<i64>, offset: Option<i64>) -> Result<(i64, i64), Rejection> {
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    let offset = offset.unwrap_or(0);
+    if !(1..=MAX_PAGE_SIZE).contains(&limit) {
+        return Err(Rejection::invalid(format!("limit must be between 1 and {}", MAX_PAGE_SIZE)));
+    }
+    if offset < 0 {
+        return Err(Rejection::invalid("offset must not be negative"));
+    }
+    Ok((limit, offset))
+}
+
+/// Tenant and branch IDs end up in composite keys (`tenant:branch`), URIs and schema names
+fn validate_id(field: &str, id: &str) -> Result<(), Rejection> {
+    let valid = !id.is_empty()
+        && id.len() <= MAX_ID_LEN
+        && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if valid {
+        Ok(())
+    } else {
+        Err(Rejection::invalid(format!(
+            "{} must be 1-{} characters of letters, digits, '_' or '-'",
+            field, MAX_ID_LEN
+        )))
+    }
+}
+
+fn validate_text(field: &str, value: &str) -> Result<(), Rejection> {
+    if value.trim().is_empty() || value.len() > 255 {
+        return Err(Rejection::invalid(format!("{} must be 1-255 characters", field)));
+    }
+    Ok(())
+}
+
+fn validate_email(value: &str) -> Result<(), Rejection> {
+    validate_text("contact_email", value)?;
+    match value.split_once('@') {
+        Some((local, domain)) if !local.is_empty() && domain.contains('.') && !domain.contains('@') => Ok(()),
+        _ => Err(Rejection::invalid("contact_email is not a valid address")),
+    }
+}
+
+fn validate_limit(field: &str, value: Option<u64>) -> Result<(), Rejection> {
+    match value {
+        Some(v) if v == 0 || v > i32::MAX as u64 => {
+            Err(Rejection::invalid(format!("{} must be between 1 and {}", field, i32::MAX)))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// When a period of `secs` from now ends, for a period of 1 to `max` seconds
+fn deadline(field: &str, secs: i64, max: i64) -> Result<chrono::DateTime<chrono::Utc>, Rejection> {
+    (1..=max)
+        .contains(&secs)
+        .then(|| chrono::Duration::try_seconds(secs))
+        .flatten()
+        .and_then(|period| chrono::Utc::now().checked_add_signed(period))
+        .ok_or_else(|| Rejection::invalid(format!("{} must be between 1 and {}", field, max)))
+}
+
+/// Build an audit log filter, rejecting an empty time range
+fn audit_filter(
+    tenant_id: TenantId,
+    branch_id: Option<String>,
+    event_type: Option<String>,
+    from: Option<chrono::DateTime<chrono::Utc>>,
+    until: Option<chrono::DateTime<chrono::Utc>>,
+) -> Result<AuditFilter, Rejection> {
+    if matches!((from, until), (Some(from), Some(until)) if from >= until) {
+        return Err(Rejection::invalid("from must be before until"));
+    }
+
+    Ok(AuditFilter {
+        tenant_id,
+        branch_id: branch_id.map(BranchId::new),
+        event_type,
+        from,
+        until,
+    })
+}
+
+/// Dedicated PostgreSQL schema for a tenant's data
+fn schema_for(tenant_id: &TenantId) -> String {
+    let id = tenant_id.as_str().to_ascii_lowercase().replace('-', "_");
+    if id.starts_with("tenant_") {
+        id
+    } else {
+        format!("tenant_{}", id)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IssueApiKeyRequest {
+    pub label: String,
+    pub expires_in_secs: Option<i64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct RotateApiKeyRequest {
+    /// Defaults to the old key's label
+    pub label: Option<String>,
+    /// Defaults to `API_KEY_ROTATION_OVERLAP`
+    pub overlap_secs: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TenantStatusRequest {
+    pub status: TenantStatus,
+    /// Only for `trial`; defaults to `TRIAL_DURATION` from now
+    pub trial_expires_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TenantListQuery {
+    pub status: Option<TenantStatus>,
+    /// Substring of the tenant ID, name or company name
+    pub search: Option<String>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BranchListQuery {
+    pub status: Option<BranchStatus>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateTenantRequest {
+    /// Generated when omitted
+    pub id: Option<String>,
+    pub name: String,
+    pub company_name: String,
+    pub contact_email: String,
+    /// `active` (default) or `trial`
+    pub status: Option<TenantStatus>,
+    pub trial_expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub max_branches: Option<usize>,
+    pub max_connections_per_branch: Option<usize>,
+    pub rate_limit_per_sec: Option<u32>,
+    /// Refuse branch data that is not sealed end to end
+    #[serde(default)]
+    pub e2e_required: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateBranchRequest {
+    pub id: String,
+    pub name: String,
+    pub location: Option<String>,
+    /// Label of the branch's first API key
+    pub api_key_label: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateBranchRequest {
+    pub name: Option<String>,
+    pub location: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ConflictListQuery {
+    /// Only conflicts still awaiting resolution
+    #[serde(default)]
+    pub unresolved: bool,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuditQuery {
+    pub branch_id: Option<String>,
+    pub event_type: Option<String>,
+    /// Inclusive, RFC 3339
+    pub from: Option<chrono::DateTime<chrono::Utc>>,
+    /// Exclusive, RFC 3339
+    pub until: Option<chrono::DateTime<chrono::Utc>>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Csv,
+    #[default]
+    Ndjson,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuditExportQuery {
+    pub branch_id: Option<String>,
+    pub event_type: Option<String>,
+    pub from: Option<chrono::DateTime<chrono::Utc>>,
+    pub until: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(default)]
+    pub format: ExportFormat,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IssueAdminTokenRequest {
+    /// Who the token is for; shows up in logs and audit entries
+    pub subject: String,
+    pub role: AdminRole,
+    /// Required for `tenant_admin`, optional for `auditor`
+    pub tenant_id: Option<String>,
+    /// Defaults to `ADMIN_TOKEN_EXPIRY`
+    pub expires_in_secs: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NotificationRequest {
+    pub target: routing::NotificationTarget,
+    pub level: NotificationLevel,
+    pub message: String,
+    #[serde(default)]
+    pub action_required: bool,
+}
+
+/// Live sessions on this hub; a scoped identity only sees its own tenant's
+pub async fn list_branches(
+    State(state): State<AppState>,
+    Extension(identity): Extension<AdminIdentity>,
+) -> Json<serde_json::Value> {
+    let connections = state
+        .connection_manager
+        .list_connections(identity.tenant_id.as_ref())
+        .await;
+    Json(serde_json::json!({
+        "total": connections.len(),
+        "branches": connections,
+    }))
+}
+
+pub async fn branch_status(
+    State(state): State<AppState>,
+    Path((tenant_id, branch_id)): Path<(String, String)>,
+) -> Json<serde_json::Value> {
+    let id = QualifiedBranchId::new(
+        TenantId::new(tenant_id),
+        BranchId::new(branch_id),
+    );
+    let is_connected = state.connection_manager.is_connected(&id).await;
+
+    Json(serde_json::json!({
+        "tenant_id": id.tenant_id.as_str(),
+        "branch_id": id.branch_id.as_str(),
+        "connected": is_connected,
+    }))
+}
+
+/// Persisted status, last-seen and reported metadata for a page of a tenant's branches
+pub async fn list_tenant_branches(
+    State(state): State<AppState>,
+    Path(tenant_id): Path<String>,
+    Query(query): Query<BranchListQuery>,
+) -> std::result::Result<Json<serde_json::Value>, Rejection> {
+    let tenant_id = TenantId::new(tenant_id);
+    let (limit, offset) = page(query.limit, query.offset)?;
+    let (branches, total) = state
+        .storage
+        .list_branches_page(&tenant_id, query.status, limit, offset)
+        .await
+        .map_err(storage_error("Failed to list branches"))?;
+
+    let mut entries = Vec::with_capacity(branches.len());
+    for branch in branches {
+        let id = QualifiedBranchId::new(tenant_id.clone(), branch.id.clone());
+        let connected = state.connection_manager.is_connected(&id).await;
+        entries.push(serde_json::json!({
+            "branch": branch,
+            "connected": connected,
+        }));
+    }
+
+    Ok(Json(serde_json::json!({
+        "tenant_id": tenant_id.as_str(),
+        "total": total,
+        "limit": limit,
+        "offset": offset,
+        "branches": entries,
+    })))
+}
+
+/// Change a tenant's status; suspending or deactivating it drops its live sessions at once
+pub async fn set_tenant_status(
+    State(state): State<AppState>,
+    Extension(identity): Extension<AdminIdentity>,
+    Path(tenant_id): Path<String>,
+    Json(request): Json<TenantStatusRequest>,
+) -> std::result::Result<Json<serde_json::Value>, Rejection> {
+    let tenant_id = TenantId::new(tenant_id);
+    let trial_expires_at = (request.status == TenantStatus::Trial).then(|| {
+        request.trial_expires_at.unwrap_or_else(|| {
+            chrono::Utc::now() + chrono::Duration::seconds(state.config.trial.duration_secs)
+        })
+    });
+
+    let updated = state
+        .storage
+        .set_tenant_status(&tenant_id, request.status, trial_expires_at)
+        .await
+        .map_err(storage_error("Failed to update tenant status"))?;
+    if !updated {
+        return Err(StatusCode::NOT_FOUND.into());
+    }
+
+    let tenant = state
+        .storage
+        .get_tenant(&tenant_id)
+        .await
+        .map_err(storage_error("Failed to load tenant"))?;
+    let sessions_closed = tenants::enforce_status(&state.connection_manager, &tenant).await;
+
+    info!("Tenant {} is now {}", tenant_id, tenant.status.as_str());
+    audit(
+        &state,
+        &identity,
+        &tenant_id,
+        None,
+        audit::TENANT_STATUS_CHANGED,
+        serde_json::json!({
+            "status": tenant.status,
+            "trial_expires_at": tenant.trial_expires_at,
+            "sessions_closed": sessions_closed,
+        }),
+    )
+    .await;
+
+    Ok(Json(serde_json::json!({
+        "tenant_id": tenant_id.as_str(),
+        "status": tenant.status,
+        "trial_expires_at": tenant.trial_expires_at,
+        "sessions_closed": sessions_closed,
+    })))
+}
+
+/// Push a SystemNotification to one branch, a tenant, or every tenant
+pub async fn send_notification(
+    State(state): State<AppState>,
+    Extension(identity): Extension<AdminIdentity>,
+    Json(request): Json<NotificationRequest>,
+) -> std::result::Result<Json<serde_json::Value>, Rejection> {
+    // ENFORCES: A tenant admin only notifies its own branches, never every tenant
+    let allowed = match &request.target {
+        routing::NotificationTarget::Branch { tenant_id, .. }
+        | routing::NotificationTarget::Tenant { tenant_id } => identity.can_access_tenant(tenant_id),
+        routing::NotificationTarget::All => identity.tenant_id.is_none(),
+    };
+    if !allowed {
+        warn!("Refused notification to {:?} from {}", request.target, identity.subject);
+        return Err(StatusCode::FORBIDDEN.into());
+    }
+
+    let notification = SystemNotification {
+        level: request.level,
+        message: request.message,
+        action_required: request.action_required,
+    };
+
+    let report = state
+        .message_router
+        .send_notification(&request.target, notification)
+        .await
+        .map_err(storage_error("Failed to send notification"))?;
+
+    Ok(Json(serde_json::json!({
+        "recipients": report.recipients,
+        "delivered": report.delivered,
+        "withheld": report.withheld,
+    })))
+}
+
+/// Record an admin action under the tenant it touched, with who did it and from where
+async fn audit(
+    state: &AppState,
+    identity: &AdminIdentity,
+    tenant_id: &TenantId,
+    branch_id: Option<&BranchId>,
+    event_type: &'static str,
+    details: serde_json::Value,
+) {
+    let mut event = admin_event(identity, tenant_id, event_type, details);
+    if let Some(branch_id) = branch_id {
+        event = event.branch(branch_id);
+    }
+    audit::record(&state.storage, event).await;
+}
+
+/// Audit entry for an admin action, naming the caller
+fn admin_event(
+    identity: &AdminIdentity,
+    tenant_id: &TenantId,
+    event_type: &'static str,
+    mut details: serde_json::Value,
+) -> AuditEvent {
+    if let Some(details) = details.as_object_mut() {
+        details.insert("actor".to_string(), identity.subject.clone().into());
+        details.insert("actor_role".to_string(), serde_json::json!(identity.role));
+    }
+    AuditEvent::new(tenant_id, event_type).data(details).ip(identity.remote_ip)
+}
+
+/// Key metadata for a branch; hashes and plaintexts are never returned
+pub async fn list_api_keys(
+    State(state): State<AppState>,
+    Path((tenant_id, branch_id)): Path<(String, String)>,
+) -> std::result::Result<Json<serde_json::Value>, Rejection> {
+    let tenant_id = TenantId::new(tenant_id);
+    let branch_id = BranchId::new(branch_id);
+    let keys = state
+        .storage
+        .list_api_keys(&tenant_id, &branch_id)
+        .await
+        .map_err(storage_error("Failed to list API keys"))?;
+
+    Ok(Json(serde_json::json!({
+        "tenant_id": tenant_id.as_str(),
+        "branch_id": branch_id.as_str(),
+        "api_keys": keys,
+    })))
+}
+
+/// Issue a new key; the plaintext is only ever in this response
+pub async fn issue_api_key(
+    State(state): State<AppState>,
+    Extension(identity): Extension<AdminIdentity>,
+    Path((tenant_id, branch_id)): Path<(String, String)>,
+    Json(request): Json<IssueApiKeyRequest>,
+) -> std::result::Result<Json<auth::IssuedApiKey>, Rejection> {
+    let id = QualifiedBranchId::new(
+        TenantId::new(tenant_id),
+        BranchId::new(branch_id),
+    );
+    validate_text("label", &request.label)?;
+    let expires_at = request
+        .expires_in_secs
+        .map(|secs| deadline("expires_in_secs", secs, MAX_API_KEY_LIFETIME_SECS))
+        .transpose()?;
+
+    state
+        .storage
+        .get_branch(&id.tenant_id, &id.branch_id)
+        .await
+        .map_err(storage_error("Failed to load branch"))?;
+
+    let issued = auth::issue_api_key(&state.storage, &id.tenant_id, &id.branch_id, &request.label, expires_at)
+        .await
+        .map_err(storage_error("Failed to issue API key"))?;
+
+    audit(
+        &state,
+        &identity,
+        &id.tenant_id,
+        Some(&id.branch_id),
+        audit::API_KEY_ISSUED,
+        serde_json::json!({ "key_id": issued.id }),
+    )
+    .await;
+    Ok(Json(issued))
+}
+
+/// Issue a replacement key; the old one keeps working for the overlap window
+pub async fn rotate_api_key(
+    State(state): State<AppState>,
+    Extension(identity): Extension<AdminIdentity>,
+    Path((tenant_id, branch_id, key_id)): Path<(String, String, String)>,
+    request: Option<Json<RotateApiKeyRequest>>,
+) -> std::result::Result<Json<serde_json::Value>, Rejection> {
+    let id = QualifiedBranchId::new(
+        TenantId::new(tenant_id),
+        BranchId::new(branch_id),
+    );
+    let request = request.map(|Json(r)| r).unwrap_or_default();
+    if let Some(label) = &request.label {
+        validate_text("label", label)?;
+    }
+    let overlap = request
+        .overlap_secs
+        .unwrap_or(state.config.security.api_key_rotation_overlap_secs);
+    let old_expires_at = deadline("overlap_secs", overlap, MAX_API_KEY_OVERLAP_SECS)?;
+
+    let old = state
+        .storage
+        .get_api_key(&id.tenant_id, &id.branch_id, &key_id)
+        .await
+        .map_err(storage_error("Failed to load API key"))?
+        .filter(|key| key.is_usable())
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let label = request.label.unwrap_or_else(|| old.label.clone());
+    let issued = auth::issue_api_key(&state.storage, &id.tenant_id, &id.branch_id, &label, None)
+        .await
+        .map_err(storage_error("Failed to issue API key"))?;
+
+    state
+        .storage
+        .expire_api_key_at(&id.tenant_id, &id.branch_id, &old.id, old_expires_at)
+        .await
+        .map_err(storage_error("Failed to expire rotated API key"))?;
+
+    audit(
+        &state,
+        &identity,
+        &id.tenant_id,
+        Some(&id.branch_id),
+        audit::API_KEY_ROTATED,
+        serde_json::json!({ "key_id": old.id, "replaced_by": issued.id }),
+    )
+    .await;
+
+    Ok(Json(serde_json::json!({
+        "api_key": issued,
+        "previous": {
+            "id": old.id,
+            "expires_at": old.expires_at.map_or(old_expires_at, |e| e.min(old_expires_at)),
+        },
+    })))
+}
+
+/// Revoke a key, end the token families obtained with it and drop its live sessions
+pub async fn revoke_api_key(
+    State(state): State<AppState>,
+    Extension(identity): Extension<AdminIdentity>,
+    Path((tenant_id, branch_id, key_id)): Path<(String, String, String)>,
+) -> std::result::Result<Json<serde_json::Value>, Rejection> {
+    let id = QualifiedBranchId::new(
+        TenantId::new(tenant_id),
+        BranchId::new(branch_id),
+    );
+
+    let revoked = state
+        .storage
+        .revoke_api_key(&id.tenant_id, &id.branch_id, &key_id)
+        .await
+        .map_err(storage_error("Failed to revoke API key"))?;
+    if !revoked {
+        return Err(StatusCode::NOT_FOUND.into());
+    }
+
+    let refresh_tokens_revoked = state
+        .storage
+        .revoke_refresh_tokens_for_api_key(&id.tenant_id, &key_id)
+        .await
+        .map_err(storage_error("Failed to revoke refresh tokens"))?;
+
+    let sessions_closed = state
+        .connection_manager
+        .close_sessions_for_api_key(
+            &id,
+            &key_id,
+            DisconnectReason::new(
+                DisconnectReason::CREDENTIALS_REVOKED,
+                "API key revoked",
+            ),
+        )
+        .await;
+
+    info!(
+        "Revoked API key {} of {}: {} sessions closed, {} refresh tokens revoked",
+        key_id, id, sessions_closed, refresh_tokens_revoked
+    );
+    audit(
+        &state,
+        &identity,
+        &id.tenant_id,
+        Some(&id.branch_id),
+        audit::API_KEY_REVOKED,
+        serde_json::json!({ "key_id": key_id }),
+    )
+    .await;
+
+    Ok(Json(serde_json::json!({
+        "key_id": key_id,
+        "sessions_closed": sessions_closed,
+        "refresh_tokens_revoked": refresh_tokens_revoked,
+    })))
+}
+
+/// Page through tenants, optionally filtered by status or a search string
+pub async fn list_tenants(
+    State(state): State<AppState>,
+    Query(query): Query<TenantListQuery>,
+) -> std::result::Result<Json<serde_json::Value>, Rejection> {
+    let (limit, offset) = page(query.limit, query.offset)?;
+    let filter = TenantFilter {
+        status: query.status,
+        search: query.search.filter(|s| !s.is_empty()),
+    };
+
+    let (tenants, total) = state
+        .storage
+        .list_tenants(&filter, limit, offset)
+        .await
+        .map_err(storage_error("Failed to list tenants"))?;
+
+    Ok(Json(serde_json::json!({
+        "total": total,
+        "limit": limit,
+        "offset": offset,
+        "tenants": tenants,
+    })))
+}
+
+/// Create a tenant together with its data schema
+pub async fn create_tenant(
+    State(state): State<AppState>,
+    Extension(identity): Extension<AdminIdentity>,
+    Json(request): Json<CreateTenantRequest>,
+) -> std::result::Result<(StatusCode, Json<Tenant>), Rejection> {
+    let id = request.id.map(TenantId::new).unwrap_or_else(TenantId::generate);
+    validate_id("id", id.as_str())?;
+    validate_text("name", &request.name)?;
+    validate_text("company_name", &request.company_name)?;
+    validate_email(&request.contact_email)?;
+    validate_limit("max_branches", request.max_branches.map(|v| v as u64))?;
+    validate_limit("max_connections_per_branch", request.max_connections_per_branch.map(|v| v as u64))?;
+    validate_limit("rate_limit_per_sec", request.rate_limit_per_sec.map(u64::from))?;
+
+    let status = request.status.unwrap_or(TenantStatus::Active);
+    let trial_expires_at = match status {
+        TenantStatus::Active => None,
+        TenantStatus::Trial => Some(request.trial_expires_at.unwrap_or_else(|| {
+            chrono::Utc::now() + chrono::Duration::seconds(state.config.trial.duration_secs)
+        })),
+        _ => return Err(Rejection::invalid("New tenants must be active or trial")),
+    };
+
+    // Its audit trail would show up under the new tenant
+    if state
+        .storage
+        .tenant_id_has_history(&id)
+        .await
+        .map_err(storage_error("Failed to check tenant ID"))?
+    {
+        return Err(Rejection::new(StatusCode::CONFLICT, "ID belongs to a deleted tenant"));
+    }
+
+    let now = chrono::Utc::now();
+    let tenant = Tenant {
+        database_schema: schema_for(&id),
+        id,
+        name: request.name,
+        company_name: request.company_name,
+        contact_email: request.contact_email,
+        status,
+        max_branches: request.max_branches.unwrap_or(10),
+        max_connections_per_branch: request.max_connections_per_branch.unwrap_or(5),
+        rate_limit_per_sec: request.rate_limit_per_sec.unwrap_or(100),
+        e2e_required: request.e2e_required,
+        trial_expires_at,
+        created_at: now,
+        updated_at: now,
+    };
+
+    state
+        .storage
+        .create_tenant(&tenant)
+        .await
+        .map_err(storage_error("Failed to create tenant"))?;
+
+    audit(
+        &state,
+        &identity,
+        &tenant.id,
+        None,
+        audit::TENANT_CREATED,
+        serde_json::json!({ "status": tenant.status, "database_schema": tenant.database_schema }),
+    )
+    .await;
+
+    Ok((StatusCode::CREATED, Json(tenant)))
+}
+
+/// A tenant with the limits currently in force and its branch and session counts
+pub async fn get_tenant(
+    State(state): State<AppState>,
+    Path(tenant_id): Path<String>,
+) -> std::result::Result<Json<serde_json::Value>, Rejection> {
+    let tenant_id = TenantId::new(tenant_id);
+    let tenant = state
+        .storage
+        .get_tenant(&tenant_id)
+        .await
+        .map_err(storage_error("Failed to load tenant"))?;
+    let (_, branches) = state
+        .storage
+        .list_branches_page(&tenant_id, None, 1, 0)
+        .await
+        .map_err(storage_error("Failed to count branches"))?;
+    let sessions = state.connection_manager.tenant_connection_count(&tenant_id).await;
+
+    Ok(Json(serde_json::json!({
+        "limits": tenant.limits(&state.config.trial),
+        "tenant": tenant,
+        "branches": branches,
+        "sessions": sessions,
+    })))
+}
+
+/// Update a tenant's contact details or limits
+pub async fn update_tenant(
+    State(state): State<AppState>,
+    Extension(identity): Extension<AdminIdentity>,
+    Path(tenant_id): Path<String>,
+    Json(update): Json<TenantUpdate>,
+) -> std::result::Result<Json<Tenant>, Rejection> {
+    let tenant_id = TenantId::new(tenant_id);
+    if let Some(name) = &update.name {
+        validate_text("name", name)?;
+    }
+    if let Some(company_name) = &update.company_name {
+        validate_text("company_name", company_name)?;
+    }
+    if let Some(contact_email) = &update.contact_email {
+        validate_email(contact_email)?;
+    }
+    validate_limit("max_branches", update.max_branches.map(|v| v as u64))?;
+    validate_limit("max_connections_per_branch", update.max_connections_per_branch.map(|v| v as u64))?;
+    validate_limit("rate_limit_per_sec", update.rate_limit_per_sec.map(u64::from))?;
+
+    let updated = state
+        .storage
+        .update_tenant(&tenant_id, &update)
+        .await
+        .map_err(storage_error("Failed to update tenant"))?;
+    if !updated {
+        return Err(StatusCode::NOT_FOUND.into());
+    }
+
+    audit(&state, &identity, &tenant_id, None, audit::TENANT_UPDATED, serde_json::json!(update)).await;
+
+    let tenant = state
+        .storage
+        .get_tenant(&tenant_id)
+        .await
+        .map_err(storage_error("Failed to load tenant"))?;
+    Ok(Json(tenant))
+}
+
+/// Delete a tenant, its branches and its data schema.
+/// Only suspended or inactive tenants can be deleted; their audit log is kept.
+pub async fn delete_tenant(
+    State(state): State<AppState>,
+    Extension(identity): Extension<AdminIdentity>,
+    Path(tenant_id): Path<String>,
+) -> std::result::Result<StatusCode, Rejection> {
+    let tenant_id = TenantId::new(tenant_id);
+    let tenant = state
+        .storage
+        .get_tenant(&tenant_id)
+        .await
+        .map_err(storage_error("Failed to load tenant"))?;
+
+    if !matches!(tenant.status, TenantStatus::Suspended | TenantStatus::Inactive) {
+        return Err(Rejection::new(
+            StatusCode::CONFLICT,
+            "Suspend or deactivate the tenant before deleting it",
+        ));
+    }
+
+    let reason = DisconnectReason::new(DisconnectReason::TENANT_INACTIVE, "Tenant deleted");
+    state.connection_manager.close_tenant_sessions(&tenant_id, reason).await;
+
+    let event = admin_event(
+        &identity,
+        &tenant_id,
+        audit::TENANT_DELETED,
+        serde_json::json!({ "name": tenant.name, "status": tenant.status, "database_schema": tenant.database_schema }),
+    );
+    let deleted = state
+        .storage
+        .delete_tenant(&tenant_id, &event)
+        .await
+        .map_err(storage_error("Failed to delete tenant"))?;
+    if !deleted {
+        return Err(StatusCode::NOT_FOUND.into());
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Create a branch within the tenant's branch quota and issue its first API key
+pub async fn create_branch(
+    State(state): State<AppState>,
+    Extension(identity): Extension<AdminIdentity>,
+    Path(tenant_id): Path<String>,
+    Json(request): Json<CreateBranchRequest>,
+) -> std::result::Result<(StatusCode, Json<serde_json::Value>), Rejection> {
+    let tenant_id = TenantId::new(tenant_id);
+    validate_id("id", &request.id)?;
+    validate_text("name", &request.name)?;
+    if let Some(location) = &request.location {
+        validate_text("location", location)?;
+    }
+    let label = request.api_key_label.unwrap_or_else(|| "initial".to_string());
+    validate_text("api_key_label", &label)?;
+
+    let tenant = state
+        .storage
+        .get_tenant(&tenant_id)
+        .await
+        .map_err(storage_error("Failed to load tenant"))?;
+    let limits = tenant.limits(&state.config.trial);
+
+    let id = QualifiedBranchId::new(tenant_id, BranchId::new(request.id));
+    let (api_key, key_hash) =
+        auth::generate_api_key(&label, None).map_err(storage_error("Failed to generate API key"))?;
+    state
+        .storage
+        .create_branch(
+            &id.tenant_id,
+            &id.branch_id,
+            &request.name,
+            request.location.as_deref(),
+            limits.max_branches,
+            &NewApiKey {
+                id: &api_key.id,
+                tenant_id: &id.tenant_id,
+                branch_id: &id.branch_id,
+                label: &label,
+                key_hash: &key_hash,
+                expires_at: None,
+            },
+        )
+        .await
+        .map_err(storage_error("Failed to create branch"))?;
+
+    audit(
+        &state,
+        &identity,
+        &id.tenant_id,
+        Some(&id.branch_id),
+        audit::BRANCH_CREATED,
+        serde_json::json!({ "key_id": api_key.id }),
+    )
+    .await;
+
+    let branch: common::BranchInfo = state
+        .storage
+        .get_branch(&id.tenant_id, &id.branch_id)
+        .await
+        .map_err(storage_error("Failed to load branch"))?
+        .into();
+
+    Ok((
+        StatusCode::CREATED,
+        Json(serde_json::json!({
+            "branch": branch,
+            "api_key": api_key,
+        })),
+    ))
+}
+
+pub async fn get_branch(
+    State(state): State<AppState>,
+    Path((tenant_id, branch_id)): Path<(String, String)>,
+) -> std::result::Result<Json<serde_json::Value>, Rejection> {
+    let id = QualifiedBranchId::new(TenantId::new(tenant_id), BranchId::new(branch_id));
+    let branch: common::BranchInfo = state
+        .storage
+        .get_branch(&id.tenant_id, &id.branch_id)
+        .await
+        .map_err(storage_error("Failed to load branch"))?
+        .into();
+    let connected = state.connection_manager.is_connected(&id).await;
+
+    Ok(Json(serde_json::json!({
+        "tenant_id": id.tenant_id.as_str(),
+        "branch": branch,
+        "connected": connected,
+    })))
+}
+
+pub async fn update_branch(
+    State(state): State<AppState>,
+    Extension(identity): Extension<AdminIdentity>,
+    Path((tenant_id, branch_id)): Path<(String, String)>,
+    Json(request): Json<UpdateBranchRequest>,
+) -> std::result::Result<Json<common::BranchInfo>, Rejection> {
+    let id = QualifiedBranchId::new(TenantId::new(tenant_id), BranchId::new(branch_id));
+    if let Some(name) = &request.name {
+        validate_text("name", name)?;
+    }
+    if let Some(location) = &request.location {
+        validate_text("location", location)?;
+    }
+
+    let updated = state
+        .storage
+        .update_branch(&id.tenant_id, &id.branch_id, request.name.as_deref(), request.location.as_deref())
+        .await
+        .map_err(storage_error("Failed to update branch"))?;
+    if !updated {
+        return Err(StatusCode::NOT_FOUND.into());
+    }
+
+    audit(
+        &state,
+        &identity,
+        &id.tenant_id,
+        Some(&id.branch_id),
+        audit::BRANCH_UPDATED,
+        serde_json::json!({ "name": request.name, "location": request.location }),
+    )
+    .await;
+
+    let branch = state
+        .storage
+        .get_branch(&id.tenant_id, &id.branch_id)
+        .await
+        .map_err(storage_error("Failed to load branch"))?;
+    Ok(Json(branch.into()))
+}
+
+/// Delete a branch with its API keys and drop its live sessions.
+/// Branches with sync history cannot be deleted (409).
+pub async fn delete_branch(
+    State(state): State<AppState>,
+    Extension(identity): Extension<AdminIdentity>,
+    Path((tenant_id, branch_id)): Path<(String, String)>,
+) -> std::result::Result<StatusCode, Rejection> {
+    let id = QualifiedBranchId::new(TenantId::new(tenant_id), BranchId::new(branch_id));
+
+    let deleted = state
+        .storage
+        .delete_branch(&id.tenant_id, &id.branch_id)
+        .await
+        .map_err(storage_error("Failed to delete branch"))?;
+    if !deleted {
+        return Err(StatusCode::NOT_FOUND.into());
+    }
+
+    let reason = DisconnectReason::new(DisconnectReason::CREDENTIALS_REVOKED, "Branch deleted");
+    let sessions_closed = state.connection_manager.close_branch_sessions(&id, reason).await;
+
+    info!("Deleted branch {}: {} sessions closed", id, sessions_closed);
+    audit(
+        &state,
+        &identity,
+        &id.tenant_id,
+        Some(&id.branch_id),
+        audit::BRANCH_DELETED,
+        serde_json::json!({ "sessions_closed": sessions_closed }),
+    )
+    .await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// End-to-end encryption public keys registered by a tenant's branches, with
+/// fingerprints to check against the branches out of band
+pub async fn list_public_keys(
+    State(state): State<AppState>,
+    Path(tenant_id): Path<String>,
+) -> std::result::Result<Json<serde_json::Value>, Rejection> {
+    let tenant_id = TenantId::new(tenant_id);
+    let keys: Vec<protocol::BranchPublicKey> = state
+        .storage
+        .list_public_keys(&tenant_id, &[])
+        .await
+        .map_err(storage_error("Failed to list public keys"))?
+        .into_iter()
+        .map(Into::into)
+        .collect();
+
+    Ok(Json(serde_json::json!({ "keys": keys })))
+}
+
+/// Drop a branch's public key so peers stop sealing for it until it registers a new one
+pub async fn delete_public_key(
+    State(state): State<AppState>,
+    Extension(identity): Extension<AdminIdentity>,
+    Path((tenant_id, branch_id)): Path<(String, String)>,
+) -> std::result::Result<StatusCode, Rejection> {
+    let id = QualifiedBranchId::new(TenantId::new(tenant_id), BranchId::new(branch_id));
+
+    let deleted = state
+        .storage
+        .delete_public_key(&id.tenant_id, &id.branch_id)
+        .await
+        .map_err(storage_error("Failed to delete public key"))?;
+    if !deleted {
+        return Err(StatusCode::NOT_FOUND.into());
+    }
+
+    info!("Deleted public key of {}", id);
+    audit(&state, &identity, &id.tenant_id, Some(&id.branch_id), "e2e_public_key_deleted", serde_json::json!({})).await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// A page of a tenant's sync conflicts, newest first
+pub async fn list_conflicts(
+    State(state): State<AppState>,
+    Path(tenant_id): Path<String>,
+    Query(query): Query<ConflictListQuery>,
+) -> std::result::Result<Json<serde_json::Value>, Rejection> {
+    let tenant_id = TenantId::new(tenant_id);
+    let (limit, offset) = page(query.limit, query.offset)?;
+    let (conflicts, total) = state
+        .storage
+        .list_conflicts(&tenant_id, query.unresolved, limit, offset)
+        .await
+        .map_err(storage_error("Failed to list conflicts"))?;
+
+    Ok(Json(serde_json::json!({
+        "tenant_id": tenant_id.as_str(),
+        "total": total,
+        "limit": limit,
+        "offset": offset,
+        "conflicts": conflicts,
+    })))
+}
+
+/// Live sessions on this hub plus the tenant's branch, conflict and queue counts
+pub async fn tenant_metrics(
+    State(state): State<AppState>,
+    Path(tenant_id): Path<String>,
+) -> std::result::Result<Json<serde_json::Value>, Rejection> {
+    let tenant_id = TenantId::new(tenant_id);
+    let tenant = state
+        .storage
+        .get_tenant(&tenant_id)
+        .await
+        .map_err(storage_error("Failed to load tenant"))?;
+    let stats = state
+        .storage
+        .tenant_stats(&tenant_id)
+        .await
+        .map_err(storage_error("Failed to load tenant metrics"))?;
+    let sessions = state.connection_manager.tenant_connection_count(&tenant_id).await;
+
+    Ok(Json(serde_json::json!({
+        "tenant_id": tenant_id.as_str(),
+        "status": tenant.status,
+        "limits": tenant.limits(&state.config.trial),
+        "sessions": sessions,
+        "branches": stats.branches,
+        "unresolved_conflicts": stats.unresolved_conflicts,
+        "queued_messages": stats.queued_messages,
+    })))
+}
+
+/// A page of a tenant's audit log, newest first
+pub async fn list_audit_events(
+    State(state): State<AppState>,
+    Path(tenant_id): Path<String>,
+    Query(query): Query<AuditQuery>,
+) -> std::result::Result<Json<serde_json::Value>, Rejection> {
+    let (limit, offset) = page(query.limit, query.offset)?;
+    let filter = audit_filter(
+        TenantId::new(tenant_id),
+        query.branch_id,
+        query.event_type,
+        query.from,
+        query.until,
+    )?;
+    let (events, total) = state
+        .storage
+        .list_audit_events(&filter, limit, offset)
+        .await
+        .map_err(storage_error("Failed to query audit log"))?;
+
+    Ok(Json(serde_json::json!({
+        "tenant_id": filter.tenant_id.as_str(),
+        "total": total,
+        "limit": limit,
+        "offset": offset,
+        "events": events,
+    })))
+}
+
+/// Download a tenant's audit log as CSV or NDJSON, newest first, up to `MAX_EXPORT_ROWS`
+pub async fn export_audit_events(
+    State(state): State<AppState>,
+    Path(tenant_id): Path<String>,
+    Query(query): Query<AuditExportQuery>,
+) -> std::result::Result<Response, Rejection> {
+    // Ends up in the Content-Disposition filename
+    validate_id("tenant_id", &tenant_id)?;
+    let filter = audit_filter(
+        TenantId::new(tenant_id),
+        query.branch_id,
+        query.event_type,
+        query.from,
+        query.until,
+    )?;
+    let (events, total) = state
+        .storage
+        .list_audit_events(&filter, MAX_EXPORT_ROWS, 0)
+        .await
+        .map_err(storage_error("Failed to export audit log"))?;
+    if total > MAX_EXPORT_ROWS {
+        warn!(
+            "Audit export for {} truncated to {} of {} entries",
+            filter.tenant_id, MAX_EXPORT_ROWS, total
+        );
+    }
+
+    let (body, content_type, extension) = match query.format {
+        ExportFormat::Csv => (audit::to_csv(&events), "text/csv; charset=utf-8", "csv"),
+        ExportFormat::Ndjson => (audit::to_ndjson(&events), "application/x-ndjson", "ndjson"),
+    };
+    let disposition = format!("attachment; filename=\"audit-{}.{}\"", filter.tenant_id, extension);
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+            (HeaderName::from_static("x-total-count"), total.to_string()),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+/// Mint an admin JWT for a tenant admin, an auditor or another operator
+pub async fn issue_admin_token(
+    State(state): State<AppState>,
+    Extension(identity): Extension<AdminIdentity>,
+    Json(request): Json<IssueAdminTokenRequest>,
+) -> std::result::Result<Json<serde_json::Value>, Rejection> {
+    validate_text("subject", &request.subject)?;
+    let tenant_id = request.tenant_id.map(TenantId::new);
+    let expires_in_secs = request
+        .expires_in_secs
+        .unwrap_or(state.config.security.admin_token_expiry_secs);
+    if expires_in_secs <= 0 {
+        return Err(Rejection::invalid("expires_in_secs must be positive"));
+    }
+
+    if let Some(tenant_id) = &tenant_id {
+        state
+            .storage
+            .get_tenant(tenant_id)
+            .await
+            .map_err(storage_error("Failed to load tenant"))?;
+    }
+
+    let (token, expires_at) = admin_auth::issue_admin_token(
+        &state.keys,
+        &request.subject,
+        request.role,
+        tenant_id.as_ref(),
+        expires_in_secs,
+    )
+    .map_err(|e| Rejection::invalid(e.to_string()))?;
+
+    info!(
+        "{} issued a {:?} admin token to {}",
+        identity.subject, request.role, request.subject
+    );
+    if let Some(tenant_id) = &tenant_id {
+        audit(
+            &state,
+            &identity,
+            tenant_id,
+            None,
+            audit::ADMIN_TOKEN_ISSUED,
+            serde_json::json!({
+                "subject": request.subject,
+                "role": request.role,
+                "expires_at": expires_at,
+            }),
+        )
+        .await;
+    }
+
+    Ok(Json(serde_json::json!({
+        "token": token,
+        "expires_at": expires_at,
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_id() {
+        assert!(validate_id("id", "tenant_a").is_ok());
+        assert!(validate_id("id", "branch-01").is_ok());
+        assert!(validate_id("id", "").is_err());
+        assert!(validate_id("id", "a:b").is_err());
+        assert!(validate_id("id", "a/b").is_err());
+        assert!(validate_id("id", &"x".repeat(MAX_ID_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn test_deadline_bounds() {
+        assert!(deadline("secs", 60, 3600).is_ok());
+        assert!(deadline("secs", 3600, 3600).is_ok());
+        assert!(deadline("secs", 0, 3600).is_err());
+        assert!(deadline("secs", -60, 3600).is_err());
+        assert!(deadline("secs", i64::MAX, i64::MAX).is_err());
+    }
+
+    #[test]
+    fn test_validate_email() {
+        assert!(validate_email("ops@example.com").is_ok());
+        assert!(validate_email("ops@localhost").is_err());
+        assert!(validate_email("@example.com").is_err());
+        assert!(validate_email("a@b@example.com").is_err());
+    }
+
+    #[test]
+    fn test_page_bounds() {
+        assert_eq!(page(None, None).unwrap(), (DEFAULT_PAGE_SIZE, 0));
+        assert_eq!(page(Some(10), Some(20)).unwrap(), (10, 20));
+        assert!(page(Some(0), None).is_err());
+        assert!(page(Some(MAX_PAGE_SIZE + 1), None).is_err());
+        assert!(page(None, Some(-1)).is_err());
+    }
+
+    #[test]
+    fn test_schema_for_tenant() {
+        assert_eq!(schema_for(&TenantId::new("Acme-Retail")), "tenant_acme_retail");
+        let generated = TenantId::generate();
+        let schema = schema_for(&generated);
+        assert!(schema.starts_with("tenant_") && !schema.contains('-'));
+        assert!(schema.len() <= 63);
+    }
+}
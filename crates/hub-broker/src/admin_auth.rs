@@ -0,0 +1,276 @@
+use axum::http::{HeaderMap, Method};
+use common::{Error, Result, TenantId};
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+
+use crate::keys::KeyRing;
+
+/// `aud` of admin tokens; branch tokens carry none, so neither passes for the other
+pub const ADMIN_AUDIENCE: &str = "hub-broker-admin";
+
+/// Subject reported for requests made with the static `ADMIN_API_TOKEN`
+const OPERATOR_TOKEN_SUBJECT: &str = "operator-token";
+
+/// Admin routes without a `:tenant_id` that scoped identities may still call;
+/// their handlers narrow the results to the caller's tenant
+const SELF_SCOPED_ROUTES: &[&str] = &["/branches", "/notifications"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AdminRole {
+    /// Sees and changes everything, including tenants themselves
+    Operator,
+    /// Manages the branches and API keys of one tenant
+    TenantAdmin,
+    /// Read-only, for one tenant or for all of them
+    Auditor,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AdminClaims {
+    pub sub: String,
+    pub role: AdminRole,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant_id: Option<String>,
+    pub aud: String,
+    pub exp: i64,
+    pub iat: i64,
+}
+
+/// Who is calling the admin API; inserted into request extensions by the admin middleware
+#[derive(Debug, Clone)]
+pub struct AdminIdentity {
+    pub subject: String,
+    pub role: AdminRole,
+    /// Tenant the identity is confined to; None reaches every tenant
+    pub tenant_id: Option<TenantId>,
+    /// Client address of the request, filled in by the admin middleware
+    pub remote_ip: Option<IpAddr>,
+}
+
+impl AdminIdentity {
+    fn operator_token() -> Self {
+        Self {
+            subject: OPERATOR_TOKEN_SUBJECT.to_string(),
+            role: AdminRole::Operator,
+            tenant_id: None,
+            remote_ip: None,
+        }
+    }
+
+    pub fn can_access_tenant(&self, tenant_id: &TenantId) -> bool {
+        !matches!(&self.tenant_id, Some(own) if own != tenant_id)
+    }
+}
+
+/// Tenant admins need a tenant; operators may not have one
+fn check_scope(role: AdminRole, tenant_id: Option<&TenantId>) -> std::result::Result<(), &'static str> {
+    match (role, tenant_id) {
+        (AdminRole::TenantAdmin, None) => Err("Tenant admin without a tenant"),
+        (AdminRole::Operator, Some(_)) => Err("Operator confined to a tenant"),
+        _ => Ok(()),
+    }
+}
+
+impl TryFrom<AdminClaims> for AdminIdentity {
+    type Error = Error;
+
+    fn try_from(claims: AdminClaims) -> Result<Self> {
+        let tenant_id = claims.tenant_id.map(TenantId::new);
+        check_scope(claims.role, tenant_id.as_ref())
+            .map_err(|reason| Error::AuthenticationFailed(reason.to_string()))?;
+        Ok(Self {
+            subject: claims.sub,
+            role: claims.role,
+            tenant_id,
+            remote_ip: None,
+        })
+    }
+}
+
+/// What a route needs beyond an authenticated admin
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    /// Creating, reconfiguring or deleting tenants, and minting admin tokens
+    Operator,
+}
+
+/// Access level of an admin route (as matched, relative to `/admin`)
+pub fn required_access(method: &Method, route: &str) -> Access {
+    match (method.as_str(), route) {
+        ("POST", "/tenants" | "/tokens")
+        | ("PATCH" | "DELETE", "/tenants/:tenant_id")
+        | ("PUT", "/tenants/:tenant_id/status") => Access::Operator,
+        ("GET" | "HEAD", _) => Access::Read,
+        _ => Access::Write,
+    }
+}
+
+/// Check an identity against an admin route
+/// CRITICAL: Tenant admins and scoped auditors never reach another tenant's data
+pub fn authorize(
+    identity: &AdminIdentity,
+    method: &Method,
+    route: &str,
+    path_tenant: Option<&TenantId>,
+) -> Result<()> {
+    let route = route.strip_prefix("/admin").unwrap_or(route);
+
+    match required_access(method, route) {
+        Access::Operator if identity.role != AdminRole::Operator => {
+            return Err(Error::AuthorizationFailed("Operator role required".to_string()));
+        }
+        Access::Write if identity.role == AdminRole::Auditor => {
+            return Err(Error::AuthorizationFailed("Auditors are read-only".to_string()));
+        }
+        _ => {}
+    }
+
+    match path_tenant {
+        Some(tenant_id) if !identity.can_access_tenant(tenant_id) => Err(Error::AuthorizationFailed(
+            format!("No access to tenant {}", tenant_id),
+        )),
+        None if identity.tenant_id.is_some() && !SELF_SCOPED_ROUTES.contains(&route) => Err(
+            Error::AuthorizationFailed("Route spans every tenant".to_string()),
+        ),
+        _ => Ok(()),
+    }
+}
+
+/// Resolve the caller from `Authorization: Bearer`: the static operator token or an admin JWT
+pub fn authenticate(headers: &HeaderMap, operator_token: Option<&str>, keys: &KeyRing) -> Result<AdminIdentity> {
+    let token = crate::auth::bearer_token(headers, None)
+        .ok_or_else(|| Error::AuthenticationFailed("Missing bearer token".to_string()))?;
+
+    // Compare digests so the check does not leak the operator token through timing
+    if let Some(expected) = operator_token {
+        if common::utils::calculate_hash(token.as_bytes()) == common::utils::calculate_hash(expected.as_bytes()) {
+            return Ok(AdminIdentity::operator_token());
+        }
+    }
+
+    keys.verify_audience::<AdminClaims>(token, ADMIN_AUDIENCE)?.try_into()
+}
+
+/// Sign an admin token; returns the token and its expiry
+pub fn issue_admin_token(
+    keys: &KeyRing,
+    subject: &str,
+    role: AdminRole,
+    tenant_id: Option<&TenantId>,
+    expiry_secs: i64,
+) -> Result<(String, i64)> {
+    check_scope(role, tenant_id).map_err(|reason| Error::InvalidMessage(reason.to_string()))?;
+
+    let now = chrono::Utc::now().timestamp();
+    let claims = AdminClaims {
+        sub: subject.to_string(),
+        role,
+        tenant_id: tenant_id.map(|id| id.as_str().to_string()),
+        aud: ADMIN_AUDIENCE.to_string(),
+        exp: now + expiry_secs,
+        iat: now,
+    };
+
+    keys.sign(&claims).map(|token| (token, claims.exp))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::header::AUTHORIZATION;
+
+    fn identity(role: AdminRole, tenant: Option<&str>) -> AdminIdentity {
+        AdminIdentity {
+            subject: "someone".to_string(),
+            role,
+            tenant_id: tenant.map(TenantId::new),
+            remote_ip: None,
+        }
+    }
+
+    fn headers(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, format!("Bearer {}", token).parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_tenant_admin_confined_to_own_tenant() {
+        let admin = identity(AdminRole::TenantAdmin, Some("tenant_a"));
+        let own = TenantId::new("tenant_a");
+        let other = TenantId::new("tenant_b");
+        let branches = "/admin/tenants/:tenant_id/branches";
+
+        assert!(authorize(&admin, &Method::GET, branches, Some(&own)).is_ok());
+        assert!(authorize(&admin, &Method::POST, branches, Some(&own)).is_ok());
+        assert!(authorize(&admin, &Method::GET, branches, Some(&other)).is_err());
+        assert!(authorize(&admin, &Method::GET, "/admin/tenants", None).is_err());
+        assert!(authorize(&admin, &Method::GET, "/admin/branches", None).is_ok());
+        assert!(authorize(&admin, &Method::PUT, "/admin/tenants/:tenant_id/status", Some(&own)).is_err());
+    }
+
+    #[test]
+    fn test_auditor_is_read_only() {
+        let auditor = identity(AdminRole::Auditor, None);
+        let tenant = TenantId::new("tenant_a");
+
+        assert!(authorize(&auditor, &Method::GET, "/admin/tenants", None).is_ok());
+        assert!(authorize(&auditor, &Method::GET, "/admin/tenants/:tenant_id", Some(&tenant)).is_ok());
+        assert!(authorize(&auditor, &Method::POST, "/admin/tenants/:tenant_id/branches", Some(&tenant)).is_err());
+        assert!(authorize(&auditor, &Method::POST, "/admin/notifications", None).is_err());
+    }
+
+    #[test]
+    fn test_operator_only_routes() {
+        let operator = identity(AdminRole::Operator, None);
+        let admin = identity(AdminRole::TenantAdmin, Some("tenant_a"));
+        let tenant = TenantId::new("tenant_a");
+
+        assert!(authorize(&operator, &Method::POST, "/admin/tenants", None).is_ok());
+        assert!(authorize(&operator, &Method::DELETE, "/admin/tenants/:tenant_id", Some(&tenant)).is_ok());
+        assert!(authorize(&admin, &Method::DELETE, "/admin/tenants/:tenant_id", Some(&tenant)).is_err());
+        assert!(authorize(&admin, &Method::PATCH, "/admin/tenants/:tenant_id", Some(&tenant)).is_err());
+        assert!(authorize(&admin, &Method::POST, "/admin/tokens", None).is_err());
+    }
+
+    #[test]
+    fn test_authenticate_operator_token_and_admin_jwt() {
+        let keys = KeyRing::ephemeral();
+
+        let operator = authenticate(&headers("s3cret"), Some("s3cret"), &keys).unwrap();
+        assert_eq!(operator.role, AdminRole::Operator);
+        assert!(authenticate(&headers("wrong"), Some("s3cret"), &keys).is_err());
+        assert!(authenticate(&HeaderMap::new(), Some("s3cret"), &keys).is_err());
+
+        let tenant = TenantId::new("tenant_a");
+        let (token, _) = issue_admin_token(&keys, "alice", AdminRole::TenantAdmin, Some(&tenant), 600).unwrap();
+        let admin = authenticate(&headers(&token), Some("s3cret"), &keys).unwrap();
+        assert_eq!(admin.role, AdminRole::TenantAdmin);
+        assert_eq!(admin.tenant_id, Some(tenant));
+    }
+
+    #[test]
+    fn test_branch_token_is_not_an_admin_token() {
+        let keys = KeyRing::ephemeral();
+        let (branch_token, _) = crate::auth::issue_token(
+            &TenantId::new("tenant_a"),
+            &common::BranchId::new("main"),
+            None,
+            &keys,
+            600,
+        )
+        .unwrap();
+
+        assert!(authenticate(&headers(&branch_token), None, &keys).is_err());
+    }
+
+    #[test]
+    fn test_issue_rejects_unscoped_tenant_admin() {
+        let keys = KeyRing::ephemeral();
+        assert!(issue_admin_token(&keys, "bob", AdminRole::TenantAdmin, None, 600).is_err());
+        assert!(issue_admin_token(&keys, "ops", AdminRole::Operator, Some(&TenantId::new("t")), 600).is_err());
+    }
+}
@@ -0,0 +1,206 @@
+use axum::http::HeaderMap;
+use common::{BranchId, TenantId};
+use std::net::{IpAddr, SocketAddr};
+use tracing::warn;
+
+use crate::storage::{AuditRow, Storage};
+
+/// Token request or Connect handshake accepted
+pub const AUTH_SUCCEEDED: &str = "auth_succeeded";
+/// Token request or Connect handshake refused
+pub const AUTH_FAILED: &str = "auth_failed";
+/// Connect handshake accepted; implies the branch authenticated
+pub const BRANCH_CONNECTED: &str = "branch_connected";
+/// Authenticated Connect refused, e.g. for tenant status or session limits
+pub const CONNECT_REFUSED: &str = "connect_refused";
+/// A rotated refresh token was presented again; its whole family is revoked
+pub const REFRESH_TOKEN_REUSE: &str = "refresh_token_reuse";
+pub const BRANCH_DISCONNECTED: &str = "branch_disconnected";
+/// A branch registered or replaced its end-to-end encryption public key
+pub const PUBLIC_KEY_REGISTERED: &str = "e2e_public_key_registered";
+/// An operator deleted a tenant; recorded under the deleted tenant's ID, which is not reused
+pub const TENANT_DELETED: &str = "tenant_deleted";
+/// A branch addressed a branch that does not exist in its own tenant; a branch of
+/// another tenant and a mistyped ID look the same from here
+pub const UNKNOWN_ROUTING_TARGET: &str = "unknown_routing_target";
+/// A trial ran out and the sweep deactivated its tenant
+pub const TRIAL_EXPIRED: &str = "trial_expired";
+
+// Messages refused before dispatch; the sender gets the matching error code
+/// `from` named a different branch than the one that authenticated
+pub const SENDER_IDENTITY_MISMATCH: &str = "sender_identity_mismatch";
+/// `RouteMessage.target_branch` disagreed with the envelope's `to`
+pub const ROUTE_TARGET_MISMATCH: &str = "route_target_mismatch";
+/// Timestamp outside the clock-skew tolerance
+pub const STALE_MESSAGE: &str = "stale_message";
+/// Message ID already seen from the branch
+pub const REPLAYED_MESSAGE: &str = "replayed_message";
+/// The session signs its messages, but this one carried no signature
+pub const UNSIGNED_MESSAGE: &str = "unsigned_message";
+pub const INVALID_MESSAGE_SIGNATURE: &str = "invalid_message_signature";
+
+// Admin API actions, recorded with the acting subject and role
+pub const TENANT_CREATED: &str = "tenant_created";
+pub const TENANT_UPDATED: &str = "tenant_updated";
+/// Status change, with the number of live sessions it closed
+pub const TENANT_STATUS_CHANGED: &str = "tenant_status_changed";
+pub const BRANCH_CREATED: &str = "branch_created";
+pub const BRANCH_UPDATED: &str = "branch_updated";
+pub const BRANCH_DELETED: &str = "branch_deleted";
+pub const API_KEY_ISSUED: &str = "api_key_issued";
+/// A replacement key was issued; the old one expires after the overlap
+pub const API_KEY_ROTATED: &str = "api_key_rotated";
+pub const API_KEY_REVOKED: &str = "api_key_revoked";
+/// A tenant-scoped admin token was issued
+pub const ADMIN_TOKEN_ISSUED: &str = "admin_token_issued";
+
+/// One entry for the tenant's audit log
+#[derive(Debug, Clone)]
+pub struct AuditEvent {
+    pub tenant_id: TenantId,
+    pub branch_id: Option<BranchId>,
+    pub event_type: &'static str,
+    pub data: serde_json::Value,
+    pub ip_address: Option<IpAddr>,
+}
+
+impl AuditEvent {
+    pub fn new(tenant_id: &TenantId, event_type: &'static str) -> Self {
+        Self {
+            tenant_id: tenant_id.clone(),
+            branch_id: None,
+            event_type,
+            data: serde_json::json!({}),
+            ip_address: None,
+        }
+    }
+
+    pub fn branch(mut self, branch_id: &BranchId) -> Self {
+        self.branch_id = Some(branch_id.clone());
+        self
+    }
+
+    pub fn data(mut self, data: serde_json::Value) -> Self {
+        self.data = data;
+        self
+    }
+
+    pub fn ip(mut self, ip_address: Option<IpAddr>) -> Self {
+        self.ip_address = ip_address;
+        self
+    }
+}
+
+/// Write an audit entry; failures are logged, never returned, so auditing cannot
+/// fail the request that triggered it
+pub async fn record(storage: &Storage, event: AuditEvent) {
+    if let Err(e) = storage
+        .write_audit_event(
+            &event.tenant_id,
+            event.branch_id.as_ref(),
+            event.event_type,
+            event.data,
+            event.ip_address,
+        )
+        .await
+    {
+        warn!("Failed to write {} audit entry for {}: {}", event.event_type, event.tenant_id, e);
+    }
+}
+
+/// Client address of a request: the peer, or the first `X-Forwarded-For` hop when the
+/// hub runs behind a trusted proxy (`TRUST_FORWARDED_PROTO`)
+pub fn client_ip(headers: &HeaderMap, peer: Option<SocketAddr>, trust_forwarded: bool) -> Option<IpAddr> {
+    let forwarded = trust_forwarded
+        .then(|| headers.get("x-forwarded-for"))
+        .flatten()
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(',').next())
+        .and_then(|hop| hop.trim().parse().ok());
+
+    forwarded.or(peer.map(|addr| addr.ip()))
+}
+
+/// Audit rows as CSV, header first
+pub fn to_csv(rows: &[AuditRow]) -> String {
+    let mut out = String::from("id,created_at,tenant_id,branch_id,event_type,ip_address,event_data\n");
+    for row in rows {
+        let fields = [
+            row.id.to_string(),
+            row.created_at.to_rfc3339(),
+            row.tenant_id.clone(),
+            row.branch_id.clone().unwrap_or_default(),
+            row.event_type.clone(),
+            row.ip_address.clone().unwrap_or_default(),
+            row.event_data.as_ref().map(|data| data.to_string()).unwrap_or_default(),
+        ];
+        let line: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+        out.push_str(&line.join(","));
+        out.push('\n');
+    }
+    out
+}
+
+/// Audit rows as newline-delimited JSON
+pub fn to_ndjson(rows: &[AuditRow]) -> String {
+    rows.iter()
+        .filter_map(|row| serde_json::to_string(row).ok())
+        .map(|line| line + "\n")
+        .collect()
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row() -> AuditRow {
+        AuditRow {
+            id: 7,
+            tenant_id: "tenant_a".to_string(),
+            branch_id: Some("main".to_string()),
+            event_type: AUTH_FAILED.to_string(),
+            event_data: Some(serde_json::json!({ "reason": "bad key, expired" })),
+            ip_address: Some("10.0.0.5".to_string()),
+            created_at: chrono::DateTime::from_timestamp(1_700_000_000, 0).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_client_ip_prefers_trusted_forwarded_for() {
+        let peer: SocketAddr = "10.1.1.1:5000".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "203.0.113.9, 10.1.1.1".parse().unwrap());
+
+        assert_eq!(client_ip(&headers, Some(peer), true), Some("203.0.113.9".parse().unwrap()));
+        assert_eq!(client_ip(&headers, Some(peer), false), Some(peer.ip()));
+        assert_eq!(client_ip(&HeaderMap::new(), None, true), None);
+    }
+
+    #[test]
+    fn test_csv_quotes_fields() {
+        let csv = to_csv(&[row()]);
+        let mut lines = csv.lines();
+
+        assert_eq!(lines.next(), Some("id,created_at,tenant_id,branch_id,event_type,ip_address,event_data"));
+        assert_eq!(
+            lines.next(),
+            Some(r#"7,2023-11-14T22:13:20+00:00,tenant_a,main,auth_failed,10.0.0.5,"{""reason"":""bad key, expired""}""#)
+        );
+    }
+
+    #[test]
+    fn test_ndjson_one_object_per_line() {
+        let ndjson = to_ndjson(&[row(), row()]);
+        assert_eq!(ndjson.lines().count(), 2);
+        let first: serde_json::Value = serde_json::from_str(ndjson.lines().next().unwrap()).unwrap();
+        assert_eq!(first["event_type"], AUTH_FAILED);
+    }
+}
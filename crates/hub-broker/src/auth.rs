@@ -1,12 +1,15 @@
-use common::{BranchId, TenantId, QualifiedBranchId, Result, Error};
-use crate::storage::Storage;
-use jsonwebtoken::{encode, decode, Header, Validation, EncodingKey, DecodingKey};
+use common::{config::SecurityConfig, BranchId, QualifiedBranchId, TenantId, Result, Error};
+use crate::storage::{ApiKeyRow, NewApiKey, NewRefreshToken, Storage};
+use crate::keys::KeyRing;
+use crate::audit::{self, AuditEvent};
 use serde::{Deserialize, Serialize};
 use axum::{
-    extract::State,
+    extract::{ConnectInfo, State},
     Json,
-    http::StatusCode,
+    http::{header::{AUTHORIZATION, RETRY_AFTER}, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
 };
+use std::net::SocketAddr;
 use tracing::{info, warn};
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -15,6 +18,9 @@ pub struct Claims {
     pub branch_id: String,
     pub exp: i64,
     pub iat: i64,
+    /// API key the token was ultimately obtained with; revoking it ends the session
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_key_id: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -28,69 +34,342 @@ pub struct TokenRequest {
 pub struct TokenResponse {
     pub token: String,
     pub expires_at: i64,
+    pub refresh_token: String,
+    pub refresh_expires_at: i64,
+}
+
+impl From<TokenResponse> for protocol::TokenRenewal {
+    fn from(tokens: TokenResponse) -> Self {
+        protocol::TokenRenewal {
+            access_token: tokens.token,
+            expires_at: tokens.expires_at,
+            refresh_token: tokens.refresh_token,
+            refresh_expires_at: tokens.refresh_expires_at,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
 }
 
 /// Generate JWT token for authenticated branch
+/// Attempts are rate limited per branch to slow down API key guessing.
 pub async fn generate_token(
     State(state): State<crate::server::AppState>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
     Json(request): Json<TokenRequest>,
-) -> std::result::Result<Json<TokenResponse>, StatusCode> {
+) -> std::result::Result<Json<TokenResponse>, Response> {
     let tenant_id = TenantId::new(request.tenant_id);
     let branch_id = BranchId::new(request.branch_id);
+    let remote_ip = audit::client_ip(
+        &headers,
+        peer.map(|ConnectInfo(addr)| addr),
+        state.config.security.trust_forwarded_proto,
+    );
+    let event = |event_type| AuditEvent::new(&tenant_id, event_type).branch(&branch_id).ip(remote_ip);
+
+    if let Err(Error::RateLimitExceeded { retry_after_ms }) = crate::rate_limit::check_auth_attempt(
+        &state.storage,
+        &tenant_id,
+        &branch_id,
+        state.config.security.auth_rate_limit_per_min,
+    )
+    .await
+    {
+        warn!("Throttled token requests for {}:{}", tenant_id, branch_id);
+        return Err(too_many_requests(retry_after_ms));
+    }
 
     // Authenticate
     match authenticate_branch(&state.storage, &tenant_id, &branch_id, &request.api_key).await {
-        Ok(true) => {
-            let now = chrono::Utc::now().timestamp();
-            let expires_at = now + state.config.security.jwt_expiry_secs;
-
-            let claims = Claims {
-                tenant_id: tenant_id.as_str().to_string(),
-                branch_id: branch_id.as_str().to_string(),
-                exp: expires_at,
-                iat: now,
-            };
-
-            match encode(
-                &Header::default(),
-                &claims,
-                &EncodingKey::from_secret(state.config.security.jwt_secret.as_bytes()),
-            ) {
-                Ok(token) => {
+        Ok(Some(api_key_id)) => {
+            match issue_tokens(
+                &state.storage,
+                &state.keys,
+                &state.config.security,
+                &tenant_id,
+                &branch_id,
+                Some(&api_key_id),
+            )
+            .await
+            {
+                Ok(tokens) => {
                     info!("Generated token for {}:{}", tenant_id, branch_id);
-                    Ok(Json(TokenResponse { token, expires_at }))
+                    audit::record(
+                        &state.storage,
+                        event(audit::AUTH_SUCCEEDED)
+                            .data(serde_json::json!({ "auth_method": "api_key", "api_key_id": api_key_id })),
+                    )
+                    .await;
+                    Ok(Json(tokens))
                 }
                 Err(e) => {
-                    warn!("Failed to encode token: {}", e);
-                    Err(StatusCode::INTERNAL_SERVER_ERROR)
+                    warn!("Failed to issue tokens: {}", e);
+                    Err(StatusCode::INTERNAL_SERVER_ERROR.into_response())
                 }
             }
         }
         _ => {
             warn!("Authentication failed for {}:{}", tenant_id, branch_id);
+            audit::record(
+                &state.storage,
+                event(audit::AUTH_FAILED).data(serde_json::json!({ "auth_method": "api_key" })),
+            )
+            .await;
+            Err(StatusCode::UNAUTHORIZED.into_response())
+        }
+    }
+}
+
+/// 429 with a `Retry-After` header (whole seconds) and the exact delay in the body
+fn too_many_requests(retry_after_ms: u64) -> Response {
+    let retry_after_secs = retry_after_ms.div_ceil(1000).max(1);
+    let body = crate::rate_limit::throttled_payload("auth/token", retry_after_ms);
+
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(RETRY_AFTER, retry_after_secs.to_string())],
+        Json(body),
+    )
+        .into_response()
+}
+
+/// Exchange a refresh token for a new access/refresh pair
+pub async fn refresh_token(
+    State(state): State<crate::server::AppState>,
+    Json(request): Json<RefreshRequest>,
+) -> std::result::Result<Json<TokenResponse>, StatusCode> {
+    match rotate_refresh_token(&state.storage, &state.keys, &state.config.security, &request.refresh_token, None).await {
+        Ok(tokens) => Ok(Json(tokens)),
+        Err(e @ (Error::AuthenticationFailed(_) | Error::AuthorizationFailed(_))) => {
+            warn!("Refresh rejected: {}", e);
             Err(StatusCode::UNAUTHORIZED)
         }
+        Err(e) => {
+            warn!("Refresh failed: {}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Revoke a refresh token and every token rotated from the same login.
+/// Unknown tokens are accepted silently so the endpoint cannot be used to probe them.
+pub async fn revoke_token(
+    State(state): State<crate::server::AppState>,
+    Json(request): Json<RefreshRequest>,
+) -> StatusCode {
+    let token_hash = hash_refresh_token(&request.refresh_token);
+    let row = match state.storage.find_refresh_token(&token_hash).await {
+        Ok(row) => row,
+        Err(e) => {
+            warn!("Failed to look up refresh token: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR;
+        }
+    };
+
+    if let Some(row) = row {
+        if let Err(e) = state.storage.revoke_refresh_token_family(&TenantId::new(row.tenant_id.clone()), &row.family_id).await {
+            warn!("Failed to revoke refresh tokens: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR;
+        }
+        info!("Revoked refresh tokens for {}:{}", row.tenant_id, row.branch_id);
+    }
+
+    StatusCode::NO_CONTENT
+}
+
+/// A login and every token pair rotated from it
+struct TokenFamily<'a> {
+    id: &'a str,
+    api_key_id: Option<&'a str>,
+}
+
+/// Sign an access token and start a new refresh token family
+pub async fn issue_tokens(
+    storage: &Storage,
+    keys: &KeyRing,
+    security: &SecurityConfig,
+    tenant_id: &TenantId,
+    branch_id: &BranchId,
+    api_key_id: Option<&str>,
+) -> Result<TokenResponse> {
+    let family = TokenFamily {
+        id: &uuid::Uuid::new_v4().to_string(),
+        api_key_id,
+    };
+
+    issue_tokens_with_id(
+        storage,
+        keys,
+        security,
+        tenant_id,
+        branch_id,
+        &uuid::Uuid::new_v4().to_string(),
+        &family,
+    )
+    .await
+}
+
+async fn issue_tokens_with_id(
+    storage: &Storage,
+    keys: &KeyRing,
+    security: &SecurityConfig,
+    tenant_id: &TenantId,
+    branch_id: &BranchId,
+    refresh_id: &str,
+    family: &TokenFamily<'_>,
+) -> Result<TokenResponse> {
+    let (token, expires_at) = issue_token(
+        tenant_id,
+        branch_id,
+        family.api_key_id,
+        keys,
+        security.jwt_expiry_secs,
+    )?;
+
+    let refresh_token = generate_secret();
+    let refresh_expires_at =
+        chrono::Utc::now() + chrono::Duration::seconds(security.refresh_token_expiry_secs);
+
+    storage
+        .insert_refresh_token(&NewRefreshToken {
+            id: refresh_id,
+            family_id: family.id,
+            tenant_id,
+            branch_id,
+            token_hash: &hash_refresh_token(&refresh_token),
+            expires_at: refresh_expires_at,
+            api_key_id: family.api_key_id,
+        })
+        .await?;
+
+    Ok(TokenResponse {
+        token,
+        expires_at,
+        refresh_token,
+        refresh_expires_at: refresh_expires_at.timestamp(),
+    })
+}
+
+/// Redeem a refresh token: retire it and issue a fresh pair in the same family.
+/// `expected` pins the token to a branch, e.g. the session renewing in-band.
+/// CRITICAL: A retired token presented again means it leaked; the whole family is revoked
+pub async fn rotate_refresh_token(
+    storage: &Storage,
+    keys: &KeyRing,
+    security: &SecurityConfig,
+    refresh_token: &str,
+    expected: Option<&QualifiedBranchId>,
+) -> Result<TokenResponse> {
+    let row = storage
+        .find_refresh_token(&hash_refresh_token(refresh_token))
+        .await?
+        .ok_or_else(|| Error::AuthenticationFailed("Unknown refresh token".to_string()))?;
+
+    let tenant_id = TenantId::new(row.tenant_id);
+    let branch_id = BranchId::new(row.branch_id);
+
+    if let Some(expected) = expected {
+        if expected.tenant_id != tenant_id || expected.branch_id != branch_id {
+            warn!("Refresh token for {}:{} presented by {}", tenant_id, branch_id, expected);
+            return Err(Error::AuthorizationFailed(
+                "Refresh token belongs to another branch".to_string(),
+            ));
+        }
+    }
+
+    if row.revoked_at.is_some() {
+        return Err(revoke_reused_family(storage, &tenant_id, &branch_id, &row.family_id).await);
+    }
+
+    if row.expires_at <= chrono::Utc::now() {
+        return Err(Error::AuthenticationFailed("Refresh token expired".to_string()));
+    }
+
+    // The tenant may have been suspended, or its trial ended, since the token was issued
+    let tenant = storage.get_tenant(&tenant_id).await?;
+    if !tenant.is_usable(chrono::Utc::now()) {
+        warn!("Tenant {} is not active", tenant_id);
+        return Err(Error::AuthenticationFailed("Tenant is not active".to_string()));
+    }
+
+    // Tokens die with the API key they were obtained with
+    if let Some(api_key_id) = row.api_key_id.as_deref() {
+        if !api_key_usable(storage, &tenant_id, &branch_id, api_key_id).await? {
+            return Err(Error::AuthenticationFailed("API key no longer valid".to_string()));
+        }
     }
+
+    let next_id = uuid::Uuid::new_v4().to_string();
+    if !storage.rotate_refresh_token(&tenant_id, &row.id, &next_id).await? {
+        // Another request redeemed the same token first
+        return Err(revoke_reused_family(storage, &tenant_id, &branch_id, &row.family_id).await);
+    }
+
+    let family = TokenFamily {
+        id: &row.family_id,
+        api_key_id: row.api_key_id.as_deref(),
+    };
+    issue_tokens_with_id(storage, keys, security, &tenant_id, &branch_id, &next_id, &family).await
 }
 
-/// Authenticate branch with API key
+async fn revoke_reused_family(
+    storage: &Storage,
+    tenant_id: &TenantId,
+    branch_id: &BranchId,
+    family_id: &str,
+) -> Error {
+    warn!("Refresh token reuse for {}:{}, revoking family {}", tenant_id, branch_id, family_id);
+
+    if let Err(e) = storage.revoke_refresh_token_family(tenant_id, family_id).await {
+        return e;
+    }
+    audit::record(
+        storage,
+        AuditEvent::new(tenant_id, audit::REFRESH_TOKEN_REUSE)
+            .branch(branch_id)
+            .data(serde_json::json!({ "family_id": family_id })),
+    )
+    .await;
+
+    Error::AuthenticationFailed("Refresh token reuse detected".to_string())
+}
+
+/// Opaque 256-bit secret, hex encoded
+fn generate_secret() -> String {
+    use argon2::password_hash::rand_core::{OsRng, RngCore};
+
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Refresh tokens are high-entropy, so a plain SHA-256 is enough to store them
+fn hash_refresh_token(refresh_token: &str) -> String {
+    common::utils::calculate_hash(refresh_token.as_bytes())
+}
+
+/// Authenticate branch with API key; returns the id of the key that matched
 /// CRITICAL: Tenant isolation must be enforced here
 pub async fn authenticate_branch(
     storage: &Storage,
     tenant_id: &TenantId,
     branch_id: &BranchId,
     api_key: &str,
-) -> Result<bool> {
-    // 1. Check if tenant exists and is active
+) -> Result<Option<String>> {
+    // 1. Check if tenant exists and is active (or on an unexpired trial)
     let tenant = storage.get_tenant(tenant_id).await?;
-    if tenant.status != common::TenantStatus::Active {
+    if !tenant.is_usable(chrono::Utc::now()) {
         warn!("Tenant {} is not active", tenant_id);
-        return Ok(false);
+        return Ok(None);
     }
 
     // 2. Verify branch belongs to this tenant
     let branch = storage.get_branch(tenant_id, branch_id).await?;
-    if branch.tenant_id != *tenant_id {
+    if branch.tenant_id != tenant_id.as_str() {
         // CRITICAL: Prevent cross-tenant access
         warn!("Branch {} does not belong to tenant {}", branch_id, tenant_id);
         return Err(Error::AuthorizationFailed(
@@ -98,9 +377,100 @@ pub async fn authenticate_branch(
         ));
     }
 
-    // 3. Verify API key
-    let stored_key_hash = storage.get_api_key_hash(tenant_id, branch_id).await?;
-    verify_api_key(api_key, &stored_key_hash)
+    // 3. Verify API key against the branch's live keys
+    let keys = storage.list_usable_api_keys(tenant_id, branch_id).await?;
+    let Some(key) = match_api_key(api_key, &keys) else {
+        return Ok(None);
+    };
+
+    if let Err(e) = storage.touch_api_key_last_used(tenant_id, &key.id).await {
+        warn!("Failed to record use of API key {}: {}", key.id, e);
+    }
+    Ok(Some(key.id.clone()))
+}
+
+/// Find the key a presented API key belongs to.
+/// Issued keys carry their id before the first '.', so only one argon2 check is needed;
+/// legacy keys without the prefix are checked against every candidate.
+fn match_api_key<'a>(api_key: &str, keys: &'a [ApiKeyRow]) -> Option<&'a ApiKeyRow> {
+    let prefixed = api_key
+        .split_once('.')
+        .and_then(|(id, _)| keys.iter().find(|k| k.id == id));
+
+    let candidates: Vec<&ApiKeyRow> = match prefixed {
+        Some(key) => vec![key],
+        None => keys.iter().collect(),
+    };
+
+    candidates
+        .into_iter()
+        .find(|key| matches!(verify_api_key(api_key, &key.key_hash), Ok(true)))
+}
+
+/// Whether a key still exists, is not revoked and has not expired
+pub async fn api_key_usable(
+    storage: &Storage,
+    tenant_id: &TenantId,
+    branch_id: &BranchId,
+    api_key_id: &str,
+) -> Result<bool> {
+    Ok(storage
+        .get_api_key(tenant_id, branch_id, api_key_id)
+        .await?
+        .is_some_and(|key| key.is_usable()))
+}
+
+/// A freshly issued API key; the plaintext is never stored and cannot be shown again
+#[derive(Debug, Serialize)]
+pub struct IssuedApiKey {
+    pub id: String,
+    pub api_key: String,
+    pub label: String,
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// Create a new API key for a branch
+pub async fn issue_api_key(
+    storage: &Storage,
+    tenant_id: &TenantId,
+    branch_id: &BranchId,
+    label: &str,
+    expires_at: Option<chrono::DateTime<chrono::Utc>>,
+) -> Result<IssuedApiKey> {
+    let (issued, key_hash) = generate_api_key(label, expires_at)?;
+    storage
+        .insert_api_key(&NewApiKey {
+            id: &issued.id,
+            tenant_id,
+            branch_id,
+            label,
+            key_hash: &key_hash,
+            expires_at,
+        })
+        .await?;
+
+    info!("Issued API key {} for {}:{}", issued.id, tenant_id, branch_id);
+    Ok(issued)
+}
+
+/// A new API key and the hash to store for it; nothing is stored yet
+pub fn generate_api_key(
+    label: &str,
+    expires_at: Option<chrono::DateTime<chrono::Utc>>,
+) -> Result<(IssuedApiKey, String)> {
+    let id = uuid::Uuid::new_v4().simple().to_string();
+    let api_key = format!("{}.{}", id, generate_secret());
+    let key_hash = hash_api_key(&api_key)?;
+
+    Ok((
+        IssuedApiKey {
+            id,
+            api_key,
+            label: label.to_string(),
+            expires_at,
+        },
+        key_hash,
+    ))
 }
 
 /// Verify API key using argon2
@@ -116,15 +486,47 @@ fn verify_api_key(api_key: &str, stored_hash: &str) -> Result<bool> {
     }
 }
 
+/// Sign a JWT for a branch; returns the token and its expiry (unix seconds)
+pub fn issue_token(
+    tenant_id: &TenantId,
+    branch_id: &BranchId,
+    api_key_id: Option<&str>,
+    keys: &KeyRing,
+    expiry_secs: i64,
+) -> Result<(String, i64)> {
+    let now = chrono::Utc::now().timestamp();
+    let expires_at = now + expiry_secs;
+
+    let claims = Claims {
+        tenant_id: tenant_id.as_str().to_string(),
+        branch_id: branch_id.as_str().to_string(),
+        exp: expires_at,
+        iat: now,
+        api_key_id: api_key_id.map(str::to_string),
+    };
+
+    keys.sign(&claims).map(|token| (token, expires_at))
+}
+
+/// Pull a bearer token from the `Authorization` header, falling back to a `token` query parameter
+pub fn bearer_token<'a>(headers: &'a HeaderMap, query_token: Option<&'a str>) -> Option<&'a str> {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+        .or(query_token)
+        .filter(|token| !token.is_empty())
+}
+
 /// Validate JWT token and extract claims
-pub fn validate_token(token: &str, secret: &str) -> Result<Claims> {
-    decode::<Claims>(
-        token,
-        &DecodingKey::from_secret(secret.as_bytes()),
-        &Validation::default(),
-    )
-    .map(|data| data.claims)
-    .map_err(|e| Error::AuthenticationFailed(format!("Invalid token: {}", e)))
+pub fn validate_token(token: &str, keys: &KeyRing) -> Result<Claims> {
+    keys.verify(token)
+}
+
+/// Public signing keys, for services that verify branch tokens themselves
+pub async fn jwks(State(state): State<crate::server::AppState>) -> Json<jsonwebtoken::jwk::JwkSet> {
+    Json(state.keys.jwks())
 }
 
 /// Hash API key using argon2
@@ -154,4 +556,70 @@ mod tests {
         assert!(verify_api_key(api_key, &hash).unwrap());
         assert!(!verify_api_key("wrong_key", &hash).unwrap());
     }
+
+    #[test]
+    fn test_issue_and_validate_token() {
+        let keys = KeyRing::ephemeral();
+        let (token, expires_at) = issue_token(
+            &TenantId::new("tenant_a"),
+            &BranchId::new("main"),
+            Some("key_1"),
+            &keys,
+            900,
+        )
+        .unwrap();
+
+        let claims = validate_token(&token, &keys).unwrap();
+        assert_eq!(claims.tenant_id, "tenant_a");
+        assert_eq!(claims.branch_id, "main");
+        assert_eq!(claims.exp, expires_at);
+        assert_eq!(claims.api_key_id.as_deref(), Some("key_1"));
+
+        assert!(validate_token(&token, &KeyRing::ephemeral()).is_err());
+    }
+
+    #[test]
+    fn test_refresh_tokens_are_random_and_hashed() {
+        let first = generate_secret();
+        let second = generate_secret();
+        assert_eq!(first.len(), 64);
+        assert_ne!(first, second);
+
+        assert_eq!(hash_refresh_token(&first), hash_refresh_token(&first));
+        assert_ne!(hash_refresh_token(&first), first);
+        assert_ne!(hash_refresh_token(&first), hash_refresh_token(&second));
+    }
+
+    #[test]
+    fn test_match_api_key_prefers_prefixed_id() {
+        let row = |id: &str, api_key: &str| ApiKeyRow {
+            id: id.to_string(),
+            label: id.to_string(),
+            key_hash: hash_api_key(api_key).unwrap(),
+            created_at: chrono::Utc::now(),
+            expires_at: None,
+            last_used_at: None,
+            revoked_at: None,
+        };
+        let keys = vec![row("legacy", "old_plain_key"), row("abc", "abc.secret")];
+
+        assert_eq!(match_api_key("abc.secret", &keys).map(|k| k.id.as_str()), Some("abc"));
+        assert_eq!(match_api_key("old_plain_key", &keys).map(|k| k.id.as_str()), Some("legacy"));
+        assert!(match_api_key("abc.wrong", &keys).is_none());
+        assert!(match_api_key("unknown.secret", &keys).is_none());
+    }
+
+    #[test]
+    fn test_bearer_token_sources() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(bearer_token(&headers, None), None);
+        assert_eq!(bearer_token(&headers, Some("from_query")), Some("from_query"));
+        assert_eq!(bearer_token(&headers, Some("")), None);
+
+        headers.insert(AUTHORIZATION, "Bearer from_header".parse().unwrap());
+        assert_eq!(bearer_token(&headers, Some("from_query")), Some("from_header"));
+
+        headers.insert(AUTHORIZATION, "Basic abc".parse().unwrap());
+        assert_eq!(bearer_token(&headers, None), None);
+    }
 }
@@ -1,5 +1,5 @@
 use anyhow::Result;
-use common::{DatabaseConfig, RedisConfig, SecurityConfig, ServerConfig};
+use common::config::{DatabaseConfig, RedisConfig, SecurityConfig, ServerConfig, TlsConfig, TrialConfig};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -8,6 +8,7 @@ pub struct Config {
     pub database: DatabaseConfig,
     pub redis: RedisConfig,
     pub security: SecurityConfig,
+    pub trial: TrialConfig,
 }
 
 impl Config {
@@ -23,9 +24,39 @@ impl Config {
             heartbeat_interval_secs: std::env::var("HEARTBEAT_INTERVAL")
                 .unwrap_or_else(|_| "30".to_string())
                 .parse()?,
+            heartbeat_timeout_secs: std::env::var("HEARTBEAT_TIMEOUT")
+                .unwrap_or_else(|_| "90".to_string())
+                .parse()?,
             message_timeout_secs: std::env::var("MESSAGE_TIMEOUT")
                 .unwrap_or_else(|_| "60".to_string())
                 .parse()?,
+            offline_message_ttl_secs: std::env::var("OFFLINE_MESSAGE_TTL")
+                .unwrap_or_else(|_| "86400".to_string())
+                .parse()?,
+            session_delivery: std::env::var("SESSION_DELIVERY")
+                .unwrap_or_else(|_| "primary".to_string())
+                .parse()?,
+            ws_compression: std::env::var("WS_COMPRESSION")
+                .unwrap_or_else(|_| "true".to_string())
+                .parse()?,
+            ws_compression_threshold: std::env::var("WS_COMPRESSION_THRESHOLD")
+                .unwrap_or_else(|_| "1024".to_string())
+                .parse()?,
+            min_protocol_version: std::env::var("MIN_PROTOCOL_VERSION")
+                .unwrap_or_else(|_| "0.1".to_string())
+                .parse()?,
+            max_message_size: std::env::var("MAX_MESSAGE_SIZE")
+                .unwrap_or_else(|_| "67108864".to_string())
+                .parse()?,
+            chunk_size: std::env::var("CHUNK_SIZE")
+                .unwrap_or_else(|_| "262144".to_string())
+                .parse()?,
+            delivery_max_attempts: std::env::var("DELIVERY_MAX_ATTEMPTS")
+                .unwrap_or_else(|_| "5".to_string())
+                .parse()?,
+            delivery_retry_max_secs: std::env::var("DELIVERY_RETRY_MAX")
+                .unwrap_or_else(|_| "900".to_string())
+                .parse()?,
         };
 
         let database = DatabaseConfig {
@@ -51,17 +82,84 @@ impl Config {
         };
 
         let security = SecurityConfig {
-            jwt_secret: std::env::var("JWT_SECRET")
-                .expect("JWT_SECRET must be set"),
             jwt_expiry_secs: std::env::var("JWT_EXPIRY")
                 .unwrap_or_else(|_| "900".to_string())
                 .parse()?,
+            jwt_key_rotation_secs: std::env::var("JWT_KEY_ROTATION")
+                .unwrap_or_else(|_| "604800".to_string())
+                .parse()?,
+            refresh_token_expiry_secs: std::env::var("REFRESH_TOKEN_EXPIRY")
+                .unwrap_or_else(|_| "2592000".to_string())
+                .parse()?,
+            api_key_rotation_overlap_secs: std::env::var("API_KEY_ROTATION_OVERLAP")
+                .unwrap_or_else(|_| "604800".to_string())
+                .parse()?,
             require_tls: std::env::var("REQUIRE_TLS")
                 .unwrap_or_else(|_| "true".to_string())
                 .parse()?,
+            trust_forwarded_proto: std::env::var("TRUST_FORWARDED_PROTO")
+                .unwrap_or_else(|_| "false".to_string())
+                .parse()?,
+            tls: match (std::env::var("TLS_CERT_PATH").ok(), std::env::var("TLS_KEY_PATH").ok()) {
+                (Some(cert_path), Some(key_path)) => Some(TlsConfig {
+                    cert_path,
+                    key_path,
+                    client_ca_path: std::env::var("TLS_CLIENT_CA_PATH").ok(),
+                    require_client_cert: std::env::var("TLS_REQUIRE_CLIENT_CERT")
+                        .unwrap_or_else(|_| "false".to_string())
+                        .parse()?,
+                }),
+                (None, None) => None,
+                _ => anyhow::bail!("TLS_CERT_PATH and TLS_KEY_PATH must be set together"),
+            },
             rate_limit_per_sec: std::env::var("RATE_LIMIT")
                 .unwrap_or_else(|_| "100".to_string())
                 .parse()?,
+            auth_rate_limit_per_min: std::env::var("AUTH_RATE_LIMIT")
+                .unwrap_or_else(|_| "10".to_string())
+                .parse()?,
+            admin_token: std::env::var("ADMIN_API_TOKEN").ok().filter(|t| !t.is_empty()),
+            admin_token_expiry_secs: std::env::var("ADMIN_TOKEN_EXPIRY")
+                .unwrap_or_else(|_| "28800".to_string())
+                .parse()?,
+            require_message_signatures: std::env::var("REQUIRE_MESSAGE_SIGNATURES")
+                .unwrap_or_else(|_| "false".to_string())
+                .parse()?,
+            max_clock_skew_secs: std::env::var("MAX_CLOCK_SKEW")
+                .unwrap_or_else(|_| "300".to_string())
+                .parse()?,
+        };
+
+        if security.max_clock_skew_secs <= 0 {
+            anyhow::bail!("MAX_CLOCK_SKEW must be positive");
+        }
+        if server.chunk_size == 0 || server.chunk_size > server.max_message_size {
+            anyhow::bail!("CHUNK_SIZE must be between 1 and MAX_MESSAGE_SIZE");
+        }
+        if server.delivery_max_attempts == 0 {
+            anyhow::bail!("DELIVERY_MAX_ATTEMPTS must be at least 1");
+        }
+        if server.min_protocol_version > protocol::version::CURRENT {
+            anyhow::bail!(
+                "MIN_PROTOCOL_VERSION {} is newer than protocol {} this hub speaks",
+                server.min_protocol_version,
+                protocol::version::CURRENT
+            );
+        }
+
+        let trial = TrialConfig {
+            duration_secs: std::env::var("TRIAL_DURATION")
+                .unwrap_or_else(|_| "1209600".to_string())
+                .parse()?,
+            max_branches: std::env::var("TRIAL_MAX_BRANCHES")
+                .unwrap_or_else(|_| "2".to_string())
+                .parse()?,
+            max_connections_per_branch: std::env::var("TRIAL_MAX_CONNECTIONS_PER_BRANCH")
+                .unwrap_or_else(|_| "1".to_string())
+                .parse()?,
+            rate_limit_per_sec: std::env::var("TRIAL_RATE_LIMIT")
+                .unwrap_or_else(|_| "10".to_string())
+                .parse()?,
         };
 
         Ok(Config {
@@ -69,6 +167,7 @@ impl Config {
             database,
             redis,
             security,
+            trial,
         })
     }
 }
@@ -0,0 +1,346 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Utc};
+use common::{Error, Result};
+use jsonwebtoken::{
+    decode, decode_header, encode,
+    jwk::{
+        AlgorithmParameters, CommonParameters, EllipticCurve, Jwk, JwkSet, KeyAlgorithm,
+        OctetKeyPairParameters, OctetKeyPairType, PublicKeyUse,
+    },
+    Algorithm, DecodingKey, EncodingKey, Header, Validation,
+};
+use parking_lot::RwLock;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use serde::{de::DeserializeOwned, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info};
+
+use crate::storage::{SigningKeyRow, Storage};
+
+/// How often each hub reloads keys, picking up ones created by other instances
+const KEY_REFRESH_INTERVAL: Duration = Duration::from_secs(300);
+
+/// One Ed25519 key pair used to sign access tokens
+pub struct SigningKey {
+    kid: String,
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+    public_key: Vec<u8>,
+    /// Signs new tokens from this point on, until a newer key activates
+    activates_at: DateTime<Utc>,
+    /// Last moment a token signed with this key can be valid
+    expires_at: DateTime<Utc>,
+}
+
+impl SigningKey {
+    /// Generate a fresh key, ready to store, that signs from `activates_at` for one rotation period
+    fn generate(
+        activates_at: DateTime<Utc>,
+        rotation: chrono::Duration,
+        token_lifetime: chrono::Duration,
+    ) -> Result<SigningKeyRow> {
+        let rng = ring::rand::SystemRandom::new();
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng)
+            .map_err(|_| Error::Internal("Failed to generate signing key".to_string()))?;
+        let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref())
+            .map_err(|_| Error::Internal("Failed to parse signing key".to_string()))?;
+
+        let retires_at = activates_at + rotation;
+        let row = SigningKeyRow {
+            kid: uuid::Uuid::new_v4().to_string(),
+            private_key: pkcs8.as_ref().to_vec(),
+            public_key: pair.public_key().as_ref().to_vec(),
+            activates_at,
+            retires_at,
+            expires_at: retires_at + token_lifetime,
+        };
+        Ok(row)
+    }
+
+    fn jwk(&self) -> Jwk {
+        Jwk {
+            common: CommonParameters {
+                public_key_use: Some(PublicKeyUse::Signature),
+                key_id: Some(self.kid.clone()),
+                key_algorithm: Some(KeyAlgorithm::EdDSA),
+                ..Default::default()
+            },
+            algorithm: AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                key_type: OctetKeyPairType::OctetKeyPair,
+                curve: EllipticCurve::Ed25519,
+                x: URL_SAFE_NO_PAD.encode(&self.public_key),
+            }),
+        }
+    }
+}
+
+impl From<&SigningKeyRow> for SigningKey {
+    fn from(row: &SigningKeyRow) -> Self {
+        Self {
+            kid: row.kid.clone(),
+            encoding: EncodingKey::from_ed_der(&row.private_key),
+            decoding: DecodingKey::from_ed_der(&row.public_key),
+            public_key: row.public_key.clone(),
+            activates_at: row.activates_at,
+            expires_at: row.expires_at,
+        }
+    }
+}
+
+/// Token signing keys shared by every hub instance through the `signing_keys` table.
+/// Tokens are signed with EdDSA and carry a `kid`, so verifiers only need the public
+/// keys from `/.well-known/jwks.json`. The next key is published a full rotation
+/// period before it starts signing, and retired keys stay published until the last
+/// token they signed has expired.
+#[derive(Clone, Default)]
+pub struct KeyRing {
+    keys: Arc<RwLock<Vec<SigningKey>>>,
+}
+
+impl KeyRing {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sign claims with the newest active key
+    pub fn sign<T: Serialize>(&self, claims: &T) -> Result<String> {
+        let now = Utc::now();
+        let keys = self.keys.read();
+        let key = keys
+            .iter()
+            .filter(|k| k.activates_at <= now)
+            .max_by_key(|k| k.activates_at)
+            .ok_or_else(|| Error::Internal("No active signing key".to_string()))?;
+
+        let mut header = Header::new(Algorithm::EdDSA);
+        header.kid = Some(key.kid.clone());
+
+        encode(&header, claims, &key.encoding)
+            .map_err(|e| Error::Internal(format!("Failed to encode token: {}", e)))
+    }
+
+    /// Verify a token against the published key named by its `kid`.
+    /// Tokens carrying an `aud` claim are refused; those are for `verify_audience`.
+    pub fn verify<T: DeserializeOwned>(&self, token: &str) -> Result<T> {
+        self.verify_with(token, Validation::new(Algorithm::EdDSA))
+    }
+
+    /// Verify a token that must name `audience` in its `aud` claim
+    pub fn verify_audience<T: DeserializeOwned>(&self, token: &str, audience: &str) -> Result<T> {
+        let mut validation = Validation::new(Algorithm::EdDSA);
+        validation.set_audience(&[audience]);
+        validation.set_required_spec_claims(&["exp", "aud"]);
+        self.verify_with(token, validation)
+    }
+
+    fn verify_with<T: DeserializeOwned>(&self, token: &str, validation: Validation) -> Result<T> {
+        let invalid = |e: &dyn std::fmt::Display| {
+            Error::AuthenticationFailed(format!("Invalid token: {}", e))
+        };
+
+        let header = decode_header(token).map_err(|e| invalid(&e))?;
+        let kid = header.kid.ok_or_else(|| invalid(&"missing kid"))?;
+
+        let keys = self.keys.read();
+        let key = keys
+            .iter()
+            .find(|k| k.kid == kid && k.expires_at > Utc::now())
+            .ok_or_else(|| invalid(&"unknown kid"))?;
+
+        decode::<T>(token, &key.decoding, &validation)
+            .map(|data| data.claims)
+            .map_err(|e| invalid(&e))
+    }
+
+    /// Public half of every key that may still verify a token
+    pub fn jwks(&self) -> JwkSet {
+        let now = Utc::now();
+        JwkSet {
+            keys: self
+                .keys
+                .read()
+                .iter()
+                .filter(|k| k.expires_at > now)
+                .map(SigningKey::jwk)
+                .collect(),
+        }
+    }
+
+    /// Make sure a key is signing now and its successor is already published, then reload
+    pub async fn rotate(&self, storage: &Storage, rotation_secs: i64, token_lifetime_secs: i64) -> Result<()> {
+        if rotation_secs <= 0 {
+            return Err(Error::Internal("Key rotation period must be positive".to_string()));
+        }
+
+        let rotation = chrono::Duration::seconds(rotation_secs);
+        let token_lifetime = chrono::Duration::seconds(token_lifetime_secs);
+        let now = Utc::now();
+
+        let removed = storage.delete_expired_signing_keys().await?;
+        if removed > 0 {
+            info!("Removed {} expired signing keys", removed);
+        }
+
+        let mut latest = storage.latest_signing_key_window().await?;
+        // Until the newest key is scheduled in the future there is no published successor
+        while !matches!(latest, Some((activates_at, _)) if activates_at > now) {
+            let activates_at = latest.map_or(now, |(_, retires_at)| retires_at.max(now));
+            let key = SigningKey::generate(activates_at, rotation, token_lifetime)?;
+
+            // Another hub may schedule the same slot first; theirs wins
+            if storage.insert_signing_key(&key).await? {
+                info!("Scheduled signing key {} from {}", key.kid, key.activates_at);
+            }
+
+            latest = storage.latest_signing_key_window().await?;
+        }
+
+        self.reload(storage).await
+    }
+
+    async fn reload(&self, storage: &Storage) -> Result<()> {
+        let keys = storage
+            .list_signing_keys()
+            .await?
+            .iter()
+            .map(SigningKey::from)
+            .collect();
+        *self.keys.write() = keys;
+        Ok(())
+    }
+
+    #[cfg(test)]
+    fn with_keys(keys: Vec<SigningKey>) -> Self {
+        Self {
+            keys: Arc::new(RwLock::new(keys)),
+        }
+    }
+
+    /// In-memory ring with one active key
+    #[cfg(test)]
+    pub(crate) fn ephemeral() -> Self {
+        let row = SigningKey::generate(
+            Utc::now(),
+            chrono::Duration::days(7),
+            chrono::Duration::minutes(15),
+        )
+        .unwrap();
+        Self::with_keys(vec![SigningKey::from(&row)])
+    }
+}
+
+/// Keep the key ring rotated and in sync with the database
+pub fn spawn_rotation(keys: KeyRing, storage: Storage, rotation_secs: i64, token_lifetime_secs: i64) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(KEY_REFRESH_INTERVAL);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            if let Err(e) = keys.rotate(&storage, rotation_secs, token_lifetime_secs).await {
+                error!("Signing key rotation failed: {}", e);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(activates_in: i64) -> SigningKey {
+        let row = SigningKey::generate(
+            Utc::now() + chrono::Duration::seconds(activates_in),
+            chrono::Duration::days(7),
+            chrono::Duration::minutes(15),
+        )
+        .unwrap();
+        SigningKey::from(&row)
+    }
+
+    #[derive(Debug, Serialize, serde::Deserialize, PartialEq)]
+    struct TestClaims {
+        sub: String,
+        exp: i64,
+    }
+
+    fn claims() -> TestClaims {
+        TestClaims {
+            sub: "tenant_a:main".to_string(),
+            exp: Utc::now().timestamp() + 900,
+        }
+    }
+
+    #[test]
+    fn test_sign_uses_active_key_and_verifies() {
+        let current = key(-60);
+        let next = key(3600);
+        let current_kid = current.kid.clone();
+        let ring = KeyRing::with_keys(vec![current, next]);
+
+        let token = ring.sign(&claims()).unwrap();
+        let header = decode_header(&token).unwrap();
+        assert_eq!(header.alg, Algorithm::EdDSA);
+        assert_eq!(header.kid.as_deref(), Some(current_kid.as_str()));
+
+        let verified: TestClaims = ring.verify(&token).unwrap();
+        assert_eq!(verified.sub, "tenant_a:main");
+    }
+
+    #[test]
+    fn test_verify_rejects_foreign_and_hmac_tokens() {
+        let ring = KeyRing::with_keys(vec![key(-60)]);
+        let other = KeyRing::with_keys(vec![key(-60)]);
+
+        let foreign = other.sign(&claims()).unwrap();
+        assert!(ring.verify::<TestClaims>(&foreign).is_err());
+
+        let hmac = encode(&Header::default(), &claims(), &EncodingKey::from_secret(b"secret")).unwrap();
+        assert!(ring.verify::<TestClaims>(&hmac).is_err());
+    }
+
+    #[test]
+    fn test_jwks_publishes_next_key_and_verifies() {
+        let ring = KeyRing::with_keys(vec![key(-60), key(3600)]);
+        let jwks = ring.jwks();
+        assert_eq!(jwks.keys.len(), 2);
+
+        // A verifier holding only the JWKS can check hub tokens
+        let token = ring.sign(&claims()).unwrap();
+        let kid = decode_header(&token).unwrap().kid.unwrap();
+        let jwk = jwks.find(&kid).unwrap();
+        let decoding = DecodingKey::from_jwk(jwk).unwrap();
+        assert!(decode::<TestClaims>(&token, &decoding, &Validation::new(Algorithm::EdDSA)).is_ok());
+    }
+
+    #[test]
+    fn test_audience_separates_token_kinds() {
+        #[derive(Debug, Serialize, serde::Deserialize)]
+        struct AudienceClaims {
+            sub: String,
+            exp: i64,
+            aud: String,
+        }
+
+        let ring = KeyRing::with_keys(vec![key(-60)]);
+        let plain = ring.sign(&claims()).unwrap();
+        let admin = ring
+            .sign(&AudienceClaims {
+                sub: "ops".to_string(),
+                exp: Utc::now().timestamp() + 900,
+                aud: "admin".to_string(),
+            })
+            .unwrap();
+
+        assert!(ring.verify::<TestClaims>(&admin).is_err());
+        assert!(ring.verify_audience::<AudienceClaims>(&plain, "admin").is_err());
+        assert!(ring.verify_audience::<AudienceClaims>(&admin, "other").is_err());
+        assert!(ring.verify_audience::<AudienceClaims>(&admin, "admin").is_ok());
+    }
+
+    #[test]
+    fn test_sign_without_active_key_fails() {
+        let ring = KeyRing::with_keys(vec![key(3600)]);
+        assert!(ring.sign(&claims()).is_err());
+    }
+}
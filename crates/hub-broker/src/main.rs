@@ -1,10 +1,19 @@
 mod config;
 mod server;
+mod admin;
+mod admin_auth;
+mod audit;
 mod websocket;
 mod auth;
+mod keys;
 mod routing;
 mod storage;
 mod metrics;
+mod rate_limit;
+mod replay;
+mod tenants;
+mod tls;
+mod transfers;
 
 use anyhow::Result;
 use tracing::{info, error};
@@ -35,11 +44,11 @@ async fn main() -> Result<()> {
     info!("Storage initialized");
 
     // Initialize metrics
-    let metrics_recorder = metrics::init_metrics();
+    let metrics_handle = metrics::init_metrics();
     info!("Metrics initialized");
 
     // Create and start server
-    let server = server::Server::new(config.clone(), storage).await?;
+    let server = server::Server::new(config.clone(), storage, metrics_handle).await?;
 
     info!("Server initialized, starting WebSocket endpoint...");
 
@@ -1,12 +1,15 @@
 use metrics::{counter, gauge, histogram};
 use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
-use axum::{response::IntoResponse, http::StatusCode};
+use axum::{extract::State, response::IntoResponse, http::StatusCode};
+
+use crate::server::AppState;
 
 /// Initialize Prometheus metrics
 pub fn init_metrics() -> PrometheusHandle {
     const EXPONENTIAL_SECONDS: &[f64] = &[
         0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
     ];
+    const COMPRESSION_RATIOS: &[f64] = &[1.0, 1.5, 2.0, 3.0, 5.0, 10.0, 20.0, 50.0];
 
     PrometheusBuilder::new()
         .set_buckets_for_metric(
@@ -14,32 +17,34 @@ pub fn init_metrics() -> PrometheusHandle {
             EXPONENTIAL_SECONDS,
         )
         .unwrap()
+        .set_buckets_for_metric(
+            Matcher::Full("hub_broker_ws_compression_ratio".to_string()),
+            COMPRESSION_RATIOS,
+        )
+        .unwrap()
         .install_recorder()
         .unwrap()
 }
 
 /// Metrics handler endpoint
-pub async fn metrics_handler() -> impl IntoResponse {
-    match metrics_exporter_prometheus::render() {
-        Ok(body) => (StatusCode::OK, body),
-        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, String::new()),
-    }
+pub async fn metrics_handler(State(state): State<AppState>) -> impl IntoResponse {
+    (StatusCode::OK, state.metrics_handle.render())
 }
 
 // Metric recording functions
 pub fn record_connection(tenant_id: &str) {
-    counter!("hub_broker_connections_total", "tenant_id" = tenant_id).increment(1);
+    counter!("hub_broker_connections_total", "tenant_id" => tenant_id.to_string()).increment(1);
 }
 
 pub fn record_disconnection(tenant_id: &str) {
-    counter!("hub_broker_disconnections_total", "tenant_id" = tenant_id).increment(1);
+    counter!("hub_broker_disconnections_total", "tenant_id" => tenant_id.to_string()).increment(1);
 }
 
 pub fn record_message(tenant_id: &str, message_type: &str) {
     counter!(
         "hub_broker_messages_total",
-        "tenant_id" = tenant_id,
-        "type" = message_type
+        "tenant_id" => tenant_id.to_string(),
+        "type" => message_type.to_string()
     )
     .increment(1);
 }
@@ -51,16 +56,50 @@ pub fn record_message_duration(duration_secs: f64) {
 pub fn set_active_connections(tenant_id: &str, count: usize) {
     gauge!(
         "hub_broker_active_connections",
-        "tenant_id" = tenant_id
+        "tenant_id" => tenant_id.to_string()
     )
     .set(count as f64);
 }
 
+pub fn record_rate_limited(tenant_id: &str, scope: &str) {
+    counter!(
+        "hub_broker_rate_limited_total",
+        "tenant_id" => tenant_id.to_string(),
+        "scope" => scope.to_string()
+    )
+    .increment(1);
+}
+
 pub fn record_routing_error(tenant_id: &str, error_type: &str) {
     counter!(
         "hub_broker_routing_errors_total",
-        "tenant_id" = tenant_id,
-        "error" = error_type
+        "tenant_id" => tenant_id.to_string(),
+        "error" => error_type.to_string()
     )
     .increment(1);
 }
+
+/// A message dropped because no session of its recipient negotiated `capability`
+pub fn record_withheld_message(capability: &'static str) {
+    counter!("hub_broker_messages_withheld_total", "capability" => capability).increment(1);
+}
+
+/// How a routed message tracked until acknowledged fared: "delivered", "retried" or "failed"
+pub fn record_delivery_outcome(outcome: &'static str) {
+    counter!("hub_broker_routed_deliveries_total", "outcome" => outcome).increment(1);
+}
+
+/// A message sent or received in chunks; `direction` is "inbound" or "outbound"
+pub fn record_chunked_transfer(direction: &'static str) {
+    counter!("hub_broker_chunked_messages_total", "direction" => direction).increment(1);
+}
+
+/// Sizes of one frame on a compressed connection; `direction` is "inbound" or "outbound"
+pub fn record_compression(direction: &'static str, uncompressed: usize, compressed: usize) {
+    counter!("hub_broker_ws_uncompressed_bytes_total", "direction" => direction).increment(uncompressed as u64);
+    counter!("hub_broker_ws_compressed_bytes_total", "direction" => direction).increment(compressed as u64);
+    if compressed > 0 {
+        histogram!("hub_broker_ws_compression_ratio", "direction" => direction)
+            .record(uncompressed as f64 / compressed as f64);
+    }
+}
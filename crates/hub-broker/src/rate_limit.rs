@@ -0,0 +1,166 @@
+use common::{BranchId, Error, QualifiedBranchId, Result, TenantId};
+use protocol::{ErrorPayload, MessagePayload};
+use tracing::warn;
+
+use crate::{
+    metrics,
+    storage::{Storage, TokenBucket},
+};
+
+/// Error code sent to a throttled branch
+pub const RATE_LIMITED: &str = "RATE_LIMITED";
+
+/// Inbound message limits for one session, fixed at Connect time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageLimits {
+    /// Hub-wide `RATE_LIMIT`, applied to each branch on its own
+    pub branch_per_sec: u32,
+    /// The tenant's `rate_limit_per_sec`, shared by all of its branches
+    pub tenant_per_sec: u32,
+}
+
+/// Whether a message draws from the branch's budget; keepalives and credential
+/// renewal never do, so a throttled branch is not also disconnected
+pub fn is_metered(payload: &MessagePayload) -> bool {
+    !matches!(
+        payload,
+        MessagePayload::Connect(_)
+            | MessagePayload::ConnectAck(_)
+            | MessagePayload::Disconnect(_)
+            | MessagePayload::Heartbeat
+            | MessagePayload::HeartbeatAck
+            | MessagePayload::TokenRenew(_)
+            | MessagePayload::TokenRenewed(_)
+            // Answers to the hub's own traffic
+            | MessagePayload::MessageDelivered(_)
+            // The reassembled message is metered instead
+            | MessagePayload::Chunk(_)
+            | MessagePayload::ChunkResume(_)
+    )
+}
+
+/// Buckets one inbound message draws from; a limit of 0 means unlimited
+/// ENFORCES: Counters are keyed by tenant, so one tenant never spends another's budget
+fn message_buckets(id: &QualifiedBranchId, limits: MessageLimits) -> Vec<TokenBucket> {
+    [
+        (format!("rate_limit:{}:{}", id.tenant_id, id.branch_id), limits.branch_per_sec),
+        (format!("rate_limit:{}", id.tenant_id), limits.tenant_per_sec),
+    ]
+    .into_iter()
+    .filter(|(_, per_sec)| *per_sec > 0)
+    .map(|(key, per_sec)| TokenBucket {
+        key,
+        rate_per_sec: per_sec as f64,
+        // One second's worth of burst
+        capacity: per_sec as f64,
+    })
+    .collect()
+}
+
+fn auth_bucket(tenant_id: &TenantId, branch_id: &BranchId, per_min: u32) -> TokenBucket {
+    TokenBucket {
+        key: format!("auth_rate_limit:{}:{}", tenant_id, branch_id),
+        rate_per_sec: per_min as f64 / 60.0,
+        capacity: per_min as f64,
+    }
+}
+
+/// Charge one inbound message to the branch and its tenant
+pub async fn check_message(storage: &Storage, id: &QualifiedBranchId, limits: MessageLimits) -> Result<()> {
+    take(storage, &message_buckets(id, limits), &id.tenant_id, "message").await
+}
+
+/// Charge one `/auth/token` attempt to the branch it names, whether or not it succeeds
+pub async fn check_auth_attempt(
+    storage: &Storage,
+    tenant_id: &TenantId,
+    branch_id: &BranchId,
+    per_min: u32,
+) -> Result<()> {
+    if per_min == 0 {
+        return Ok(());
+    }
+    take(storage, &[auth_bucket(tenant_id, branch_id, per_min)], tenant_id, "auth").await
+}
+
+async fn take(storage: &Storage, buckets: &[TokenBucket], tenant_id: &TenantId, scope: &str) -> Result<()> {
+    if buckets.is_empty() {
+        return Ok(());
+    }
+
+    match storage.take_rate_limit_tokens(buckets).await {
+        Ok(0) => Ok(()),
+        Ok(retry_after_ms) => {
+            metrics::record_rate_limited(tenant_id.as_str(), scope);
+            Err(Error::RateLimitExceeded { retry_after_ms })
+        }
+        // Fail open: a Redis outage must not take every branch down with it
+        Err(e) => {
+            warn!("Rate limiter unavailable, allowing {} for {}: {}", scope, tenant_id, e);
+            Ok(())
+        }
+    }
+}
+
+/// Tell a branch its message was dropped and when to try again
+pub fn throttled_payload(message_id: &str, retry_after_ms: u64) -> ErrorPayload {
+    ErrorPayload {
+        code: RATE_LIMITED.to_string(),
+        message: format!("Message {} dropped: rate limit exceeded", message_id),
+        details: Some(serde_json::json!({
+            "message_id": message_id,
+            "retry_after_ms": retry_after_ms,
+        })),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn branch() -> QualifiedBranchId {
+        QualifiedBranchId::new(TenantId::new("tenant_a"), BranchId::new("main"))
+    }
+
+    #[test]
+    fn test_message_buckets_per_branch_and_tenant() {
+        let limits = MessageLimits { branch_per_sec: 100, tenant_per_sec: 500 };
+        let buckets = message_buckets(&branch(), limits);
+
+        assert_eq!(buckets.len(), 2);
+        assert_eq!(buckets[0].key, "rate_limit:tenant_a:main");
+        assert_eq!(buckets[0].rate_per_sec, 100.0);
+        assert_eq!(buckets[1].key, "rate_limit:tenant_a");
+        assert_eq!(buckets[1].capacity, 500.0);
+    }
+
+    #[test]
+    fn test_zero_limit_is_unlimited() {
+        let limits = MessageLimits { branch_per_sec: 0, tenant_per_sec: 50 };
+        let buckets = message_buckets(&branch(), limits);
+
+        assert_eq!(buckets.len(), 1);
+        assert_eq!(buckets[0].key, "rate_limit:tenant_a");
+    }
+
+    #[test]
+    fn test_auth_bucket_refills_per_minute() {
+        let bucket = auth_bucket(&TenantId::new("tenant_a"), &BranchId::new("main"), 30);
+        assert_eq!(bucket.key, "auth_rate_limit:tenant_a:main");
+        assert_eq!(bucket.rate_per_sec, 0.5);
+        assert_eq!(bucket.capacity, 30.0);
+    }
+
+    #[test]
+    fn test_keepalives_are_not_metered() {
+        assert!(!is_metered(&MessagePayload::Heartbeat));
+        assert!(is_metered(&MessagePayload::Error(throttled_payload("m1", 10))));
+    }
+
+    #[test]
+    fn test_throttled_payload_carries_retry_after() {
+        let payload = throttled_payload("m1", 250);
+        assert_eq!(payload.code, RATE_LIMITED);
+        assert_eq!(payload.details.unwrap()["retry_after_ms"], 250);
+    }
+}
@@ -0,0 +1,65 @@
+use chrono::{DateTime, Utc};
+use common::QualifiedBranchId;
+use tracing::warn;
+
+use crate::storage::Storage;
+
+/// Whether a message stamped `timestamp` is within `max_skew_secs` of `now`, either way
+pub fn is_fresh(timestamp: DateTime<Utc>, now: DateTime<Utc>, max_skew_secs: i64) -> bool {
+    (now - timestamp).num_seconds().abs() <= max_skew_secs
+}
+
+/// Redis key remembering one message ID; the ID is hashed since the branch picks it
+/// ENFORCES: Keyed by tenant and branch, so one branch cannot burn another's IDs
+fn seen_key(id: &QualifiedBranchId, message_id: &str) -> String {
+    format!(
+        "seen_message:{}:{}:{}",
+        id.tenant_id,
+        id.branch_id,
+        common::utils::calculate_hash(message_id.as_bytes())
+    )
+}
+
+/// Record a message ID; false if the branch already sent it within the window.
+/// Only fresh messages get here, and those are at most `max_skew_secs` old or early,
+/// so remembering IDs for twice that long covers every message that could pass.
+pub async fn first_sighting(storage: &Storage, id: &QualifiedBranchId, message_id: &str, max_skew_secs: i64) -> bool {
+    match storage.mark_seen(&seen_key(id, message_id), 2 * max_skew_secs as u64).await {
+        Ok(first) => first,
+        // Fail open like the rate limiter: a Redis outage must not stop all traffic
+        Err(e) => {
+            warn!("Replay check unavailable, allowing {} from {}: {}", message_id, id, e);
+            true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::{BranchId, TenantId};
+
+    #[test]
+    fn test_freshness_window_is_symmetric() {
+        let now = Utc::now();
+        let skew = chrono::Duration::seconds(300);
+
+        assert!(is_fresh(now, now, 300));
+        assert!(is_fresh(now - skew, now, 300));
+        assert!(is_fresh(now + skew, now, 300));
+        assert!(!is_fresh(now - skew - chrono::Duration::seconds(1), now, 300));
+        assert!(!is_fresh(now + skew + chrono::Duration::seconds(1), now, 300));
+    }
+
+    #[test]
+    fn test_seen_key_scoped_to_branch() {
+        let a = QualifiedBranchId::new(TenantId::new("tenant_a"), BranchId::new("main"));
+        let b = QualifiedBranchId::new(TenantId::new("tenant_b"), BranchId::new("main"));
+
+        assert!(seen_key(&a, "m1").starts_with("seen_message:tenant_a:main:"));
+        assert_ne!(seen_key(&a, "m1"), seen_key(&b, "m1"));
+        assert_ne!(seen_key(&a, "m1"), seen_key(&a, "m2"));
+        // Arbitrary IDs never leak into the key structure
+        assert_eq!(seen_key(&a, "x:y:z").matches(':').count(), 3);
+    }
+}
@@ -1,127 +1,467 @@
-use common::{BranchId, TenantId, QualifiedBranchId, Result, Error};
-use protocol::{Message, MessagePayload};
-use crate::{websocket::ConnectionManager, storage::Storage};
+use common::{utils::calculate_backoff_duration, BranchId, TenantId, QualifiedBranchId, Result, Error};
+use protocol::{Message, MessageDelivered, MessageFailed, MessagePayload, SystemNotification};
+use crate::{
+    audit::{self, AuditEvent},
+    metrics,
+    storage::{QueuedMessage, Storage},
+    websocket::{ConnectionManager, Delivered},
+};
+use serde::Deserialize;
 use std::sync::Arc;
-use tracing::{debug, warn, error};
+use std::time::Duration;
+use tracing::{debug, info, warn, error};
+
+/// How often overdue acknowledgements are looked for
+const RETRY_SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Due deliveries handled per tenant and sweep; the rest wait for the next one
+const RETRY_BATCH: i64 = 500;
+
+/// How routed messages nobody acknowledged are resent
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Wait for an acknowledgement before the first resend; doubles with every send
+    pub ack_timeout: Duration,
+    pub max_backoff: Duration,
+    /// Unacknowledged sends before the sender is told the message failed
+    pub max_attempts: u32,
+}
+
+impl RetryPolicy {
+    /// When to resend a message that has gone out `attempts` times before this send
+    fn next_attempt(&self, attempts: u32) -> chrono::DateTime<chrono::Utc> {
+        let backoff = calculate_backoff_duration(
+            attempts,
+            self.ack_timeout.as_millis() as u64,
+            self.max_backoff.as_millis() as u64,
+        );
+        chrono::Duration::from_std(backoff)
+            .ok()
+            .and_then(|backoff| chrono::Utc::now().checked_add_signed(backoff))
+            .unwrap_or(chrono::DateTime::<chrono::Utc>::MAX_UTC)
+    }
+}
+
+/// Recipients of a hub-originated notification
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "scope", rename_all = "snake_case")]
+pub enum NotificationTarget {
+    Branch { tenant_id: TenantId, branch_id: BranchId },
+    Tenant { tenant_id: TenantId },
+    All,
+}
+
+/// Outcome of a notification fan-out
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NotificationReport {
+    pub recipients: usize,
+    pub delivered: usize,
+    /// Connected, but on a protocol that cannot read it; left queued for a reconnect
+    pub withheld: usize,
+}
 
 /// Message router handles routing messages between branches
 /// CRITICAL: Enforces tenant isolation - messages can only be routed within same tenant
 pub struct MessageRouter {
     connection_manager: Arc<ConnectionManager>,
     storage: Storage,
+    offline_ttl_secs: i64,
+    retry: RetryPolicy,
 }
 
 impl MessageRouter {
-    pub fn new(connection_manager: Arc<ConnectionManager>, storage: Storage) -> Self {
+    pub fn new(
+        connection_manager: Arc<ConnectionManager>,
+        storage: Storage,
+        offline_ttl_secs: i64,
+        retry: RetryPolicy,
+    ) -> Self {
         Self {
             connection_manager,
             storage,
+            offline_ttl_secs,
+            retry,
         }
     }
 
     /// Route message to appropriate destination
-    /// ENFORCES: Tenant isolation
-    pub async fn route_message(&self, message: Message) -> Result<()> {
-        // Extract tenant_id from sender
-        let sender_tenant = self.get_tenant_for_branch(&message.from).await?;
-
+    /// ENFORCES: Tenant isolation - targets are resolved inside the sender's tenant only
+    pub async fn route_message(&self, sender: &QualifiedBranchId, message: Message) -> Result<()> {
         // If message has a specific destination
-        if let Some(ref target_branch) = message.to {
-            // CRITICAL: Verify target branch belongs to same tenant
-            let target_tenant = self.get_tenant_for_branch(target_branch).await?;
-
-            if sender_tenant != target_tenant {
-                error!(
-                    "Cross-tenant routing attempt: {} -> {}",
-                    sender_tenant, target_tenant
-                );
-                return Err(Error::AuthorizationFailed(
-                    "Cannot route messages across tenants".to_string(),
-                ));
+        if let Some(target_branch) = message.to.clone() {
+            // CRITICAL: A branch ID from another tenant never resolves here
+            let target = QualifiedBranchId::new(sender.tenant_id.clone(), target_branch);
+
+            match self.storage.get_branch(&target.tenant_id, &target.branch_id).await {
+                Ok(_) => {}
+                Err(Error::DatabaseError(sqlx::Error::RowNotFound)) => {
+                    error!("Routing attempt to unknown branch: {} -> {}", sender, target);
+                    metrics::record_routing_error(sender.tenant_id.as_str(), "unknown_target");
+                    audit::record(
+                        &self.storage,
+                        AuditEvent::new(&sender.tenant_id, audit::UNKNOWN_ROUTING_TARGET)
+                            .branch(&sender.branch_id)
+                            .data(serde_json::json!({
+                                "message_id": message.id,
+                                "target_branch": target.branch_id,
+                            })),
+                    )
+                    .await;
+                    return Err(Error::RoutingError(format!(
+                        "Unknown target branch {}",
+                        target.branch_id
+                    )));
+                }
+                Err(e) => return Err(e),
             }
 
             // Route to specific branch
-            self.forward_to_branch(target_branch, message).await?;
+            self.forward_to_branch(&target, message).await?;
         } else {
-            // Broadcast to all branches in same tenant
-            self.broadcast_to_tenant(&sender_tenant, message, Some(&message.from))
-                .await?;
+            // Broadcast to the tenant's live sessions. branches.status is not a guide: a
+            // connected branch that reports Syncing or Error overwrites it but still listens.
+            self.connection_manager
+                .broadcast_message(&sender.tenant_id, message, Some(&sender.branch_id))
+                .await;
         }
 
         Ok(())
     }
 
-    /// Forward message to specific branch
-    pub async fn forward_to_branch(&self, target: &BranchId, message: Message) -> Result<()> {
+    /// Forward message to specific branch.
+    /// Routed messages are queued first and stay queued until the target acknowledges
+    /// them; `retry_deliveries` resends them in the meantime.
+    pub async fn forward_to_branch(&self, target: &QualifiedBranchId, message: Message) -> Result<()> {
+        if protocol::delivery::needs_ack(&message.payload) {
+            let sender = message.from.clone();
+            if !self.store_offline_message(target, message.clone(), Some(&sender)).await? {
+                warn!("Refused message {} from {}: its ID is already queued", message.id, sender);
+                metrics::record_delivery_outcome("failed");
+                let failed = MessageFailed {
+                    message_id: message.id,
+                    reason: "Another message with this ID is already queued".to_string(),
+                };
+                return self
+                    .notify_sender(&target.tenant_id, sender, MessagePayload::MessageFailed(failed))
+                    .await;
+            }
+
+            if !self.connection_manager.is_connected(target).await {
+                warn!("Branch {} offline, storing message", target);
+                return Ok(());
+            }
+            // Still queued if this fails; the retry sweep picks it up
+            if let Err(e) = self.send_queued(target, message, 0, Some(sender)).await {
+                warn!("Failed to forward message to {}: {}", target, e);
+            }
+            return Ok(());
+        }
+
+        self.forward_untracked(target, message).await
+    }
+
+    /// Send a message nothing acknowledges, or queue it while the branch is offline
+    async fn forward_untracked(&self, target: &QualifiedBranchId, message: Message) -> Result<()> {
         if self.connection_manager.is_connected(target).await {
             self.connection_manager.send_message(target, message).await?;
             debug!("Message forwarded to {}", target);
         } else {
             // Store message for offline delivery
             warn!("Branch {} offline, storing message", target);
-            self.store_offline_message(target, message).await?;
+            let message_id = message.id.clone();
+            if !self.store_offline_message(target, message, None).await? {
+                warn!("Dropped message {} to {}: its ID is already queued", message_id, target);
+            }
         }
 
         Ok(())
     }
 
-    /// Broadcast message to all branches in a tenant
-    /// ENFORCES: Only broadcasts within tenant boundary
-    async fn broadcast_to_tenant(
+    /// Send a queued message. One a session will acknowledge stays queued with its resend
+    /// scheduled, one no session can read fails; anything else is done once sent.
+    async fn send_queued(
         &self,
-        tenant_id: &TenantId,
+        target: &QualifiedBranchId,
         message: Message,
-        exclude: Option<&BranchId>,
+        attempts: u32,
+        sender: Option<BranchId>,
     ) -> Result<()> {
-        // Get all branches for this tenant
-        let branches = self.storage.list_branches_for_tenant(tenant_id).await?;
-
-        for branch in branches {
-            // Skip excluded branch (usually sender)
-            if let Some(exclude_id) = exclude {
-                if &branch.id == exclude_id {
-                    continue;
+        let message_id = message.id.clone();
+        match self.connection_manager.send_message(target, message).await? {
+            Delivered::AwaitsAck => {
+                debug!("Message {} forwarded to {}, awaiting acknowledgement", message_id, target);
+                self.storage
+                    .record_delivery_attempt(&target.tenant_id, &message_id, self.retry.next_attempt(attempts))
+                    .await
+            }
+            Delivered::Sent => {
+                self.storage
+                    .mark_offline_message_delivered(&target.tenant_id, &message_id)
+                    .await
+            }
+            Delivered::Withheld => {
+                let reason = format!("{} cannot read the message with the protocol it connected with", target.branch_id);
+                self.fail_delivery(target, message_id, sender, reason).await
+            }
+        }
+    }
+
+    /// A branch acknowledged a message routed to it: settle it and tell the sender
+    /// ENFORCES: Only messages addressed to the acknowledging branch are settled
+    pub async fn acknowledge(&self, receiver: &QualifiedBranchId, ack: &MessageDelivered) -> Result<()> {
+        let sender = self
+            .storage
+            .acknowledge_offline_message(&receiver.tenant_id, &receiver.branch_id, &ack.message_id)
+            .await?;
+        let Some(sender) = sender else {
+            debug!("Ignoring acknowledgement of message {} from {}", ack.message_id, receiver);
+            return Ok(());
+        };
+
+        debug!("Message {} acknowledged by {}", ack.message_id, receiver);
+        metrics::record_delivery_outcome("delivered");
+        self.notify_sender(&receiver.tenant_id, sender, MessagePayload::MessageDelivered(ack.clone()))
+            .await
+    }
+
+    /// Resend routed messages whose acknowledgement is overdue, and give up on those out
+    /// of attempts or past their TTL. Tenants that may no longer connect are swept too:
+    /// their messages wait out the TTL and then fail.
+    pub async fn retry_deliveries(&self) -> Result<()> {
+        for tenant_id in self.storage.list_tenant_ids_with_due_deliveries().await? {
+            for queued in self.storage.fetch_due_deliveries(&tenant_id, RETRY_BATCH).await? {
+                let target = QualifiedBranchId::new(tenant_id.clone(), queued.target_branch_id.clone());
+
+                if queued.expired || queued.attempts >= self.retry.max_attempts {
+                    self.give_up(&target, queued).await?;
+                } else if self.connection_manager.is_connected(&target).await {
+                    debug!("Resending message {} to {} (attempt {})", queued.message.id, target, queued.attempts + 1);
+                    metrics::record_delivery_outcome("retried");
+                    if let Err(e) = self
+                        .send_queued(&target, queued.message, queued.attempts, queued.sender_branch_id)
+                        .await
+                    {
+                        warn!("Failed to resend message to {}: {}", target, e);
+                    }
+                } else {
+                    // Resent on reconnect; the sweep comes back only once the TTL passes
+                    self.storage.defer_delivery(&tenant_id, &queued.message.id).await?;
                 }
             }
+        }
+
+        Ok(())
+    }
+
+    /// Fail a routed message out of attempts or past its TTL
+    async fn give_up(&self, target: &QualifiedBranchId, queued: QueuedMessage) -> Result<()> {
+        let reason = if queued.expired {
+            format!("Not acknowledged by {} before the message expired", target.branch_id)
+        } else {
+            format!("Not acknowledged by {} after {} attempts", target.branch_id, queued.attempts)
+        };
+
+        self.fail_delivery(target, queued.message.id, queued.sender_branch_id, reason).await
+    }
+
+    /// Mark a queued message failed and tell its sender, if it has one to tell
+    async fn fail_delivery(
+        &self,
+        target: &QualifiedBranchId,
+        message_id: String,
+        sender: Option<BranchId>,
+        reason: String,
+    ) -> Result<()> {
+        // Acknowledged in the meantime
+        if !self.storage.mark_offline_message_failed(&target.tenant_id, &message_id).await? {
+            return Ok(());
+        }
 
-            // Only send to online branches
-            if self.connection_manager.is_connected(&branch.id).await {
-                if let Err(e) = self
-                    .connection_manager
-                    .send_message(&branch.id, message.clone())
+        warn!("Giving up on message {} to {}: {}", message_id, target, reason);
+        metrics::record_delivery_outcome("failed");
+        match sender {
+            Some(sender) => {
+                let failed = MessageFailed { message_id, reason };
+                self.notify_sender(&target.tenant_id, sender, MessagePayload::MessageFailed(failed))
                     .await
-                {
-                    warn!("Failed to send to {}: {}", branch.id, e);
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Tell a sender how its message fared; queued while the sender is offline
+    async fn notify_sender(&self, tenant_id: &TenantId, sender: BranchId, payload: MessagePayload) -> Result<()> {
+        let sender = QualifiedBranchId::new(tenant_id.clone(), sender);
+        let message = Message::new(BranchId::new("hub"), Some(sender.branch_id.clone()), payload);
+        // Delivery reports are not acknowledged themselves
+        self.forward_untracked(&sender, message).await
+    }
+
+    /// Push a system notification from the hub.
+    /// Every copy is queued first so delivery is tracked in `offline_messages`;
+    /// connected branches get it immediately, the rest on reconnect.
+    pub async fn send_notification(
+        &self,
+        target: &NotificationTarget,
+        notification: SystemNotification,
+    ) -> Result<NotificationReport> {
+        let recipients: Vec<QualifiedBranchId> = match target {
+            NotificationTarget::Branch { tenant_id, branch_id } => {
+                // Validates that the branch belongs to the tenant
+                self.storage.get_branch(tenant_id, branch_id).await?;
+                vec![QualifiedBranchId::new(tenant_id.clone(), branch_id.clone())]
+            }
+            NotificationTarget::Tenant { tenant_id } => self.tenant_recipients(tenant_id).await?,
+            NotificationTarget::All => {
+                let mut all = Vec::new();
+                for tenant_id in self.storage.list_active_tenant_ids().await? {
+                    all.extend(self.tenant_recipients(&tenant_id).await?);
+                }
+                all
+            }
+        };
+
+        let mut report = NotificationReport {
+            recipients: recipients.len(),
+            ..Default::default()
+        };
+
+        for recipient in recipients {
+            let message = Message::new(
+                BranchId::new("hub"),
+                Some(recipient.branch_id.clone()),
+                MessagePayload::SystemNotification(notification.clone()),
+            );
+            let message_id = message.id.clone();
+
+            self.store_offline_message(&recipient, message.clone(), None).await?;
+
+            if !self.connection_manager.is_connected(&recipient).await {
+                continue;
+            }
+            match self.connection_manager.send_message(&recipient, message).await {
+                Ok(Delivered::Sent | Delivered::AwaitsAck) => {
+                    self.storage
+                        .mark_offline_message_delivered(&recipient.tenant_id, &message_id)
+                        .await?;
+                    report.delivered += 1;
                 }
+                Ok(Delivered::Withheld) => report.withheld += 1,
+                Err(e) => debug!("Notification {} to {} stays queued: {}", message_id, recipient, e),
             }
         }
 
-        Ok(())
+        info!(
+            "Notification sent to {} branches ({} delivered immediately, {} withheld)",
+            report.recipients, report.delivered, report.withheld
+        );
+
+        Ok(report)
     }
 
-    /// Get tenant ID for a branch
-    async fn get_tenant_for_branch(&self, branch_id: &BranchId) -> Result<TenantId> {
-        // This should be cached in production
-        self.storage.get_tenant_for_branch(branch_id).await
+    /// All branches of a tenant, online or not
+    async fn tenant_recipients(&self, tenant_id: &TenantId) -> Result<Vec<QualifiedBranchId>> {
+        Ok(self
+            .storage
+            .list_all_branches_for_tenant(tenant_id)
+            .await?
+            .into_iter()
+            .map(|branch| QualifiedBranchId::new(tenant_id.clone(), branch.id))
+            .collect())
     }
 
-    /// Store message for offline delivery
-    async fn store_offline_message(&self, _target: &BranchId, _message: Message) -> Result<()> {
-        // TODO: Implement Redis-based message queue
-        // Messages should be stored with TTL
-        Ok(())
+    /// Store message for offline delivery; with a `sender`, it is tracked until acknowledged.
+    /// Returns false if its ID is already queued in the tenant.
+    async fn store_offline_message(
+        &self,
+        target: &QualifiedBranchId,
+        message: Message,
+        sender: Option<&BranchId>,
+    ) -> Result<bool> {
+        let ttl = chrono::Utc::now() + chrono::Duration::seconds(self.offline_ttl_secs);
+        self.storage
+            .enqueue_offline_message(&target.tenant_id, &target.branch_id, &message, ttl, sender)
+            .await
     }
 
     /// Deliver pending offline messages when branch reconnects
-    pub async fn deliver_offline_messages(&self, _branch_id: &BranchId) -> Result<()> {
-        // TODO: Retrieve and deliver stored messages
+    pub async fn deliver_offline_messages(&self, target: &QualifiedBranchId) -> Result<()> {
+        let pending = self
+            .storage
+            .fetch_pending_offline_messages(&target.tenant_id, &target.branch_id)
+            .await?;
+
+        if pending.is_empty() {
+            return Ok(());
+        }
+
+        info!("Delivering {} queued messages to {}", pending.len(), target);
+
+        for queued in pending {
+            // Stop at the first failure; the rest stay queued for the next connect
+            self.send_queued(target, queued.message, queued.attempts, queued.sender_branch_id).await?;
+        }
+
         Ok(())
     }
 }
 
+/// Keep resending unacknowledged routed messages and failing the ones that run out
+pub fn spawn_delivery_retries(router: Arc<MessageRouter>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(RETRY_SWEEP_INTERVAL);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            if let Err(e) = router.retry_deliveries().await {
+                error!("Delivery retry sweep failed: {}", e);
+            }
+        }
+    });
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     // Add tests for routing logic
+
+    #[test]
+    fn test_resends_back_off_up_to_the_cap() {
+        let retry = RetryPolicy {
+            ack_timeout: Duration::from_secs(60),
+            max_backoff: Duration::from_secs(900),
+            max_attempts: 5,
+        };
+        let wait = |attempts| (retry.next_attempt(attempts) - chrono::Utc::now()).num_seconds();
+
+        assert!((59..=60).contains(&wait(0)));
+        assert!((119..=120).contains(&wait(1)));
+        assert!((899..=900).contains(&wait(10)));
+        assert!((899..=900).contains(&wait(u32::MAX)));
+    }
+
+    #[test]
+    fn test_notification_target_deserialize() {
+        let target: NotificationTarget = serde_json::from_value(serde_json::json!({
+            "scope": "branch",
+            "tenant_id": "tenant_a",
+            "branch_id": "main",
+        }))
+        .unwrap();
+        assert!(matches!(
+            target,
+            NotificationTarget::Branch { ref tenant_id, ref branch_id }
+                if tenant_id.as_str() == "tenant_a" && branch_id.as_str() == "main"
+        ));
+
+        let target: NotificationTarget =
+            serde_json::from_value(serde_json::json!({ "scope": "tenant", "tenant_id": "tenant_a" }))
+                .unwrap();
+        assert!(matches!(target, NotificationTarget::Tenant { .. }));
+
+        let target: NotificationTarget =
+            serde_json::from_value(serde_json::json!({ "scope": "all" })).unwrap();
+        assert!(matches!(target, NotificationTarget::All));
+    }
 }
@@ -1,18 +1,18 @@
-use crate::{config::Config, storage::Storage, websocket, auth, routing, metrics};
+use crate::{admin, config::Config, storage::Storage, websocket, auth, keys, routing, metrics, tenants, tls, transfers};
 use anyhow::Result;
 use axum::{
     routing::{get, post},
     Router,
-    extract::State,
     response::Json,
 };
-use std::sync::Arc;
+use metrics_exporter_prometheus::PrometheusHandle;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 use tower_http::{
     cors::CorsLayer,
     trace::TraceLayer,
     compression::CompressionLayer,
 };
-use tracing::info;
+use tracing::{info, warn};
 
 #[derive(Clone)]
 pub struct AppState {
@@ -20,6 +20,10 @@ pub struct AppState {
     pub storage: Storage,
     pub connection_manager: Arc<websocket::ConnectionManager>,
     pub message_router: Arc<routing::MessageRouter>,
+    /// Chunked transfers to and from branches, resumable across reconnects
+    pub transfers: Arc<transfers::TransferStore>,
+    pub keys: keys::KeyRing,
+    pub metrics_handle: PrometheusHandle,
 }
 
 pub struct Server {
@@ -28,21 +32,60 @@ pub struct Server {
 }
 
 impl Server {
-    pub async fn new(config: Config, storage: Storage) -> Result<Self> {
+    pub async fn new(
+        config: Config,
+        storage: Storage,
+        metrics_handle: PrometheusHandle,
+    ) -> Result<Self> {
         let connection_manager = Arc::new(websocket::ConnectionManager::new(
             config.server.max_connections,
+            config.server.session_delivery,
         ));
 
         let message_router = Arc::new(routing::MessageRouter::new(
             connection_manager.clone(),
             storage.clone(),
+            config.server.offline_message_ttl_secs,
+            routing::RetryPolicy {
+                ack_timeout: Duration::from_secs(config.server.message_timeout_secs),
+                max_backoff: Duration::from_secs(config.server.delivery_retry_max_secs),
+                max_attempts: config.server.delivery_max_attempts,
+            },
         ));
+        routing::spawn_delivery_retries(message_router.clone());
+
+        // Tokens cannot be issued or checked until a signing key is loaded
+        let keys = keys::KeyRing::new();
+        keys.rotate(
+            &storage,
+            config.security.jwt_key_rotation_secs,
+            config.security.jwt_expiry_secs,
+        )
+        .await?;
+        keys::spawn_rotation(
+            keys.clone(),
+            storage.clone(),
+            config.security.jwt_key_rotation_secs,
+            config.security.jwt_expiry_secs,
+        );
+
+        tenants::spawn_status_sweep(storage.clone(), connection_manager.clone());
+        websocket::spawn_presence_sweep(
+            storage.clone(),
+            Duration::from_secs(config.server.heartbeat_timeout_secs),
+        );
+
+        let transfers = Arc::new(transfers::TransferStore::new(config.server.max_message_size));
+        transfers::spawn_sweep(transfers.clone());
 
         let state = AppState {
             config: config.clone(),
             storage,
             connection_manager,
             message_router,
+            transfers,
+            keys,
+            metrics_handle,
         };
 
         Ok(Self { config, state })
@@ -54,9 +97,24 @@ impl Server {
         let addr = format!("{}:{}", self.config.server.host, self.config.server.port);
         let listener = tokio::net::TcpListener::bind(&addr).await?;
 
-        info!("Server listening on {}", addr);
-
-        axum::serve(listener, app).await?;
+        match &self.config.security.tls {
+            Some(tls_config) => {
+                let acceptor = tls::acceptor(tls_config)?;
+                info!(
+                    "Server listening on {} (TLS{})",
+                    addr,
+                    if tls_config.client_ca_path.is_some() { ", client certificates accepted" } else { "" }
+                );
+                tls::serve(listener, app, acceptor).await?;
+            }
+            None => {
+                info!("Server listening on {}", addr);
+                if self.config.security.require_tls && !self.config.security.trust_forwarded_proto {
+                    warn!("REQUIRE_TLS is set without TLS_CERT_PATH or TRUST_FORWARDED_PROTO; every WebSocket upgrade will be refused");
+                }
+                axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await?;
+            }
+        }
 
         Ok(())
     }
@@ -73,11 +131,13 @@ impl Server {
             .route("/metrics", get(metrics::metrics_handler))
 
             // Admin endpoints
-            .route("/admin/branches", get(admin::list_branches))
-            .route("/admin/branches/:id/status", get(admin::branch_status))
+            .nest("/admin", admin::router(self.state.clone()))
 
             // Authentication
             .route("/auth/token", post(auth::generate_token))
+            .route("/auth/refresh", post(auth::refresh_token))
+            .route("/auth/revoke", post(auth::revoke_token))
+            .route("/.well-known/jwks.json", get(auth::jwks))
 
             .layer(CorsLayer::permissive())
             .layer(TraceLayer::new_for_http())
@@ -92,31 +152,3 @@ async fn health_check() -> Json<serde_json::Value> {
         "timestamp": chrono::Utc::now().to_rfc3339(),
     }))
 }
-
-mod admin {
-    use super::*;
-    use axum::extract::Path;
-
-    pub async fn list_branches(
-        State(state): State<AppState>,
-    ) -> Json<serde_json::Value> {
-        let connections = state.connection_manager.list_connections().await;
-        Json(serde_json::json!({
-            "total": connections.len(),
-            "branches": connections,
-        }))
-    }
-
-    pub async fn branch_status(
-        State(state): State<AppState>,
-        Path(id): Path<String>,
-    ) -> Json<serde_json::Value> {
-        let branch_id = common::BranchId::new(id);
-        let is_connected = state.connection_manager.is_connected(&branch_id).await;
-
-        Json(serde_json::json!({
-            "branch_id": branch_id.as_str(),
-            "connected": is_connected,
-        }))
-    }
-}
@@ -1,9 +1,17 @@
-use common::{BranchId, TenantId, Tenant, BranchInfo, Result, Error};
-use sqlx::{PgPool, postgres::PgPoolOptions};
+use common::{BranchId, BranchStatus, TenantId, Tenant, TenantStatus, BranchInfo, Result, Error};
+use protocol::Message;
+use sqlx::{PgPool, Postgres, Transaction, postgres::PgPoolOptions, types::Json};
 use redis::aio::ConnectionManager as RedisConnectionManager;
+use std::collections::HashMap;
+use std::net::IpAddr;
 use std::time::Duration;
 use tracing::info;
 
+use crate::audit::AuditEvent;
+
+/// Role tenant-scoped transactions run as; the row-level security policies apply to it
+const TENANT_ROLE: &str = "hub_tenant";
+
 /// Storage layer handles all persistence
 /// CRITICAL: Implements tenant isolation at database level
 #[derive(Clone)]
@@ -21,7 +29,7 @@ impl Storage {
             .acquire_timeout(Duration::from_secs(config.database.connect_timeout_secs))
             .connect(&config.database.url)
             .await
-            .map_err(|e| Error::DatabaseError(e))?;
+            .map_err(Error::DatabaseError)?;
 
         info!("PostgreSQL pool created");
 
@@ -46,6 +54,23 @@ impl Storage {
         Ok(Self { pg_pool, redis })
     }
 
+    /// Begin a transaction confined to one tenant: it runs as `hub_tenant` with
+    /// `app.current_tenant` set, so row-level security hides every other tenant's
+    /// branches, sync history, queued messages, conflicts, audit entries and credentials
+    /// CRITICAL: Defense in depth; queries inside still filter by tenant_id themselves
+    pub async fn begin_tenant(&self, tenant_id: &TenantId) -> Result<Transaction<'static, Postgres>> {
+        let mut tx = self.pg_pool.begin().await.map_err(Error::DatabaseError)?;
+
+        sqlx::query("SELECT set_config('role', $1, true), set_config('app.current_tenant', $2, true)")
+            .bind(TENANT_ROLE)
+            .bind(tenant_id.as_str())
+            .execute(&mut *tx)
+            .await
+            .map_err(Error::DatabaseError)?;
+
+        Ok(tx)
+    }
+
     /// Get tenant by ID
     pub async fn get_tenant(&self, tenant_id: &TenantId) -> Result<Tenant> {
         let row = sqlx::query_as::<_, TenantRow>(
@@ -54,7 +79,7 @@ impl Storage {
         .bind(tenant_id.as_str())
         .fetch_one(&self.pg_pool)
         .await
-        .map_err(|e| Error::DatabaseError(e))?;
+        .map_err(Error::DatabaseError)?;
 
         Ok(row.into())
     }
@@ -62,205 +87,1611 @@ impl Storage {
     /// Get branch with tenant validation
     /// CRITICAL: Always validates tenant ownership
     pub async fn get_branch(&self, tenant_id: &TenantId, branch_id: &BranchId) -> Result<BranchRow> {
+        let mut tx = self.begin_tenant(tenant_id).await?;
+
         let row = sqlx::query_as::<_, BranchRow>(
             "SELECT * FROM branches WHERE id = $1 AND tenant_id = $2"
         )
         .bind(branch_id.as_str())
         .bind(tenant_id.as_str())
-        .fetch_one(&self.pg_pool)
+        .fetch_one(&mut *tx)
         .await
-        .map_err(|e| Error::DatabaseError(e))?;
+        .map_err(Error::DatabaseError)?;
 
+        tx.commit().await.map_err(Error::DatabaseError)?;
         Ok(row)
     }
 
-    /// Get API key hash for branch
-    pub async fn get_api_key_hash(&self, tenant_id: &TenantId, branch_id: &BranchId) -> Result<String> {
-        let row: (String,) = sqlx::query_as(
-            "SELECT api_key_hash FROM branches WHERE id = $1 AND tenant_id = $2"
+    /// One page of a tenant's branches by ID, plus the total matching the filter
+    /// CRITICAL: Only returns branches belonging to specified tenant
+    pub async fn list_branches_page(
+        &self,
+        tenant_id: &TenantId,
+        status: Option<BranchStatus>,
+        limit: i64,
+        offset: i64,
+    ) -> Result<(Vec<BranchInfo>, i64)> {
+        let mut tx = self.begin_tenant(tenant_id).await?;
+
+        let status = status.map(|s| s.as_str());
+
+        let rows = sqlx::query_as::<_, BranchRow>(
+            r#"
+            SELECT * FROM branches
+            WHERE tenant_id = $1 AND ($2::text IS NULL OR status = $2)
+            ORDER BY id LIMIT $3 OFFSET $4
+            "#
+        )
+        .bind(tenant_id.as_str())
+        .bind(status)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(Error::DatabaseError)?;
+
+        let (total,): (i64,) = sqlx::query_as(
+            "SELECT COUNT(*) FROM branches WHERE tenant_id = $1 AND ($2::text IS NULL OR status = $2)"
         )
-        .bind(branch_id.as_str())
         .bind(tenant_id.as_str())
-        .fetch_one(&self.pg_pool)
+        .bind(status)
+        .fetch_one(&mut *tx)
         .await
-        .map_err(|e| Error::DatabaseError(e))?;
+        .map_err(Error::DatabaseError)?;
 
-        Ok(row.0)
+        tx.commit().await.map_err(Error::DatabaseError)?;
+        Ok((rows.into_iter().map(|row| row.into()).collect(), total))
     }
 
-    /// Get tenant for a branch
-    pub async fn get_tenant_for_branch(&self, branch_id: &BranchId) -> Result<TenantId> {
-        let row: (String,) = sqlx::query_as(
-            "SELECT tenant_id FROM branches WHERE id = $1"
+    /// A page of a tenant's sync conflicts, newest first, with the total count
+    /// CRITICAL: Scoped to the tenant
+    pub async fn list_conflicts(
+        &self,
+        tenant_id: &TenantId,
+        unresolved_only: bool,
+        limit: i64,
+        offset: i64,
+    ) -> Result<(Vec<ConflictRow>, i64)> {
+        let mut tx = self.begin_tenant(tenant_id).await?;
+
+        let rows = sqlx::query_as::<_, ConflictRow>(
+            r#"
+            SELECT id, table_name, primary_key, branch_a_id, branch_b_id, branch_a_change,
+                   branch_b_change, resolution_strategy, winning_branch_id, resolved_at, created_at
+            FROM conflict_resolutions
+            WHERE tenant_id = $1 AND (NOT $2 OR resolved_at IS NULL)
+            ORDER BY created_at DESC LIMIT $3 OFFSET $4
+            "#
         )
-        .bind(branch_id.as_str())
-        .fetch_one(&self.pg_pool)
+        .bind(tenant_id.as_str())
+        .bind(unresolved_only)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(Error::DatabaseError)?;
+
+        let (total,): (i64,) = sqlx::query_as(
+            "SELECT COUNT(*) FROM conflict_resolutions WHERE tenant_id = $1 AND (NOT $2 OR resolved_at IS NULL)"
+        )
+        .bind(tenant_id.as_str())
+        .bind(unresolved_only)
+        .fetch_one(&mut *tx)
         .await
-        .map_err(|e| Error::DatabaseError(e))?;
+        .map_err(Error::DatabaseError)?;
 
-        Ok(TenantId::new(row.0))
+        tx.commit().await.map_err(Error::DatabaseError)?;
+        Ok((rows, total))
     }
 
-    /// List all branches for a tenant
-    /// CRITICAL: Only returns branches belonging to specified tenant
-    pub async fn list_branches_for_tenant(&self, tenant_id: &TenantId) -> Result<Vec<BranchInfo>> {
+    /// Branch counts by status, unresolved conflicts and undelivered offline messages
+    /// CRITICAL: Scoped to the tenant
+    pub async fn tenant_stats(&self, tenant_id: &TenantId) -> Result<TenantStats> {
+        let mut tx = self.begin_tenant(tenant_id).await?;
+
+        let branches: Vec<(String, i64)> = sqlx::query_as(
+            "SELECT status, COUNT(*) FROM branches WHERE tenant_id = $1 GROUP BY status"
+        )
+        .bind(tenant_id.as_str())
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(Error::DatabaseError)?;
+
+        let (unresolved_conflicts, queued_messages): (i64, i64) = sqlx::query_as(
+            r#"
+            SELECT
+                (SELECT COUNT(*) FROM conflict_resolutions WHERE tenant_id = $1 AND resolved_at IS NULL),
+                (SELECT COUNT(*) FROM offline_messages WHERE tenant_id = $1 AND delivered_at IS NULL)
+            "#
+        )
+        .bind(tenant_id.as_str())
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(Error::DatabaseError)?;
+
+        tx.commit().await.map_err(Error::DatabaseError)?;
+        Ok(TenantStats {
+            branches: branches.into_iter().collect(),
+            unresolved_conflicts,
+            queued_messages,
+        })
+    }
+
+    /// List every branch of a tenant regardless of status (admin view)
+    pub async fn list_all_branches_for_tenant(&self, tenant_id: &TenantId) -> Result<Vec<BranchInfo>> {
+        let mut tx = self.begin_tenant(tenant_id).await?;
+
         let rows = sqlx::query_as::<_, BranchRow>(
-            "SELECT * FROM branches WHERE tenant_id = $1 AND status = 'online'"
+            "SELECT * FROM branches WHERE tenant_id = $1 ORDER BY id"
         )
         .bind(tenant_id.as_str())
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(Error::DatabaseError)?;
+
+        tx.commit().await.map_err(Error::DatabaseError)?;
+        Ok(rows.into_iter().map(|row| row.into()).collect())
+    }
+
+    /// Load several tenants at once; unknown IDs are skipped
+    pub async fn get_tenants(&self, tenant_ids: &[TenantId]) -> Result<Vec<Tenant>> {
+        let ids: Vec<&str> = tenant_ids.iter().map(TenantId::as_str).collect();
+        let rows = sqlx::query_as::<_, TenantRow>(
+            "SELECT * FROM tenants WHERE id = ANY($1)"
+        )
+        .bind(&ids)
         .fetch_all(&self.pg_pool)
         .await
-        .map_err(|e| Error::DatabaseError(e))?;
+        .map_err(Error::DatabaseError)?;
 
         Ok(rows.into_iter().map(|row| row.into()).collect())
     }
 
+    /// List IDs of all tenants whose branches may connect, including unexpired trials
+    pub async fn list_active_tenant_ids(&self) -> Result<Vec<TenantId>> {
+        let rows: Vec<(String,)> = sqlx::query_as(
+            r#"
+            SELECT id FROM tenants
+            WHERE status = 'active'
+               OR (status = 'trial' AND (trial_expires_at IS NULL OR trial_expires_at > NOW()))
+            ORDER BY id
+            "#
+        )
+        .fetch_all(&self.pg_pool)
+        .await
+        .map_err(Error::DatabaseError)?;
+
+        Ok(rows.into_iter().map(|row| TenantId::new(row.0)).collect())
+    }
+
+    /// One page of tenants, newest first, plus the total matching the filter
+    pub async fn list_tenants(&self, filter: &TenantFilter, limit: i64, offset: i64) -> Result<(Vec<Tenant>, i64)> {
+        const WHERE: &str = r#"
+            WHERE ($1::text IS NULL OR status = $1)
+              AND ($2::text IS NULL OR id ILIKE $2 OR name ILIKE $2 OR company_name ILIKE $2)
+        "#;
+        let status = filter.status.map(|s| s.as_str());
+        let search = filter.search.as_deref().map(|s| format!("%{}%", escape_like(s)));
+
+        let rows = sqlx::query_as::<_, TenantRow>(&format!(
+            "SELECT * FROM tenants {} ORDER BY created_at DESC, id LIMIT $3 OFFSET $4",
+            WHERE
+        ))
+        .bind(status)
+        .bind(&search)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&self.pg_pool)
+        .await
+        .map_err(Error::DatabaseError)?;
+
+        let (total,): (i64,) = sqlx::query_as(&format!("SELECT COUNT(*) FROM tenants {}", WHERE))
+            .bind(status)
+            .bind(&search)
+            .fetch_one(&self.pg_pool)
+            .await
+            .map_err(Error::DatabaseError)?;
+
+        Ok((rows.into_iter().map(|row| row.into()).collect(), total))
+    }
+
     /// Create new tenant (admin operation)
+    /// The row and the tenant's schema are created together or not at all.
+    /// Whether a tenant ID has an audit trail, which a deleted tenant leaves behind
+    pub async fn tenant_id_has_history(&self, tenant_id: &TenantId) -> Result<bool> {
+        sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM audit_log WHERE tenant_id = $1)")
+            .bind(tenant_id.as_str())
+            .fetch_one(&self.pg_pool)
+            .await
+            .map_err(Error::DatabaseError)
+    }
+
     pub async fn create_tenant(&self, tenant: &Tenant) -> Result<()> {
+        let mut tx = self.pg_pool.begin().await.map_err(Error::DatabaseError)?;
+
         sqlx::query(
             r#"
             INSERT INTO tenants (id, name, company_name, contact_email, status, max_branches,
-                                max_connections_per_branch, rate_limit_per_sec, database_schema)
-            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+                                max_connections_per_branch, rate_limit_per_sec, database_schema,
+                                trial_expires_at, e2e_required)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
             "#
         )
         .bind(tenant.id.as_str())
         .bind(&tenant.name)
         .bind(&tenant.company_name)
         .bind(&tenant.contact_email)
-        .bind(format!("{:?}", tenant.status))
+        .bind(tenant.status.as_str())
         .bind(tenant.max_branches as i32)
         .bind(tenant.max_connections_per_branch as i32)
         .bind(tenant.rate_limit_per_sec as i32)
         .bind(&tenant.database_schema)
-        .execute(&self.pg_pool)
+        .bind(tenant.trial_expires_at)
+        .bind(tenant.e2e_required)
+        .execute(&mut *tx)
         .await
-        .map_err(|e| Error::DatabaseError(e))?;
+        .map_err(Error::DatabaseError)?;
 
         // Create dedicated schema for tenant's data
         let schema_name = &tenant.database_schema;
-        sqlx::query(&format!("CREATE SCHEMA IF NOT EXISTS {}", schema_name))
-            .execute(&self.pg_pool)
+        sqlx::query(&format!("CREATE SCHEMA IF NOT EXISTS {}", quote_ident(schema_name)))
+            .execute(&mut *tx)
             .await
-            .map_err(|e| Error::DatabaseError(e))?;
+            .map_err(Error::DatabaseError)?;
+
+        tx.commit().await.map_err(Error::DatabaseError)?;
 
         info!("Created tenant {} with schema {}", tenant.id, schema_name);
 
         Ok(())
     }
 
-    /// Create new branch
+    /// Apply the fields set in `update`; returns false if the tenant does not exist
+    pub async fn update_tenant(&self, tenant_id: &TenantId, update: &TenantUpdate) -> Result<bool> {
+        let result = sqlx::query(
+            r#"
+            UPDATE tenants SET
+                name = COALESCE($1, name),
+                company_name = COALESCE($2, company_name),
+                contact_email = COALESCE($3, contact_email),
+                max_branches = COALESCE($4, max_branches),
+                max_connections_per_branch = COALESCE($5, max_connections_per_branch),
+                rate_limit_per_sec = COALESCE($6, rate_limit_per_sec),
+                e2e_required = COALESCE($7, e2e_required),
+                updated_at = NOW()
+            WHERE id = $8
+            "#
+        )
+        .bind(&update.name)
+        .bind(&update.company_name)
+        .bind(&update.contact_email)
+        .bind(update.max_branches.map(|v| v as i32))
+        .bind(update.max_connections_per_branch.map(|v| v as i32))
+        .bind(update.rate_limit_per_sec.map(|v| v as i32))
+        .bind(update.e2e_required)
+        .bind(tenant_id.as_str())
+        .execute(&self.pg_pool)
+        .await
+        .map_err(Error::DatabaseError)?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Delete a tenant with everything it owns, including its data schema
+    pub async fn delete_tenant(&self, tenant_id: &TenantId, audit: &AuditEvent) -> Result<bool> {
+        let mut tx = self.pg_pool.begin().await.map_err(Error::DatabaseError)?;
+
+        let schema: Option<(String,)> = sqlx::query_as(
+            "DELETE FROM tenants WHERE id = $1 RETURNING database_schema"
+        )
+        .bind(tenant_id.as_str())
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(Error::DatabaseError)?;
+
+        let Some((schema,)) = schema else {
+            return Ok(false);
+        };
+
+        sqlx::query(&format!("DROP SCHEMA IF EXISTS {} CASCADE", quote_ident(&schema)))
+            .execute(&mut *tx)
+            .await
+            .map_err(Error::DatabaseError)?;
+
+        // Committed with the delete, so there is a record exactly when the tenant is gone
+        sqlx::query(
+            r#"
+            INSERT INTO audit_log (tenant_id, branch_id, event_type, event_data, ip_address)
+            VALUES ($1, $2, $3, $4, $5::inet)
+            "#
+        )
+        .bind(audit.tenant_id.as_str())
+        .bind(audit.branch_id.as_ref().map(BranchId::as_str))
+        .bind(audit.event_type)
+        .bind(&audit.data)
+        .bind(audit.ip_address.map(|ip| ip.to_string()))
+        .execute(&mut *tx)
+        .await
+        .map_err(Error::DatabaseError)?;
+
+        tx.commit().await.map_err(Error::DatabaseError)?;
+
+        info!("Deleted tenant {} and schema {}", tenant_id, schema);
+
+        Ok(true)
+    }
+
+    /// Create new branch together with its first API key
+    /// ENFORCES: The tenant's branch quota; the tenant row is locked so concurrent
+    /// creations cannot both squeeze under the limit
     pub async fn create_branch(
         &self,
         tenant_id: &TenantId,
         branch_id: &BranchId,
         name: &str,
-        api_key_hash: &str,
+        location: Option<&str>,
+        max_branches: usize,
+        initial_key: &NewApiKey<'_>,
     ) -> Result<()> {
+        let mut tx = self.begin_tenant(tenant_id).await?;
+
+        sqlx::query("SELECT id FROM tenants WHERE id = $1 FOR UPDATE")
+            .bind(tenant_id.as_str())
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(Error::DatabaseError)?;
+
+        let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM branches WHERE tenant_id = $1")
+            .bind(tenant_id.as_str())
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(Error::DatabaseError)?;
+
+        if count as usize >= max_branches {
+            return Err(Error::QuotaExceeded(format!(
+                "Tenant {} already has {} of {} allowed branches",
+                tenant_id, count, max_branches
+            )));
+        }
+
         sqlx::query(
             r#"
-            INSERT INTO branches (id, tenant_id, name, api_key_hash, status)
+            INSERT INTO branches (id, tenant_id, name, location, status)
             VALUES ($1, $2, $3, $4, 'offline')
             "#
         )
         .bind(branch_id.as_str())
         .bind(tenant_id.as_str())
         .bind(name)
-        .bind(api_key_hash)
-        .execute(&self.pg_pool)
+        .bind(location)
+        .execute(&mut *tx)
         .await
-        .map_err(|e| Error::DatabaseError(e))?;
+        .map_err(Error::DatabaseError)?;
+
+        // A branch nobody can connect as would count against the quota for nothing
+        Self::insert_api_key_row(&mut tx, initial_key).await?;
+
+        tx.commit().await.map_err(Error::DatabaseError)?;
 
         info!("Created branch {} for tenant {}", branch_id, tenant_id);
 
         Ok(())
     }
 
-    /// Update branch status
+    /// Rename or relocate a branch; returns false if it does not exist in the tenant
+    pub async fn update_branch(
+        &self,
+        tenant_id: &TenantId,
+        branch_id: &BranchId,
+        name: Option<&str>,
+        location: Option<&str>,
+    ) -> Result<bool> {
+        let mut tx = self.begin_tenant(tenant_id).await?;
+
+        let result = sqlx::query(
+            r#"
+            UPDATE branches SET name = COALESCE($1, name), location = COALESCE($2, location), updated_at = NOW()
+            WHERE id = $3 AND tenant_id = $4
+            "#
+        )
+        .bind(name)
+        .bind(location)
+        .bind(branch_id.as_str())
+        .bind(tenant_id.as_str())
+        .execute(&mut *tx)
+        .await
+        .map_err(Error::DatabaseError)?;
+
+        tx.commit().await.map_err(Error::DatabaseError)?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Delete a branch with its API keys and refresh tokens.
+    /// Fails with a foreign key violation while sync history still references it.
+    pub async fn delete_branch(&self, tenant_id: &TenantId, branch_id: &BranchId) -> Result<bool> {
+        let mut tx = self.begin_tenant(tenant_id).await?;
+
+        let result = sqlx::query("DELETE FROM branches WHERE id = $1 AND tenant_id = $2")
+            .bind(branch_id.as_str())
+            .bind(tenant_id.as_str())
+            .execute(&mut *tx)
+            .await
+            .map_err(Error::DatabaseError)?;
+
+        tx.commit().await.map_err(Error::DatabaseError)?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Change a tenant's status; `trial_expires_at` is only kept for `Trial`
+    pub async fn set_tenant_status(
+        &self,
+        tenant_id: &TenantId,
+        status: TenantStatus,
+        trial_expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    ) -> Result<bool> {
+        let trial_expires_at = trial_expires_at.filter(|_| status == TenantStatus::Trial);
+        let result = sqlx::query(
+            "UPDATE tenants SET status = $1, trial_expires_at = $2, updated_at = NOW() WHERE id = $3"
+        )
+        .bind(status.as_str())
+        .bind(trial_expires_at)
+        .bind(tenant_id.as_str())
+        .execute(&self.pg_pool)
+        .await
+        .map_err(Error::DatabaseError)?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Deactivate every tenant whose trial has run out; returns their IDs
+    pub async fn expire_trials(&self) -> Result<Vec<TenantId>> {
+        let rows: Vec<(String,)> = sqlx::query_as(
+            r#"
+            UPDATE tenants SET status = 'inactive', updated_at = NOW()
+            WHERE status = 'trial' AND trial_expires_at <= NOW()
+            RETURNING id
+            "#
+        )
+        .fetch_all(&self.pg_pool)
+        .await
+        .map_err(Error::DatabaseError)?;
+
+        Ok(rows.into_iter().map(|row| TenantId::new(row.0)).collect())
+    }
+
+    /// Update branch status and last-seen timestamp
     pub async fn update_branch_status(
         &self,
         tenant_id: &TenantId,
         branch_id: &BranchId,
-        status: &str,
+        status: BranchStatus,
     ) -> Result<()> {
+        let mut tx = self.begin_tenant(tenant_id).await?;
+
         sqlx::query(
-            "UPDATE branches SET status = $1, updated_at = NOW() WHERE id = $2 AND tenant_id = $3"
+            r#"
+            UPDATE branches SET status = $1, last_seen_at = NOW(), updated_at = NOW()
+            WHERE id = $2 AND tenant_id = $3
+            "#
         )
-        .bind(status)
+        .bind(status.as_str())
         .bind(branch_id.as_str())
         .bind(tenant_id.as_str())
-        .execute(&self.pg_pool)
+        .execute(&mut *tx)
         .await
-        .map_err(|e| Error::DatabaseError(e))?;
+        .map_err(Error::DatabaseError)?;
 
+        tx.commit().await.map_err(Error::DatabaseError)?;
         Ok(())
     }
-}
 
-// Database row types
-#[derive(Debug, sqlx::FromRow)]
-struct TenantRow {
-    id: String,
-    name: String,
-    company_name: String,
-    contact_email: String,
-    status: String,
-    max_branches: i32,
-    max_connections_per_branch: i32,
-    rate_limit_per_sec: i32,
-    database_schema: String,
-    created_at: chrono::DateTime<chrono::Utc>,
-    updated_at: chrono::DateTime<chrono::Utc>,
-}
+    /// Persist a status report sent by the branch itself
+    pub async fn record_branch_status_report(
+        &self,
+        tenant_id: &TenantId,
+        branch_id: &BranchId,
+        status: BranchStatus,
+        message: Option<&str>,
+        metadata: &HashMap<String, String>,
+    ) -> Result<()> {
+        let mut tx = self.begin_tenant(tenant_id).await?;
 
-impl From<TenantRow> for Tenant {
-    fn from(row: TenantRow) -> Self {
-        Tenant {
-            id: TenantId::new(row.id),
-            name: row.name,
-            company_name: row.company_name,
-            contact_email: row.contact_email,
-            status: match row.status.as_str() {
-                "active" => common::TenantStatus::Active,
-                "suspended" => common::TenantStatus::Suspended,
-                "trial" => common::TenantStatus::Trial,
-                _ => common::TenantStatus::Inactive,
-            },
-            max_branches: row.max_branches as usize,
-            max_connections_per_branch: row.max_connections_per_branch as usize,
-            rate_limit_per_sec: row.rate_limit_per_sec as u32,
-            database_schema: row.database_schema,
-            created_at: row.created_at,
-            updated_at: row.updated_at,
-        }
+        sqlx::query(
+            r#"
+            UPDATE branches
+            SET status = $1, status_message = $2, metadata = $3, last_seen_at = NOW(), updated_at = NOW()
+            WHERE id = $4 AND tenant_id = $5
+            "#
+        )
+        .bind(status.as_str())
+        .bind(message)
+        .bind(Json(metadata))
+        .bind(branch_id.as_str())
+        .bind(tenant_id.as_str())
+        .execute(&mut *tx)
+        .await
+        .map_err(Error::DatabaseError)?;
+
+        tx.commit().await.map_err(Error::DatabaseError)?;
+        Ok(())
     }
-}
 
-#[derive(Debug, sqlx::FromRow)]
-pub struct BranchRow {
-    pub id: String,
-    pub tenant_id: String,
-    pub name: String,
-    pub status: String,
-    pub api_key_hash: String,
-    pub created_at: chrono::DateTime<chrono::Utc>,
-    pub updated_at: chrono::DateTime<chrono::Utc>,
-}
+    /// Record that a branch is still alive without changing its status
+    pub async fn touch_branch_last_seen(&self, tenant_id: &TenantId, branch_id: &BranchId) -> Result<()> {
+        let mut tx = self.begin_tenant(tenant_id).await?;
 
-impl From<BranchRow> for BranchInfo {
-    fn from(row: BranchRow) -> Self {
-        BranchInfo {
-            id: BranchId::new(row.id),
-            name: row.name,
-            location: String::new(), // TODO: Add location field
-            status: match row.status.as_str() {
-                "online" => common::BranchStatus::Online,
-                "syncing" => common::BranchStatus::Syncing,
-                "error" => common::BranchStatus::Error,
-                _ => common::BranchStatus::Offline,
-            },
-            last_seen: row.updated_at,
-            metadata: std::collections::HashMap::new(),
+        sqlx::query(
+            "UPDATE branches SET last_seen_at = NOW() WHERE id = $1 AND tenant_id = $2"
+        )
+        .bind(branch_id.as_str())
+        .bind(tenant_id.as_str())
+        .execute(&mut *tx)
+        .await
+        .map_err(Error::DatabaseError)?;
+
+        tx.commit().await.map_err(Error::DatabaseError)?;
+        Ok(())
+    }
+
+    /// Register or replace a branch's end-to-end encryption public key
+    pub async fn register_public_key(&self, tenant_id: &TenantId, branch_id: &BranchId, public_key: &str) -> Result<()> {
+        let mut tx = self.begin_tenant(tenant_id).await?;
+
+        sqlx::query(
+            r#"
+            INSERT INTO branch_public_keys (tenant_id, branch_id, public_key)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (tenant_id, branch_id)
+            DO UPDATE SET public_key = EXCLUDED.public_key, registered_at = NOW()
+            "#
+        )
+        .bind(tenant_id.as_str())
+        .bind(branch_id.as_str())
+        .bind(public_key)
+        .execute(&mut *tx)
+        .await
+        .map_err(Error::DatabaseError)?;
+
+        tx.commit().await.map_err(Error::DatabaseError)?;
+        Ok(())
+    }
+
+    /// Registered public keys of the given branches, or of every branch when `branch_ids` is empty
+    /// CRITICAL: Scoped to the tenant
+    pub async fn list_public_keys(&self, tenant_id: &TenantId, branch_ids: &[BranchId]) -> Result<Vec<PublicKeyRow>> {
+        let mut tx = self.begin_tenant(tenant_id).await?;
+        let branch_ids: Vec<&str> = branch_ids.iter().map(|id| id.as_str()).collect();
+
+        let rows = sqlx::query_as::<_, PublicKeyRow>(
+            r#"
+            SELECT branch_id, public_key, registered_at FROM branch_public_keys
+            WHERE tenant_id = $1 AND (cardinality($2::text[]) = 0 OR branch_id = ANY($2))
+            ORDER BY branch_id
+            "#
+        )
+        .bind(tenant_id.as_str())
+        .bind(&branch_ids)
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(Error::DatabaseError)?;
+
+        tx.commit().await.map_err(Error::DatabaseError)?;
+        Ok(rows)
+    }
+
+    /// Drop a branch's public key, e.g. after the branch lost its private key
+    pub async fn delete_public_key(&self, tenant_id: &TenantId, branch_id: &BranchId) -> Result<bool> {
+        let mut tx = self.begin_tenant(tenant_id).await?;
+
+        let result = sqlx::query("DELETE FROM branch_public_keys WHERE tenant_id = $1 AND branch_id = $2")
+            .bind(tenant_id.as_str())
+            .bind(branch_id.as_str())
+            .execute(&mut *tx)
+            .await
+            .map_err(Error::DatabaseError)?;
+
+        tx.commit().await.map_err(Error::DatabaseError)?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Mark offline every branch not seen for `stale_after`, whichever hub it was connected to.
+    /// Connected branches keep last_seen_at fresh with their heartbeats, so only branches
+    /// whose hub went down without closing their sessions are left behind.
+    pub async fn mark_stale_branches_offline(&self, stale_after: Duration) -> Result<u64> {
+        let result = sqlx::query(
+            r#"
+            UPDATE branches SET status = 'offline', updated_at = NOW()
+            WHERE status <> 'offline'
+              AND (last_seen_at IS NULL OR last_seen_at < NOW() - make_interval(secs => $1))
+            "#
+        )
+        .bind(stale_after.as_secs_f64())
+        .execute(&self.pg_pool)
+        .await
+        .map_err(Error::DatabaseError)?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// Append an entry to the tenant's audit log; skipped when the tenant does not exist
+    /// (e.g. a failed login naming an unknown tenant)
+    pub async fn write_audit_event(
+        &self,
+        tenant_id: &TenantId,
+        branch_id: Option<&BranchId>,
+        event_type: &str,
+        event_data: serde_json::Value,
+        ip_address: Option<IpAddr>,
+    ) -> Result<()> {
+        let mut tx = self.begin_tenant(tenant_id).await?;
+
+        sqlx::query(
+            r#"
+            INSERT INTO audit_log (tenant_id, branch_id, event_type, event_data, ip_address)
+            SELECT $1, $2, $3, $4, $5::inet
+            WHERE EXISTS (SELECT 1 FROM tenants WHERE id = $1)
+            "#
+        )
+        .bind(tenant_id.as_str())
+        .bind(branch_id.map(|id| id.as_str()))
+        .bind(event_type)
+        .bind(event_data)
+        .bind(ip_address.map(|ip| ip.to_string()))
+        .execute(&mut *tx)
+        .await
+        .map_err(Error::DatabaseError)?;
+
+        tx.commit().await.map_err(Error::DatabaseError)?;
+        Ok(())
+    }
+
+    /// A page of a tenant's audit log, newest first, with the total count
+    /// CRITICAL: Scoped to the tenant
+    pub async fn list_audit_events(
+        &self,
+        filter: &AuditFilter,
+        limit: i64,
+        offset: i64,
+    ) -> Result<(Vec<AuditRow>, i64)> {
+        let mut tx = self.begin_tenant(&filter.tenant_id).await?;
+
+        const CONDITIONS: &str = r#"
+            WHERE tenant_id = $1
+              AND ($2::text IS NULL OR branch_id = $2)
+              AND ($3::text IS NULL OR event_type = $3)
+              AND ($4::timestamptz IS NULL OR created_at >= $4)
+              AND ($5::timestamptz IS NULL OR created_at < $5)
+        "#;
+
+        let rows = sqlx::query_as::<_, AuditRow>(&format!(
+            r#"
+            SELECT id, tenant_id, branch_id, event_type, event_data, host(ip_address) AS ip_address, created_at
+            FROM audit_log {}
+            ORDER BY created_at DESC, id DESC LIMIT $6 OFFSET $7
+            "#,
+            CONDITIONS
+        ))
+        .bind(filter.tenant_id.as_str())
+        .bind(filter.branch_id.as_ref().map(|id| id.as_str()))
+        .bind(filter.event_type.as_deref())
+        .bind(filter.from)
+        .bind(filter.until)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(Error::DatabaseError)?;
+
+        let (total,): (i64,) = sqlx::query_as(&format!("SELECT COUNT(*) FROM audit_log {}", CONDITIONS))
+            .bind(filter.tenant_id.as_str())
+            .bind(filter.branch_id.as_ref().map(|id| id.as_str()))
+            .bind(filter.event_type.as_deref())
+            .bind(filter.from)
+            .bind(filter.until)
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(Error::DatabaseError)?;
+
+        tx.commit().await.map_err(Error::DatabaseError)?;
+        Ok((rows, total))
+    }
+
+    /// Queue a message for a branch; it stays pending until marked delivered or `ttl` passes.
+    /// With a `sender`, the message is tracked until acknowledged and the sender told the outcome.
+    /// Returns false if the tenant already has a message with this ID queued.
+    pub async fn enqueue_offline_message(
+        &self,
+        tenant_id: &TenantId,
+        target: &BranchId,
+        message: &Message,
+        ttl: chrono::DateTime<chrono::Utc>,
+        sender: Option<&BranchId>,
+    ) -> Result<bool> {
+        let mut tx = self.begin_tenant(tenant_id).await?;
+
+        let result = sqlx::query(
+            r#"
+            INSERT INTO offline_messages (id, tenant_id, target_branch_id, message_payload, ttl, sender_branch_id)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            ON CONFLICT (tenant_id, id) DO NOTHING
+            "#
+        )
+        .bind(&message.id)
+        .bind(tenant_id.as_str())
+        .bind(target.as_str())
+        .bind(Json(message))
+        .bind(ttl)
+        .bind(sender.map(BranchId::as_str))
+        .execute(&mut *tx)
+        .await
+        .map_err(Error::DatabaseError)?;
+
+        tx.commit().await.map_err(Error::DatabaseError)?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Fetch undelivered, unexpired messages for a branch in delivery order, including
+    /// those sent before but not yet acknowledged
+    pub async fn fetch_pending_offline_messages(
+        &self,
+        tenant_id: &TenantId,
+        target: &BranchId,
+    ) -> Result<Vec<QueuedMessage>> {
+        let mut tx = self.begin_tenant(tenant_id).await?;
+
+        let rows = sqlx::query_as::<_, QueuedMessageRow>(
+            r#"
+            SELECT message_payload, target_branch_id, sender_branch_id, attempts, FALSE AS expired
+            FROM offline_messages
+            WHERE tenant_id = $1 AND target_branch_id = $2
+              AND delivered_at IS NULL AND failed_at IS NULL AND ttl > NOW()
+            ORDER BY priority, created_at
+            "#
+        )
+        .bind(tenant_id.as_str())
+        .bind(target.as_str())
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(Error::DatabaseError)?;
+
+        tx.commit().await.map_err(Error::DatabaseError)?;
+        Ok(rows.into_iter().map(Into::into).collect())
+    }
+
+    /// IDs of tenants with tracked messages whose resend is due or whose TTL has passed,
+    /// whatever the tenant's status, so a suspended tenant's messages still expire
+    pub async fn list_tenant_ids_with_due_deliveries(&self) -> Result<Vec<TenantId>> {
+        let rows: Vec<(String,)> = sqlx::query_as(
+            r#"
+            SELECT DISTINCT tenant_id FROM offline_messages
+            WHERE sender_branch_id IS NOT NULL
+              AND delivered_at IS NULL AND failed_at IS NULL
+              AND (next_attempt_at <= NOW() OR ttl <= NOW())
+            ORDER BY tenant_id
+            "#
+        )
+        .fetch_all(&self.pg_pool)
+        .await
+        .map_err(Error::DatabaseError)?;
+
+        Ok(rows.into_iter().map(|row| TenantId::new(row.0)).collect())
+    }
+
+    /// Tracked messages of a tenant whose resend is due or whose TTL has passed
+    pub async fn fetch_due_deliveries(&self, tenant_id: &TenantId, limit: i64) -> Result<Vec<QueuedMessage>> {
+        let mut tx = self.begin_tenant(tenant_id).await?;
+
+        let rows = sqlx::query_as::<_, QueuedMessageRow>(
+            r#"
+            SELECT message_payload, target_branch_id, sender_branch_id, attempts, ttl <= NOW() AS expired
+            FROM offline_messages
+            WHERE tenant_id = $1 AND sender_branch_id IS NOT NULL
+              AND delivered_at IS NULL AND failed_at IS NULL
+              AND (next_attempt_at <= NOW() OR ttl <= NOW())
+            ORDER BY created_at
+            LIMIT $2
+            "#
+        )
+        .bind(tenant_id.as_str())
+        .bind(limit)
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(Error::DatabaseError)?;
+
+        tx.commit().await.map_err(Error::DatabaseError)?;
+        Ok(rows.into_iter().map(Into::into).collect())
+    }
+
+    /// Count a send of a tracked message and schedule the resend should it go unacknowledged
+    pub async fn record_delivery_attempt(
+        &self,
+        tenant_id: &TenantId,
+        message_id: &str,
+        next_attempt_at: chrono::DateTime<chrono::Utc>,
+    ) -> Result<()> {
+        let mut tx = self.begin_tenant(tenant_id).await?;
+
+        sqlx::query(
+            "UPDATE offline_messages SET attempts = attempts + 1, next_attempt_at = $3 WHERE id = $1 AND tenant_id = $2"
+        )
+        .bind(message_id)
+        .bind(tenant_id.as_str())
+        .bind(next_attempt_at)
+        .execute(&mut *tx)
+        .await
+        .map_err(Error::DatabaseError)?;
+
+        tx.commit().await.map_err(Error::DatabaseError)?;
+        Ok(())
+    }
+
+    /// Stop resending a tracked message to an offline branch; it goes out again on reconnect
+    pub async fn defer_delivery(&self, tenant_id: &TenantId, message_id: &str) -> Result<()> {
+        let mut tx = self.begin_tenant(tenant_id).await?;
+
+        sqlx::query("UPDATE offline_messages SET next_attempt_at = NULL WHERE id = $1 AND tenant_id = $2")
+            .bind(message_id)
+            .bind(tenant_id.as_str())
+            .execute(&mut *tx)
+            .await
+            .map_err(Error::DatabaseError)?;
+
+        tx.commit().await.map_err(Error::DatabaseError)?;
+        Ok(())
+    }
+
+    /// Settle a tracked message its target acknowledged; returns the sender to tell, or
+    /// None when the message is unknown, not for this branch, or already settled
+    /// CRITICAL: A branch can only acknowledge messages addressed to itself
+    pub async fn acknowledge_offline_message(
+        &self,
+        tenant_id: &TenantId,
+        target: &BranchId,
+        message_id: &str,
+    ) -> Result<Option<BranchId>> {
+        let mut tx = self.begin_tenant(tenant_id).await?;
+
+        let sender: Option<(Option<String>,)> = sqlx::query_as(
+            r#"
+            UPDATE offline_messages SET delivered_at = NOW()
+            WHERE id = $1 AND tenant_id = $2 AND target_branch_id = $3
+              AND delivered_at IS NULL AND failed_at IS NULL
+            RETURNING sender_branch_id
+            "#
+        )
+        .bind(message_id)
+        .bind(tenant_id.as_str())
+        .bind(target.as_str())
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(Error::DatabaseError)?;
+
+        tx.commit().await.map_err(Error::DatabaseError)?;
+        Ok(sender.and_then(|row| row.0).map(BranchId::new))
+    }
+
+    /// Give up on a tracked message; false if it was acknowledged in the meantime
+    pub async fn mark_offline_message_failed(&self, tenant_id: &TenantId, message_id: &str) -> Result<bool> {
+        let mut tx = self.begin_tenant(tenant_id).await?;
+
+        let result = sqlx::query(
+            r#"
+            UPDATE offline_messages SET failed_at = NOW()
+            WHERE id = $1 AND tenant_id = $2 AND delivered_at IS NULL AND failed_at IS NULL
+            "#
+        )
+        .bind(message_id)
+        .bind(tenant_id.as_str())
+        .execute(&mut *tx)
+        .await
+        .map_err(Error::DatabaseError)?;
+
+        tx.commit().await.map_err(Error::DatabaseError)?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Mark a queued message as delivered
+    pub async fn mark_offline_message_delivered(&self, tenant_id: &TenantId, message_id: &str) -> Result<()> {
+        let mut tx = self.begin_tenant(tenant_id).await?;
+
+        sqlx::query(
+            "UPDATE offline_messages SET delivered_at = NOW() WHERE id = $1 AND tenant_id = $2"
+        )
+        .bind(message_id)
+        .bind(tenant_id.as_str())
+        .execute(&mut *tx)
+        .await
+        .map_err(Error::DatabaseError)?;
+
+        tx.commit().await.map_err(Error::DatabaseError)?;
+        Ok(())
+    }
+
+    /// Store a newly issued refresh token by its hash
+    pub async fn insert_refresh_token(&self, token: &NewRefreshToken<'_>) -> Result<()> {
+        let mut tx = self.begin_tenant(token.tenant_id).await?;
+
+        sqlx::query(
+            r#"
+            INSERT INTO refresh_tokens (id, family_id, tenant_id, branch_id, token_hash, expires_at, api_key_id)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            "#
+        )
+        .bind(token.id)
+        .bind(token.family_id)
+        .bind(token.tenant_id.as_str())
+        .bind(token.branch_id.as_str())
+        .bind(token.token_hash)
+        .bind(token.expires_at)
+        .bind(token.api_key_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(Error::DatabaseError)?;
+
+        tx.commit().await.map_err(Error::DatabaseError)?;
+        Ok(())
+    }
+
+    /// Look up a refresh token by hash, including revoked and expired ones.
+    /// PRIVILEGED: Runs outside row-level security, as the token is what names its tenant;
+    /// everything done with the row afterwards is scoped to that tenant
+    pub async fn find_refresh_token(&self, token_hash: &str) -> Result<Option<RefreshTokenRow>> {
+        sqlx::query_as::<_, RefreshTokenRow>(
+            r#"
+            SELECT id, family_id, tenant_id, branch_id, api_key_id, expires_at, revoked_at
+            FROM refresh_tokens
+            WHERE token_hash = $1
+            "#
+        )
+        .bind(token_hash)
+        .fetch_optional(&self.pg_pool)
+        .await
+        .map_err(Error::DatabaseError)
+    }
+
+    /// Retire a refresh token in favour of its successor.
+    /// Returns false if it was already revoked, i.e. another request won the race.
+    pub async fn rotate_refresh_token(&self, tenant_id: &TenantId, id: &str, replaced_by: &str) -> Result<bool> {
+        let mut tx = self.begin_tenant(tenant_id).await?;
+
+        let result = sqlx::query(
+            r#"
+            UPDATE refresh_tokens
+            SET revoked_at = NOW(), replaced_by = $3
+            WHERE tenant_id = $1 AND id = $2 AND revoked_at IS NULL
+            "#
+        )
+        .bind(tenant_id.as_str())
+        .bind(id)
+        .bind(replaced_by)
+        .execute(&mut *tx)
+        .await
+        .map_err(Error::DatabaseError)?;
+
+        tx.commit().await.map_err(Error::DatabaseError)?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Revoke every live token of a rotation family
+    pub async fn revoke_refresh_token_family(&self, tenant_id: &TenantId, family_id: &str) -> Result<u64> {
+        let mut tx = self.begin_tenant(tenant_id).await?;
+
+        let result = sqlx::query(
+            r#"
+            UPDATE refresh_tokens SET revoked_at = NOW()
+            WHERE tenant_id = $1 AND family_id = $2 AND revoked_at IS NULL
+            "#
+        )
+        .bind(tenant_id.as_str())
+        .bind(family_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(Error::DatabaseError)?;
+
+        tx.commit().await.map_err(Error::DatabaseError)?;
+        Ok(result.rows_affected())
+    }
+
+    /// Tenant-scoped revocation of every token family obtained with an API key
+    pub async fn revoke_refresh_tokens_for_api_key(
+        &self,
+        tenant_id: &TenantId,
+        api_key_id: &str,
+    ) -> Result<u64> {
+        let mut tx = self.begin_tenant(tenant_id).await?;
+
+        let result = sqlx::query(
+            r#"
+            UPDATE refresh_tokens SET revoked_at = NOW()
+            WHERE tenant_id = $1 AND api_key_id = $2 AND revoked_at IS NULL
+            "#
+        )
+        .bind(tenant_id.as_str())
+        .bind(api_key_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(Error::DatabaseError)?;
+
+        tx.commit().await.map_err(Error::DatabaseError)?;
+        Ok(result.rows_affected())
+    }
+
+    pub async fn insert_api_key(&self, key: &NewApiKey<'_>) -> Result<()> {
+        let mut tx = self.begin_tenant(key.tenant_id).await?;
+        Self::insert_api_key_row(&mut tx, key).await?;
+        tx.commit().await.map_err(Error::DatabaseError)?;
+        Ok(())
+    }
+
+    async fn insert_api_key_row(tx: &mut Transaction<'static, Postgres>, key: &NewApiKey<'_>) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO api_keys (id, tenant_id, branch_id, label, key_hash, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            "#
+        )
+        .bind(key.id)
+        .bind(key.tenant_id.as_str())
+        .bind(key.branch_id.as_str())
+        .bind(key.label)
+        .bind(key.key_hash)
+        .bind(key.expires_at)
+        .execute(&mut **tx)
+        .await
+        .map_err(Error::DatabaseError)?;
+        Ok(())
+    }
+
+    /// Every key of a branch, including expired and revoked ones
+    /// CRITICAL: Scoped to the tenant
+    pub async fn list_api_keys(&self, tenant_id: &TenantId, branch_id: &BranchId) -> Result<Vec<ApiKeyRow>> {
+        let mut tx = self.begin_tenant(tenant_id).await?;
+
+        let rows = sqlx::query_as::<_, ApiKeyRow>(
+            r#"
+            SELECT id, label, key_hash, created_at, expires_at, last_used_at, revoked_at
+            FROM api_keys
+            WHERE tenant_id = $1 AND branch_id = $2
+            ORDER BY created_at
+            "#
+        )
+        .bind(tenant_id.as_str())
+        .bind(branch_id.as_str())
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(Error::DatabaseError)?;
+
+        tx.commit().await.map_err(Error::DatabaseError)?;
+        Ok(rows)
+    }
+
+    /// Keys of a branch that may still authenticate
+    pub async fn list_usable_api_keys(&self, tenant_id: &TenantId, branch_id: &BranchId) -> Result<Vec<ApiKeyRow>> {
+        let mut tx = self.begin_tenant(tenant_id).await?;
+
+        let rows = sqlx::query_as::<_, ApiKeyRow>(
+            r#"
+            SELECT id, label, key_hash, created_at, expires_at, last_used_at, revoked_at
+            FROM api_keys
+            WHERE tenant_id = $1 AND branch_id = $2
+              AND revoked_at IS NULL
+              AND (expires_at IS NULL OR expires_at > NOW())
+            ORDER BY created_at DESC
+            "#
+        )
+        .bind(tenant_id.as_str())
+        .bind(branch_id.as_str())
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(Error::DatabaseError)?;
+
+        tx.commit().await.map_err(Error::DatabaseError)?;
+        Ok(rows)
+    }
+
+    pub async fn get_api_key(
+        &self,
+        tenant_id: &TenantId,
+        branch_id: &BranchId,
+        key_id: &str,
+    ) -> Result<Option<ApiKeyRow>> {
+        let mut tx = self.begin_tenant(tenant_id).await?;
+
+        let row = sqlx::query_as::<_, ApiKeyRow>(
+            r#"
+            SELECT id, label, key_hash, created_at, expires_at, last_used_at, revoked_at
+            FROM api_keys
+            WHERE tenant_id = $1 AND branch_id = $2 AND id = $3
+            "#
+        )
+        .bind(tenant_id.as_str())
+        .bind(branch_id.as_str())
+        .bind(key_id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(Error::DatabaseError)?;
+
+        tx.commit().await.map_err(Error::DatabaseError)?;
+        Ok(row)
+    }
+
+    pub async fn touch_api_key_last_used(&self, tenant_id: &TenantId, key_id: &str) -> Result<()> {
+        let mut tx = self.begin_tenant(tenant_id).await?;
+
+        sqlx::query("UPDATE api_keys SET last_used_at = NOW() WHERE tenant_id = $1 AND id = $2")
+            .bind(tenant_id.as_str())
+            .bind(key_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(Error::DatabaseError)?;
+
+        tx.commit().await.map_err(Error::DatabaseError)?;
+        Ok(())
+    }
+
+    /// Bring a key's expiry forward, never extending it
+    pub async fn expire_api_key_at(
+        &self,
+        tenant_id: &TenantId,
+        branch_id: &BranchId,
+        key_id: &str,
+        expires_at: chrono::DateTime<chrono::Utc>,
+    ) -> Result<()> {
+        let mut tx = self.begin_tenant(tenant_id).await?;
+
+        sqlx::query(
+            r#"
+            UPDATE api_keys
+            SET expires_at = LEAST(COALESCE(expires_at, $4), $4)
+            WHERE tenant_id = $1 AND branch_id = $2 AND id = $3
+            "#
+        )
+        .bind(tenant_id.as_str())
+        .bind(branch_id.as_str())
+        .bind(key_id)
+        .bind(expires_at)
+        .execute(&mut *tx)
+        .await
+        .map_err(Error::DatabaseError)?;
+
+        tx.commit().await.map_err(Error::DatabaseError)?;
+        Ok(())
+    }
+
+    /// Returns false if the key does not exist or was already revoked
+    pub async fn revoke_api_key(&self, tenant_id: &TenantId, branch_id: &BranchId, key_id: &str) -> Result<bool> {
+        let mut tx = self.begin_tenant(tenant_id).await?;
+
+        let result = sqlx::query(
+            r#"
+            UPDATE api_keys SET revoked_at = NOW()
+            WHERE tenant_id = $1 AND branch_id = $2 AND id = $3 AND revoked_at IS NULL
+            "#
+        )
+        .bind(tenant_id.as_str())
+        .bind(branch_id.as_str())
+        .bind(key_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(Error::DatabaseError)?;
+
+        tx.commit().await.map_err(Error::DatabaseError)?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Store a scheduled signing key; returns false if its slot is already taken
+    pub async fn insert_signing_key(&self, key: &SigningKeyRow) -> Result<bool> {
+        let result = sqlx::query(
+            r#"
+            INSERT INTO signing_keys (kid, private_key, public_key, activates_at, retires_at, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            ON CONFLICT (activates_at) DO NOTHING
+            "#
+        )
+        .bind(&key.kid)
+        .bind(&key.private_key)
+        .bind(&key.public_key)
+        .bind(key.activates_at)
+        .bind(key.retires_at)
+        .bind(key.expires_at)
+        .execute(&self.pg_pool)
+        .await
+        .map_err(Error::DatabaseError)?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Signing window (activates_at, retires_at) of the most recently scheduled key
+    pub async fn latest_signing_key_window(
+        &self,
+    ) -> Result<Option<(chrono::DateTime<chrono::Utc>, chrono::DateTime<chrono::Utc>)>> {
+        sqlx::query_as(
+            "SELECT activates_at, retires_at FROM signing_keys ORDER BY activates_at DESC LIMIT 1"
+        )
+        .fetch_optional(&self.pg_pool)
+        .await
+        .map_err(Error::DatabaseError)
+    }
+
+    /// Keys that can still verify a token
+    pub async fn list_signing_keys(&self) -> Result<Vec<SigningKeyRow>> {
+        sqlx::query_as::<_, SigningKeyRow>(
+            r#"
+            SELECT kid, private_key, public_key, activates_at, retires_at, expires_at
+            FROM signing_keys
+            WHERE expires_at > NOW()
+            ORDER BY activates_at
+            "#
+        )
+        .fetch_all(&self.pg_pool)
+        .await
+        .map_err(Error::DatabaseError)
+    }
+
+    pub async fn delete_expired_signing_keys(&self) -> Result<u64> {
+        let result = sqlx::query("DELETE FROM signing_keys WHERE expires_at <= NOW()")
+            .execute(&self.pg_pool)
+            .await
+            .map_err(Error::DatabaseError)?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// Remember `key` for `ttl_secs`; false if it was already there
+    pub async fn mark_seen(&self, key: &str, ttl_secs: u64) -> Result<bool> {
+        let mut redis = self.redis.clone();
+        let set: Option<String> = redis::cmd("SET")
+            .arg(key)
+            .arg(1)
+            .arg("NX")
+            .arg("EX")
+            .arg(ttl_secs)
+            .query_async(&mut redis)
+            .await?;
+        Ok(set.is_some())
+    }
+
+    /// Take one token from every bucket, or from none if any is empty.
+    /// Returns 0 when allowed, otherwise milliseconds until all buckets have a token again.
+    pub async fn take_rate_limit_tokens(&self, buckets: &[TokenBucket]) -> Result<u64> {
+        let script = redis::Script::new(TOKEN_BUCKET_SCRIPT);
+        let mut invocation = script.prepare_invoke();
+        for bucket in buckets {
+            invocation
+                .key(&bucket.key)
+                .arg(bucket.rate_per_sec)
+                .arg(bucket.capacity);
+        }
+
+        let mut redis = self.redis.clone();
+        let retry_after_ms: u64 = invocation.invoke_async(&mut redis).await?;
+        Ok(retry_after_ms)
+    }
+}
+
+/// Refill each bucket (a hash of `tokens` and `ts`) by elapsed time on the Redis clock,
+/// then debit all of them only if every one holds a full token. ARGV holds a
+/// rate-per-second and capacity pair for each key.
+const TOKEN_BUCKET_SCRIPT: &str = r#"
+local t = redis.call('TIME')
+local now = tonumber(t[1]) * 1000 + math.floor(tonumber(t[2]) / 1000)
+local levels = {}
+local retry = 0
+
+for i, key in ipairs(KEYS) do
+    local rate = tonumber(ARGV[2 * i - 1])
+    local capacity = tonumber(ARGV[2 * i])
+    local bucket = redis.call('HMGET', key, 'tokens', 'ts')
+    local tokens = tonumber(bucket[1]) or capacity
+    local ts = tonumber(bucket[2]) or now
+    tokens = math.min(capacity, tokens + math.max(0, now - ts) * rate / 1000)
+    levels[i] = tokens
+    if tokens < 1 then
+        retry = math.max(retry, math.ceil((1 - tokens) * 1000 / rate))
+    end
+end
+
+for i, key in ipairs(KEYS) do
+    local rate = tonumber(ARGV[2 * i - 1])
+    local capacity = tonumber(ARGV[2 * i])
+    local tokens = levels[i]
+    if retry == 0 then
+        tokens = tokens - 1
+    end
+    redis.call('HSET', key, 'tokens', tostring(tokens), 'ts', now)
+    -- Idle buckets are full again after capacity / rate seconds and can simply vanish
+    redis.call('PEXPIRE', key, math.ceil(capacity * 1000 / rate) + 1000)
+end
+
+return retry
+"#;
+
+/// Quote an identifier for splicing into DDL
+fn quote_ident(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// Escape `%`, `_` and `\` so user input matches literally inside ILIKE
+fn escape_like(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Tenant list filter for the admin API
+#[derive(Debug, Default)]
+pub struct TenantFilter {
+    pub status: Option<TenantStatus>,
+    /// Substring of the ID, name or company name
+    pub search: Option<String>,
+}
+
+/// Tenant fields the admin API may change; None leaves a field as it is
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct TenantUpdate {
+    pub name: Option<String>,
+    pub company_name: Option<String>,
+    pub contact_email: Option<String>,
+    pub max_branches: Option<usize>,
+    pub max_connections_per_branch: Option<usize>,
+    pub rate_limit_per_sec: Option<u32>,
+    pub e2e_required: Option<bool>,
+}
+
+/// Audit log query for the admin API
+#[derive(Debug)]
+pub struct AuditFilter {
+    pub tenant_id: TenantId,
+    pub branch_id: Option<BranchId>,
+    pub event_type: Option<String>,
+    /// Inclusive lower bound on `created_at`
+    pub from: Option<chrono::DateTime<chrono::Utc>>,
+    /// Exclusive upper bound on `created_at`
+    pub until: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// Per-tenant counters for the admin metrics view
+#[derive(Debug, Default, serde::Serialize)]
+pub struct TenantStats {
+    /// Branch count per status
+    pub branches: HashMap<String, i64>,
+    pub unresolved_conflicts: i64,
+    /// Offline messages not yet delivered
+    pub queued_messages: i64,
+}
+
+/// One Redis-backed token bucket
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenBucket {
+    pub key: String,
+    /// Tokens added per second
+    pub rate_per_sec: f64,
+    /// Most tokens the bucket holds, i.e. the allowed burst
+    pub capacity: f64,
+}
+
+// Database row types
+#[derive(Debug, sqlx::FromRow)]
+struct TenantRow {
+    id: String,
+    name: String,
+    company_name: String,
+    contact_email: String,
+    status: String,
+    max_branches: i32,
+    max_connections_per_branch: i32,
+    rate_limit_per_sec: i32,
+    database_schema: String,
+    e2e_required: bool,
+    trial_expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    created_at: chrono::DateTime<chrono::Utc>,
+    updated_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl From<TenantRow> for Tenant {
+    fn from(row: TenantRow) -> Self {
+        Tenant {
+            id: TenantId::new(row.id),
+            name: row.name,
+            company_name: row.company_name,
+            contact_email: row.contact_email,
+            status: match row.status.as_str() {
+                "active" => TenantStatus::Active,
+                "suspended" => TenantStatus::Suspended,
+                "trial" => TenantStatus::Trial,
+                _ => TenantStatus::Inactive,
+            },
+            max_branches: row.max_branches as usize,
+            max_connections_per_branch: row.max_connections_per_branch as usize,
+            rate_limit_per_sec: row.rate_limit_per_sec as u32,
+            database_schema: row.database_schema,
+            e2e_required: row.e2e_required,
+            trial_expires_at: row.trial_expires_at,
+            created_at: row.created_at,
+            updated_at: row.updated_at,
+        }
+    }
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct BranchRow {
+    pub id: String,
+    pub tenant_id: String,
+    pub name: String,
+    pub location: Option<String>,
+    pub status: String,
+    pub status_message: Option<String>,
+    pub metadata: Option<Json<HashMap<String, String>>>,
+    pub last_seen_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub updated_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl From<BranchRow> for BranchInfo {
+    fn from(row: BranchRow) -> Self {
+        BranchInfo {
+            id: BranchId::new(row.id),
+            name: row.name,
+            location: row.location.unwrap_or_default(),
+            status: match row.status.as_str() {
+                "online" => common::BranchStatus::Online,
+                "syncing" => common::BranchStatus::Syncing,
+                "error" => common::BranchStatus::Error,
+                _ => common::BranchStatus::Offline,
+            },
+            status_message: row.status_message,
+            last_seen: row.last_seen_at.unwrap_or(row.updated_at),
+            metadata: row.metadata.map(|m| m.0).unwrap_or_default(),
+        }
+    }
+}
+
+/// Refresh token about to be stored
+pub struct NewRefreshToken<'a> {
+    pub id: &'a str,
+    pub family_id: &'a str,
+    pub tenant_id: &'a TenantId,
+    pub branch_id: &'a BranchId,
+    pub token_hash: &'a str,
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+    /// API key the token family was originally obtained with
+    pub api_key_id: Option<&'a str>,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct RefreshTokenRow {
+    pub id: String,
+    pub family_id: String,
+    pub tenant_id: String,
+    pub branch_id: String,
+    pub api_key_id: Option<String>,
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+    pub revoked_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct SigningKeyRow {
+    pub kid: String,
+    pub private_key: Vec<u8>,
+    pub public_key: Vec<u8>,
+    pub activates_at: chrono::DateTime<chrono::Utc>,
+    pub retires_at: chrono::DateTime<chrono::Utc>,
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// API key about to be stored
+pub struct NewApiKey<'a> {
+    pub id: &'a str,
+    pub tenant_id: &'a TenantId,
+    pub branch_id: &'a BranchId,
+    pub label: &'a str,
+    pub key_hash: &'a str,
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Debug, serde::Serialize, sqlx::FromRow)]
+pub struct ApiKeyRow {
+    pub id: String,
+    pub label: String,
+    #[serde(skip_serializing)]
+    pub key_hash: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub last_used_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub revoked_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl ApiKeyRow {
+    /// Not revoked and not past its expiry
+    pub fn is_usable(&self) -> bool {
+        self.revoked_at.is_none()
+            && !matches!(self.expires_at, Some(expires_at) if expires_at <= chrono::Utc::now())
+    }
+}
+
+#[derive(Debug, serde::Serialize, sqlx::FromRow)]
+pub struct ConflictRow {
+    pub id: String,
+    pub table_name: String,
+    pub primary_key: serde_json::Value,
+    pub branch_a_id: String,
+    pub branch_b_id: String,
+    pub branch_a_change: serde_json::Value,
+    pub branch_b_change: serde_json::Value,
+    pub resolution_strategy: String,
+    pub winning_branch_id: Option<String>,
+    pub resolved_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, serde::Serialize, sqlx::FromRow)]
+pub struct AuditRow {
+    pub id: i64,
+    pub tenant_id: String,
+    pub branch_id: Option<String>,
+    pub event_type: String,
+    pub event_data: Option<serde_json::Value>,
+    pub ip_address: Option<String>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct PublicKeyRow {
+    pub branch_id: String,
+    pub public_key: String,
+    pub registered_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl From<PublicKeyRow> for protocol::BranchPublicKey {
+    fn from(row: PublicKeyRow) -> Self {
+        // Keys are validated on registration
+        let fingerprint = protocol::e2e::parse_public_key(&row.public_key)
+            .map(|key| protocol::e2e::fingerprint(&key))
+            .unwrap_or_default();
+        protocol::BranchPublicKey {
+            branch_id: BranchId::new(row.branch_id),
+            public_key: row.public_key,
+            fingerprint,
+            registered_at: row.registered_at,
+        }
+    }
+}
+
+/// A queued message and the state of its delivery
+#[derive(Debug, Clone)]
+pub struct QueuedMessage {
+    pub message: Message,
+    pub target_branch_id: BranchId,
+    /// Set for routed messages tracked until acknowledged
+    pub sender_branch_id: Option<BranchId>,
+    /// Sends so far that went unacknowledged
+    pub attempts: u32,
+    /// Past its TTL
+    pub expired: bool,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct QueuedMessageRow {
+    message_payload: Json<Message>,
+    target_branch_id: String,
+    sender_branch_id: Option<String>,
+    attempts: i32,
+    expired: bool,
+}
+
+impl From<QueuedMessageRow> for QueuedMessage {
+    fn from(row: QueuedMessageRow) -> Self {
+        QueuedMessage {
+            message: row.message_payload.0,
+            target_branch_id: BranchId::new(row.target_branch_id),
+            sender_branch_id: row.sender_branch_id.map(BranchId::new),
+            attempts: row.attempts.max(0) as u32,
+            expired: row.expired,
         }
     }
 }
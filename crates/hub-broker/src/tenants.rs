@@ -0,0 +1,77 @@
+use chrono::Utc;
+use common::{Result, Tenant, TenantStatus};
+use protocol::DisconnectReason;
+use std::{sync::Arc, time::Duration};
+use tracing::{error, info};
+
+use crate::{
+    audit::{self, AuditEvent},
+    storage::Storage,
+    websocket::ConnectionManager,
+};
+
+/// How often each hub re-checks the tenants of its live sessions
+const STATUS_SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Why a tenant's branches are being turned away
+pub fn disconnect_reason(tenant: &Tenant) -> DisconnectReason {
+    let reason = match tenant.status {
+        TenantStatus::Suspended => "Tenant suspended",
+        TenantStatus::Trial => "Trial expired",
+        TenantStatus::Inactive | TenantStatus::Active => "Tenant inactive",
+    };
+    DisconnectReason::new(DisconnectReason::TENANT_INACTIVE, reason)
+}
+
+/// Close the tenant's sessions on this hub if it may no longer connect
+/// ENFORCES: Suspension and deactivation take effect on live sessions, not just at login
+pub async fn enforce_status(connection_manager: &ConnectionManager, tenant: &Tenant) -> usize {
+    if tenant.is_usable(Utc::now()) {
+        return 0;
+    }
+
+    let closed = connection_manager
+        .close_tenant_sessions(&tenant.id, disconnect_reason(tenant))
+        .await;
+    if closed > 0 {
+        info!("Closed {} sessions of {} tenant {}", closed, tenant.status.as_str(), tenant.id);
+    }
+    closed
+}
+
+/// Deactivate expired trials, then drop the sessions of every connected tenant that may
+/// no longer connect. Picks up status changes made by other hubs or directly in the database.
+pub async fn sweep(storage: &Storage, connection_manager: &ConnectionManager) -> Result<()> {
+    for tenant_id in storage.expire_trials().await? {
+        info!("Trial of tenant {} expired", tenant_id);
+        audit::record(storage, AuditEvent::new(&tenant_id, audit::TRIAL_EXPIRED)).await;
+
+        let reason = DisconnectReason::new(DisconnectReason::TENANT_INACTIVE, "Trial expired");
+        connection_manager.close_tenant_sessions(&tenant_id, reason).await;
+    }
+
+    let connected = connection_manager.connected_tenants().await;
+    if connected.is_empty() {
+        return Ok(());
+    }
+
+    for tenant in storage.get_tenants(&connected).await? {
+        enforce_status(connection_manager, &tenant).await;
+    }
+
+    Ok(())
+}
+
+/// Keep trials expiring and tenant status enforced on this hub
+pub fn spawn_status_sweep(storage: Storage, connection_manager: Arc<ConnectionManager>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(STATUS_SWEEP_INTERVAL);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            if let Err(e) = sweep(&storage, &connection_manager).await {
+                error!("Tenant status sweep failed: {}", e);
+            }
+        }
+    });
+}
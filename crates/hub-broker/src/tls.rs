@@ -0,0 +1,174 @@
+use anyhow::{Context, Result};
+use axum::{extract::ConnectInfo, Router};
+use common::{config::TlsConfig, BranchId, QualifiedBranchId, TenantId};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use std::{fs::File, io::BufReader, sync::Arc, time::Duration};
+use tokio::net::TcpListener;
+use tokio_rustls::{
+    rustls::{
+        crypto::ring::default_provider,
+        pki_types::{CertificateDer, PrivateKeyDer},
+        server::WebPkiClientVerifier,
+        RootCertStore, ServerConfig,
+    },
+    TlsAcceptor,
+};
+use tower::Service;
+use tracing::{debug, error};
+use x509_parser::{extensions::GeneralName, prelude::FromDer};
+
+/// URI SAN that names a branch in its client certificate: `hub-broker://<tenant_id>/<branch_id>`
+pub const IDENTITY_URI_SCHEME: &str = "hub-broker://";
+
+/// Attached to every request that arrived over the hub's own TLS listener
+#[derive(Debug, Clone)]
+pub struct TlsConnectionInfo {
+    /// Branch named by a verified client certificate
+    pub client_identity: Option<QualifiedBranchId>,
+}
+
+/// Build the rustls acceptor; a client CA turns on mTLS
+pub fn acceptor(config: &TlsConfig) -> Result<TlsAcceptor> {
+    let provider = Arc::new(default_provider());
+    let certs = load_certs(&config.cert_path)?;
+    let key = load_key(&config.key_path)?;
+
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+
+    let builder = match &config.client_ca_path {
+        Some(ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(ca_path)? {
+                roots.add(cert)?;
+            }
+
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+            let verifier = if config.require_client_cert {
+                verifier.build()?
+            } else {
+                // Branches without a certificate fall back to API keys or tokens
+                verifier.allow_unauthenticated().build()?
+            };
+            builder.with_client_cert_verifier(verifier)
+        }
+        None if config.require_client_cert => {
+            anyhow::bail!("TLS_REQUIRE_CLIENT_CERT needs TLS_CLIENT_CA_PATH")
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut server_config = builder.with_single_cert(certs, key)?;
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<std::io::Result<Vec<_>>>()
+        .with_context(|| format!("Failed to read certificates from {}", path))?;
+
+    if certs.is_empty() {
+        anyhow::bail!("No certificates found in {}", path);
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &str) -> Result<PrivateKeyDer<'static>> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .with_context(|| format!("Failed to read private key from {}", path))?
+        .with_context(|| format!("No private key found in {}", path))
+}
+
+/// Branch named by a client certificate's `hub-broker://` URI SAN
+pub fn client_identity(cert: &CertificateDer<'_>) -> Option<QualifiedBranchId> {
+    let (_, cert) = x509_parser::certificate::X509Certificate::from_der(cert.as_ref()).ok()?;
+    let san = cert.subject_alternative_name().ok()??;
+
+    san.value.general_names.iter().find_map(|name| match name {
+        GeneralName::URI(uri) => parse_identity_uri(uri),
+        _ => None,
+    })
+}
+
+fn parse_identity_uri(uri: &str) -> Option<QualifiedBranchId> {
+    let (tenant_id, branch_id) = uri.strip_prefix(IDENTITY_URI_SCHEME)?.split_once('/')?;
+    if tenant_id.is_empty() || branch_id.is_empty() || branch_id.contains('/') {
+        return None;
+    }
+
+    Some(QualifiedBranchId::new(
+        TenantId::new(tenant_id),
+        BranchId::new(branch_id),
+    ))
+}
+
+/// Serve `app` over TLS, tagging each request with the connection's client identity
+pub async fn serve(listener: TcpListener, app: Router, acceptor: TlsAcceptor) -> Result<()> {
+    loop {
+        let (stream, remote_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                // Usually fd exhaustion; back off instead of spinning
+                error!("Failed to accept connection: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+
+        let acceptor = acceptor.clone();
+        let app = app.clone();
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    debug!("TLS handshake with {} failed: {}", remote_addr, e);
+                    return;
+                }
+            };
+
+            let info = TlsConnectionInfo {
+                client_identity: stream
+                    .get_ref()
+                    .1
+                    .peer_certificates()
+                    .and_then(|certs| certs.first())
+                    .and_then(client_identity),
+            };
+
+            let service = hyper::service::service_fn(move |mut request: hyper::Request<hyper::body::Incoming>| {
+                request.extensions_mut().insert(info.clone());
+                request.extensions_mut().insert(ConnectInfo(remote_addr));
+                app.clone().call(request)
+            });
+
+            if let Err(e) = hyper_util::server::conn::auto::Builder::new(TokioExecutor::new())
+                .serve_connection_with_upgrades(TokioIo::new(stream), service)
+                .await
+            {
+                debug!("Connection from {} closed with error: {}", remote_addr, e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_identity_uri() {
+        let id = parse_identity_uri("hub-broker://tenant_a/main").unwrap();
+        assert_eq!(id.tenant_id.as_str(), "tenant_a");
+        assert_eq!(id.branch_id.as_str(), "main");
+
+        assert!(parse_identity_uri("https://tenant_a/main").is_none());
+        assert!(parse_identity_uri("hub-broker://tenant_a").is_none());
+        assert!(parse_identity_uri("hub-broker:///main").is_none());
+        assert!(parse_identity_uri("hub-broker://tenant_a/").is_none());
+        assert!(parse_identity_uri("hub-broker://tenant_a/main/extra").is_none());
+    }
+}
@@ -0,0 +1,82 @@
+use common::QualifiedBranchId;
+use dashmap::DashMap;
+use protocol::chunking::{Outbox, Reassembler, Transfer, RESUME_WINDOW};
+use protocol::{ChunkResume, MessageChunk};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How often transfers nobody came back for are dropped
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Chunked transfers per branch, kept across its sessions so a reconnect can resume them
+/// ENFORCES: Keyed by tenant-qualified branch, so a branch only ever resumes its own transfers
+pub struct TransferStore {
+    inbound: DashMap<QualifiedBranchId, Reassembler>,
+    outbound: DashMap<QualifiedBranchId, Outbox>,
+    max_message_size: usize,
+}
+
+impl TransferStore {
+    pub fn new(max_message_size: usize) -> Self {
+        Self {
+            inbound: DashMap::new(),
+            outbound: DashMap::new(),
+            max_message_size,
+        }
+    }
+
+    /// Add a chunk from a branch; returns the encoded message once every chunk is in
+    pub fn receive(&self, id: &QualifiedBranchId, chunk: MessageChunk) -> common::Result<Option<Vec<u8>>> {
+        let result = self
+            .inbound
+            .entry(id.clone())
+            .or_insert_with(|| Reassembler::new(self.max_message_size, RESUME_WINDOW))
+            .push(chunk);
+        self.inbound.remove_if(id, |_, reassembler| reassembler.is_empty());
+        result
+    }
+
+    /// `ChunkResume` requests for the branch's unfinished uploads, to send once it reconnects
+    pub fn resume_requests(&self, id: &QualifiedBranchId) -> Vec<ChunkResume> {
+        self.inbound
+            .get_mut(id)
+            .map(|mut reassembler| reassembler.resume_requests())
+            .unwrap_or_default()
+    }
+
+    /// Remember a transfer to the branch as it starts
+    pub fn start(&self, id: &QualifiedBranchId, transfer: &Transfer) {
+        self.outbound.entry(id.clone()).or_default().insert(transfer);
+    }
+
+    /// The transfer to the branch a `ChunkResume` names, from where it stopped
+    pub fn resume(&self, id: &QualifiedBranchId, request: &ChunkResume) -> Option<Transfer> {
+        let transfer = self.outbound.get_mut(id).and_then(|mut outbox| outbox.resume(request));
+        self.outbound.remove_if(id, |_, outbox| outbox.is_empty());
+        transfer
+    }
+
+    /// Drop transfers past the resume window, and branches left with none
+    fn evict_expired(&self) {
+        self.inbound.retain(|_, reassembler| {
+            reassembler.evict_expired();
+            !reassembler.is_empty()
+        });
+        self.outbound.retain(|_, outbox| {
+            outbox.evict_expired();
+            !outbox.is_empty()
+        });
+    }
+}
+
+/// Release the memory of transfers whose branch never reconnected to resume them
+pub fn spawn_sweep(store: Arc<TransferStore>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            store.evict_expired();
+        }
+    });
+}
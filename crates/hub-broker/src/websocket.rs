@@ -1,281 +1,1563 @@
 use axum::{
     extract::{
         ws::{Message as WsMessage, WebSocket, WebSocketUpgrade},
-        State,
+        ConnectInfo, Extension, Query, State,
     },
-    response::Response,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use common::{
+    config::SessionDelivery, BranchId, BranchStatus, ConnectionMetadata, QualifiedBranchId, TenantId,
 };
-use common::{BranchId, ConnectionMetadata};
 use dashmap::DashMap;
-use futures::{sink::SinkExt, stream::StreamExt};
-use protocol::{Message, MessagePayload, JsonCodec, MessageCodec};
+use futures::{future::BoxFuture, sink::SinkExt, stream::StreamExt};
+use parking_lot::Mutex;
+use protocol::{
+    chunking::{Transfer, CHUNKED_CAPABILITY}, compression::{self, Compression}, signing::SigningKey, version::Negotiated, CodecType, ConnectRequest, DisconnectReason, JsonCodec, Message, MessageChunk, MessageCodec, MessagePayload, WireFormat,
+};
+use std::collections::VecDeque;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
 use tracing::{debug, info, warn, error};
 
-use crate::server::AppState;
+use crate::{
+    audit::{self, AuditEvent},
+    auth::Claims,
+    metrics,
+    rate_limit::{self, MessageLimits},
+    replay,
+    server::AppState,
+    storage::Storage,
+    tls::TlsConnectionInfo,
+};
+
+/// How stale a connected branch's last_seen_at may get before a heartbeat writes it
+const LAST_SEEN_WRITE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// What a socket hands the manager when it registers
+pub struct SessionChannel {
+    pub sender: mpsc::UnboundedSender<Message>,
+    /// Cancelled by the manager to force the socket closed
+    pub closed: CancellationToken,
+    /// API key the session authenticated with, directly or through a token minted from it
+    pub api_key_id: Option<String>,
+    /// Protocol revision and capabilities agreed at Connect
+    pub protocol: Negotiated,
+}
+
+/// One live socket of a branch
+struct SessionHandle {
+    sender: mpsc::UnboundedSender<Message>,
+    closed: CancellationToken,
+    api_key_id: Option<String>,
+    protocol: Negotiated,
+    metadata: ConnectionMetadata,
+}
+
+impl SessionHandle {
+    /// Queue a Disconnect notice and make the socket shut down; its own cleanup unregisters it
+    fn close(&mut self, reason: DisconnectReason) {
+        let notice = Message::new(
+            BranchId::new("hub"),
+            Some(self.metadata.branch_id.clone()),
+            MessagePayload::Disconnect(reason),
+        );
+        self.send(notice);
+        self.closed.cancel();
+    }
+
+    /// Whether the session negotiated everything needed to decode the message
+    fn can_read(&self, message: &Message) -> bool {
+        self.protocol.missing_capability(&message.payload).is_none()
+    }
+
+    /// Drop a message no session of the branch can read; resending it would not help
+    fn withhold(&self, message: &Message) {
+        if let Some(capability) = self.protocol.missing_capability(&message.payload) {
+            warn!(
+                "Withholding message {} from {}:{}, which did not negotiate {}",
+                message.id, self.metadata.tenant_id, self.metadata.branch_id, capability
+            );
+            metrics::record_withheld_message(capability);
+        }
+    }
+
+    fn send(&mut self, message: Message) -> bool {
+        if self.sender.send(message).is_ok() {
+            self.metadata.message_count += 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Sessions a message went to, and whether one of them will acknowledge it
+#[derive(Debug, Default, Clone, Copy)]
+struct Delivery {
+    sessions: usize,
+    awaits_ack: bool,
+    /// No session could read it, so none was sent it
+    withheld: bool,
+}
+
+/// How a message handed to a branch fared
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivered {
+    /// Sent; nothing will acknowledge it
+    Sent,
+    /// Sent to a session that will acknowledge it with `MessageDelivered`
+    AwaitsAck,
+    /// No session negotiated what reading it takes; resending it would not help
+    Withheld,
+}
 
 /// Connection manager handles all active WebSocket connections
+/// CRITICAL: Keyed by tenant-qualified branch ID - branch IDs are only unique per tenant
 pub struct ConnectionManager {
-    connections: DashMap<BranchId, mpsc::UnboundedSender<Message>>,
-    metadata: DashMap<BranchId, ConnectionMetadata>,
+    /// Live sessions per branch, oldest first
+    connections: DashMap<QualifiedBranchId, Vec<SessionHandle>>,
     max_connections: usize,
+    delivery: SessionDelivery,
 }
 
 impl ConnectionManager {
-    pub fn new(max_connections: usize) -> Self {
+    pub fn new(max_connections: usize, delivery: SessionDelivery) -> Self {
         Self {
             connections: DashMap::new(),
-            metadata: DashMap::new(),
             max_connections,
+            delivery,
         }
     }
 
+    /// Register a new session for a branch
+    /// ENFORCES: Hub-wide `max_connections` and the tenant's `max_connections_per_branch`
     pub async fn add_connection(
         &self,
-        branch_id: BranchId,
-        sender: mpsc::UnboundedSender<Message>,
+        id: QualifiedBranchId,
+        session_id: String,
+        channel: SessionChannel,
+        max_per_branch: usize,
     ) -> common::Result<()> {
-        if self.connections.len() >= self.max_connections {
-            return Err(common::Error::ConnectionError(
+        if self.session_count() >= self.max_connections {
+            return Err(common::Error::ConnectionLimitReached(
                 "Max connections reached".to_string(),
             ));
         }
 
-        let metadata = ConnectionMetadata {
-            branch_id: branch_id.clone(),
-            connected_at: chrono::Utc::now(),
-            last_heartbeat: chrono::Utc::now(),
-            message_count: 0,
-        };
+        let mut sessions = self.connections.entry(id.clone()).or_default();
+        if sessions.len() >= max_per_branch {
+            return Err(common::Error::ConnectionLimitReached(format!(
+                "Branch {} already has {} of {} allowed sessions",
+                id,
+                sessions.len(),
+                max_per_branch
+            )));
+        }
 
-        self.connections.insert(branch_id.clone(), sender);
-        self.metadata.insert(branch_id, metadata);
+        let now = chrono::Utc::now();
+        sessions.push(SessionHandle {
+            sender: channel.sender,
+            closed: channel.closed,
+            api_key_id: channel.api_key_id,
+            protocol: channel.protocol,
+            metadata: ConnectionMetadata {
+                tenant_id: id.tenant_id.clone(),
+                branch_id: id.branch_id.clone(),
+                session_id,
+                connected_at: now,
+                last_heartbeat: now,
+                message_count: 0,
+            },
+        });
 
         Ok(())
     }
 
-    pub async fn remove_connection(&self, branch_id: &BranchId) {
-        self.connections.remove(branch_id);
-        self.metadata.remove(branch_id);
+    /// Drop one session; the branch stays connected while it has others
+    pub async fn remove_connection(&self, id: &QualifiedBranchId, session_id: &str) {
+        if let Some(mut sessions) = self.connections.get_mut(id) {
+            sessions.retain(|s| s.metadata.session_id != session_id);
+        }
+        self.connections.remove_if(id, |_, sessions| sessions.is_empty());
     }
 
-    pub async fn send_message(&self, branch_id: &BranchId, message: Message) -> common::Result<()> {
-        if let Some(sender) = self.connections.get(branch_id) {
-            sender
-                .send(message)
-                .map_err(|e| common::Error::ConnectionError(format!("Failed to send: {}", e)))?;
+    /// Deliver to a branch according to the configured `SessionDelivery`
+    pub async fn send_message(&self, id: &QualifiedBranchId, message: Message) -> common::Result<Delivered> {
+        let Some(mut sessions) = self.connections.get_mut(id) else {
+            return Err(common::Error::ConnectionError(
+                format!("Branch {} not connected", id),
+            ));
+        };
 
-            // Update metadata
-            if let Some(mut meta) = self.metadata.get_mut(branch_id) {
-                meta.message_count += 1;
-            }
+        let delivery = self.deliver(&mut sessions, message);
+        if delivery.sessions == 0 {
+            return Err(common::Error::ConnectionError(
+                format!("Failed to send to {}", id),
+            ));
+        }
+
+        Ok(match delivery {
+            Delivery { withheld: true, .. } => Delivered::Withheld,
+            Delivery { awaits_ack: true, .. } => Delivered::AwaitsAck,
+            _ => Delivered::Sent,
+        })
+    }
 
+    /// Deliver to one specific session, e.g. a reply to something it sent
+    pub async fn send_to_session(
+        &self,
+        id: &QualifiedBranchId,
+        session_id: &str,
+        message: Message,
+    ) -> common::Result<()> {
+        let sent = self
+            .connections
+            .get_mut(id)
+            .and_then(|mut sessions| {
+                sessions
+                    .iter_mut()
+                    .find(|s| s.metadata.session_id == session_id)
+                    .map(|s| {
+                        if s.can_read(&message) {
+                            s.send(message)
+                        } else {
+                            s.withhold(&message);
+                            true
+                        }
+                    })
+            })
+            .unwrap_or(false);
+
+        if sent {
             Ok(())
         } else {
             Err(common::Error::ConnectionError(
-                format!("Branch {} not connected", branch_id),
+                format!("Session {} of {} not connected", session_id, id),
             ))
         }
     }
 
-    pub async fn broadcast_message(&self, message: Message, exclude: Option<&BranchId>) {
-        for entry in self.connections.iter() {
-            let branch_id = entry.key();
+    /// Broadcast to every connected branch of one tenant
+    /// ENFORCES: Never crosses the tenant boundary
+    pub async fn broadcast_message(
+        &self,
+        tenant_id: &TenantId,
+        message: Message,
+        exclude: Option<&BranchId>,
+    ) {
+        for mut entry in self.connections.iter_mut() {
+            let id = entry.key().clone();
+            if &id.tenant_id != tenant_id {
+                continue;
+            }
             if let Some(exclude_id) = exclude {
-                if branch_id == exclude_id {
+                if &id.branch_id == exclude_id {
                     continue;
                 }
             }
 
-            if let Err(e) = entry.value().send(message.clone()) {
-                warn!("Failed to broadcast to {}: {}", branch_id, e);
+            if self.deliver(entry.value_mut(), message.clone()).sessions == 0 {
+                warn!("Failed to broadcast to {}", id);
+            }
+        }
+    }
+
+    /// Send to the sessions selected by the delivery mode.
+    /// Only sessions that can read the message are considered; when there are none it is
+    /// withheld, which the router settles instead of keeping it queued for the branch.
+    fn deliver(&self, sessions: &mut [SessionHandle], message: Message) -> Delivery {
+        if !sessions.is_empty() && !sessions.iter().any(|s| s.can_read(&message)) {
+            sessions[0].withhold(&message);
+            return Delivery { sessions: sessions.len(), awaits_ack: false, withheld: true };
+        }
+
+        let tracked = protocol::delivery::needs_ack(&message.payload);
+        let mut delivery = Delivery::default();
+        for session in sessions.iter_mut().filter(|s| s.can_read(&message)) {
+            if session.send(message.clone()) {
+                delivery.sessions += 1;
+                delivery.awaits_ack |= tracked && session.protocol.has(protocol::delivery::ACK_CAPABILITY);
+                // Fall through to the next-oldest session only if the primary's channel is gone
+                if self.delivery == SessionDelivery::Primary {
+                    break;
+                }
+            }
+        }
+        delivery
+    }
+
+    /// Force-close the branch's sessions that authenticated with `api_key_id`
+    pub async fn close_sessions_for_api_key(
+        &self,
+        id: &QualifiedBranchId,
+        api_key_id: &str,
+        reason: DisconnectReason,
+    ) -> usize {
+        let Some(mut sessions) = self.connections.get_mut(id) else {
+            return 0;
+        };
+
+        let mut closed = 0;
+        for session in sessions
+            .iter_mut()
+            .filter(|s| s.api_key_id.as_deref() == Some(api_key_id))
+        {
+            session.close(reason.clone());
+            closed += 1;
+        }
+        closed
+    }
+
+    /// Force-close every session of one branch
+    pub async fn close_branch_sessions(&self, id: &QualifiedBranchId, reason: DisconnectReason) -> usize {
+        let Some(mut sessions) = self.connections.get_mut(id) else {
+            return 0;
+        };
+
+        for session in sessions.iter_mut() {
+            session.close(reason.clone());
+        }
+        sessions.len()
+    }
+
+    /// Force-close every session of a tenant
+    /// ENFORCES: Only touches branches of `tenant_id`
+    pub async fn close_tenant_sessions(&self, tenant_id: &TenantId, reason: DisconnectReason) -> usize {
+        let mut closed = 0;
+        for mut entry in self.connections.iter_mut() {
+            if &entry.key().tenant_id != tenant_id {
+                continue;
+            }
+            for session in entry.value_mut().iter_mut() {
+                session.close(reason.clone());
+                closed += 1;
             }
         }
+        closed
+    }
+
+    /// Tenants with at least one live session on this hub
+    pub async fn connected_tenants(&self) -> Vec<TenantId> {
+        let mut tenants: Vec<TenantId> = self
+            .connections
+            .iter()
+            .map(|entry| entry.key().tenant_id.clone())
+            .collect();
+        tenants.sort_by(|a, b| a.0.cmp(&b.0));
+        tenants.dedup();
+        tenants
     }
 
-    pub async fn is_connected(&self, branch_id: &BranchId) -> bool {
-        self.connections.contains_key(branch_id)
+    pub async fn is_connected(&self, id: &QualifiedBranchId) -> bool {
+        self.connections.contains_key(id)
     }
 
-    pub async fn update_heartbeat(&self, branch_id: &BranchId) {
-        if let Some(mut meta) = self.metadata.get_mut(branch_id) {
-            meta.last_heartbeat = chrono::Utc::now();
+    fn session_count(&self) -> usize {
+        self.connections.iter().map(|entry| entry.value().len()).sum()
+    }
+
+    /// Number of live sessions belonging to a tenant
+    pub async fn tenant_connection_count(&self, tenant_id: &TenantId) -> usize {
+        self.connections
+            .iter()
+            .filter(|entry| &entry.key().tenant_id == tenant_id)
+            .map(|entry| entry.value().len())
+            .sum()
+    }
+
+    pub async fn update_heartbeat(&self, id: &QualifiedBranchId, session_id: &str) {
+        if let Some(mut sessions) = self.connections.get_mut(id) {
+            if let Some(session) = sessions
+                .iter_mut()
+                .find(|s| s.metadata.session_id == session_id)
+            {
+                session.metadata.last_heartbeat = chrono::Utc::now();
+            }
         }
     }
 
-    pub async fn list_connections(&self) -> Vec<serde_json::Value> {
-        self.metadata
+    /// Live sessions on this hub, optionally only those of one tenant
+    pub async fn list_connections(&self, tenant_id: Option<&TenantId>) -> Vec<serde_json::Value> {
+        self.connections
             .iter()
-            .map(|entry| {
-                let meta = entry.value();
-                serde_json::json!({
-                    "branch_id": meta.branch_id.as_str(),
-                    "connected_at": meta.connected_at,
-                    "last_heartbeat": meta.last_heartbeat,
-                    "message_count": meta.message_count,
-                })
+            .filter(|entry| !matches!(tenant_id, Some(tenant_id) if &entry.key().tenant_id != tenant_id))
+            .flat_map(|entry| {
+                entry
+                    .value()
+                    .iter()
+                    .map(|session| {
+                        let meta = &session.metadata;
+                        serde_json::json!({
+                            "tenant_id": meta.tenant_id.as_str(),
+                            "branch_id": meta.branch_id.as_str(),
+                            "session_id": meta.session_id,
+                            "connected_at": meta.connected_at,
+                            "last_heartbeat": meta.last_heartbeat,
+                            "message_count": meta.message_count,
+                        })
+                    })
+                    .collect::<Vec<_>>()
             })
             .collect()
     }
 }
 
+#[derive(Debug, serde::Deserialize)]
+pub struct WsParams {
+    token: Option<String>,
+}
+
+/// Credentials checked before the upgrade, applied to the Connect that follows
+struct Credentials {
+    claims: Option<Claims>,
+    /// The bearer token `claims` came from
+    bearer_token: Option<String>,
+    /// Branch named by a verified mTLS client certificate
+    client_cert: Option<QualifiedBranchId>,
+    /// Client address of the upgrade request, for the audit log
+    remote_ip: Option<IpAddr>,
+}
+
+impl Credentials {
+    /// How the Connect that follows will be authenticated, in the order `open_session` tries
+    fn method(&self, connect_req: &ConnectRequest) -> &'static str {
+        match (&self.claims, &self.client_cert, &connect_req.api_key) {
+            (Some(_), _, _) => "token",
+            (None, Some(_), _) => "client_certificate",
+            (None, None, Some(_)) => "api_key",
+            (None, None, None) => "none",
+        }
+    }
+
+    /// Secret the Connect is authenticated with, which message signatures are keyed on;
+    /// None for client certificates, whose channel already authenticates every frame
+    fn secret<'a>(&'a self, connect_req: &'a ConnectRequest) -> Option<&'a str> {
+        match (&self.bearer_token, &self.client_cert, &connect_req.api_key) {
+            (Some(token), _, _) => Some(token),
+            (None, Some(_), _) => None,
+            (None, None, api_key) => api_key.as_deref(),
+        }
+    }
+}
+
+/// Keep branches whose hub died with them connected from showing as online. A live
+/// session writes last_seen_at every `LAST_SEEN_WRITE_INTERVAL` or is dropped after
+/// `heartbeat_timeout`, so a row older than both together has no session behind it.
+pub fn spawn_presence_sweep(storage: Storage, heartbeat_timeout: Duration) {
+    let stale_after = heartbeat_timeout + LAST_SEEN_WRITE_INTERVAL;
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(LAST_SEEN_WRITE_INTERVAL);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            match storage.mark_stale_branches_offline(stale_after).await {
+                Ok(0) => {}
+                Ok(marked) => info!("Marked {} stale branches offline", marked),
+                Err(e) => error!("Branch presence sweep failed: {}", e),
+            }
+        }
+    });
+}
+
+/// Whether the upgrade request reached us over TLS, directly or via a trusted proxy
+fn arrived_over_tls(tls: Option<&TlsConnectionInfo>, headers: &HeaderMap, trust_forwarded_proto: bool) -> bool {
+    tls.is_some()
+        || (trust_forwarded_proto
+            && headers
+                .get("x-forwarded-proto")
+                .and_then(|value| value.to_str().ok())
+                .is_some_and(|proto| proto.eq_ignore_ascii_case("https")))
+}
+
 /// WebSocket handler - entry point for WebSocket connections
+/// A bearer JWT (header or `?token=`) is validated before the upgrade. Without one the
+/// branch authenticates with its mTLS client certificate or with an API key in the
+/// Connect message.
 pub async fn ws_handler(
     ws: WebSocketUpgrade,
+    headers: HeaderMap,
+    Query(params): Query<WsParams>,
+    tls: Option<Extension<TlsConnectionInfo>>,
+    peer: Option<ConnectInfo<SocketAddr>>,
     State(state): State<AppState>,
 ) -> Response {
-    ws.on_upgrade(|socket| handle_socket(socket, state))
+    let tls = tls.map(|Extension(info)| info);
+    let security = &state.config.security;
+    let remote_ip = audit::client_ip(&headers, peer.map(|ConnectInfo(addr)| addr), security.trust_forwarded_proto);
+    if security.require_tls && !arrived_over_tls(tls.as_ref(), &headers, security.trust_forwarded_proto) {
+        warn!("Refused plaintext WebSocket upgrade");
+        return (StatusCode::FORBIDDEN, "TLS required").into_response();
+    }
+
+    let bearer_token = crate::auth::bearer_token(&headers, params.token.as_deref());
+    let claims = match bearer_token {
+        Some(token) => match crate::auth::validate_token(token, &state.keys) {
+            Ok(claims) => Some(claims),
+            Err(e) => {
+                warn!("Rejected WebSocket upgrade: {}", e);
+                return StatusCode::UNAUTHORIZED.into_response();
+            }
+        },
+        None => None,
+    };
+
+    let credentials = Credentials {
+        claims,
+        bearer_token: bearer_token.map(str::to_string),
+        client_cert: tls.and_then(|info| info.client_identity),
+        remote_ip,
+    };
+
+    let compression_enabled = state.config.server.ws_compression;
+    let formats = WireFormat::all()
+        .into_iter()
+        .filter(|format| compression_enabled || format.compression == Compression::None)
+        .map(|format| format.subprotocol());
+
+    // Branches without chunking send large messages whole, up to the same limit
+    let max_message_size = state.config.server.max_message_size;
+    ws.protocols(formats)
+        .max_message_size(max_message_size)
+        .max_frame_size(max_message_size)
+        .on_upgrade(|socket| handle_socket(socket, state, credentials))
+}
+
+/// Identity established by the Connect handshake
+#[derive(Debug, Clone)]
+struct Session {
+    id: QualifiedBranchId,
+    session_id: String,
+    /// Expiry (unix seconds) of the access token the session runs on; None for API-key sessions
+    token_expires_at: Option<i64>,
+    limits: MessageLimits,
+    remote_ip: Option<IpAddr>,
+    /// Tenant policy at Connect time: refuse row data and route payloads in the clear
+    e2e_required: bool,
+    /// Set when every message must carry a signature made with this key
+    signing_key: Option<SigningKey>,
+    /// Key of the token renewed away from, accepted until the branch first signs with the new one
+    previous_signing_key: Option<SigningKey>,
+    /// When the branch's last_seen_at was last written
+    last_seen_written: Instant,
+    protocol: Negotiated,
+    /// Codec of the connection, which the data of chunked messages is encoded with
+    codec: CodecType,
+    /// Hands transfers the branch asked to resume to the socket's writer
+    resumed: mpsc::UnboundedSender<Transfer>,
+}
+
+impl Session {
+    fn token_expired(&self) -> bool {
+        self.token_expires_at
+            .is_some_and(|exp| exp <= chrono::Utc::now().timestamp())
+    }
+
+    /// How long to wait for the next frame: the heartbeat timeout, cut short by token expiry
+    fn read_timeout(&self, heartbeat_timeout: Duration) -> Duration {
+        match self.token_expires_at {
+            Some(exp) => {
+                let left = (exp - chrono::Utc::now().timestamp()).max(0) as u64;
+                heartbeat_timeout.min(Duration::from_secs(left))
+            }
+            None => heartbeat_timeout,
+        }
+    }
+
+    /// Check a message's timestamp and signature. A branch keeps signing with its old
+    /// token until it has read TokenRenewed, so that key stays valid up to then
+    fn check_authenticity(
+        &mut self,
+        message: &Message,
+        now: Option<chrono::DateTime<chrono::Utc>>,
+        max_skew_secs: i64,
+    ) -> Result<(), MessageViolation> {
+        match check_authenticity(message, self.signing_key.as_ref(), now, max_skew_secs) {
+            Ok(()) => {
+                self.previous_signing_key = None;
+                Ok(())
+            }
+            Err(MessageViolation::InvalidSignature) if self.previous_signing_key.is_some() => {
+                check_authenticity(message, self.previous_signing_key.as_ref(), now, max_skew_secs)
+            }
+            Err(violation) => Err(violation),
+        }
+    }
 }
 
 /// Handle individual WebSocket connection
-async fn handle_socket(socket: WebSocket, state: AppState) {
+async fn handle_socket(socket: WebSocket, state: AppState, credentials: Credentials) {
+    // Branches that offer no subprotocol speak uncompressed JSON in text frames
+    let format = socket
+        .protocol()
+        .and_then(|protocol| protocol.to_str().ok())
+        .and_then(WireFormat::from_subprotocol)
+        .unwrap_or_default();
+    debug!("WebSocket wire format: {:?}", format);
+    let compression_threshold = state.config.server.ws_compression_threshold;
+
     let (mut sender, mut receiver) = socket.split();
     let (tx, mut rx) = mpsc::unbounded_channel::<Message>();
+    let (resumed_tx, mut resumed_rx) = mpsc::unbounded_channel::<Transfer>();
 
-    let codec = JsonCodec;
-    let mut branch_id: Option<BranchId> = None;
-    let mut authenticated = false;
+    let codec = format.codec.create();
+    let heartbeat_timeout = Duration::from_secs(state.config.server.heartbeat_timeout_secs);
+
+    // Shared with the cleanup below, which must run even if recv_task is aborted
+    let session: Arc<Mutex<Option<Session>>> = Arc::new(Mutex::new(None));
+    let closed = CancellationToken::new();
 
     // Spawn task to handle outgoing messages
+    let send_session = session.clone();
+    let transfers = state.transfers.clone();
+    let chunk_size = state.config.server.chunk_size;
+    let max_message_size = state.config.server.max_message_size;
     let mut send_task = tokio::spawn(async move {
-        while let Some(message) = rx.recv().await {
-            if let Ok(encoded) = codec.encode(&message) {
-                if let Ok(text) = String::from_utf8(encoded) {
-                    if sender.send(WsMessage::Text(text)).await.is_err() {
-                        break;
+        // Transfers in flight take turns with each other, and whole messages go ahead of them all
+        let mut in_flight: VecDeque<Transfer> = VecDeque::new();
+
+        loop {
+            let message = match rx.try_recv() {
+                Ok(message) => message,
+                Err(mpsc::error::TryRecvError::Disconnected) => break,
+                Err(mpsc::error::TryRecvError::Empty) => {
+                    if let Ok(transfer) = resumed_rx.try_recv() {
+                        in_flight.push_back(transfer);
                     }
+                    match next_chunk(&mut in_flight) {
+                        Some(chunk) => chunk,
+                        None => tokio::select! {
+                            message = rx.recv() => match message {
+                                Some(message) => message,
+                                None => break,
+                            },
+                            Some(transfer) = resumed_rx.recv() => {
+                                in_flight.push_back(transfer);
+                                continue;
+                            }
+                        },
+                    }
+                }
+            };
+
+            let encoded = match codec.encode(&message) {
+                Ok(encoded) => encoded,
+                Err(e) => {
+                    error!("Failed to encode message {}: {}", message.id, e);
+                    continue;
+                }
+            };
+
+            if encoded.len() > chunk_size && !matches!(message.payload, MessagePayload::Chunk(_)) {
+                let chunked_for = send_session
+                    .lock()
+                    .as_ref()
+                    .filter(|s| s.protocol.has(CHUNKED_CAPABILITY))
+                    .map(|s| s.id.clone());
+                if let Some(id) = chunked_for {
+                    match Transfer::split(&message, encoded, chunk_size, max_message_size) {
+                        Ok(Some(transfer)) => {
+                            debug!("Sending message {} to {} in {} chunks", message.id, id, transfer.total());
+                            metrics::record_chunked_transfer("outbound");
+                            transfers.start(&id, &transfer);
+                            in_flight.push_back(transfer);
+                        }
+                        Ok(None) => {}
+                        Err(e) => error!("Dropping message to {}: {}", id, e),
+                    }
+                    continue;
+                }
+            }
+
+            if encoded.len() > max_message_size {
+                error!("Dropping message {}: {} bytes, over the {} byte limit", message.id, encoded.len(), max_message_size);
+                continue;
+            }
+
+            let frame = match frame_encoded(encoded, format, compression_threshold) {
+                Ok(frame) => frame,
+                Err(e) => {
+                    error!("Failed to frame message {}: {}", message.id, e);
+                    continue;
                 }
+            };
+            if sender.send(frame).await.is_err() {
+                return;
             }
         }
+        let _ = sender.send(WsMessage::Close(None)).await;
     });
 
     // Handle incoming messages
+    let recv_state = state.clone();
+    let recv_session = session.clone();
     let mut recv_task = tokio::spawn(async move {
-        while let Some(Ok(msg)) = receiver.next().await {
-            if let WsMessage::Text(text) = msg {
-                match serde_json::from_str::<Message>(&text) {
-                    Ok(message) => {
-                        if !authenticated {
-                            // First message must be Connect
-                            if let MessagePayload::Connect(connect_req) = &message.payload {
-                                // Authenticate
-                                match crate::auth::authenticate_branch(
-                                    &state.storage,
-                                    &connect_req.tenant_id,
-                                    &connect_req.branch_id,
-                                    &connect_req.api_key,
-                                )
-                                .await
-                                {
-                                    Ok(true) => {
-                                        authenticated = true;
-                                        branch_id = Some(connect_req.branch_id.clone());
-
-                                        // Add to connection manager
-                                        if let Err(e) = state
-                                            .connection_manager
-                                            .add_connection(connect_req.branch_id.clone(), tx.clone())
-                                            .await
-                                        {
-                                            error!("Failed to add connection: {}", e);
-                                            break;
-                                        }
-
-                                        info!("Branch {} connected", connect_req.branch_id);
-
-                                        // Send ConnectAck
-                                        let ack = Message::new(
-                                            BranchId::new("hub"),
-                                            Some(connect_req.branch_id.clone()),
-                                            MessagePayload::ConnectAck(protocol::ConnectAck {
-                                                session_id: uuid::Uuid::new_v4().to_string(),
-                                                server_version: env!("CARGO_PKG_VERSION").to_string(),
-                                                heartbeat_interval_secs: state
-                                                    .config
-                                                    .server
-                                                    .heartbeat_interval_secs,
-                                                assigned_config: std::collections::HashMap::new(),
-                                            }),
-                                        );
-
-                                        let _ = tx.send(ack);
-                                    }
-                                    _ => {
-                                        error!("Authentication failed for {}", connect_req.branch_id);
-                                        break;
-                                    }
-                                }
-                            } else {
-                                warn!("First message must be Connect");
-                                break;
+        let state = recv_state;
+        let codec = format.codec.create();
+        let mut current: Option<Session> = None;
+
+        loop {
+            if let Some(session) = current.as_ref().filter(|s| s.token_expired()) {
+                warn!("Token for session {} of {} expired, closing connection", session.session_id, session.id);
+                let _ = tx.send(Message::new(
+                    BranchId::new("hub"),
+                    Some(session.id.branch_id.clone()),
+                    MessagePayload::Disconnect(DisconnectReason::new(
+                        DisconnectReason::TOKEN_EXPIRED,
+                        "Access token expired",
+                    )),
+                ));
+                break;
+            }
+
+            let wait = current
+                .as_ref()
+                .map_or(heartbeat_timeout, |s| s.read_timeout(heartbeat_timeout));
+
+            // Any inbound frame counts as liveness; silence past the timeout drops the branch
+            let next = tokio::select! {
+                // The manager already queued the Disconnect notice
+                _ = closed.cancelled() => break,
+                next = tokio::time::timeout(wait, receiver.next()) => next,
+            };
+            let msg = match next {
+                Ok(Some(Ok(msg))) => msg,
+                Ok(_) => break,
+                // Token expiry is handled at the top of the loop
+                Err(_) if current.as_ref().is_some_and(Session::token_expired) => continue,
+                Err(_) => {
+                    warn!("Heartbeat timeout after {:?}, closing connection", heartbeat_timeout);
+                    break;
+                }
+            };
+
+            let decoded = match msg {
+                // Text frames are always JSON, whatever was negotiated
+                WsMessage::Text(text) => JsonCodec.decode(text.as_bytes()),
+                WsMessage::Binary(data) => decode_frame(&data, codec.as_ref(), format),
+                WsMessage::Close(_) => {
+                    info!("Client requested close");
+                    break;
+                }
+                _ => continue,
+            };
+
+            match decoded {
+                Ok(message) => {
+                    if let Some(session) = current.as_mut() {
+                        // Handle authenticated messages
+                        let started = Instant::now();
+                        metrics::record_message(session.id.tenant_id.as_str(), message.payload.type_name());
+                        if let Err(e) = handle_message(message, session, &state, false).await {
+                            error!("Error handling message: {}", e);
+                        }
+                        metrics::record_message_duration(started.elapsed().as_secs_f64());
+                    } else {
+                        // First message must be Connect
+                        let result = match &message.payload {
+                            MessagePayload::Connect(connect_req) => {
+                                let socket = SocketChannels {
+                                    tx: &tx,
+                                    resumed: &resumed_tx,
+                                    closed: &closed,
+                                    codec: format.codec,
+                                    session: &recv_session,
+                                };
+                                let result = open_session(connect_req, &credentials, socket, &state).await;
+                                audit_connect(connect_req, &credentials, &result, &state).await;
+                                result
                             }
-                        } else {
-                            // Handle authenticated messages
-                            if let Err(e) = handle_message(message, &state).await {
-                                error!("Error handling message: {}", e);
+                            _ => Err(DisconnectReason::new(
+                                DisconnectReason::PROTOCOL_ERROR,
+                                "First message must be Connect",
+                            )),
+                        };
+
+                        match result {
+                            Ok(new_session) => {
+                                session_ready(&new_session, &tx, &state).await;
+                                current = Some(new_session);
+                            }
+                            Err(reason) => {
+                                warn!("Rejecting connection: {} ({})", reason.reason, reason.code);
+                                let _ = tx.send(Message::new(
+                                    BranchId::new("hub"),
+                                    Some(message.from.clone()),
+                                    MessagePayload::Disconnect(reason),
+                                ));
+                                break;
                             }
                         }
                     }
-                    Err(e) => {
-                        error!("Failed to parse message: {}", e);
-                    }
                 }
-            } else if let WsMessage::Close(_) = msg {
-                info!("Client requested close");
-                break;
+                Err(e) => {
+                    error!("Failed to parse message: {}", e);
+                }
             }
         }
-
-        // Cleanup on disconnect
-        if let Some(id) = branch_id {
-            info!("Branch {} disconnected", id);
-            state.connection_manager.remove_connection(&id).await;
-        }
     });
 
     // Wait for either task to finish
-    tokio::select! {
-        _ = (&mut send_task) => recv_task.abort(),
-        _ = (&mut recv_task) => send_task.abort(),
+    let send_finished = tokio::select! {
+        _ = (&mut send_task) => {
+            recv_task.abort();
+            true
+        }
+        _ = (&mut recv_task) => false,
+    };
+
+    // Cleanup on disconnect
+    let session = session.lock().take();
+    if let Some(session) = session {
+        let id = session.id;
+        info!("Branch {} session {} disconnected", id, session.session_id);
+        audit::record(
+            &state.storage,
+            AuditEvent::new(&id.tenant_id, audit::BRANCH_DISCONNECTED)
+                .branch(&id.branch_id)
+                .data(serde_json::json!({ "session_id": session.session_id }))
+                .ip(session.remote_ip),
+        )
+        .await;
+        state
+            .connection_manager
+            .remove_connection(&id, &session.session_id)
+            .await;
+
+        metrics::record_disconnection(id.tenant_id.as_str());
+        metrics::set_active_connections(
+            id.tenant_id.as_str(),
+            state
+                .connection_manager
+                .tenant_connection_count(&id.tenant_id)
+                .await,
+        );
+
+        // Other sessions of the branch may still be live
+        if !state.connection_manager.is_connected(&id).await {
+            if let Err(e) = state
+                .storage
+                .update_branch_status(&id.tenant_id, &id.branch_id, BranchStatus::Offline)
+                .await
+            {
+                error!("Failed to mark {} offline: {}", id, e);
+            }
+        }
+    }
+
+    // Once every sender is dropped the writer drains its queue (e.g. a Disconnect notice) and exits
+    if !send_finished && tokio::time::timeout(Duration::from_secs(5), &mut send_task).await.is_err() {
+        send_task.abort();
+    }
+}
+
+/// The next chunk of the transfer whose turn it is; finished transfers leave the rotation
+fn next_chunk(in_flight: &mut VecDeque<Transfer>) -> Option<Message> {
+    while let Some(mut transfer) = in_flight.pop_front() {
+        if let Some(chunk) = transfer.next_chunk() {
+            in_flight.push_back(transfer);
+            return Some(chunk);
+        }
+    }
+    None
+}
+
+/// Put an encoded message in a frame of the connection's wire format
+fn frame_encoded(encoded: Vec<u8>, format: WireFormat, compression_threshold: usize) -> common::Result<WsMessage> {
+    match format.compression {
+        Compression::Zstd => {
+            let uncompressed = encoded.len();
+            let frame = compression::compress(encoded, compression_threshold)?;
+            metrics::record_compression("outbound", uncompressed, frame.len());
+            Ok(WsMessage::Binary(frame))
+        }
+        Compression::None if format.codec.is_binary() => Ok(WsMessage::Binary(encoded)),
+        Compression::None => String::from_utf8(encoded)
+            .map(WsMessage::Text)
+            .map_err(|e| common::Error::SerializationError(e.to_string())),
+    }
+}
+
+/// Decode an incoming binary frame of the connection's wire format
+fn decode_frame(data: &[u8], codec: &dyn MessageCodec, format: WireFormat) -> common::Result<Message> {
+    match format.compression {
+        Compression::Zstd => {
+            let encoded = compression::decompress(data)?;
+            metrics::record_compression("inbound", encoded.len(), data.len());
+            codec.decode(&encoded)
+        }
+        Compression::None => codec.decode(data),
+    }
+}
+
+/// Run the Connect handshake: authenticate, enforce session limits and register the session.
+/// On failure returns the reason to send back before the socket is closed.
+async fn open_session(
+    connect_req: &ConnectRequest,
+    credentials: &Credentials,
+    socket: SocketChannels<'_>,
+    state: &AppState,
+) -> Result<Session, DisconnectReason> {
+    let auth_failed = || {
+        error!("Authentication failed for {}:{}", connect_req.tenant_id, connect_req.branch_id);
+        DisconnectReason::new(DisconnectReason::AUTH_FAILED, "Authentication failed")
+    };
+
+    // Refuse outdated branches before spending an argon2 verify on them
+    let min_protocol_version = state.config.server.min_protocol_version;
+    let protocol = Negotiated::for_connect(connect_req, min_protocol_version)?;
+
+    // CRITICAL: A client certificate pins the socket to its branch, whatever else is presented
+    if let Some(cert_id) = &credentials.client_cert {
+        if cert_id.tenant_id != connect_req.tenant_id || cert_id.branch_id != connect_req.branch_id {
+            warn!(
+                "Connect for {}:{} does not match client certificate for {}",
+                connect_req.tenant_id, connect_req.branch_id, cert_id
+            );
+            return Err(auth_failed());
+        }
+    }
+
+    // Cheap existence check in place of the argon2 verify; catches deleted branches
+    let branch_exists = || async {
+        state
+            .storage
+            .get_branch(&connect_req.tenant_id, &connect_req.branch_id)
+            .await
+            .is_ok()
+    };
+
+    // Authenticate
+    let api_key_id = match (&credentials.claims, &credentials.client_cert, &connect_req.api_key) {
+        // CRITICAL: The token, not the Connect body, decides who this socket is
+        (Some(claims), _, _) => {
+            if claims.tenant_id != connect_req.tenant_id.as_str()
+                || claims.branch_id != connect_req.branch_id.as_str()
+            {
+                warn!(
+                    "Connect for {}:{} does not match token for {}:{}",
+                    connect_req.tenant_id, connect_req.branch_id, claims.tenant_id, claims.branch_id
+                );
+                return Err(auth_failed());
+            }
+
+            if !branch_exists().await {
+                return Err(auth_failed());
+            }
+
+            // A token outlives neither the API key it came from nor that key's revocation
+            if let Some(api_key_id) = &claims.api_key_id {
+                match crate::auth::api_key_usable(
+                    &state.storage,
+                    &connect_req.tenant_id,
+                    &connect_req.branch_id,
+                    api_key_id,
+                )
+                .await
+                {
+                    Ok(true) => {}
+                    _ => return Err(auth_failed()),
+                }
+            }
+
+            claims.api_key_id.clone()
+        }
+        (None, Some(_), _) => {
+            if !branch_exists().await {
+                return Err(auth_failed());
+            }
+            None
+        }
+        (None, None, Some(api_key)) => {
+            match crate::auth::authenticate_branch(
+                &state.storage,
+                &connect_req.tenant_id,
+                &connect_req.branch_id,
+                api_key,
+            )
+            .await
+            {
+                Ok(Some(api_key_id)) => Some(api_key_id),
+                _ => return Err(auth_failed()),
+            }
+        }
+        (None, None, None) => return Err(auth_failed()),
+    };
+
+    let tenant = state
+        .storage
+        .get_tenant(&connect_req.tenant_id)
+        .await
+        .map_err(|e| {
+            error!("Failed to load tenant {}: {}", connect_req.tenant_id, e);
+            DisconnectReason::new(DisconnectReason::INTERNAL_ERROR, "Failed to load tenant")
+        })?;
+
+    // A token may outlive the tenant's active status or trial
+    if !tenant.is_usable(chrono::Utc::now()) {
+        warn!("Tenant {} is not active", tenant.id);
+        return Err(crate::tenants::disconnect_reason(&tenant));
+    }
+    let limits = tenant.limits(&state.config.trial);
+
+    let id = QualifiedBranchId::new(connect_req.tenant_id.clone(), connect_req.branch_id.clone());
+    let session_id = uuid::Uuid::new_v4().to_string();
+    let session = Session {
+        id: id.clone(),
+        session_id: session_id.clone(),
+        token_expires_at: credentials.claims.as_ref().map(|c| c.exp),
+        limits: MessageLimits {
+            branch_per_sec: state.config.security.rate_limit_per_sec,
+            tenant_per_sec: limits.rate_limit_per_sec,
+        },
+        remote_ip: credentials.remote_ip,
+        e2e_required: tenant.e2e_required,
+        signing_key: signing_key(connect_req, credentials, state.config.security.require_message_signatures),
+        previous_signing_key: None,
+        // Going online below sets last_seen_at
+        last_seen_written: Instant::now(),
+        protocol: protocol.clone(),
+        codec: socket.codec,
+        resumed: socket.resumed.clone(),
+    };
+
+    // Stored before the session can be sent anything, so the writer knows whether to chunk
+    *socket.session.lock() = Some(session.clone());
+
+    // Add to connection manager
+    if let Err(e) = state
+        .connection_manager
+        .add_connection(
+            id.clone(),
+            session_id.clone(),
+            SessionChannel {
+                sender: socket.tx.clone(),
+                closed: socket.closed.clone(),
+                api_key_id,
+                protocol: protocol.clone(),
+            },
+            limits.max_connections_per_branch,
+        )
+        .await
+    {
+        // Nothing to clean up for a session that was never registered
+        socket.session.lock().take();
+        return Err(match e {
+            common::Error::ConnectionLimitReached(reason) => {
+                DisconnectReason::new(DisconnectReason::CONNECTION_LIMIT, reason)
+            }
+            other => DisconnectReason::new(DisconnectReason::INTERNAL_ERROR, other.to_string()),
+        });
+    }
+
+    metrics::record_connection(id.tenant_id.as_str());
+    metrics::set_active_connections(
+        id.tenant_id.as_str(),
+        state
+            .connection_manager
+            .tenant_connection_count(&id.tenant_id)
+            .await,
+    );
+
+    if let Err(e) = state
+        .storage
+        .update_branch_status(&id.tenant_id, &id.branch_id, BranchStatus::Online)
+        .await
+    {
+        error!("Failed to mark {} online: {}", id, e);
+    }
+
+    info!(
+        "Branch {} connected with session {} (protocol {}, {})",
+        id,
+        session_id,
+        protocol.version,
+        protocol.capabilities.join(",")
+    );
+
+    let mut assigned_config = protocol.assigned_config(min_protocol_version);
+    assigned_config.insert(
+        protocol::e2e::E2E_REQUIRED_CONFIG.to_string(),
+        tenant.e2e_required.to_string(),
+    );
+
+    // Send ConnectAck
+    let ack = Message::new(
+        BranchId::new("hub"),
+        Some(connect_req.branch_id.clone()),
+        MessagePayload::ConnectAck(protocol::ConnectAck {
+            session_id: session_id.clone(),
+            server_version: env!("CARGO_PKG_VERSION").to_string(),
+            heartbeat_interval_secs: state.config.server.heartbeat_interval_secs,
+            assigned_config,
+        }),
+    );
+
+    let _ = socket.tx.send(ack);
+
+    Ok(session)
+}
+
+/// Ends of the socket's channels a session is opened with
+struct SocketChannels<'a> {
+    tx: &'a mpsc::UnboundedSender<Message>,
+    resumed: &'a mpsc::UnboundedSender<Transfer>,
+    closed: &'a CancellationToken,
+    codec: CodecType,
+    /// Where the socket's writer and cleanup find the session once it is open
+    session: &'a Mutex<Option<Session>>,
+}
+
+/// Pick up where the branch left off: ask it to resume unfinished uploads, then hand it
+/// the messages queued while it was away
+async fn session_ready(session: &Session, tx: &mpsc::UnboundedSender<Message>, state: &AppState) {
+    if session.protocol.has(CHUNKED_CAPABILITY) {
+        for request in state.transfers.resume_requests(&session.id) {
+            debug!("Asking {} to resume message {} at chunk {}", session.id, request.message_id, request.next_index);
+            let _ = tx.send(Message::new(
+                BranchId::new("hub"),
+                Some(session.id.branch_id.clone()),
+                MessagePayload::ChunkResume(request),
+            ));
+        }
+    }
+
+    if let Err(e) = state.message_router.deliver_offline_messages(&session.id).await {
+        warn!("Failed to deliver queued messages to {}: {}", session.id, e);
+    }
+}
+
+/// Key for the session's message signatures, if it has to sign: the branch offered to,
+/// or the hub requires it and the branch authenticated with a secret
+fn signing_key(connect_req: &ConnectRequest, credentials: &Credentials, required: bool) -> Option<SigningKey> {
+    let offered = connect_req.capabilities.iter().any(|c| c == protocol::signing::SIGNING_CAPABILITY);
+    credentials
+        .secret(connect_req)
+        .filter(|_| offered || required)
+        .map(SigningKey::derive)
+}
+
+/// Record the outcome of a Connect handshake under the tenant it claimed
+async fn audit_connect(
+    connect_req: &ConnectRequest,
+    credentials: &Credentials,
+    result: &Result<Session, DisconnectReason>,
+    state: &AppState,
+) {
+    let method = credentials.method(connect_req);
+    let event = match result {
+        Ok(session) => AuditEvent::new(&connect_req.tenant_id, audit::BRANCH_CONNECTED).data(serde_json::json!({
+            "session_id": session.session_id,
+            "auth_method": method,
+        })),
+        Err(reason) => {
+            let event_type = if reason.code == DisconnectReason::AUTH_FAILED {
+                audit::AUTH_FAILED
+            } else {
+                audit::CONNECT_REFUSED
+            };
+            AuditEvent::new(&connect_req.tenant_id, event_type).data(serde_json::json!({
+                "auth_method": method,
+                "code": reason.code,
+                "reason": reason.reason,
+            }))
+        }
+    };
+
+    audit::record(
+        &state.storage,
+        event.branch(&connect_req.branch_id).ip(credentials.remote_ip),
+    )
+    .await;
+}
+
+/// Why an authenticated message was refused before dispatch
+#[derive(Debug, PartialEq, Eq)]
+enum MessageViolation {
+    /// `from` names a different branch than the one that authenticated
+    SenderMismatch,
+    /// `RouteMessage.target_branch` disagrees with the envelope's `to`
+    RouteTargetMismatch,
+    /// Timestamp outside the clock-skew tolerance
+    Stale,
+    /// Message ID already seen from this branch
+    Replayed,
+    /// The session signs its messages, but this one carries no signature
+    MissingSignature,
+    InvalidSignature,
+}
+
+impl MessageViolation {
+    fn code(&self) -> &'static str {
+        match self {
+            MessageViolation::SenderMismatch => "SENDER_MISMATCH",
+            MessageViolation::RouteTargetMismatch => "ROUTE_TARGET_MISMATCH",
+            MessageViolation::Stale => "STALE_MESSAGE",
+            MessageViolation::Replayed => "DUPLICATE_MESSAGE",
+            MessageViolation::MissingSignature => "SIGNATURE_REQUIRED",
+            MessageViolation::InvalidSignature => "INVALID_SIGNATURE",
+        }
+    }
+
+    fn event_type(&self) -> &'static str {
+        match self {
+            MessageViolation::SenderMismatch => audit::SENDER_IDENTITY_MISMATCH,
+            MessageViolation::RouteTargetMismatch => audit::ROUTE_TARGET_MISMATCH,
+            MessageViolation::Stale => audit::STALE_MESSAGE,
+            MessageViolation::Replayed => audit::REPLAYED_MESSAGE,
+            MessageViolation::MissingSignature => audit::UNSIGNED_MESSAGE,
+            MessageViolation::InvalidSignature => audit::INVALID_MESSAGE_SIGNATURE,
+        }
+    }
+}
+
+/// Check a message against the identity bound at Connect time
+/// CRITICAL: Nothing after the handshake may speak for another branch
+fn check_identity(message: &Message, session: &QualifiedBranchId) -> Result<(), MessageViolation> {
+    if message.from != session.branch_id {
+        return Err(MessageViolation::SenderMismatch);
     }
+
+    if let MessagePayload::RouteMessage(route) = &message.payload {
+        if message.to.as_ref() != Some(&route.target_branch) {
+            return Err(MessageViolation::RouteTargetMismatch);
+        }
+    }
+
+    Ok(())
+}
+
+/// Check a message's timestamp against `now` and, for signing sessions, its signature.
+/// `now` is None for a reassembled message, whose chunk envelopes were each checked instead
+/// ENFORCES: A captured message cannot be replayed once it is past the skew tolerance
+fn check_authenticity(
+    message: &Message,
+    signing_key: Option<&SigningKey>,
+    now: Option<chrono::DateTime<chrono::Utc>>,
+    max_skew_secs: i64,
+) -> Result<(), MessageViolation> {
+    if now.is_some_and(|now| !replay::is_fresh(message.timestamp, now, max_skew_secs)) {
+        return Err(MessageViolation::Stale);
+    }
+
+    match signing_key {
+        Some(_) if message.signature.is_none() => Err(MessageViolation::MissingSignature),
+        Some(key) => key.verify(message).map_err(|_| MessageViolation::InvalidSignature),
+        None => Ok(()),
+    }
+}
+
+/// Refuse a message that failed a pre-dispatch check: audit it and tell the sender why
+async fn reject_message(
+    message: &Message,
+    violation: MessageViolation,
+    session: &Session,
+    state: &AppState,
+) -> common::Result<()> {
+    warn!(
+        "Rejected message {} from session {}: {:?} (claimed from {}, to {:?})",
+        message.id, session.id, violation, message.from, message.to
+    );
+
+    audit::record(
+        &state.storage,
+        AuditEvent::new(&session.id.tenant_id, violation.event_type())
+            .branch(&session.id.branch_id)
+            .data(serde_json::json!({
+                "message_id": message.id,
+                "claimed_from": message.from,
+                "to": message.to,
+                "timestamp": message.timestamp,
+            }))
+            .ip(session.remote_ip),
+    )
+    .await;
+
+    let error = Message::new(
+        BranchId::new("hub"),
+        Some(session.id.branch_id.clone()),
+        MessagePayload::Error(protocol::ErrorPayload {
+            code: violation.code().to_string(),
+            message: format!("Message {} rejected", message.id),
+            details: Some(serde_json::json!({ "message_id": message.id })),
+        }),
+    );
+    state
+        .connection_manager
+        .send_to_session(&session.id, &session.session_id, error)
+        .await
 }
 
-/// Handle authenticated messages
-async fn handle_message(message: Message, state: &AppState) -> common::Result<()> {
+/// Add a chunk to the branch's upload and handle the message once every chunk is in.
+/// Boxed, as it handles the reassembled message through `handle_message` in turn.
+fn receive_chunk<'a>(chunk: MessageChunk, session: &'a mut Session, state: &'a AppState) -> BoxFuture<'a, common::Result<()>> {
+    Box::pin(async move {
+        let message_id = chunk.message_id.clone();
+        let reassembled = state.transfers.receive(&session.id, chunk).and_then(|encoded| {
+            let Some(encoded) = encoded else { return Ok(None) };
+            let message = session.codec.create().decode(&encoded)?;
+            if message.id != message_id || matches!(message.payload, MessagePayload::Chunk(_)) {
+                return Err(common::Error::InvalidMessage(format!(
+                    "Chunks of {} do not reassemble into that message",
+                    message_id
+                )));
+            }
+            Ok(Some(message))
+        });
+
+        match reassembled {
+            Ok(None) => Ok(()),
+            Ok(Some(message)) => {
+                metrics::record_chunked_transfer("inbound");
+                handle_message(message, session, state, true).await
+            }
+            Err(e) => {
+                warn!("Dropped chunked message from {}: {}", session.id, e);
+                let error = Message::new(
+                    BranchId::new("hub"),
+                    Some(session.id.branch_id.clone()),
+                    MessagePayload::Error(protocol::ErrorPayload {
+                        code: "INVALID_CHUNK".to_string(),
+                        message: e.to_string(),
+                        details: Some(serde_json::json!({ "message_id": message_id })),
+                    }),
+                );
+                state
+                    .connection_manager
+                    .send_to_session(&session.id, &session.session_id, error)
+                    .await
+            }
+        }
+    })
+}
+
+/// Handle authenticated messages; `reassembled` marks one put back together from chunks
+async fn handle_message(message: Message, session: &mut Session, state: &AppState, reassembled: bool) -> common::Result<()> {
     debug!("Received message: {:?}", message.payload);
 
+    // A reassembled message is as old as its transfer, which a resume can stretch past the
+    // skew tolerance. Each of its chunk envelopes was checked for freshness and replay already
+    let max_skew_secs = state.config.security.max_clock_skew_secs;
+    let now = (!reassembled).then(chrono::Utc::now);
+    let checked = check_identity(&message, &session.id)
+        .and_then(|()| session.check_authenticity(&message, now, max_skew_secs));
+    if let Err(violation) = checked {
+        return reject_message(&message, violation, session, state).await;
+    }
+
+    // Only authentic messages claim an ID, so forgeries cannot burn a branch's IDs. A replayed
+    // heartbeat only keeps a live session alive, which is not worth a Redis write per beat
+    if !matches!(message.payload, MessagePayload::Heartbeat)
+        && !replay::first_sighting(&state.storage, &session.id, &message.id, max_skew_secs).await {
+        return reject_message(&message, MessageViolation::Replayed, session, state).await;
+    }
+
+    // Over-limit messages are dropped, but the socket stays up
+    if rate_limit::is_metered(&message.payload) {
+        if let Err(common::Error::RateLimitExceeded { retry_after_ms }) =
+            rate_limit::check_message(&state.storage, &session.id, session.limits).await
+        {
+            debug!("Throttled message {} from {}, retry after {}ms", message.id, session.id, retry_after_ms);
+            let error = Message::new(
+                BranchId::new("hub"),
+                Some(session.id.branch_id.clone()),
+                MessagePayload::Error(rate_limit::throttled_payload(&message.id, retry_after_ms)),
+            );
+            return state
+                .connection_manager
+                .send_to_session(&session.id, &session.session_id, error)
+                .await;
+        }
+    }
+
+    // ENFORCES: Tenants requiring end-to-end encryption never see branch data pass through in the clear
+    if session.e2e_required && protocol::e2e::carries_plaintext(&message.payload) {
+        warn!("Refused unsealed {} from {}", message.id, session.id);
+        let error = Message::new(
+            BranchId::new("hub"),
+            Some(session.id.branch_id.clone()),
+            MessagePayload::Error(protocol::ErrorPayload {
+                code: "E2E_REQUIRED".to_string(),
+                message: "Tenant requires end-to-end encrypted payloads".to_string(),
+                details: Some(serde_json::json!({ "message_id": message.id })),
+            }),
+        );
+        return state
+            .connection_manager
+            .send_to_session(&session.id, &session.session_id, error)
+            .await;
+    }
+
+    // The whole message goes through every check above in its own right once reassembled
+    if let MessagePayload::Chunk(chunk) = message.payload {
+        return receive_chunk(chunk, session, state).await;
+    }
+
     match &message.payload {
         MessagePayload::Heartbeat => {
             state
                 .connection_manager
-                .update_heartbeat(&message.from)
+                .update_heartbeat(&session.id, &session.session_id)
                 .await;
+            if session.last_seen_written.elapsed() >= LAST_SEEN_WRITE_INTERVAL {
+                state
+                    .storage
+                    .touch_branch_last_seen(&session.id.tenant_id, &session.id.branch_id)
+                    .await?;
+                session.last_seen_written = Instant::now();
+            }
 
             // Send HeartbeatAck
             let ack = Message::new(
                 BranchId::new("hub"),
-                Some(message.from.clone()),
+                Some(session.id.branch_id.clone()),
                 MessagePayload::HeartbeatAck,
             );
-            state.connection_manager.send_message(&message.from, ack).await?;
+            state
+                .connection_manager
+                .send_to_session(&session.id, &session.session_id, ack)
+                .await?;
+        }
+
+        MessagePayload::BranchStatus(update) => {
+            state
+                .storage
+                .record_branch_status_report(
+                    &session.id.tenant_id,
+                    &session.id.branch_id,
+                    update.status,
+                    update.message.as_deref(),
+                    &update.metadata,
+                )
+                .await?;
+            debug!("Branch {} reported status {:?}", session.id, update.status);
+        }
+
+        MessagePayload::TokenRenew(request) => {
+            let reply = match crate::auth::rotate_refresh_token(
+                &state.storage,
+                &state.keys,
+                &state.config.security,
+                &request.refresh_token,
+                Some(&session.id),
+            )
+            .await
+            {
+                Ok(tokens) => {
+                    // API-key sessions have no token deadline to push back, nor sign with the token
+                    if session.token_expires_at.is_some() {
+                        session.token_expires_at = Some(tokens.expires_at);
+                        if let Some(key) = session.signing_key.as_mut() {
+                            let previous = std::mem::replace(key, SigningKey::derive(&tokens.token));
+                            session.previous_signing_key = Some(previous);
+                        }
+                    }
+                    info!("Renewed token for session {} of {}", session.session_id, session.id);
+                    MessagePayload::TokenRenewed(tokens.into())
+                }
+                Err(e) => {
+                    warn!("Token renewal failed for {}: {}", session.id, e);
+                    MessagePayload::Error(protocol::ErrorPayload {
+                        code: "TOKEN_RENEWAL_FAILED".to_string(),
+                        message: "Refresh token rejected".to_string(),
+                        details: Some(serde_json::json!({ "message_id": message.id })),
+                    })
+                }
+            };
+
+            let reply = Message::new(BranchId::new("hub"), Some(session.id.branch_id.clone()), reply);
+            state
+                .connection_manager
+                .send_to_session(&session.id, &session.session_id, reply)
+                .await?;
+        }
+
+        MessagePayload::PublicKeyRegister(registration) => {
+            let reply = match protocol::e2e::parse_public_key(&registration.public_key) {
+                Ok(key) => {
+                    state
+                        .storage
+                        .register_public_key(&session.id.tenant_id, &session.id.branch_id, &registration.public_key)
+                        .await?;
+                    let fingerprint = protocol::e2e::fingerprint(&key);
+                    info!("Branch {} registered public key {}", session.id, fingerprint);
+                    audit::record(
+                        &state.storage,
+                        AuditEvent::new(&session.id.tenant_id, audit::PUBLIC_KEY_REGISTERED)
+                            .branch(&session.id.branch_id)
+                            .data(serde_json::json!({ "fingerprint": fingerprint }))
+                            .ip(session.remote_ip),
+                    )
+                    .await;
+                    let keys = state
+                        .storage
+                        .list_public_keys(&session.id.tenant_id, std::slice::from_ref(&session.id.branch_id))
+                        .await?;
+                    MessagePayload::PublicKeys(protocol::PublicKeyList {
+                        keys: keys.into_iter().map(Into::into).collect(),
+                    })
+                }
+                Err(e) => MessagePayload::Error(protocol::ErrorPayload {
+                    code: "INVALID_PUBLIC_KEY".to_string(),
+                    message: e.to_string(),
+                    details: Some(serde_json::json!({ "message_id": message.id })),
+                }),
+            };
+
+            let reply = Message::new(BranchId::new("hub"), Some(session.id.branch_id.clone()), reply);
+            state
+                .connection_manager
+                .send_to_session(&session.id, &session.session_id, reply)
+                .await?;
+        }
+
+        MessagePayload::PublicKeyRequest(query) => {
+            // CRITICAL: Only keys of the sender's own tenant are ever returned
+            let keys = state
+                .storage
+                .list_public_keys(&session.id.tenant_id, &query.branch_ids)
+                .await?;
+            let reply = Message::new(
+                BranchId::new("hub"),
+                Some(session.id.branch_id.clone()),
+                MessagePayload::PublicKeys(protocol::PublicKeyList {
+                    keys: keys.into_iter().map(Into::into).collect(),
+                }),
+            );
+            state
+                .connection_manager
+                .send_to_session(&session.id, &session.session_id, reply)
+                .await?;
         }
 
         MessagePayload::SyncRequest(_) | MessagePayload::SyncBatch(_) => {
             // Route to message router for processing
-            state.message_router.route_message(message).await?;
+            state.message_router.route_message(&session.id, message).await?;
         }
 
-        MessagePayload::RouteMessage(route) => {
-            // Forward message to target branch
-            if let Some(target) = &message.to {
-                state
-                    .message_router
-                    .forward_to_branch(target, message)
-                    .await?;
+        MessagePayload::RouteMessage(_) => {
+            // Forward message to target branch within the sender's tenant
+            if message.to.is_some() {
+                state.message_router.route_message(&session.id, message).await?;
             }
         }
 
+        MessagePayload::MessageDelivered(ack) => {
+            state.message_router.acknowledge(&session.id, ack).await?;
+        }
+
+        MessagePayload::ChunkResume(request) => match state.transfers.resume(&session.id, request) {
+            Some(transfer) => {
+                debug!("Resuming message {} to {} at chunk {}", request.message_id, session.id, request.next_index);
+                let _ = session.resumed.send(transfer);
+            }
+            // Expired or never sent; the branch drops its part on its own
+            None => debug!("No transfer of message {} to resume for {}", request.message_id, session.id),
+        },
+
         _ => {
             debug!("Unhandled message type");
         }
@@ -283,3 +1565,515 @@ async fn handle_message(message: Message, state: &AppState) -> common::Result<()
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn qid(tenant: &str, branch: &str) -> QualifiedBranchId {
+        QualifiedBranchId::new(TenantId::new(tenant), BranchId::new(branch))
+    }
+
+    fn channel(sender: mpsc::UnboundedSender<Message>) -> SessionChannel {
+        SessionChannel {
+            sender,
+            closed: CancellationToken::new(),
+            api_key_id: None,
+            protocol: Negotiated {
+                version: protocol::version::CURRENT,
+                capabilities: protocol::version::CAPABILITIES.iter().map(|(name, _)| name.to_string()).collect(),
+            },
+        }
+    }
+
+    fn heartbeat() -> Message {
+        Message::new(BranchId::new("hub"), None, MessagePayload::HeartbeatAck)
+    }
+
+    #[test]
+    fn test_arrived_over_tls() {
+        let tls = TlsConnectionInfo { client_identity: None };
+        let mut forwarded = HeaderMap::new();
+        forwarded.insert("x-forwarded-proto", "HTTPS".parse().unwrap());
+
+        assert!(arrived_over_tls(Some(&tls), &HeaderMap::new(), false));
+        assert!(!arrived_over_tls(None, &HeaderMap::new(), true));
+        // The header is only believed when a terminating proxy is configured
+        assert!(!arrived_over_tls(None, &forwarded, false));
+        assert!(arrived_over_tls(None, &forwarded, true));
+    }
+
+    #[test]
+    fn test_transfers_in_flight_take_turns() {
+        let transfer = |size: usize| {
+            let message = Message::new(BranchId::new("hub"), Some(BranchId::new("main")), MessagePayload::Heartbeat);
+            Transfer::split(&message, vec![7; size], 10, 1000).unwrap().unwrap()
+        };
+        let (long, short) = (transfer(30), transfer(15));
+        let mut in_flight = VecDeque::from([long.clone(), short.clone()]);
+
+        let order: Vec<(String, u32)> = std::iter::from_fn(|| next_chunk(&mut in_flight))
+            .map(|message| match message.payload {
+                MessagePayload::Chunk(chunk) => (chunk.message_id, chunk.index),
+                other => panic!("unexpected payload {:?}", other),
+            })
+            .collect();
+
+        let (l, s) = (long.message_id().to_string(), short.message_id().to_string());
+        assert_eq!(order, vec![(l.clone(), 0), (s.clone(), 0), (l.clone(), 1), (s, 1), (l, 2)]);
+        assert!(in_flight.is_empty());
+    }
+
+    #[test]
+    fn test_session_read_timeout_tracks_token_expiry() {
+        let heartbeat_timeout = Duration::from_secs(90);
+        let now = chrono::Utc::now().timestamp();
+        let session = |token_expires_at| Session {
+            id: qid("tenant_a", "main"),
+            session_id: "s1".into(),
+            token_expires_at,
+            limits: MessageLimits { branch_per_sec: 100, tenant_per_sec: 100 },
+            remote_ip: None,
+            e2e_required: false,
+            signing_key: None,
+            previous_signing_key: None,
+            last_seen_written: Instant::now(),
+            protocol: Negotiated { version: protocol::version::CURRENT, capabilities: vec![] },
+            codec: CodecType::Json,
+            resumed: mpsc::unbounded_channel().0,
+        };
+
+        let api_key = session(None);
+        assert!(!api_key.token_expired());
+        assert_eq!(api_key.read_timeout(heartbeat_timeout), heartbeat_timeout);
+
+        let long_lived = session(Some(now + 3600));
+        assert!(!long_lived.token_expired());
+        assert_eq!(long_lived.read_timeout(heartbeat_timeout), heartbeat_timeout);
+
+        let expiring = session(Some(now + 30));
+        assert!(expiring.read_timeout(heartbeat_timeout) <= Duration::from_secs(30));
+
+        let expired = session(Some(now - 1));
+        assert!(expired.token_expired());
+        assert_eq!(expired.read_timeout(heartbeat_timeout), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_check_identity_rejects_spoofed_sender() {
+        let session = qid("tenant_a", "main");
+
+        let own = Message::new(BranchId::new("main"), None, MessagePayload::Heartbeat);
+        assert_eq!(check_identity(&own, &session), Ok(()));
+
+        let spoofed = Message::new(BranchId::new("store_2"), None, MessagePayload::Heartbeat);
+        assert_eq!(check_identity(&spoofed, &session), Err(MessageViolation::SenderMismatch));
+    }
+
+    #[test]
+    fn test_check_identity_route_target_must_match_envelope() {
+        let session = qid("tenant_a", "main");
+        let route = |to: &str, target: &str| {
+            Message::new(
+                BranchId::new("main"),
+                Some(BranchId::new(to)),
+                MessagePayload::RouteMessage(protocol::RouteMessage {
+                    target_branch: BranchId::new(target),
+                    payload: vec![],
+                    sealed: None,
+                }),
+            )
+        };
+
+        assert_eq!(check_identity(&route("store_2", "store_2"), &session), Ok(()));
+        assert_eq!(
+            check_identity(&route("store_2", "store_3"), &session),
+            Err(MessageViolation::RouteTargetMismatch)
+        );
+    }
+
+    #[test]
+    fn test_frames_follow_the_negotiated_wire_format() {
+        let message = Message::new(BranchId::new("main"), None, MessagePayload::Heartbeat);
+
+        for format in WireFormat::all() {
+            let codec = format.codec.create();
+            let frame = frame_encoded(codec.encode(&message).unwrap(), format, 0).unwrap();
+            let decoded = match frame {
+                WsMessage::Text(text) => {
+                    assert!(!format.is_binary(), "{:?} sent a text frame", format);
+                    JsonCodec.decode(text.as_bytes()).unwrap()
+                }
+                WsMessage::Binary(data) => {
+                    assert!(format.is_binary(), "{:?} sent a binary frame", format);
+                    decode_frame(&data, codec.as_ref(), format).unwrap()
+                }
+                other => panic!("unexpected frame {:?}", other),
+            };
+            assert_eq!(decoded.id, message.id);
+        }
+    }
+
+    #[test]
+    fn test_check_authenticity_timestamp_and_signature() {
+        let key = SigningKey::derive("key_id.secret");
+        let mut message = Message::new(BranchId::new("main"), None, MessagePayload::Heartbeat);
+        let now = chrono::Utc::now();
+
+        assert_eq!(check_authenticity(&message, None, Some(now), 300), Ok(()));
+        assert_eq!(check_authenticity(&message, Some(&key), Some(now), 300), Err(MessageViolation::MissingSignature));
+
+        key.sign(&mut message).unwrap();
+        assert_eq!(check_authenticity(&message, Some(&key), Some(now), 300), Ok(()));
+        assert_eq!(
+            check_authenticity(&message, Some(&SigningKey::derive("other.secret")), Some(now), 300),
+            Err(MessageViolation::InvalidSignature)
+        );
+
+        // A validly signed message captured and replayed later is stale
+        let later = now + chrono::Duration::seconds(301);
+        assert_eq!(check_authenticity(&message, Some(&key), Some(later), 300), Err(MessageViolation::Stale));
+    }
+
+    #[test]
+    fn test_transfer_completing_after_skew_window_is_accepted() {
+        let key = SigningKey::derive("key_id.secret");
+        let mut message = Message::new(
+            BranchId::new("main"),
+            Some(BranchId::new("store")),
+            MessagePayload::RouteMessage(protocol::RouteMessage {
+                target_branch: BranchId::new("store"),
+                payload: vec![7; 4096],
+                sealed: None,
+            }),
+        );
+        // The upload started, stalled and was resumed after a reconnect, well past the skew
+        message.timestamp = chrono::Utc::now() - chrono::Duration::seconds(450);
+        key.sign(&mut message).unwrap();
+
+        let codec = CodecType::Json.create();
+        let encoded = codec.encode(&message).unwrap();
+        let mut transfer = Transfer::split(&message, encoded, 1024, 1 << 20).unwrap().unwrap();
+        let store = crate::transfers::TransferStore::new(1 << 20);
+        let id = qid("tenant_a", "main");
+
+        let mut reassembled = None;
+        while let Some(mut envelope) = transfer.next_chunk() {
+            key.sign(&mut envelope).unwrap();
+            assert_eq!(check_authenticity(&envelope, Some(&key), Some(chrono::Utc::now()), 300), Ok(()));
+            let MessagePayload::Chunk(chunk) = envelope.payload else { unreachable!() };
+            reassembled = store.receive(&id, chunk).unwrap();
+        }
+        let reassembled = codec.decode(&reassembled.unwrap()).unwrap();
+
+        assert_eq!(
+            check_authenticity(&reassembled, Some(&key), Some(chrono::Utc::now()), 300),
+            Err(MessageViolation::Stale)
+        );
+        assert_eq!(check_authenticity(&reassembled, Some(&key), None, 300), Ok(()));
+    }
+
+    #[test]
+    fn test_renewed_session_accepts_old_key_until_switch() {
+        let (old, new) = (SigningKey::derive("old.token"), SigningKey::derive("new.token"));
+        let mut session = Session {
+            id: qid("tenant_a", "main"),
+            session_id: "s1".into(),
+            token_expires_at: Some(chrono::Utc::now().timestamp() + 3600),
+            limits: MessageLimits { branch_per_sec: 100, tenant_per_sec: 100 },
+            remote_ip: None,
+            e2e_required: false,
+            signing_key: Some(new.clone()),
+            previous_signing_key: Some(old.clone()),
+            last_seen_written: Instant::now(),
+            protocol: Negotiated { version: protocol::version::CURRENT, capabilities: vec![] },
+            codec: CodecType::Json,
+            resumed: mpsc::unbounded_channel().0,
+        };
+        let signed = |key: &SigningKey| {
+            let mut message = Message::new(BranchId::new("main"), None, MessagePayload::Heartbeat);
+            key.sign(&mut message).unwrap();
+            message
+        };
+        let now = chrono::Utc::now();
+
+        assert_eq!(session.check_authenticity(&signed(&old), Some(now), 300), Ok(()));
+        assert_eq!(session.check_authenticity(&signed(&new), Some(now), 300), Ok(()));
+        assert_eq!(
+            session.check_authenticity(&signed(&old), Some(now), 300),
+            Err(MessageViolation::InvalidSignature)
+        );
+    }
+
+    #[test]
+    fn test_signing_key_follows_credential_and_policy() {
+        let connect = |api_key: Option<&str>, capabilities: &[&str]| ConnectRequest {
+            tenant_id: TenantId::new("tenant_a"),
+            branch_id: BranchId::new("main"),
+            api_key: api_key.map(str::to_string),
+            version: protocol::version::CURRENT.to_string(),
+            capabilities: capabilities.iter().map(|c| c.to_string()).collect(),
+            metadata: std::collections::HashMap::new(),
+        };
+        let credentials = |client_cert: Option<QualifiedBranchId>| Credentials {
+            claims: None,
+            bearer_token: None,
+            client_cert,
+            remote_ip: None,
+        };
+        let signed = [protocol::signing::SIGNING_CAPABILITY];
+
+        assert!(signing_key(&connect(Some("k.s"), &signed), &credentials(None), false).is_some());
+        assert!(signing_key(&connect(Some("k.s"), &[]), &credentials(None), true).is_some());
+        assert!(signing_key(&connect(Some("k.s"), &[]), &credentials(None), false).is_none());
+        // Certificate sessions have no secret to key on; TLS authenticates their frames
+        let cert = Some(qid("tenant_a", "main"));
+        assert!(signing_key(&connect(None, &signed), &credentials(cert), true).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_same_branch_id_in_two_tenants_is_isolated() {
+        let manager = ConnectionManager::new(10, SessionDelivery::Primary);
+        let (tx_a, mut rx_a) = mpsc::unbounded_channel();
+        let (tx_b, mut rx_b) = mpsc::unbounded_channel();
+
+        manager.add_connection(qid("tenant_a", "main"), "s1".into(), channel(tx_a), 1).await.unwrap();
+        manager.add_connection(qid("tenant_b", "main"), "s2".into(), channel(tx_b), 1).await.unwrap();
+
+        // Second registration must not overwrite the first
+        assert!(manager.is_connected(&qid("tenant_a", "main")).await);
+        assert!(manager.is_connected(&qid("tenant_b", "main")).await);
+
+        manager.send_message(&qid("tenant_a", "main"), heartbeat()).await.unwrap();
+        assert!(rx_a.try_recv().is_ok());
+        assert!(rx_b.try_recv().is_err());
+
+        manager.remove_connection(&qid("tenant_a", "main"), "s1").await;
+        assert!(!manager.is_connected(&qid("tenant_a", "main")).await);
+        assert!(manager.is_connected(&qid("tenant_b", "main")).await);
+        assert!(manager.send_message(&qid("tenant_a", "main"), heartbeat()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_stays_within_tenant() {
+        let manager = ConnectionManager::new(10, SessionDelivery::Primary);
+        let (tx_a1, mut rx_a1) = mpsc::unbounded_channel();
+        let (tx_a2, mut rx_a2) = mpsc::unbounded_channel();
+        let (tx_b, mut rx_b) = mpsc::unbounded_channel();
+
+        manager.add_connection(qid("tenant_a", "main"), "s1".into(), channel(tx_a1), 1).await.unwrap();
+        manager.add_connection(qid("tenant_a", "store_2"), "s2".into(), channel(tx_a2), 1).await.unwrap();
+        manager.add_connection(qid("tenant_b", "store_2"), "s3".into(), channel(tx_b), 1).await.unwrap();
+
+        manager
+            .broadcast_message(&TenantId::new("tenant_a"), heartbeat(), Some(&BranchId::new("main")))
+            .await;
+
+        assert!(rx_a1.try_recv().is_err());
+        assert!(rx_a2.try_recv().is_ok());
+        assert!(rx_b.try_recv().is_err());
+
+        assert_eq!(manager.tenant_connection_count(&TenantId::new("tenant_a")).await, 2);
+        assert_eq!(manager.tenant_connection_count(&TenantId::new("tenant_b")).await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_reaches_branch_that_reported_syncing() {
+        let manager = ConnectionManager::new(10, SessionDelivery::Primary);
+        let (tx_main, _rx_main) = mpsc::unbounded_channel();
+        let (tx_store, mut rx_store) = mpsc::unbounded_channel();
+        manager.add_connection(qid("tenant_a", "main"), "s1".into(), channel(tx_main), 1).await.unwrap();
+        manager.add_connection(qid("tenant_a", "store_2"), "s2".into(), channel(tx_store), 1).await.unwrap();
+
+        // store_2 reporting Syncing takes it off 'online' in branches.status, which the
+        // manager never reads: a branch is a broadcast target for as long as a session is up
+        manager
+            .broadcast_message(&TenantId::new("tenant_a"), heartbeat(), Some(&BranchId::new("main")))
+            .await;
+        assert!(rx_store.try_recv().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_sessions_per_branch_limit() {
+        let manager = ConnectionManager::new(10, SessionDelivery::Primary);
+        let id = qid("tenant_a", "main");
+
+        for session in ["s1", "s2"] {
+            let (tx, _rx) = mpsc::unbounded_channel();
+            manager.add_connection(id.clone(), session.into(), channel(tx), 2).await.unwrap();
+        }
+
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let result = manager.add_connection(id.clone(), "s3".into(), channel(tx), 2).await;
+        assert!(matches!(result, Err(common::Error::ConnectionLimitReached(_))));
+
+        // Dropping one session frees a slot and keeps the branch connected
+        manager.remove_connection(&id, "s1").await;
+        assert!(manager.is_connected(&id).await);
+        let (tx, _rx) = mpsc::unbounded_channel();
+        manager.add_connection(id.clone(), "s3".into(), channel(tx), 2).await.unwrap();
+        assert_eq!(manager.tenant_connection_count(&id.tenant_id).await, 2);
+    }
+
+    #[tokio::test]
+    async fn test_primary_delivery_targets_oldest_session() {
+        let manager = ConnectionManager::new(10, SessionDelivery::Primary);
+        let id = qid("tenant_a", "main");
+        let (tx1, mut rx1) = mpsc::unbounded_channel();
+        let (tx2, mut rx2) = mpsc::unbounded_channel();
+        manager.add_connection(id.clone(), "s1".into(), channel(tx1), 2).await.unwrap();
+        manager.add_connection(id.clone(), "s2".into(), channel(tx2), 2).await.unwrap();
+
+        manager.send_message(&id, heartbeat()).await.unwrap();
+        assert!(rx1.try_recv().is_ok());
+        assert!(rx2.try_recv().is_err());
+
+        // Replies go to the session that asked
+        manager.send_to_session(&id, "s2", heartbeat()).await.unwrap();
+        assert!(rx1.try_recv().is_err());
+        assert!(rx2.try_recv().is_ok());
+
+        // Primary falls over to the next session once the oldest is gone
+        drop(rx1);
+        manager.send_message(&id, heartbeat()).await.unwrap();
+        assert!(rx2.try_recv().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_messages_only_reach_sessions_that_can_read_them() {
+        let manager = ConnectionManager::new(10, SessionDelivery::Primary);
+        let id = qid("tenant_a", "main");
+        let legacy = |sender| SessionChannel {
+            protocol: Negotiated { version: protocol::version::LEGACY, capabilities: vec![] },
+            ..channel(sender)
+        };
+        let keys = || {
+            Message::new(
+                BranchId::new("hub"),
+                None,
+                MessagePayload::PublicKeys(protocol::PublicKeyList { keys: vec![] }),
+            )
+        };
+        let (tx1, mut rx1) = mpsc::unbounded_channel();
+        manager.add_connection(id.clone(), "s1".into(), legacy(tx1), 2).await.unwrap();
+
+        // Nobody can read it: dropped, and reported as such rather than as sent
+        assert_eq!(manager.send_message(&id, keys()).await.unwrap(), Delivered::Withheld);
+        manager.send_to_session(&id, "s1", keys()).await.unwrap();
+        assert!(rx1.try_recv().is_err());
+
+        // The primary is skipped for a newer session that negotiated e2e
+        let (tx2, mut rx2) = mpsc::unbounded_channel();
+        manager.add_connection(id.clone(), "s2".into(), channel(tx2), 2).await.unwrap();
+        assert_eq!(manager.send_message(&id, keys()).await.unwrap(), Delivered::Sent);
+        assert!(rx1.try_recv().is_err());
+        assert!(rx2.try_recv().is_ok());
+
+        manager.send_message(&id, heartbeat()).await.unwrap();
+        assert!(rx1.try_recv().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_routed_messages_await_an_ack_only_from_sessions_that_send_one() {
+        let manager = ConnectionManager::new(10, SessionDelivery::Primary);
+        let route = || {
+            Message::new(
+                BranchId::new("store_2"),
+                Some(BranchId::new("main")),
+                MessagePayload::RouteMessage(protocol::RouteMessage {
+                    target_branch: BranchId::new("main"),
+                    payload: vec![1],
+                    sealed: None,
+                }),
+            )
+        };
+
+        let (tx1, _rx1) = mpsc::unbounded_channel();
+        let (tx2, _rx2) = mpsc::unbounded_channel();
+
+        let acking = qid("tenant_a", "main");
+        manager.add_connection(acking.clone(), "s1".into(), channel(tx1), 1).await.unwrap();
+        assert_eq!(manager.send_message(&acking, route()).await.unwrap(), Delivered::AwaitsAck);
+        assert_eq!(manager.send_message(&acking, heartbeat()).await.unwrap(), Delivered::Sent);
+
+        let legacy = qid("tenant_a", "old");
+        let without_acks = SessionChannel {
+            protocol: Negotiated { version: protocol::version::CHUNKING, capabilities: vec![] },
+            ..channel(tx2)
+        };
+        manager.add_connection(legacy.clone(), "s2".into(), without_acks, 1).await.unwrap();
+        assert_eq!(manager.send_message(&legacy, route()).await.unwrap(), Delivered::Sent);
+    }
+
+    #[tokio::test]
+    async fn test_fan_out_delivery_reaches_every_session() {
+        let manager = ConnectionManager::new(10, SessionDelivery::FanOut);
+        let id = qid("tenant_a", "main");
+        let (tx1, mut rx1) = mpsc::unbounded_channel();
+        let (tx2, mut rx2) = mpsc::unbounded_channel();
+        manager.add_connection(id.clone(), "s1".into(), channel(tx1), 2).await.unwrap();
+        manager.add_connection(id.clone(), "s2".into(), channel(tx2), 2).await.unwrap();
+
+        manager.send_message(&id, heartbeat()).await.unwrap();
+        assert!(rx1.try_recv().is_ok());
+        assert!(rx2.try_recv().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_close_sessions_for_api_key() {
+        let manager = ConnectionManager::new(10, SessionDelivery::FanOut);
+        let id = qid("tenant_a", "main");
+        let (tx_old, mut rx_old) = mpsc::unbounded_channel();
+        let (tx_new, mut rx_new) = mpsc::unbounded_channel();
+        let old_closed = CancellationToken::new();
+        let new_closed = CancellationToken::new();
+
+        let keyed = |sender, closed: &CancellationToken, key: &str| SessionChannel {
+            closed: closed.clone(),
+            api_key_id: Some(key.to_string()),
+            ..channel(sender)
+        };
+        manager.add_connection(id.clone(), "s1".into(), keyed(tx_old, &old_closed, "old"), 2).await.unwrap();
+        manager.add_connection(id.clone(), "s2".into(), keyed(tx_new, &new_closed, "new"), 2).await.unwrap();
+
+        let reason = DisconnectReason::new(DisconnectReason::CREDENTIALS_REVOKED, "API key revoked");
+        assert_eq!(manager.close_sessions_for_api_key(&qid("tenant_b", "main"), "old", reason.clone()).await, 0);
+        assert_eq!(manager.close_sessions_for_api_key(&id, "old", reason).await, 1);
+
+        assert!(old_closed.is_cancelled());
+        assert!(!new_closed.is_cancelled());
+        assert!(matches!(
+            rx_old.try_recv().unwrap().payload,
+            MessagePayload::Disconnect(DisconnectReason { code: DisconnectReason::CREDENTIALS_REVOKED, .. })
+        ));
+        assert!(rx_new.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_close_tenant_sessions_stays_within_tenant() {
+        let manager = ConnectionManager::new(10, SessionDelivery::FanOut);
+        let (tx_a, mut rx_a) = mpsc::unbounded_channel();
+        let (tx_b, mut rx_b) = mpsc::unbounded_channel();
+        let a_closed = CancellationToken::new();
+        let b_closed = CancellationToken::new();
+
+        let with_token = |sender, closed: &CancellationToken| SessionChannel {
+            closed: closed.clone(),
+            ..channel(sender)
+        };
+        manager.add_connection(qid("tenant_a", "main"), "s1".into(), with_token(tx_a, &a_closed), 1).await.unwrap();
+        manager.add_connection(qid("tenant_b", "main"), "s2".into(), with_token(tx_b, &b_closed), 1).await.unwrap();
+        assert_eq!(manager.connected_tenants().await.len(), 2);
+
+        let reason = DisconnectReason::new(DisconnectReason::TENANT_INACTIVE, "Tenant suspended");
+        assert_eq!(manager.close_tenant_sessions(&TenantId::new("tenant_a"), reason).await, 1);
+
+        assert!(a_closed.is_cancelled());
+        assert!(!b_closed.is_cancelled());
+        assert!(matches!(
+            rx_a.try_recv().unwrap().payload,
+            MessagePayload::Disconnect(DisconnectReason { code: DisconnectReason::TENANT_INACTIVE, .. })
+        ));
+        assert!(rx_b.try_recv().is_err());
+    }
+}
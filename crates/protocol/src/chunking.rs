@@ -0,0 +1,383 @@
+//! Chunked transfer of messages too large for one WebSocket frame.
+//!
+//! The sender encodes a message with the connection's codec and, when the result is
+//! larger than the chunk size, sends a sequence of `Chunk` messages instead, one per
+//! frame, so other traffic can go out between them. The receiver appends the chunks in
+//! order and hands back the encoded message once the last one has arrived and the
+//! SHA-256 of the whole matches the checksum every chunk carries.
+//!
+//! Both sides keep unfinished transfers for a while. After a reconnect the receiver
+//! sends a `ChunkResume` for each transfer it holds part of, and the sender continues
+//! from the chunk named there.
+
+use crate::{ChunkResume, Message, MessageChunk, MessagePayload};
+use common::utils::calculate_hash;
+use common::{BranchId, Error, Result};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Connect capability of branches that send and accept `Chunk` messages
+pub const CHUNKED_CAPABILITY: &str = "chunked_v1";
+
+/// Encoded messages larger than this are chunked by default
+pub const DEFAULT_CHUNK_SIZE: usize = 256 * 1024;
+
+/// Largest message accepted whole or reassembled, by default
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 * 1024 * 1024;
+
+/// How long an unfinished transfer is kept for a reconnect to resume it
+pub const RESUME_WINDOW: Duration = Duration::from_secs(600);
+
+/// Unfinished transfers kept per peer and direction; the oldest give way beyond this
+const MAX_TRANSFERS: usize = 16;
+
+/// A message going out in chunks
+#[derive(Debug, Clone)]
+pub struct Transfer {
+    message_id: String,
+    from: BranchId,
+    to: Option<BranchId>,
+    checksum: String,
+    data: Arc<[u8]>,
+    chunk_size: usize,
+    next: u32,
+}
+
+impl Transfer {
+    /// Split an encoded message, or None when it fits in one frame.
+    /// Fails for messages over `max_message_size`, which the receiver would refuse.
+    pub fn split(message: &Message, encoded: Vec<u8>, chunk_size: usize, max_message_size: usize) -> Result<Option<Self>> {
+        if encoded.len() > max_message_size {
+            return Err(Error::InvalidMessage(format!(
+                "Message {} is {} bytes, over the {} byte limit",
+                message.id,
+                encoded.len(),
+                max_message_size
+            )));
+        }
+        if encoded.len() <= chunk_size {
+            return Ok(None);
+        }
+
+        Ok(Some(Self {
+            message_id: message.id.clone(),
+            from: message.from.clone(),
+            to: message.to.clone(),
+            checksum: calculate_hash(&encoded),
+            data: encoded.into(),
+            chunk_size,
+            next: 0,
+        }))
+    }
+
+    pub fn message_id(&self) -> &str {
+        &self.message_id
+    }
+
+    pub fn total(&self) -> u32 {
+        self.data.len().div_ceil(self.chunk_size) as u32
+    }
+
+    /// The next chunk, in a message of its own; None once all have been handed out
+    pub fn next_chunk(&mut self) -> Option<Message> {
+        if self.next >= self.total() {
+            return None;
+        }
+
+        let start = self.next as usize * self.chunk_size;
+        let end = (start + self.chunk_size).min(self.data.len());
+        let chunk = MessageChunk {
+            message_id: self.message_id.clone(),
+            index: self.next,
+            total: self.total(),
+            total_size: self.data.len() as u64,
+            checksum: self.checksum.clone(),
+            data: self.data[start..end].to_vec(),
+        };
+        self.next += 1;
+
+        Some(Message::new(self.from.clone(), self.to.clone(), MessagePayload::Chunk(chunk)))
+    }
+
+    /// Continue from the chunk a reconnected receiver asked for
+    pub fn resume_at(&mut self, index: u32) {
+        self.next = index.min(self.total());
+    }
+}
+
+/// Outgoing transfers, kept after sending so a reconnected receiver can resume them
+#[derive(Debug)]
+pub struct Outbox {
+    transfers: HashMap<String, (Instant, Transfer)>,
+    window: Duration,
+}
+
+impl Default for Outbox {
+    fn default() -> Self {
+        Self::new(RESUME_WINDOW)
+    }
+}
+
+impl Outbox {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            transfers: HashMap::new(),
+            window,
+        }
+    }
+
+    /// Remember a transfer as it starts
+    pub fn insert(&mut self, transfer: &Transfer) {
+        self.evict_expired();
+        if self.transfers.len() >= MAX_TRANSFERS {
+            let oldest = self.transfers.iter().min_by_key(|(_, (at, _))| *at).map(|(id, _)| id.clone());
+            if let Some(oldest) = oldest {
+                self.transfers.remove(&oldest);
+            }
+        }
+        self.transfers
+            .insert(transfer.message_id.clone(), (Instant::now(), transfer.clone()));
+    }
+
+    /// The transfer a `ChunkResume` names, rewound to where the receiver stopped
+    pub fn resume(&mut self, request: &ChunkResume) -> Option<Transfer> {
+        self.evict_expired();
+        self.transfers.get(&request.message_id).map(|(_, transfer)| {
+            let mut transfer = transfer.clone();
+            transfer.resume_at(request.next_index);
+            transfer
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transfers.is_empty()
+    }
+
+    /// Forget transfers older than the resume window
+    pub fn evict_expired(&mut self) {
+        let window = self.window;
+        self.transfers.retain(|_, (at, _)| at.elapsed() < window);
+    }
+}
+
+/// A message partly received
+#[derive(Debug)]
+struct Partial {
+    checksum: String,
+    total: u32,
+    total_size: usize,
+    data: Vec<u8>,
+    next: u32,
+    updated: Instant,
+}
+
+/// Reassembles chunked messages from one peer
+#[derive(Debug)]
+pub struct Reassembler {
+    partial: HashMap<String, Partial>,
+    max_message_size: usize,
+    window: Duration,
+}
+
+impl Reassembler {
+    pub fn new(max_message_size: usize, window: Duration) -> Self {
+        Self {
+            partial: HashMap::new(),
+            max_message_size,
+            window,
+        }
+    }
+
+    /// Add the next chunk of a transfer; returns the encoded message once it is complete
+    /// and matches its checksum. A failed transfer is dropped and has to start over.
+    pub fn push(&mut self, chunk: MessageChunk) -> Result<Option<Vec<u8>>> {
+        let now = Instant::now();
+        self.evict_expired();
+
+        let invalid = |problem: &str| {
+            Error::InvalidMessage(format!("Chunk {} of message {}: {}", chunk.index, chunk.message_id, problem))
+        };
+        if chunk.total_size > self.max_message_size as u64 {
+            self.partial.remove(&chunk.message_id);
+            return Err(invalid("message exceeds the size limit"));
+        }
+        if chunk.index >= chunk.total {
+            self.partial.remove(&chunk.message_id);
+            return Err(invalid("index out of range"));
+        }
+
+        // A first chunk, or one of a different rendition, (re)starts the transfer
+        let current = self
+            .partial
+            .get(&chunk.message_id)
+            .is_some_and(|p| p.checksum == chunk.checksum && p.total == chunk.total && chunk.index != 0);
+        if !current {
+            if chunk.index != 0 {
+                self.partial.remove(&chunk.message_id);
+                return Err(invalid("transfer was not started"));
+            }
+            if !self.partial.contains_key(&chunk.message_id) && self.partial.len() >= MAX_TRANSFERS {
+                return Err(invalid("too many unfinished transfers"));
+            }
+            self.partial.insert(
+                chunk.message_id.clone(),
+                Partial {
+                    checksum: chunk.checksum.clone(),
+                    total: chunk.total,
+                    total_size: chunk.total_size as usize,
+                    data: Vec::new(),
+                    next: 0,
+                    updated: now,
+                },
+            );
+        }
+
+        let Some(partial) = self.partial.get_mut(&chunk.message_id) else {
+            return Ok(None);
+        };
+        // Already have it: the sender resumed from an earlier point than needed
+        if chunk.index < partial.next {
+            return Ok(None);
+        }
+        if chunk.index > partial.next {
+            self.partial.remove(&chunk.message_id);
+            return Err(invalid("chunks missing before it"));
+        }
+        if partial.data.len() + chunk.data.len() > partial.total_size {
+            self.partial.remove(&chunk.message_id);
+            return Err(invalid("more data than announced"));
+        }
+
+        partial.data.extend_from_slice(&chunk.data);
+        partial.next += 1;
+        partial.updated = now;
+        if partial.next < partial.total {
+            return Ok(None);
+        }
+
+        let Some(partial) = self.partial.remove(&chunk.message_id) else {
+            return Ok(None);
+        };
+        if partial.data.len() != partial.total_size || calculate_hash(&partial.data) != partial.checksum {
+            return Err(invalid("checksum mismatch"));
+        }
+        Ok(Some(partial.data))
+    }
+
+    /// Where each unfinished transfer stands, to send as `ChunkResume` after reconnecting
+    pub fn resume_requests(&mut self) -> Vec<ChunkResume> {
+        self.evict_expired();
+        self.partial
+            .iter()
+            .map(|(message_id, partial)| ChunkResume {
+                message_id: message_id.clone(),
+                next_index: partial.next,
+            })
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.partial.is_empty()
+    }
+
+    /// Forget transfers that made no progress within the resume window
+    pub fn evict_expired(&mut self) {
+        let window = self.window;
+        self.partial.retain(|_, partial| partial.updated.elapsed() < window);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{JsonCodec, MessageCodec, RouteMessage};
+
+    fn large_message(size: usize) -> (Message, Vec<u8>) {
+        let message = Message::new(
+            BranchId::new("store_1"),
+            Some(BranchId::new("store_2")),
+            MessagePayload::RouteMessage(RouteMessage {
+                target_branch: BranchId::new("store_2"),
+                payload: (0..size).map(|i| (i % 251) as u8).collect(),
+                sealed: None,
+            }),
+        );
+        let encoded = JsonCodec.encode(&message).unwrap();
+        (message, encoded)
+    }
+
+    fn chunks(transfer: &mut Transfer) -> Vec<MessageChunk> {
+        std::iter::from_fn(|| transfer.next_chunk())
+            .map(|message| match message.payload {
+                MessagePayload::Chunk(chunk) => chunk,
+                other => panic!("expected a chunk, got {:?}", other),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_split_and_reassemble() {
+        let (message, encoded) = large_message(10_000);
+        assert!(Transfer::split(&message, encoded.clone(), encoded.len(), usize::MAX).unwrap().is_none());
+        assert!(Transfer::split(&message, encoded.clone(), 1024, 1024).is_err());
+
+        let mut transfer = Transfer::split(&message, encoded.clone(), 1024, usize::MAX).unwrap().unwrap();
+        let chunks = chunks(&mut transfer);
+        assert_eq!(chunks.len(), encoded.len().div_ceil(1024));
+
+        let mut reassembler = Reassembler::new(usize::MAX, RESUME_WINDOW);
+        let (last, rest) = chunks.split_last().unwrap();
+        for chunk in rest {
+            assert_eq!(reassembler.push(chunk.clone()).unwrap(), None);
+        }
+        let whole = reassembler.push(last.clone()).unwrap().unwrap();
+        assert_eq!(JsonCodec.decode(&whole).unwrap().id, message.id);
+        assert!(reassembler.is_empty());
+    }
+
+    #[test]
+    fn test_resume_after_reconnect() {
+        let (message, encoded) = large_message(10_000);
+        let mut transfer = Transfer::split(&message, encoded, 1024, usize::MAX).unwrap().unwrap();
+        let mut outbox = Outbox::default();
+        outbox.insert(&transfer);
+        let chunks = chunks(&mut transfer);
+
+        // The connection dropped after three chunks made it across
+        let mut reassembler = Reassembler::new(usize::MAX, RESUME_WINDOW);
+        for chunk in &chunks[..3] {
+            reassembler.push(chunk.clone()).unwrap();
+        }
+        let requests = reassembler.resume_requests();
+        assert_eq!(requests, vec![ChunkResume { message_id: message.id.clone(), next_index: 3 }]);
+
+        let mut resumed = outbox.resume(&requests[0]).unwrap();
+        let mut whole = None;
+        while let Some(MessagePayload::Chunk(chunk)) = resumed.next_chunk().map(|m| m.payload) {
+            whole = reassembler.push(chunk).unwrap();
+        }
+        assert_eq!(JsonCodec.decode(&whole.unwrap()).unwrap().id, message.id);
+    }
+
+    #[test]
+    fn test_bad_transfers_are_refused() {
+        let (message, encoded) = large_message(10_000);
+        let mut transfer = Transfer::split(&message, encoded, 1024, usize::MAX).unwrap().unwrap();
+        let chunks = chunks(&mut transfer);
+        let mut reassembler = Reassembler::new(usize::MAX, RESUME_WINDOW);
+
+        // A gap, a transfer that never started, and an oversized one
+        reassembler.push(chunks[0].clone()).unwrap();
+        assert!(reassembler.push(chunks[2].clone()).is_err());
+        assert!(reassembler.push(chunks[1].clone()).is_err());
+        assert!(Reassembler::new(1024, RESUME_WINDOW).push(chunks[0].clone()).is_err());
+
+        // Tampered data fails the checksum at the end
+        let mut tampered = chunks.clone();
+        tampered[1].data[0] ^= 1;
+        let results: Vec<_> = tampered.into_iter().map(|c| reassembler.push(c)).collect();
+        assert!(results.last().unwrap().is_err());
+        assert!(reassembler.is_empty());
+    }
+}
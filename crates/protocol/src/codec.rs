@@ -1,8 +1,9 @@
-use crate::Message;
+use crate::compression::{CompressedCodec, Compression};
+use crate::{proto, Message};
 use common::Result;
 
 /// Message codec for serialization/deserialization
-pub trait MessageCodec {
+pub trait MessageCodec: Send + Sync {
     fn encode(&self, message: &Message) -> Result<Vec<u8>>;
     fn decode(&self, data: &[u8]) -> Result<Message>;
 }
@@ -33,17 +34,121 @@ impl MessageCodec for BincodeCodec {
     }
 }
 
+/// Protobuf codec (language-neutral, schema in `proto/hub_broker.proto`)
+pub struct ProtobufCodec;
+
+impl MessageCodec for ProtobufCodec {
+    fn encode(&self, message: &Message) -> Result<Vec<u8>> {
+        Ok(prost::Message::encode_to_vec(&proto::Message::from(message)))
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<Message> {
+        let decoded = <proto::Message as prost::Message>::decode(data)
+            .map_err(|e| common::Error::SerializationError(e.to_string()))?;
+        decoded.try_into()
+    }
+}
+
 /// Codec factory
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
 pub enum CodecType {
+    #[default]
     Json,
     Bincode,
+    Protobuf,
 }
 
 impl CodecType {
+    /// Every codec, most compact first; the hub picks the first one a branch offers
+    pub const ALL: [CodecType; 3] = [CodecType::Bincode, CodecType::Protobuf, CodecType::Json];
+
     pub fn create(&self) -> Box<dyn MessageCodec> {
         match self {
             CodecType::Json => Box::new(JsonCodec),
             CodecType::Bincode => Box::new(BincodeCodec),
+            CodecType::Protobuf => Box::new(ProtobufCodec),
+        }
+    }
+
+    /// WebSocket subprotocol (`Sec-WebSocket-Protocol`) that selects this codec
+    pub fn subprotocol(&self) -> &'static str {
+        match self {
+            CodecType::Json => "hub-broker.json",
+            CodecType::Bincode => "hub-broker.bincode",
+            CodecType::Protobuf => "hub-broker.protobuf",
+        }
+    }
+
+    pub fn from_subprotocol(subprotocol: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|codec| codec.subprotocol() == subprotocol)
+    }
+
+    /// Binary codecs travel in binary frames, JSON in text frames
+    pub fn is_binary(&self) -> bool {
+        !matches!(self, CodecType::Json)
+    }
+}
+
+/// Codec and compression of one connection, negotiated as a WebSocket subprotocol
+/// such as `hub-broker.protobuf` or `hub-broker.protobuf+zstd`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct WireFormat {
+    pub codec: CodecType,
+    pub compression: Compression,
+}
+
+impl WireFormat {
+    const ZSTD_SUFFIX: &'static str = "+zstd";
+
+    pub fn new(codec: CodecType, compression: Compression) -> Self {
+        Self { codec, compression }
+    }
+
+    /// Every format in the hub's order of preference: compact codecs first, compressed before plain
+    pub fn all() -> Vec<WireFormat> {
+        CodecType::ALL
+            .into_iter()
+            .flat_map(|codec| [Compression::Zstd, Compression::None].map(|compression| Self::new(codec, compression)))
+            .collect()
+    }
+
+    pub fn subprotocol(&self) -> String {
+        match self.compression {
+            Compression::None => self.codec.subprotocol().to_string(),
+            Compression::Zstd => format!("{}{}", self.codec.subprotocol(), Self::ZSTD_SUFFIX),
+        }
+    }
+
+    pub fn from_subprotocol(subprotocol: &str) -> Option<Self> {
+        match subprotocol.strip_suffix(Self::ZSTD_SUFFIX) {
+            Some(codec) => Some(Self::new(CodecType::from_subprotocol(codec)?, Compression::Zstd)),
+            None => Some(Self::new(CodecType::from_subprotocol(subprotocol)?, Compression::None)),
+        }
+    }
+
+    /// Compressed frames are bytes whatever the codec
+    pub fn is_binary(&self) -> bool {
+        self.codec.is_binary() || self.compression != Compression::None
+    }
+
+    /// Codec for binary frames of this format
+    pub fn create(&self, compression_threshold: usize) -> Box<dyn MessageCodec> {
+        match self.compression {
+            Compression::None => self.codec.create(),
+            Compression::Zstd => Box::new(CompressedCodec::new(self.codec.create(), compression_threshold)),
+        }
+    }
+}
+
+impl std::str::FromStr for CodecType {
+    type Err = common::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "json" => Ok(CodecType::Json),
+            "bincode" => Ok(CodecType::Bincode),
+            "protobuf" | "proto" => Ok(CodecType::Protobuf),
+            other => Err(common::Error::InvalidMessage(format!("Unknown codec: {}", other))),
         }
     }
 }
@@ -63,7 +168,7 @@ mod tests {
             MessagePayload::Connect(ConnectRequest {
                 tenant_id: TenantId::new("test_tenant"),
                 branch_id: BranchId::new("test"),
-                api_key: "key".to_string(),
+                api_key: Some("key".to_string()),
                 version: "1.0.0".to_string(),
                 capabilities: vec![],
                 metadata: HashMap::new(),
@@ -77,6 +182,45 @@ mod tests {
         assert_eq!(message.id, decoded.id);
     }
 
+    #[test]
+    fn test_payload_type_name_matches_json_tag() {
+        let payloads = [
+            MessagePayload::Heartbeat,
+            MessagePayload::PublicKeyRequest(crate::PublicKeyQuery { branch_ids: vec![] }),
+        ];
+        for payload in payloads {
+            let json = serde_json::to_value(&payload).unwrap();
+            assert_eq!(json["type"], payload.type_name());
+        }
+    }
+
+    #[test]
+    fn test_codec_subprotocol_round_trip() {
+        for codec in CodecType::ALL {
+            assert_eq!(CodecType::from_subprotocol(codec.subprotocol()), Some(codec));
+        }
+        assert_eq!(CodecType::from_subprotocol("graphql-ws"), None);
+        assert_eq!("BINCODE".parse::<CodecType>().unwrap(), CodecType::Bincode);
+        assert_eq!("proto".parse::<CodecType>().unwrap(), CodecType::Protobuf);
+        assert!("xml".parse::<CodecType>().is_err());
+    }
+
+    #[test]
+    fn test_wire_format_subprotocols() {
+        let formats = WireFormat::all();
+        assert_eq!(formats.len(), CodecType::ALL.len() * 2);
+        for format in &formats {
+            assert_eq!(WireFormat::from_subprotocol(&format.subprotocol()), Some(*format));
+        }
+        assert_eq!(
+            WireFormat::from_subprotocol("hub-broker.json+zstd"),
+            Some(WireFormat::new(CodecType::Json, Compression::Zstd))
+        );
+        assert!(WireFormat::new(CodecType::Json, Compression::Zstd).is_binary());
+        assert!(!WireFormat::default().is_binary());
+        assert_eq!(WireFormat::from_subprotocol("hub-broker.xml+zstd"), None);
+    }
+
     #[test]
     fn test_bincode_codec() {
         let message = Message::new(
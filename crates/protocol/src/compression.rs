@@ -0,0 +1,118 @@
+//! zstd compression of encoded messages, negotiated per connection.
+//!
+//! A compressed connection sends every message in a binary frame that starts with a
+//! flag byte: `FLAG_RAW` for messages below the threshold (not worth the CPU), or
+//! `FLAG_ZSTD` for a zstd frame. Text frames stay uncompressed JSON.
+
+use crate::{Message, MessageCodec};
+use common::{Error, Result};
+
+pub const FLAG_RAW: u8 = 0;
+pub const FLAG_ZSTD: u8 = 1;
+
+/// Messages smaller than this are sent uncompressed by default
+pub const DEFAULT_THRESHOLD: usize = 1024;
+
+/// Fast level; sync batches of JSON rows still shrink several times over
+const LEVEL: i32 = 3;
+
+/// Refuse to inflate a frame past this, so a tiny frame cannot exhaust memory
+pub const MAX_DECOMPRESSED_SIZE: usize = 64 * 1024 * 1024;
+
+/// How a connection compresses its frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd,
+}
+
+impl std::str::FromStr for Compression {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" | "off" | "" => Ok(Compression::None),
+            "zstd" => Ok(Compression::Zstd),
+            other => Err(Error::InvalidMessage(format!("Unknown compression: {}", other))),
+        }
+    }
+}
+
+/// Frame an encoded message, compressing it when it is at least `threshold` bytes
+pub fn compress(encoded: Vec<u8>, threshold: usize) -> Result<Vec<u8>> {
+    if encoded.len() < threshold {
+        let mut frame = Vec::with_capacity(encoded.len() + 1);
+        frame.push(FLAG_RAW);
+        frame.extend_from_slice(&encoded);
+        return Ok(frame);
+    }
+
+    let mut frame = vec![FLAG_ZSTD];
+    zstd::stream::copy_encode(encoded.as_slice(), &mut frame, LEVEL)
+        .map_err(|e| Error::SerializationError(format!("zstd: {}", e)))?;
+    Ok(frame)
+}
+
+/// Undo `compress`
+pub fn decompress(frame: &[u8]) -> Result<Vec<u8>> {
+    match frame.split_first() {
+        Some((&FLAG_RAW, encoded)) => Ok(encoded.to_vec()),
+        Some((&FLAG_ZSTD, compressed)) => zstd::bulk::decompress(compressed, MAX_DECOMPRESSED_SIZE)
+            .map_err(|e| Error::InvalidMessage(format!("zstd: {}", e))),
+        Some((flag, _)) => Err(Error::InvalidMessage(format!("Unknown compression flag {}", flag))),
+        None => Err(Error::InvalidMessage("Empty frame".to_string())),
+    }
+}
+
+/// Wraps another codec with `compress` / `decompress`
+pub struct CompressedCodec {
+    inner: Box<dyn MessageCodec>,
+    threshold: usize,
+}
+
+impl CompressedCodec {
+    pub fn new(inner: Box<dyn MessageCodec>, threshold: usize) -> Self {
+        Self { inner, threshold }
+    }
+}
+
+impl MessageCodec for CompressedCodec {
+    fn encode(&self, message: &Message) -> Result<Vec<u8>> {
+        compress(self.inner.encode(message)?, self.threshold)
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<Message> {
+        self.inner.decode(&decompress(data)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_small_frames_stay_raw_and_large_ones_shrink() {
+        let small = b"{\"type\":\"Heartbeat\"}".to_vec();
+        let frame = compress(small.clone(), DEFAULT_THRESHOLD).unwrap();
+        assert_eq!(frame[0], FLAG_RAW);
+        assert_eq!(decompress(&frame).unwrap(), small);
+
+        let rows = "{\"id\":1,\"customer\":\"Ada\",\"total\":19.5},".repeat(500).into_bytes();
+        let frame = compress(rows.clone(), DEFAULT_THRESHOLD).unwrap();
+        assert_eq!(frame[0], FLAG_ZSTD);
+        assert!(frame.len() * 10 < rows.len());
+        assert_eq!(decompress(&frame).unwrap(), rows);
+    }
+
+    #[test]
+    fn test_decompress_rejects_bad_frames() {
+        assert!(decompress(&[]).is_err());
+        assert!(decompress(&[7, 1, 2, 3]).is_err());
+        assert!(decompress(&[FLAG_ZSTD, 1, 2, 3]).is_err());
+
+        // A highly compressible frame that would inflate past the limit
+        let bomb = compress(vec![0; MAX_DECOMPRESSED_SIZE + 1], 0).unwrap();
+        assert!(decompress(&bomb).is_err());
+    }
+}
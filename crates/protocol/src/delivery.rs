@@ -0,0 +1,105 @@
+//! Acknowledged delivery of routed messages.
+//!
+//! A branch that negotiated `ACK_CAPABILITY` answers every `RouteMessage` it receives
+//! with a `MessageDelivered` naming it. The hub keeps the message queued until then and
+//! resends it with backoff, relays the acknowledgement to the sender, and sends the
+//! sender a `MessageFailed` once it gives up. Delivery is at least once: a resend can
+//! cross the acknowledgement, so receivers skip message IDs they have already seen.
+
+use crate::{Message, MessageDelivered, MessagePayload};
+use common::BranchId;
+use std::collections::{HashSet, VecDeque};
+
+/// Connect capability of branches that acknowledge routed messages
+pub const ACK_CAPABILITY: &str = "ack_v1";
+
+/// Whether a message stays queued until the receiving branch acknowledges it
+pub fn needs_ack(payload: &MessagePayload) -> bool {
+    matches!(payload, MessagePayload::RouteMessage(_))
+}
+
+/// The acknowledgement a branch sends for a message it received
+pub fn acknowledge(received: &Message, from: BranchId) -> Message {
+    Message::new(
+        from,
+        None,
+        MessagePayload::MessageDelivered(MessageDelivered {
+            message_id: received.id.clone(),
+            delivered_at: chrono::Utc::now(),
+        }),
+    )
+}
+
+/// The most recent message IDs a receiver has handled, to recognise resends
+#[derive(Debug)]
+pub struct SeenMessages {
+    order: VecDeque<String>,
+    ids: HashSet<String>,
+    capacity: usize,
+}
+
+impl SeenMessages {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            order: VecDeque::with_capacity(capacity),
+            ids: HashSet::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Record a message ID; false if it was already seen
+    pub fn first_sighting(&mut self, message_id: &str) -> bool {
+        if self.ids.contains(message_id) {
+            return false;
+        }
+
+        if self.order.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        self.order.push_back(message_id.to_string());
+        self.ids.insert(message_id.to_string());
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resends_are_recognised_until_pushed_out() {
+        let mut seen = SeenMessages::new(2);
+        assert!(seen.first_sighting("a"));
+        assert!(!seen.first_sighting("a"));
+
+        assert!(seen.first_sighting("b"));
+        assert!(seen.first_sighting("c"));
+        // "a" was the oldest and made room for "c"
+        assert!(seen.first_sighting("a"));
+        assert!(!seen.first_sighting("c"));
+    }
+
+    #[test]
+    fn test_only_routed_messages_are_acknowledged() {
+        let route = Message::new(
+            BranchId::new("store_1"),
+            Some(BranchId::new("store_2")),
+            MessagePayload::RouteMessage(crate::RouteMessage {
+                target_branch: BranchId::new("store_2"),
+                payload: vec![1, 2, 3],
+                sealed: None,
+            }),
+        );
+        assert!(needs_ack(&route.payload));
+        assert!(!needs_ack(&MessagePayload::Heartbeat));
+
+        let ack = acknowledge(&route, BranchId::new("store_2"));
+        assert_eq!(ack.from.as_str(), "store_2");
+        match ack.payload {
+            MessagePayload::MessageDelivered(delivered) => assert_eq!(delivered.message_id, route.id),
+            other => panic!("unexpected payload {:?}", other),
+        }
+    }
+}
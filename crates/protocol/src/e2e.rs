@@ -0,0 +1,549 @@
+//! End-to-end payload encryption between branches of one tenant.
+//!
+//! Row data in `DatabaseChange` and the body of a `RouteMessage` can be sealed so
+//! the hub only ever routes ciphertext. Routing metadata (tenant, branches, table,
+//! operation, primary key) stays in the clear and is bound to the ciphertext as
+//! associated data, so a sealed body cannot be replayed onto another row or branch.
+//!
+//! Two key schemes:
+//! - a shared tenant key every branch is provisioned with (`TenantKey`), or
+//! - per-branch X25519 key pairs (`BranchKeyPair`) whose public halves are registered
+//!   with the hub; each payload gets a fresh content key wrapped for every recipient.
+
+use crate::{DatabaseChange, MessagePayload, RouteMessage};
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use common::{BranchId, Error, Result, TenantId};
+use hkdf::Hkdf;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use x25519_dalek::{SharedSecret, StaticSecret};
+
+pub use x25519_dalek::PublicKey;
+
+/// Connect capability of branches that can seal and open payloads
+pub const E2E_CAPABILITY: &str = "e2e_v1";
+
+/// `ConnectAck.assigned_config` key, "true" when the tenant only accepts sealed payloads
+pub const E2E_REQUIRED_CONFIG: &str = "e2e_required";
+
+const KEY_LEN: usize = 32;
+
+/// HKDF info for key-encryption keys; bumping it invalidates every wrapped key
+const KEK_INFO: &[u8] = b"hub-broker e2e key wrap v1";
+
+/// Encrypted body in place of plaintext row data or a route payload
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SealedPayload {
+    pub key: SealedKey,
+    #[serde(with = "base64_bytes")]
+    pub nonce: Vec<u8>,
+    /// AES-256-GCM ciphertext with the tag appended
+    #[serde(with = "base64_bytes")]
+    pub ciphertext: Vec<u8>,
+}
+
+/// How a reader gets the key for a `SealedPayload`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SealedKey {
+    /// Encrypted directly with the shared tenant key
+    TenantKey { key_id: String },
+    /// Encrypted with a one-off content key, wrapped for each recipient's registered public key
+    Recipients {
+        #[serde(with = "base64_bytes")]
+        ephemeral_public_key: Vec<u8>,
+        recipients: Vec<WrappedKey>,
+    },
+}
+
+/// Content key wrapped for one branch
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WrappedKey {
+    pub branch_id: BranchId,
+    #[serde(with = "base64_bytes")]
+    pub wrapped_key: Vec<u8>,
+}
+
+/// Seals and opens payloads under one key scheme
+pub trait PayloadCipher {
+    fn seal(&self, plaintext: &[u8], aad: &[u8]) -> Result<SealedPayload>;
+    fn open(&self, sealed: &SealedPayload, aad: &[u8]) -> Result<Vec<u8>>;
+}
+
+/// Symmetric key shared by every branch of a tenant
+pub struct TenantKey {
+    key_id: String,
+    cipher: Aes256Gcm,
+}
+
+impl TenantKey {
+    /// Key from its base64 form (32 bytes)
+    pub fn from_base64(encoded: &str) -> Result<Self> {
+        let key = decode_key(encoded, "tenant key")?;
+        Ok(Self {
+            key_id: hex_prefix(&Sha256::digest(key), 8),
+            cipher: Aes256Gcm::new(&key.into()),
+        })
+    }
+
+    /// Fresh random key in base64, for provisioning
+    pub fn generate_base64() -> String {
+        STANDARD.encode(Aes256Gcm::generate_key(&mut OsRng))
+    }
+
+    /// Identifies the key in sealed payloads without revealing it
+    pub fn key_id(&self) -> &str {
+        &self.key_id
+    }
+}
+
+impl std::fmt::Debug for TenantKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TenantKey").field("key_id", &self.key_id).finish_non_exhaustive()
+    }
+}
+
+impl PayloadCipher for TenantKey {
+    fn seal(&self, plaintext: &[u8], aad: &[u8]) -> Result<SealedPayload> {
+        let (nonce, ciphertext) = encrypt(&self.cipher, plaintext, aad)?;
+        Ok(SealedPayload {
+            key: SealedKey::TenantKey { key_id: self.key_id.clone() },
+            nonce,
+            ciphertext,
+        })
+    }
+
+    fn open(&self, sealed: &SealedPayload, aad: &[u8]) -> Result<Vec<u8>> {
+        match &sealed.key {
+            SealedKey::TenantKey { key_id } if *key_id == self.key_id => {
+                decrypt(&self.cipher, &sealed.nonce, &sealed.ciphertext, aad)
+            }
+            SealedKey::TenantKey { key_id } => Err(Error::Encryption(format!(
+                "Sealed with tenant key {}, have {}",
+                key_id, self.key_id
+            ))),
+            SealedKey::Recipients { .. } => {
+                Err(Error::Encryption("Sealed for recipients, not with the tenant key".to_string()))
+            }
+        }
+    }
+}
+
+/// A branch's X25519 key pair; the public half is registered with the hub
+pub struct BranchKeyPair {
+    secret: StaticSecret,
+    public: PublicKey,
+}
+
+impl BranchKeyPair {
+    pub fn generate() -> Self {
+        Self::from_secret(StaticSecret::random_from_rng(OsRng))
+    }
+
+    /// Key pair from the base64 secret written by `secret_base64`
+    pub fn from_base64(encoded: &str) -> Result<Self> {
+        Ok(Self::from_secret(StaticSecret::from(decode_key(encoded, "private key")?)))
+    }
+
+    fn from_secret(secret: StaticSecret) -> Self {
+        let public = PublicKey::from(&secret);
+        Self { secret, public }
+    }
+
+    /// Secret half in base64, for persisting the key pair
+    pub fn secret_base64(&self) -> String {
+        STANDARD.encode(self.secret.as_bytes())
+    }
+
+    /// Public half in base64, as registered with the hub
+    pub fn public_key_base64(&self) -> String {
+        STANDARD.encode(self.public.as_bytes())
+    }
+
+    pub fn public_key(&self) -> &PublicKey {
+        &self.public
+    }
+}
+
+impl std::fmt::Debug for BranchKeyPair {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BranchKeyPair")
+            .field("public", &self.public_key_base64())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Per-branch key scheme as seen by one branch: its own key pair plus the
+/// public keys of the branches it writes to
+pub struct RecipientKeys<'a> {
+    pub branch_id: &'a BranchId,
+    pub keypair: &'a BranchKeyPair,
+    pub recipients: Vec<(BranchId, PublicKey)>,
+}
+
+impl PayloadCipher for RecipientKeys<'_> {
+    fn seal(&self, plaintext: &[u8], aad: &[u8]) -> Result<SealedPayload> {
+        if self.recipients.is_empty() {
+            return Err(Error::Encryption("No recipient public keys".to_string()));
+        }
+
+        let content_key = Aes256Gcm::generate_key(&mut OsRng);
+        let ephemeral = StaticSecret::random_from_rng(OsRng);
+        let ephemeral_public = PublicKey::from(&ephemeral);
+
+        let recipients = self
+            .recipients
+            .iter()
+            .map(|(branch_id, public)| {
+                let kek = key_encryption_key(ephemeral.diffie_hellman(public), &ephemeral_public, public)?;
+                // Each key-encryption key wraps exactly one content key, so a fixed nonce is safe
+                let wrapped_key = kek
+                    .encrypt(&Nonce::default(), Payload { msg: &content_key, aad: branch_id.as_str().as_bytes() })
+                    .map_err(|_| Error::Encryption("Key wrap failed".to_string()))?;
+                Ok(WrappedKey { branch_id: branch_id.clone(), wrapped_key })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let (nonce, ciphertext) = encrypt(&Aes256Gcm::new(&content_key), plaintext, aad)?;
+        Ok(SealedPayload {
+            key: SealedKey::Recipients {
+                ephemeral_public_key: ephemeral_public.as_bytes().to_vec(),
+                recipients,
+            },
+            nonce,
+            ciphertext,
+        })
+    }
+
+    fn open(&self, sealed: &SealedPayload, aad: &[u8]) -> Result<Vec<u8>> {
+        let SealedKey::Recipients { ephemeral_public_key, recipients } = &sealed.key else {
+            return Err(Error::Encryption("Sealed with a tenant key, not for recipients".to_string()));
+        };
+        let wrapped = recipients
+            .iter()
+            .find(|wrapped| wrapped.branch_id == *self.branch_id)
+            .ok_or_else(|| Error::Encryption(format!("Payload not addressed to {}", self.branch_id)))?;
+
+        let ephemeral_public = parse_public_key_bytes(ephemeral_public_key)?;
+        let kek = key_encryption_key(
+            self.keypair.secret.diffie_hellman(&ephemeral_public),
+            &ephemeral_public,
+            &self.keypair.public,
+        )?;
+        let content_key = kek
+            .decrypt(
+                &Nonce::default(),
+                Payload { msg: &wrapped.wrapped_key, aad: self.branch_id.as_str().as_bytes() },
+            )
+            .map_err(|_| Error::Encryption("Key unwrap failed".to_string()))?;
+        let cipher = Aes256Gcm::new_from_slice(&content_key)
+            .map_err(|_| Error::Encryption("Bad content key length".to_string()))?;
+
+        decrypt(&cipher, &sealed.nonce, &sealed.ciphertext, aad)
+    }
+}
+
+/// AES key wrapping the content key for one recipient, from the X25519 secret shared
+/// between the ephemeral key and the recipient's key
+fn key_encryption_key(shared: SharedSecret, ephemeral: &PublicKey, recipient: &PublicKey) -> Result<Aes256Gcm> {
+    if !shared.was_contributory() {
+        return Err(Error::Encryption("Low-order public key".to_string()));
+    }
+
+    let salt = [ephemeral.as_bytes().as_slice(), recipient.as_bytes()].concat();
+    let mut kek = [0u8; KEY_LEN];
+    Hkdf::<Sha256>::new(Some(&salt), shared.as_bytes())
+        .expand(KEK_INFO, &mut kek)
+        .map_err(|_| Error::Encryption("Key derivation failed".to_string()))?;
+    Ok(Aes256Gcm::new(&kek.into()))
+}
+
+fn encrypt(cipher: &Aes256Gcm, plaintext: &[u8], aad: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, Payload { msg: plaintext, aad })
+        .map_err(|_| Error::Encryption("Encryption failed".to_string()))?;
+    Ok((nonce.to_vec(), ciphertext))
+}
+
+fn decrypt(cipher: &Aes256Gcm, nonce: &[u8], ciphertext: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    if nonce.len() != 12 {
+        return Err(Error::Encryption("Bad nonce length".to_string()));
+    }
+    // Wrong key, tampered ciphertext and mismatched metadata all look the same here
+    cipher
+        .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad })
+        .map_err(|_| Error::Encryption("Decryption failed".to_string()))
+}
+
+fn decode_key(encoded: &str, what: &str) -> Result<[u8; KEY_LEN]> {
+    STANDARD
+        .decode(encoded.trim())
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| Error::Encryption(format!("Invalid {}: expected {} bytes of base64", what, KEY_LEN)))
+}
+
+fn parse_public_key_bytes(bytes: &[u8]) -> Result<PublicKey> {
+    let bytes: [u8; KEY_LEN] = bytes
+        .try_into()
+        .map_err(|_| Error::Encryption("Invalid public key length".to_string()))?;
+    Ok(PublicKey::from(bytes))
+}
+
+/// Parse a base64 X25519 public key as registered with the hub
+pub fn parse_public_key(encoded: &str) -> Result<PublicKey> {
+    parse_public_key_bytes(&decode_key(encoded, "public key")?)
+}
+
+/// Short digest of a public key for comparing it out of band
+pub fn fingerprint(public_key: &PublicKey) -> String {
+    hex_prefix(&Sha256::digest(public_key.as_bytes()), 16)
+}
+
+fn hex_prefix(bytes: &[u8], len: usize) -> String {
+    bytes.iter().take(len).map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Metadata a sealed row is bound to
+fn change_aad(tenant_id: &TenantId, change: &DatabaseChange) -> Vec<u8> {
+    serde_json::json!([tenant_id, change.table_name, change.operation, change.primary_key])
+        .to_string()
+        .into_bytes()
+}
+
+/// Metadata a sealed route payload is bound to
+fn route_aad(tenant_id: &TenantId, from: &BranchId, route: &RouteMessage) -> Vec<u8> {
+    serde_json::json!([tenant_id, from, route.target_branch]).to_string().into_bytes()
+}
+
+/// Move a change's row data into a sealed payload, leaving `null` in the clear
+pub fn seal_change(cipher: &dyn PayloadCipher, tenant_id: &TenantId, change: &mut DatabaseChange) -> Result<()> {
+    let plaintext = serde_json::to_vec(&change.data)?;
+    change.sealed = Some(Box::new(cipher.seal(&plaintext, &change_aad(tenant_id, change))?));
+    change.data = serde_json::Value::Null;
+    Ok(())
+}
+
+/// Restore a sealed change's row data; unsealed changes are left as they are
+pub fn open_change(cipher: &dyn PayloadCipher, tenant_id: &TenantId, change: &mut DatabaseChange) -> Result<()> {
+    let Some(sealed) = &change.sealed else {
+        return Ok(());
+    };
+    let plaintext = cipher.open(sealed, &change_aad(tenant_id, change))?;
+    change.data = serde_json::from_slice(&plaintext)?;
+    change.sealed = None;
+    Ok(())
+}
+
+/// Replace a route payload with its sealed form
+pub fn seal_route(cipher: &dyn PayloadCipher, tenant_id: &TenantId, from: &BranchId, route: &mut RouteMessage) -> Result<()> {
+    route.sealed = Some(Box::new(cipher.seal(&route.payload, &route_aad(tenant_id, from, route))?));
+    route.payload.clear();
+    Ok(())
+}
+
+/// Restore a sealed route payload sent by `from`; unsealed payloads are left as they are
+pub fn open_route(cipher: &dyn PayloadCipher, tenant_id: &TenantId, from: &BranchId, route: &mut RouteMessage) -> Result<()> {
+    let Some(sealed) = &route.sealed else {
+        return Ok(());
+    };
+    route.payload = cipher.open(sealed, &route_aad(tenant_id, from, route))?;
+    route.sealed = None;
+    Ok(())
+}
+
+/// Whether a message carries branch data in the clear
+pub fn carries_plaintext(payload: &MessagePayload) -> bool {
+    match payload {
+        MessagePayload::SyncBatch(batch) => batch.changes.iter().any(|change| change.sealed.is_none()),
+        MessagePayload::RouteMessage(route) => route.sealed.is_none(),
+        _ => false,
+    }
+}
+
+/// Whether a message carries sealed data only end-to-end capable branches can open
+pub fn carries_sealed(payload: &MessagePayload) -> bool {
+    match payload {
+        MessagePayload::SyncBatch(batch) => batch.changes.iter().any(|change| change.sealed.is_some()),
+        MessagePayload::RouteMessage(route) => route.sealed.is_some(),
+        MessagePayload::ConflictDetected(conflict) => {
+            conflict.local_change.sealed.is_some() || conflict.remote_change.sealed.is_some()
+        }
+        MessagePayload::ConflictResolved(resolution) => resolution.winning_change.sealed.is_some(),
+        _ => false,
+    }
+}
+
+/// Byte fields as base64 strings instead of JSON number arrays
+/// Base64 text in human-readable formats, raw bytes in binary ones
+pub(crate) mod base64_bytes {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use serde::de::{self, SeqAccess, Visitor};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&STANDARD.encode(bytes))
+        } else {
+            serializer.serialize_bytes(bytes)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        if deserializer.is_human_readable() {
+            let encoded = String::deserialize(deserializer)?;
+            STANDARD.decode(encoded).map_err(de::Error::custom)
+        } else {
+            deserializer.deserialize_byte_buf(BytesVisitor)
+        }
+    }
+
+    struct BytesVisitor;
+
+    impl<'de> Visitor<'de> for BytesVisitor {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.write_str("bytes")
+        }
+
+        fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Vec<u8>, E> {
+            Ok(bytes.to_vec())
+        }
+
+        fn visit_byte_buf<E: de::Error>(self, bytes: Vec<u8>) -> Result<Vec<u8>, E> {
+            Ok(bytes)
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<u8>, A::Error> {
+            let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+            while let Some(byte) = seq.next_element()? {
+                bytes.push(byte);
+            }
+            Ok(bytes)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Operation, SyncBatch};
+    use common::VectorClock;
+
+    fn change() -> DatabaseChange {
+        DatabaseChange {
+            table_name: "orders".to_string(),
+            operation: Operation::Insert,
+            primary_key: serde_json::json!({ "id": 42 }),
+            data: serde_json::json!({ "id": 42, "customer": "Ada", "total": 19.5 }),
+            timestamp: chrono::Utc::now(),
+            schema_version: 1,
+            sealed: None,
+        }
+    }
+
+    fn tenant() -> TenantId {
+        TenantId::new("tenant_a")
+    }
+
+    #[test]
+    fn test_tenant_key_round_trip_hides_row_data() {
+        let key = TenantKey::from_base64(&TenantKey::generate_base64()).unwrap();
+        let original = change();
+        let mut sealed = original.clone();
+
+        seal_change(&key, &tenant(), &mut sealed).unwrap();
+        assert_eq!(sealed.data, serde_json::Value::Null);
+        let wire = serde_json::to_string(&sealed).unwrap();
+        assert!(!wire.contains("Ada"));
+        assert!(wire.contains(key.key_id()));
+
+        let mut received: DatabaseChange = serde_json::from_str(&wire).unwrap();
+        open_change(&key, &tenant(), &mut received).unwrap();
+        assert_eq!(received.data, original.data);
+        assert!(received.sealed.is_none());
+    }
+
+    #[test]
+    fn test_sealed_row_is_bound_to_its_metadata() {
+        let key = TenantKey::from_base64(&TenantKey::generate_base64()).unwrap();
+        let mut sealed = change();
+        seal_change(&key, &tenant(), &mut sealed).unwrap();
+
+        let mut moved = sealed.clone();
+        moved.primary_key = serde_json::json!({ "id": 43 });
+        assert!(open_change(&key, &tenant(), &mut moved).is_err());
+
+        let mut other_tenant = sealed.clone();
+        assert!(open_change(&key, &TenantId::new("tenant_b"), &mut other_tenant).is_err());
+
+        let other_key = TenantKey::from_base64(&TenantKey::generate_base64()).unwrap();
+        assert!(open_change(&other_key, &tenant(), &mut sealed).is_err());
+    }
+
+    #[test]
+    fn test_recipient_keys_only_open_for_recipients() {
+        let (main, store_2, store_3) = (BranchId::new("main"), BranchId::new("store_2"), BranchId::new("store_3"));
+        let (main_keys, store_2_keys, store_3_keys) =
+            (BranchKeyPair::generate(), BranchKeyPair::generate(), BranchKeyPair::generate());
+
+        let sender = RecipientKeys {
+            branch_id: &main,
+            keypair: &main_keys,
+            recipients: vec![(store_2.clone(), *store_2_keys.public_key())],
+        };
+        let mut route = RouteMessage { target_branch: store_2.clone(), payload: b"price list".to_vec(), sealed: None };
+        seal_route(&sender, &tenant(), &main, &mut route).unwrap();
+        assert!(route.payload.is_empty());
+
+        let reader = |branch_id, keypair| RecipientKeys { branch_id, keypair, recipients: vec![] };
+
+        let mut intercepted = route.clone();
+        assert!(open_route(&reader(&store_3, &store_3_keys), &tenant(), &main, &mut intercepted).is_err());
+        // Claiming the recipient's branch ID does not help without its private key
+        assert!(open_route(&reader(&store_2, &store_3_keys), &tenant(), &main, &mut intercepted).is_err());
+        // Nor does pretending the message came from someone else
+        let mut forged = route.clone();
+        assert!(open_route(&reader(&store_2, &store_2_keys), &tenant(), &store_3, &mut forged).is_err());
+
+        open_route(&reader(&store_2, &store_2_keys), &tenant(), &main, &mut route).unwrap();
+        assert_eq!(route.payload, b"price list");
+    }
+
+    #[test]
+    fn test_key_pair_and_public_key_encoding() {
+        let keypair = BranchKeyPair::generate();
+        let restored = BranchKeyPair::from_base64(&keypair.secret_base64()).unwrap();
+        assert_eq!(restored.public_key_base64(), keypair.public_key_base64());
+
+        let public = parse_public_key(&keypair.public_key_base64()).unwrap();
+        assert_eq!(fingerprint(&public), fingerprint(keypair.public_key()));
+        assert_eq!(fingerprint(&public).len(), 32);
+
+        assert!(parse_public_key("c2hvcnQ=").is_err());
+        assert!(TenantKey::from_base64("not base64!").is_err());
+    }
+
+    #[test]
+    fn test_carries_plaintext() {
+        let key = TenantKey::from_base64(&TenantKey::generate_base64()).unwrap();
+        let mut sealed = change();
+        seal_change(&key, &tenant(), &mut sealed).unwrap();
+        let batch = |changes| {
+            MessagePayload::SyncBatch(SyncBatch {
+                transaction_id: "tx".to_string(),
+                vector_clock: VectorClock::new(),
+                changes,
+                is_final: true,
+            })
+        };
+
+        assert!(!carries_plaintext(&batch(vec![sealed.clone()])));
+        assert!(carries_plaintext(&batch(vec![sealed, change()])));
+        assert!(!carries_plaintext(&MessagePayload::Heartbeat));
+    }
+}
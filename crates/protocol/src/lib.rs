@@ -1,5 +1,12 @@
 pub mod messages;
+pub mod chunking;
 pub mod codec;
+pub mod compression;
+pub mod delivery;
+pub mod e2e;
+pub mod proto;
+pub mod signing;
+pub mod version;
 
 pub use messages::*;
 pub use codec::*;
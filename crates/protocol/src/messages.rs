@@ -1,3 +1,4 @@
+use crate::e2e::SealedPayload;
 use common::{BranchId, TenantId, VectorClock};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
@@ -11,6 +12,9 @@ pub struct Message {
     pub from: BranchId,
     pub to: Option<BranchId>,
     pub payload: MessagePayload,
+    /// HMAC over the rest of the message, see `signing`
+    #[serde(default)]
+    pub signature: Option<String>,
 }
 
 impl Message {
@@ -21,46 +25,162 @@ impl Message {
             from,
             to,
             payload,
+            signature: None,
         }
     }
 }
 
-/// All possible message types
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(tag = "type", content = "data")]
-pub enum MessagePayload {
-    // Connection lifecycle
-    Connect(ConnectRequest),
-    ConnectAck(ConnectAck),
-    Disconnect(DisconnectReason),
-    Heartbeat,
-    HeartbeatAck,
-
-    // Sync operations
-    SyncRequest(SyncRequest),
-    SyncBatch(SyncBatch),
-    SyncAck(SyncAck),
-    SyncComplete(SyncComplete),
-
-    // Conflict resolution
-    ConflictDetected(ConflictNotification),
-    ConflictResolved(ConflictResolution),
-
-    // Schema management
-    SchemaVersion(SchemaVersionInfo),
-    SchemaUpdate(SchemaUpdate),
-
-    // Routing
-    RouteMessage(RouteMessage),
-    MessageDelivered(MessageDelivered),
-    MessageFailed(MessageFailed),
-
-    // Admin/Control
-    BranchStatus(BranchStatusUpdate),
-    SystemNotification(SystemNotification),
-
-    // Error handling
-    Error(ErrorPayload),
+/// Serialize-side pattern or constructor for one `MessagePayload` variant
+macro_rules! payload_variant {
+    ($($path:ident)::+, $value:ident) => {
+        $($path)::+
+    };
+    ($($path:ident)::+, $value:ident, $inner:ty) => {
+        $($path)::+($value)
+    };
+}
+
+/// Defines `MessagePayload` together with its wire forms. Human-readable formats
+/// see it adjacently tagged (`{"type": ..., "data": ...}`); binary formats cannot
+/// read variants by name, so they get it tagged by variant index instead.
+macro_rules! message_payload {
+    (
+        $(#[$meta:meta])*
+        pub enum MessagePayload {
+            $($variant:ident $(($inner:ty))?),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone)]
+        pub enum MessagePayload {
+            $($variant $(($inner))?),*
+        }
+
+        mod payload_repr {
+            use super::*;
+            use serde::{Deserialize, Serialize};
+
+            #[derive(Serialize)]
+            #[serde(tag = "type", content = "data")]
+            pub enum TaggedRef<'a> {
+                $($variant $((&'a $inner))?),*
+            }
+
+            #[derive(Deserialize)]
+            #[serde(tag = "type", content = "data")]
+            pub enum Tagged {
+                $($variant $(($inner))?),*
+            }
+
+            #[derive(Serialize)]
+            pub enum IndexedRef<'a> {
+                $($variant $((&'a $inner))?),*
+            }
+
+            #[derive(Deserialize)]
+            pub enum Indexed {
+                $($variant $(($inner))?),*
+            }
+        }
+
+        impl Serialize for MessagePayload {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+                if serializer.is_human_readable() {
+                    match self {
+                        $(payload_variant!(MessagePayload::$variant, value $(, $inner)?) => {
+                            payload_variant!(payload_repr::TaggedRef::$variant, value $(, $inner)?).serialize(serializer)
+                        })*
+                    }
+                } else {
+                    match self {
+                        $(payload_variant!(MessagePayload::$variant, value $(, $inner)?) => {
+                            payload_variant!(payload_repr::IndexedRef::$variant, value $(, $inner)?).serialize(serializer)
+                        })*
+                    }
+                }
+            }
+        }
+
+        impl<'de> Deserialize<'de> for MessagePayload {
+            fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+                if deserializer.is_human_readable() {
+                    Ok(match payload_repr::Tagged::deserialize(deserializer)? {
+                        $(payload_variant!(payload_repr::Tagged::$variant, value $(, $inner)?) => {
+                            payload_variant!(MessagePayload::$variant, value $(, $inner)?)
+                        })*
+                    })
+                } else {
+                    Ok(match payload_repr::Indexed::deserialize(deserializer)? {
+                        $(payload_variant!(payload_repr::Indexed::$variant, value $(, $inner)?) => {
+                            payload_variant!(MessagePayload::$variant, value $(, $inner)?)
+                        })*
+                    })
+                }
+            }
+        }
+
+        impl MessagePayload {
+            /// Variant name, as in the `type` tag of human-readable formats
+            pub fn type_name(&self) -> &'static str {
+                match self {
+                    $(payload_variant!(MessagePayload::$variant, _value $(, $inner)?) => stringify!($variant)),*
+                }
+            }
+        }
+    };
+}
+
+message_payload! {
+    /// All possible message types
+    ///
+    /// Binary codecs encode the variant by position: append new variants at the end.
+    pub enum MessagePayload {
+        // Connection lifecycle
+        Connect(ConnectRequest),
+        ConnectAck(ConnectAck),
+        Disconnect(DisconnectReason),
+        Heartbeat,
+        HeartbeatAck,
+
+        // Sync operations
+        SyncRequest(SyncRequest),
+        SyncBatch(SyncBatch),
+        SyncAck(SyncAck),
+        SyncComplete(SyncComplete),
+
+        // Conflict resolution
+        ConflictDetected(ConflictNotification),
+        ConflictResolved(ConflictResolution),
+
+        // Schema management
+        SchemaVersion(SchemaVersionInfo),
+        SchemaUpdate(SchemaUpdate),
+
+        // Routing
+        RouteMessage(RouteMessage),
+        MessageDelivered(MessageDelivered),
+        MessageFailed(MessageFailed),
+
+        // Admin/Control
+        BranchStatus(BranchStatusUpdate),
+        SystemNotification(SystemNotification),
+
+        // Error handling
+        Error(ErrorPayload),
+
+        // In-session credential renewal
+        TokenRenew(TokenRenewRequest),
+        TokenRenewed(TokenRenewal),
+
+        // End-to-end encryption keys
+        PublicKeyRegister(PublicKeyRegistration),
+        PublicKeyRequest(PublicKeyQuery),
+        PublicKeys(PublicKeyList),
+
+        // Chunked transfer of oversized messages
+        Chunk(MessageChunk),
+        ChunkResume(ChunkResume),
+    }
 }
 
 /// Connect request from client
@@ -68,7 +188,9 @@ pub enum MessagePayload {
 pub struct ConnectRequest {
     pub tenant_id: TenantId,
     pub branch_id: BranchId,
-    pub api_key: String,
+    /// Not needed when the upgrade request carried a bearer JWT
+    #[serde(default)]
+    pub api_key: Option<String>,
     pub version: String,
     pub capabilities: Vec<String>,
     pub metadata: HashMap<String, String>,
@@ -88,6 +210,32 @@ pub struct DisconnectReason {
     pub reason: String,
 }
 
+impl DisconnectReason {
+    /// Credentials in the Connect request were rejected
+    pub const AUTH_FAILED: u16 = 4001;
+    /// Protocol violation, e.g. first message was not Connect
+    pub const PROTOCOL_ERROR: u16 = 4002;
+    /// Access token expired and was not renewed in-session
+    pub const TOKEN_EXPIRED: u16 = 4003;
+    /// The API key the session authenticated with was revoked
+    pub const CREDENTIALS_REVOKED: u16 = 4004;
+    /// Tenant was suspended or deactivated, or its trial ended
+    pub const TENANT_INACTIVE: u16 = 4005;
+    /// Branch speaks a protocol revision the hub no longer serves; the reason says what to upgrade to
+    pub const UNSUPPORTED_VERSION: u16 = 4006;
+    /// Hub-wide or per-branch session limit reached
+    pub const CONNECTION_LIMIT: u16 = 4008;
+    /// Unexpected server-side failure
+    pub const INTERNAL_ERROR: u16 = 4011;
+
+    pub fn new(code: u16, reason: impl Into<String>) -> Self {
+        Self {
+            code,
+            reason: reason.into(),
+        }
+    }
+}
+
 /// Sync request to get changes
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SyncRequest {
@@ -110,10 +258,15 @@ pub struct SyncBatch {
 pub struct DatabaseChange {
     pub table_name: String,
     pub operation: Operation,
+    #[serde(with = "json_value")]
     pub primary_key: serde_json::Value,
+    #[serde(with = "json_value")]
     pub data: serde_json::Value,
     pub timestamp: DateTime<Utc>,
     pub schema_version: u32,
+    /// Row data sealed end to end; `data` is then `null`
+    #[serde(default)]
+    pub sealed: Option<Box<SealedPayload>>,
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
@@ -149,6 +302,7 @@ pub struct SyncComplete {
 pub struct ConflictNotification {
     pub conflict_id: String,
     pub table_name: String,
+    #[serde(with = "json_value")]
     pub primary_key: serde_json::Value,
     pub local_change: DatabaseChange,
     pub remote_change: DatabaseChange,
@@ -214,6 +368,9 @@ pub struct SchemaUpdate {
 pub struct RouteMessage {
     pub target_branch: BranchId,
     pub payload: Vec<u8>,
+    /// Payload sealed end to end; `payload` is then empty
+    #[serde(default)]
+    pub sealed: Option<Box<SealedPayload>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -256,5 +413,116 @@ pub enum NotificationLevel {
 pub struct ErrorPayload {
     pub code: String,
     pub message: String,
+    #[serde(default, with = "json_value::option")]
     pub details: Option<serde_json::Value>,
 }
+
+/// Exchange a refresh token for a new access token without reconnecting
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenRenewRequest {
+    pub refresh_token: String,
+}
+
+/// Fresh credentials; the presented refresh token is no longer valid
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenRenewal {
+    pub access_token: String,
+    pub expires_at: i64,
+    pub refresh_token: String,
+    pub refresh_expires_at: i64,
+}
+
+/// Publish the sender's X25519 public key (base64) for end-to-end encryption
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PublicKeyRegistration {
+    pub public_key: String,
+}
+
+/// Ask for registered public keys; an empty list asks for every branch of the tenant
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PublicKeyQuery {
+    pub branch_ids: Vec<BranchId>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PublicKeyList {
+    pub keys: Vec<BranchPublicKey>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BranchPublicKey {
+    pub branch_id: BranchId,
+    pub public_key: String,
+    /// Compare out of band before trusting a key; the hub could substitute its own
+    pub fingerprint: String,
+    pub registered_at: DateTime<Utc>,
+}
+
+/// One piece of a message too large for a single frame, see `chunking`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MessageChunk {
+    /// ID of the message being transferred
+    pub message_id: String,
+    /// Position of this chunk, from 0
+    pub index: u32,
+    pub total: u32,
+    /// Size of the whole encoded message
+    pub total_size: u64,
+    /// SHA-256 of the whole encoded message (`utils::calculate_hash`)
+    pub checksum: String,
+    #[serde(with = "crate::e2e::base64_bytes")]
+    pub data: Vec<u8>,
+}
+
+/// Sent by a receiver after reconnecting: continue the transfer from `next_index`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkResume {
+    pub message_id: String,
+    pub next_index: u32,
+}
+
+/// `serde_json::Value` as itself in human-readable formats and as JSON text in binary
+/// ones, which cannot deserialize a self-describing value
+mod json_value {
+    use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+    use serde_json::Value;
+
+    pub fn serialize<S: Serializer>(value: &Value, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            value.serialize(serializer)
+        } else {
+            serializer.serialize_str(&value.to_string())
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Value, D::Error> {
+        if deserializer.is_human_readable() {
+            Value::deserialize(deserializer)
+        } else {
+            let text = String::deserialize(deserializer)?;
+            serde_json::from_str(&text).map_err(D::Error::custom)
+        }
+    }
+
+    pub mod option {
+        use super::*;
+
+        pub fn serialize<S: Serializer>(value: &Option<Value>, serializer: S) -> Result<S::Ok, S::Error> {
+            if serializer.is_human_readable() {
+                value.serialize(serializer)
+            } else {
+                value.as_ref().map(Value::to_string).serialize(serializer)
+            }
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Value>, D::Error> {
+            if deserializer.is_human_readable() {
+                Option::<Value>::deserialize(deserializer)
+            } else {
+                Option::<String>::deserialize(deserializer)?
+                    .map(|text| serde_json::from_str(&text).map_err(D::Error::custom))
+                    .transpose()
+            }
+        }
+    }
+}
@@ -0,0 +1,1171 @@
+//! Protobuf wire types, matching `proto/hub_broker.proto` field for field.
+//!
+//! Written by hand in the shape prost-build would generate, so the build needs no
+//! `protoc`. Any change here must be made to the `.proto` file too (and vice versa);
+//! field numbers are the compatibility contract with non-Rust clients.
+
+use chrono::{DateTime, Utc};
+use common::{BranchId, Error, Result, TenantId, VectorClock as DomainVectorClock};
+use prost_types::Timestamp;
+use std::collections::HashMap;
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Message {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    #[prost(message, optional, tag = "2")]
+    pub timestamp: Option<Timestamp>,
+    #[prost(string, tag = "3")]
+    pub from: String,
+    #[prost(string, optional, tag = "4")]
+    pub to: Option<String>,
+    #[prost(string, optional, tag = "5")]
+    pub signature: Option<String>,
+    #[prost(
+        oneof = "message::Payload",
+        tags = "10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32, 33, 34, 35"
+    )]
+    pub payload: Option<message::Payload>,
+}
+
+pub mod message {
+    use super::*;
+
+    #[allow(clippy::large_enum_variant)]
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub enum Payload {
+        #[prost(message, tag = "10")]
+        Connect(ConnectRequest),
+        #[prost(message, tag = "11")]
+        ConnectAck(ConnectAck),
+        #[prost(message, tag = "12")]
+        Disconnect(DisconnectReason),
+        #[prost(message, tag = "13")]
+        Heartbeat(Empty),
+        #[prost(message, tag = "14")]
+        HeartbeatAck(Empty),
+        #[prost(message, tag = "15")]
+        SyncRequest(SyncRequest),
+        #[prost(message, tag = "16")]
+        SyncBatch(SyncBatch),
+        #[prost(message, tag = "17")]
+        SyncAck(SyncAck),
+        #[prost(message, tag = "18")]
+        SyncComplete(SyncComplete),
+        #[prost(message, tag = "19")]
+        ConflictDetected(ConflictNotification),
+        #[prost(message, tag = "20")]
+        ConflictResolved(ConflictResolution),
+        #[prost(message, tag = "21")]
+        SchemaVersion(SchemaVersionInfo),
+        #[prost(message, tag = "22")]
+        SchemaUpdate(SchemaUpdate),
+        #[prost(message, tag = "23")]
+        RouteMessage(RouteMessage),
+        #[prost(message, tag = "24")]
+        MessageDelivered(MessageDelivered),
+        #[prost(message, tag = "25")]
+        MessageFailed(MessageFailed),
+        #[prost(message, tag = "26")]
+        BranchStatus(BranchStatusUpdate),
+        #[prost(message, tag = "27")]
+        SystemNotification(SystemNotification),
+        #[prost(message, tag = "28")]
+        Error(ErrorPayload),
+        #[prost(message, tag = "29")]
+        TokenRenew(TokenRenewRequest),
+        #[prost(message, tag = "30")]
+        TokenRenewed(TokenRenewal),
+        #[prost(message, tag = "31")]
+        PublicKeyRegister(PublicKeyRegistration),
+        #[prost(message, tag = "32")]
+        PublicKeyRequest(PublicKeyQuery),
+        #[prost(message, tag = "33")]
+        PublicKeys(PublicKeyList),
+        #[prost(message, tag = "34")]
+        Chunk(MessageChunk),
+        #[prost(message, tag = "35")]
+        ChunkResume(ChunkResume),
+    }
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Empty {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ConnectRequest {
+    #[prost(string, tag = "1")]
+    pub tenant_id: String,
+    #[prost(string, tag = "2")]
+    pub branch_id: String,
+    #[prost(string, optional, tag = "3")]
+    pub api_key: Option<String>,
+    #[prost(string, tag = "4")]
+    pub version: String,
+    #[prost(string, repeated, tag = "5")]
+    pub capabilities: Vec<String>,
+    #[prost(map = "string, string", tag = "6")]
+    pub metadata: HashMap<String, String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ConnectAck {
+    #[prost(string, tag = "1")]
+    pub session_id: String,
+    #[prost(string, tag = "2")]
+    pub server_version: String,
+    #[prost(uint64, tag = "3")]
+    pub heartbeat_interval_secs: u64,
+    #[prost(map = "string, string", tag = "4")]
+    pub assigned_config: HashMap<String, String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DisconnectReason {
+    #[prost(uint32, tag = "1")]
+    pub code: u32,
+    #[prost(string, tag = "2")]
+    pub reason: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct VectorClock {
+    #[prost(map = "string, uint64", tag = "1")]
+    pub clocks: HashMap<String, u64>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SyncRequest {
+    #[prost(string, tag = "1")]
+    pub transaction_id: String,
+    #[prost(message, optional, tag = "2")]
+    pub last_sync_timestamp: Option<Timestamp>,
+    #[prost(message, optional, tag = "3")]
+    pub vector_clock: Option<VectorClock>,
+    #[prost(string, repeated, tag = "4")]
+    pub tables: Vec<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SyncBatch {
+    #[prost(string, tag = "1")]
+    pub transaction_id: String,
+    #[prost(message, optional, tag = "2")]
+    pub vector_clock: Option<VectorClock>,
+    #[prost(message, repeated, tag = "3")]
+    pub changes: Vec<DatabaseChange>,
+    #[prost(bool, tag = "4")]
+    pub is_final: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum Operation {
+    Unspecified = 0,
+    Insert = 1,
+    Update = 2,
+    Delete = 3,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DatabaseChange {
+    #[prost(string, tag = "1")]
+    pub table_name: String,
+    #[prost(enumeration = "Operation", tag = "2")]
+    pub operation: i32,
+    #[prost(string, tag = "3")]
+    pub primary_key_json: String,
+    #[prost(string, tag = "4")]
+    pub data_json: String,
+    #[prost(message, optional, tag = "5")]
+    pub timestamp: Option<Timestamp>,
+    #[prost(uint32, tag = "6")]
+    pub schema_version: u32,
+    #[prost(message, optional, tag = "7")]
+    pub sealed: Option<SealedPayload>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SealedPayload {
+    #[prost(oneof = "sealed_payload::Key", tags = "1, 2")]
+    pub key: Option<sealed_payload::Key>,
+    #[prost(bytes = "vec", tag = "3")]
+    pub nonce: Vec<u8>,
+    #[prost(bytes = "vec", tag = "4")]
+    pub ciphertext: Vec<u8>,
+}
+
+pub mod sealed_payload {
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub enum Key {
+        #[prost(message, tag = "1")]
+        TenantKey(super::TenantKeyId),
+        #[prost(message, tag = "2")]
+        Recipients(super::Recipients),
+    }
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct TenantKeyId {
+    #[prost(string, tag = "1")]
+    pub key_id: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Recipients {
+    #[prost(bytes = "vec", tag = "1")]
+    pub ephemeral_public_key: Vec<u8>,
+    #[prost(message, repeated, tag = "2")]
+    pub recipients: Vec<WrappedKey>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct WrappedKey {
+    #[prost(string, tag = "1")]
+    pub branch_id: String,
+    #[prost(bytes = "vec", tag = "2")]
+    pub wrapped_key: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SyncAck {
+    #[prost(string, tag = "1")]
+    pub transaction_id: String,
+    #[prost(uint64, tag = "2")]
+    pub applied_changes: u64,
+    #[prost(message, repeated, tag = "3")]
+    pub failed_changes: Vec<FailedChange>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct FailedChange {
+    #[prost(uint64, tag = "1")]
+    pub index: u64,
+    #[prost(string, tag = "2")]
+    pub reason: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SyncComplete {
+    #[prost(string, tag = "1")]
+    pub transaction_id: String,
+    #[prost(uint64, tag = "2")]
+    pub total_changes: u64,
+    #[prost(uint64, tag = "3")]
+    pub duration_ms: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum ConflictStrategy {
+    Unspecified = 0,
+    LastWriteWins = 1,
+    FirstWriteWins = 2,
+    ManualResolution = 3,
+    MergeFields = 4,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ConflictNotification {
+    #[prost(string, tag = "1")]
+    pub conflict_id: String,
+    #[prost(string, tag = "2")]
+    pub table_name: String,
+    #[prost(string, tag = "3")]
+    pub primary_key_json: String,
+    #[prost(message, optional, tag = "4")]
+    pub local_change: Option<DatabaseChange>,
+    #[prost(message, optional, tag = "5")]
+    pub remote_change: Option<DatabaseChange>,
+    #[prost(enumeration = "ConflictStrategy", tag = "6")]
+    pub strategy: i32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum ConflictResolutionType {
+    Unspecified = 0,
+    LocalWins = 1,
+    RemoteWins = 2,
+    Merged = 3,
+    Manual = 4,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ConflictResolution {
+    #[prost(string, tag = "1")]
+    pub conflict_id: String,
+    #[prost(enumeration = "ConflictResolutionType", tag = "2")]
+    pub resolution: i32,
+    #[prost(message, optional, tag = "3")]
+    pub winning_change: Option<DatabaseChange>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SchemaVersionInfo {
+    #[prost(uint32, tag = "1")]
+    pub version: u32,
+    #[prost(string, tag = "2")]
+    pub checksum: String,
+    #[prost(message, repeated, tag = "3")]
+    pub tables: Vec<TableSchema>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct TableSchema {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(uint32, tag = "2")]
+    pub version: u32,
+    #[prost(message, repeated, tag = "3")]
+    pub columns: Vec<ColumnSchema>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ColumnSchema {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub data_type: String,
+    #[prost(bool, tag = "3")]
+    pub nullable: bool,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SchemaUpdate {
+    #[prost(uint32, tag = "1")]
+    pub old_version: u32,
+    #[prost(uint32, tag = "2")]
+    pub new_version: u32,
+    #[prost(string, tag = "3")]
+    pub migration_sql: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RouteMessage {
+    #[prost(string, tag = "1")]
+    pub target_branch: String,
+    #[prost(bytes = "vec", tag = "2")]
+    pub payload: Vec<u8>,
+    #[prost(message, optional, tag = "3")]
+    pub sealed: Option<SealedPayload>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct MessageDelivered {
+    #[prost(string, tag = "1")]
+    pub message_id: String,
+    #[prost(message, optional, tag = "2")]
+    pub delivered_at: Option<Timestamp>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct MessageFailed {
+    #[prost(string, tag = "1")]
+    pub message_id: String,
+    #[prost(string, tag = "2")]
+    pub reason: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum BranchStatus {
+    Unspecified = 0,
+    Online = 1,
+    Offline = 2,
+    Syncing = 3,
+    Error = 4,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct BranchStatusUpdate {
+    #[prost(enumeration = "BranchStatus", tag = "1")]
+    pub status: i32,
+    #[prost(string, optional, tag = "2")]
+    pub message: Option<String>,
+    #[prost(map = "string, string", tag = "3")]
+    pub metadata: HashMap<String, String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum NotificationLevel {
+    Unspecified = 0,
+    Info = 1,
+    Warning = 2,
+    Error = 3,
+    Critical = 4,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SystemNotification {
+    #[prost(enumeration = "NotificationLevel", tag = "1")]
+    pub level: i32,
+    #[prost(string, tag = "2")]
+    pub message: String,
+    #[prost(bool, tag = "3")]
+    pub action_required: bool,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ErrorPayload {
+    #[prost(string, tag = "1")]
+    pub code: String,
+    #[prost(string, tag = "2")]
+    pub message: String,
+    #[prost(string, optional, tag = "3")]
+    pub details_json: Option<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct TokenRenewRequest {
+    #[prost(string, tag = "1")]
+    pub refresh_token: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct TokenRenewal {
+    #[prost(string, tag = "1")]
+    pub access_token: String,
+    #[prost(int64, tag = "2")]
+    pub expires_at: i64,
+    #[prost(string, tag = "3")]
+    pub refresh_token: String,
+    #[prost(int64, tag = "4")]
+    pub refresh_expires_at: i64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PublicKeyRegistration {
+    #[prost(string, tag = "1")]
+    pub public_key: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PublicKeyQuery {
+    #[prost(string, repeated, tag = "1")]
+    pub branch_ids: Vec<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PublicKeyList {
+    #[prost(message, repeated, tag = "1")]
+    pub keys: Vec<BranchPublicKey>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct BranchPublicKey {
+    #[prost(string, tag = "1")]
+    pub branch_id: String,
+    #[prost(string, tag = "2")]
+    pub public_key: String,
+    #[prost(string, tag = "3")]
+    pub fingerprint: String,
+    #[prost(message, optional, tag = "4")]
+    pub registered_at: Option<Timestamp>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct MessageChunk {
+    #[prost(string, tag = "1")]
+    pub message_id: String,
+    #[prost(uint32, tag = "2")]
+    pub index: u32,
+    #[prost(uint32, tag = "3")]
+    pub total: u32,
+    #[prost(uint64, tag = "4")]
+    pub total_size: u64,
+    #[prost(string, tag = "5")]
+    pub checksum: String,
+    #[prost(bytes = "vec", tag = "6")]
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ChunkResume {
+    #[prost(string, tag = "1")]
+    pub message_id: String,
+    #[prost(uint32, tag = "2")]
+    pub next_index: u32,
+}
+
+// Conversions from the domain types (encoding) and back (decoding). Decoding is
+// fallible: proto3 has no required fields, so a peer can omit anything.
+
+fn invalid(field: &str, problem: impl std::fmt::Display) -> Error {
+    Error::InvalidMessage(format!("{}: {}", field, problem))
+}
+
+fn required<T>(value: Option<T>, field: &str) -> Result<T> {
+    value.ok_or_else(|| invalid(field, "missing"))
+}
+
+fn timestamp(at: &DateTime<Utc>) -> Timestamp {
+    Timestamp {
+        seconds: at.timestamp(),
+        nanos: at.timestamp_subsec_nanos() as i32,
+    }
+}
+
+fn from_timestamp(at: Option<Timestamp>, field: &str) -> Result<DateTime<Utc>> {
+    let at = required(at, field)?;
+    u32::try_from(at.nanos)
+        .ok()
+        .and_then(|nanos| DateTime::from_timestamp(at.seconds, nanos))
+        .ok_or_else(|| invalid(field, "timestamp out of range"))
+}
+
+fn json_text(value: &serde_json::Value) -> String {
+    value.to_string()
+}
+
+/// An absent (empty) JSON field reads as `null`, proto3's default
+fn from_json_text(text: &str, field: &str) -> Result<serde_json::Value> {
+    if text.is_empty() {
+        return Ok(serde_json::Value::Null);
+    }
+    serde_json::from_str(text).map_err(|e| invalid(field, e))
+}
+
+fn to_usize(value: u64, field: &str) -> Result<usize> {
+    usize::try_from(value).map_err(|e| invalid(field, e))
+}
+
+fn branch_ids(ids: &[BranchId]) -> Vec<String> {
+    ids.iter().map(|id| id.0.clone()).collect()
+}
+
+fn from_branch_ids(ids: Vec<String>) -> Vec<BranchId> {
+    ids.into_iter().map(BranchId::from).collect()
+}
+
+impl From<&DomainVectorClock> for VectorClock {
+    fn from(clock: &DomainVectorClock) -> Self {
+        Self {
+            clocks: clock.clocks.iter().map(|(id, tick)| (id.0.clone(), *tick)).collect(),
+        }
+    }
+}
+
+/// An absent clock is an empty one
+fn from_vector_clock(clock: Option<VectorClock>) -> DomainVectorClock {
+    DomainVectorClock {
+        clocks: clock
+            .map(|clock| clock.clocks.into_iter().map(|(id, tick)| (BranchId::from(id), tick)).collect())
+            .unwrap_or_default(),
+    }
+}
+
+impl From<crate::Operation> for Operation {
+    fn from(operation: crate::Operation) -> Self {
+        match operation {
+            crate::Operation::Insert => Operation::Insert,
+            crate::Operation::Update => Operation::Update,
+            crate::Operation::Delete => Operation::Delete,
+        }
+    }
+}
+
+fn operation(value: i32) -> Result<crate::Operation> {
+    match Operation::try_from(value).ok() {
+        Some(Operation::Insert) => Ok(crate::Operation::Insert),
+        Some(Operation::Update) => Ok(crate::Operation::Update),
+        Some(Operation::Delete) => Ok(crate::Operation::Delete),
+        _ => Err(invalid("operation", value)),
+    }
+}
+
+impl From<crate::ConflictStrategy> for ConflictStrategy {
+    fn from(strategy: crate::ConflictStrategy) -> Self {
+        match strategy {
+            crate::ConflictStrategy::LastWriteWins => ConflictStrategy::LastWriteWins,
+            crate::ConflictStrategy::FirstWriteWins => ConflictStrategy::FirstWriteWins,
+            crate::ConflictStrategy::ManualResolution => ConflictStrategy::ManualResolution,
+            crate::ConflictStrategy::MergeFields => ConflictStrategy::MergeFields,
+        }
+    }
+}
+
+fn conflict_strategy(value: i32) -> Result<crate::ConflictStrategy> {
+    match ConflictStrategy::try_from(value).ok() {
+        Some(ConflictStrategy::LastWriteWins) => Ok(crate::ConflictStrategy::LastWriteWins),
+        Some(ConflictStrategy::FirstWriteWins) => Ok(crate::ConflictStrategy::FirstWriteWins),
+        Some(ConflictStrategy::ManualResolution) => Ok(crate::ConflictStrategy::ManualResolution),
+        Some(ConflictStrategy::MergeFields) => Ok(crate::ConflictStrategy::MergeFields),
+        _ => Err(invalid("strategy", value)),
+    }
+}
+
+impl From<crate::ConflictResolutionType> for ConflictResolutionType {
+    fn from(resolution: crate::ConflictResolutionType) -> Self {
+        match resolution {
+            crate::ConflictResolutionType::LocalWins => ConflictResolutionType::LocalWins,
+            crate::ConflictResolutionType::RemoteWins => ConflictResolutionType::RemoteWins,
+            crate::ConflictResolutionType::Merged => ConflictResolutionType::Merged,
+            crate::ConflictResolutionType::Manual => ConflictResolutionType::Manual,
+        }
+    }
+}
+
+fn conflict_resolution_type(value: i32) -> Result<crate::ConflictResolutionType> {
+    match ConflictResolutionType::try_from(value).ok() {
+        Some(ConflictResolutionType::LocalWins) => Ok(crate::ConflictResolutionType::LocalWins),
+        Some(ConflictResolutionType::RemoteWins) => Ok(crate::ConflictResolutionType::RemoteWins),
+        Some(ConflictResolutionType::Merged) => Ok(crate::ConflictResolutionType::Merged),
+        Some(ConflictResolutionType::Manual) => Ok(crate::ConflictResolutionType::Manual),
+        _ => Err(invalid("resolution", value)),
+    }
+}
+
+impl From<common::BranchStatus> for BranchStatus {
+    fn from(status: common::BranchStatus) -> Self {
+        match status {
+            common::BranchStatus::Online => BranchStatus::Online,
+            common::BranchStatus::Offline => BranchStatus::Offline,
+            common::BranchStatus::Syncing => BranchStatus::Syncing,
+            common::BranchStatus::Error => BranchStatus::Error,
+        }
+    }
+}
+
+fn branch_status(value: i32) -> Result<common::BranchStatus> {
+    match BranchStatus::try_from(value).ok() {
+        Some(BranchStatus::Online) => Ok(common::BranchStatus::Online),
+        Some(BranchStatus::Offline) => Ok(common::BranchStatus::Offline),
+        Some(BranchStatus::Syncing) => Ok(common::BranchStatus::Syncing),
+        Some(BranchStatus::Error) => Ok(common::BranchStatus::Error),
+        _ => Err(invalid("status", value)),
+    }
+}
+
+impl From<crate::NotificationLevel> for NotificationLevel {
+    fn from(level: crate::NotificationLevel) -> Self {
+        match level {
+            crate::NotificationLevel::Info => NotificationLevel::Info,
+            crate::NotificationLevel::Warning => NotificationLevel::Warning,
+            crate::NotificationLevel::Error => NotificationLevel::Error,
+            crate::NotificationLevel::Critical => NotificationLevel::Critical,
+        }
+    }
+}
+
+fn notification_level(value: i32) -> Result<crate::NotificationLevel> {
+    match NotificationLevel::try_from(value).ok() {
+        Some(NotificationLevel::Info) => Ok(crate::NotificationLevel::Info),
+        Some(NotificationLevel::Warning) => Ok(crate::NotificationLevel::Warning),
+        Some(NotificationLevel::Error) => Ok(crate::NotificationLevel::Error),
+        Some(NotificationLevel::Critical) => Ok(crate::NotificationLevel::Critical),
+        _ => Err(invalid("level", value)),
+    }
+}
+
+impl From<&crate::e2e::SealedPayload> for SealedPayload {
+    fn from(sealed: &crate::e2e::SealedPayload) -> Self {
+        let key = match &sealed.key {
+            crate::e2e::SealedKey::TenantKey { key_id } => {
+                sealed_payload::Key::TenantKey(TenantKeyId { key_id: key_id.clone() })
+            }
+            crate::e2e::SealedKey::Recipients { ephemeral_public_key, recipients } => {
+                sealed_payload::Key::Recipients(Recipients {
+                    ephemeral_public_key: ephemeral_public_key.clone(),
+                    recipients: recipients
+                        .iter()
+                        .map(|wrapped| WrappedKey {
+                            branch_id: wrapped.branch_id.0.clone(),
+                            wrapped_key: wrapped.wrapped_key.clone(),
+                        })
+                        .collect(),
+                })
+            }
+        };
+        Self {
+            key: Some(key),
+            nonce: sealed.nonce.clone(),
+            ciphertext: sealed.ciphertext.clone(),
+        }
+    }
+}
+
+impl TryFrom<SealedPayload> for crate::e2e::SealedPayload {
+    type Error = Error;
+
+    fn try_from(sealed: SealedPayload) -> Result<Self> {
+        let key = match required(sealed.key, "sealed.key")? {
+            sealed_payload::Key::TenantKey(key) => crate::e2e::SealedKey::TenantKey { key_id: key.key_id },
+            sealed_payload::Key::Recipients(recipients) => crate::e2e::SealedKey::Recipients {
+                ephemeral_public_key: recipients.ephemeral_public_key,
+                recipients: recipients
+                    .recipients
+                    .into_iter()
+                    .map(|wrapped| crate::e2e::WrappedKey {
+                        branch_id: BranchId::from(wrapped.branch_id),
+                        wrapped_key: wrapped.wrapped_key,
+                    })
+                    .collect(),
+            },
+        };
+        Ok(Self {
+            key,
+            nonce: sealed.nonce,
+            ciphertext: sealed.ciphertext,
+        })
+    }
+}
+
+impl From<&crate::DatabaseChange> for DatabaseChange {
+    fn from(change: &crate::DatabaseChange) -> Self {
+        Self {
+            table_name: change.table_name.clone(),
+            operation: Operation::from(change.operation) as i32,
+            primary_key_json: json_text(&change.primary_key),
+            data_json: json_text(&change.data),
+            timestamp: Some(timestamp(&change.timestamp)),
+            schema_version: change.schema_version,
+            sealed: change.sealed.as_deref().map(SealedPayload::from),
+        }
+    }
+}
+
+impl TryFrom<DatabaseChange> for crate::DatabaseChange {
+    type Error = Error;
+
+    fn try_from(change: DatabaseChange) -> Result<Self> {
+        Ok(Self {
+            table_name: change.table_name,
+            operation: operation(change.operation)?,
+            primary_key: from_json_text(&change.primary_key_json, "primary_key_json")?,
+            data: from_json_text(&change.data_json, "data_json")?,
+            timestamp: from_timestamp(change.timestamp, "timestamp")?,
+            schema_version: change.schema_version,
+            sealed: change.sealed.map(TryInto::try_into).transpose()?.map(Box::new),
+        })
+    }
+}
+
+fn changes(changes: &[crate::DatabaseChange]) -> Vec<DatabaseChange> {
+    changes.iter().map(DatabaseChange::from).collect()
+}
+
+fn from_changes(changes: Vec<DatabaseChange>) -> Result<Vec<crate::DatabaseChange>> {
+    changes.into_iter().map(TryInto::try_into).collect()
+}
+
+fn from_change(change: Option<DatabaseChange>, field: &str) -> Result<crate::DatabaseChange> {
+    required(change, field)?.try_into()
+}
+
+impl From<&crate::MessagePayload> for message::Payload {
+    fn from(payload: &crate::MessagePayload) -> Self {
+        use crate::MessagePayload as Domain;
+        use message::Payload;
+
+        match payload {
+            Domain::Connect(connect) => Payload::Connect(ConnectRequest {
+                tenant_id: connect.tenant_id.0.clone(),
+                branch_id: connect.branch_id.0.clone(),
+                api_key: connect.api_key.clone(),
+                version: connect.version.clone(),
+                capabilities: connect.capabilities.clone(),
+                metadata: connect.metadata.clone(),
+            }),
+            Domain::ConnectAck(ack) => Payload::ConnectAck(ConnectAck {
+                session_id: ack.session_id.clone(),
+                server_version: ack.server_version.clone(),
+                heartbeat_interval_secs: ack.heartbeat_interval_secs,
+                assigned_config: ack.assigned_config.clone(),
+            }),
+            Domain::Disconnect(reason) => Payload::Disconnect(DisconnectReason {
+                code: u32::from(reason.code),
+                reason: reason.reason.clone(),
+            }),
+            Domain::Heartbeat => Payload::Heartbeat(Empty {}),
+            Domain::HeartbeatAck => Payload::HeartbeatAck(Empty {}),
+            Domain::SyncRequest(request) => Payload::SyncRequest(SyncRequest {
+                transaction_id: request.transaction_id.clone(),
+                last_sync_timestamp: request.last_sync_timestamp.as_ref().map(timestamp),
+                vector_clock: Some((&request.vector_clock).into()),
+                tables: request.tables.clone(),
+            }),
+            Domain::SyncBatch(batch) => Payload::SyncBatch(SyncBatch {
+                transaction_id: batch.transaction_id.clone(),
+                vector_clock: Some((&batch.vector_clock).into()),
+                changes: changes(&batch.changes),
+                is_final: batch.is_final,
+            }),
+            Domain::SyncAck(ack) => Payload::SyncAck(SyncAck {
+                transaction_id: ack.transaction_id.clone(),
+                applied_changes: ack.applied_changes as u64,
+                failed_changes: ack
+                    .failed_changes
+                    .iter()
+                    .map(|failed| FailedChange {
+                        index: failed.index as u64,
+                        reason: failed.reason.clone(),
+                    })
+                    .collect(),
+            }),
+            Domain::SyncComplete(complete) => Payload::SyncComplete(SyncComplete {
+                transaction_id: complete.transaction_id.clone(),
+                total_changes: complete.total_changes as u64,
+                duration_ms: complete.duration_ms,
+            }),
+            Domain::ConflictDetected(conflict) => Payload::ConflictDetected(ConflictNotification {
+                conflict_id: conflict.conflict_id.clone(),
+                table_name: conflict.table_name.clone(),
+                primary_key_json: json_text(&conflict.primary_key),
+                local_change: Some((&conflict.local_change).into()),
+                remote_change: Some((&conflict.remote_change).into()),
+                strategy: ConflictStrategy::from(conflict.strategy) as i32,
+            }),
+            Domain::ConflictResolved(resolution) => Payload::ConflictResolved(ConflictResolution {
+                conflict_id: resolution.conflict_id.clone(),
+                resolution: ConflictResolutionType::from(resolution.resolution) as i32,
+                winning_change: Some((&resolution.winning_change).into()),
+            }),
+            Domain::SchemaVersion(info) => Payload::SchemaVersion(SchemaVersionInfo {
+                version: info.version,
+                checksum: info.checksum.clone(),
+                tables: info
+                    .tables
+                    .iter()
+                    .map(|table| TableSchema {
+                        name: table.name.clone(),
+                        version: table.version,
+                        columns: table
+                            .columns
+                            .iter()
+                            .map(|column| ColumnSchema {
+                                name: column.name.clone(),
+                                data_type: column.data_type.clone(),
+                                nullable: column.nullable,
+                            })
+                            .collect(),
+                    })
+                    .collect(),
+            }),
+            Domain::SchemaUpdate(update) => Payload::SchemaUpdate(SchemaUpdate {
+                old_version: update.old_version,
+                new_version: update.new_version,
+                migration_sql: update.migration_sql.clone(),
+            }),
+            Domain::RouteMessage(route) => Payload::RouteMessage(RouteMessage {
+                target_branch: route.target_branch.0.clone(),
+                payload: route.payload.clone(),
+                sealed: route.sealed.as_deref().map(SealedPayload::from),
+            }),
+            Domain::MessageDelivered(delivered) => Payload::MessageDelivered(MessageDelivered {
+                message_id: delivered.message_id.clone(),
+                delivered_at: Some(timestamp(&delivered.delivered_at)),
+            }),
+            Domain::MessageFailed(failed) => Payload::MessageFailed(MessageFailed {
+                message_id: failed.message_id.clone(),
+                reason: failed.reason.clone(),
+            }),
+            Domain::BranchStatus(update) => Payload::BranchStatus(BranchStatusUpdate {
+                status: BranchStatus::from(update.status) as i32,
+                message: update.message.clone(),
+                metadata: update.metadata.clone(),
+            }),
+            Domain::SystemNotification(notification) => Payload::SystemNotification(SystemNotification {
+                level: NotificationLevel::from(notification.level) as i32,
+                message: notification.message.clone(),
+                action_required: notification.action_required,
+            }),
+            Domain::Error(error) => Payload::Error(ErrorPayload {
+                code: error.code.clone(),
+                message: error.message.clone(),
+                details_json: error.details.as_ref().map(json_text),
+            }),
+            Domain::TokenRenew(request) => Payload::TokenRenew(TokenRenewRequest {
+                refresh_token: request.refresh_token.clone(),
+            }),
+            Domain::TokenRenewed(renewal) => Payload::TokenRenewed(TokenRenewal {
+                access_token: renewal.access_token.clone(),
+                expires_at: renewal.expires_at,
+                refresh_token: renewal.refresh_token.clone(),
+                refresh_expires_at: renewal.refresh_expires_at,
+            }),
+            Domain::PublicKeyRegister(registration) => Payload::PublicKeyRegister(PublicKeyRegistration {
+                public_key: registration.public_key.clone(),
+            }),
+            Domain::PublicKeyRequest(query) => Payload::PublicKeyRequest(PublicKeyQuery {
+                branch_ids: branch_ids(&query.branch_ids),
+            }),
+            Domain::PublicKeys(list) => Payload::PublicKeys(PublicKeyList {
+                keys: list
+                    .keys
+                    .iter()
+                    .map(|key| BranchPublicKey {
+                        branch_id: key.branch_id.0.clone(),
+                        public_key: key.public_key.clone(),
+                        fingerprint: key.fingerprint.clone(),
+                        registered_at: Some(timestamp(&key.registered_at)),
+                    })
+                    .collect(),
+            }),
+            Domain::Chunk(chunk) => Payload::Chunk(MessageChunk {
+                message_id: chunk.message_id.clone(),
+                index: chunk.index,
+                total: chunk.total,
+                total_size: chunk.total_size,
+                checksum: chunk.checksum.clone(),
+                data: chunk.data.clone(),
+            }),
+            Domain::ChunkResume(resume) => Payload::ChunkResume(ChunkResume {
+                message_id: resume.message_id.clone(),
+                next_index: resume.next_index,
+            }),
+        }
+    }
+}
+
+impl TryFrom<message::Payload> for crate::MessagePayload {
+    type Error = Error;
+
+    fn try_from(payload: message::Payload) -> Result<Self> {
+        use crate::MessagePayload as Domain;
+        use message::Payload;
+
+        Ok(match payload {
+            Payload::Connect(connect) => Domain::Connect(crate::ConnectRequest {
+                tenant_id: TenantId::new(connect.tenant_id),
+                branch_id: BranchId::from(connect.branch_id),
+                api_key: connect.api_key,
+                version: connect.version,
+                capabilities: connect.capabilities,
+                metadata: connect.metadata,
+            }),
+            Payload::ConnectAck(ack) => Domain::ConnectAck(crate::ConnectAck {
+                session_id: ack.session_id,
+                server_version: ack.server_version,
+                heartbeat_interval_secs: ack.heartbeat_interval_secs,
+                assigned_config: ack.assigned_config,
+            }),
+            Payload::Disconnect(reason) => Domain::Disconnect(crate::DisconnectReason {
+                code: u16::try_from(reason.code).map_err(|e| invalid("code", e))?,
+                reason: reason.reason,
+            }),
+            Payload::Heartbeat(_) => Domain::Heartbeat,
+            Payload::HeartbeatAck(_) => Domain::HeartbeatAck,
+            Payload::SyncRequest(request) => Domain::SyncRequest(crate::SyncRequest {
+                transaction_id: request.transaction_id,
+                last_sync_timestamp: request
+                    .last_sync_timestamp
+                    .map(|at| from_timestamp(Some(at), "last_sync_timestamp"))
+                    .transpose()?,
+                vector_clock: from_vector_clock(request.vector_clock),
+                tables: request.tables,
+            }),
+            Payload::SyncBatch(batch) => Domain::SyncBatch(crate::SyncBatch {
+                transaction_id: batch.transaction_id,
+                vector_clock: from_vector_clock(batch.vector_clock),
+                changes: from_changes(batch.changes)?,
+                is_final: batch.is_final,
+            }),
+            Payload::SyncAck(ack) => Domain::SyncAck(crate::SyncAck {
+                transaction_id: ack.transaction_id,
+                applied_changes: to_usize(ack.applied_changes, "applied_changes")?,
+                failed_changes: ack
+                    .failed_changes
+                    .into_iter()
+                    .map(|failed| {
+                        Ok(crate::FailedChange {
+                            index: to_usize(failed.index, "index")?,
+                            reason: failed.reason,
+                        })
+                    })
+                    .collect::<Result<_>>()?,
+            }),
+            Payload::SyncComplete(complete) => Domain::SyncComplete(crate::SyncComplete {
+                transaction_id: complete.transaction_id,
+                total_changes: to_usize(complete.total_changes, "total_changes")?,
+                duration_ms: complete.duration_ms,
+            }),
+            Payload::ConflictDetected(conflict) => Domain::ConflictDetected(crate::ConflictNotification {
+                conflict_id: conflict.conflict_id,
+                table_name: conflict.table_name,
+                primary_key: from_json_text(&conflict.primary_key_json, "primary_key_json")?,
+                local_change: from_change(conflict.local_change, "local_change")?,
+                remote_change: from_change(conflict.remote_change, "remote_change")?,
+                strategy: conflict_strategy(conflict.strategy)?,
+            }),
+            Payload::ConflictResolved(resolution) => Domain::ConflictResolved(crate::ConflictResolution {
+                conflict_id: resolution.conflict_id,
+                resolution: conflict_resolution_type(resolution.resolution)?,
+                winning_change: from_change(resolution.winning_change, "winning_change")?,
+            }),
+            Payload::SchemaVersion(info) => Domain::SchemaVersion(crate::SchemaVersionInfo {
+                version: info.version,
+                checksum: info.checksum,
+                tables: info
+                    .tables
+                    .into_iter()
+                    .map(|table| crate::TableSchema {
+                        name: table.name,
+                        version: table.version,
+                        columns: table
+                            .columns
+                            .into_iter()
+                            .map(|column| crate::ColumnSchema {
+                                name: column.name,
+                                data_type: column.data_type,
+                                nullable: column.nullable,
+                            })
+                            .collect(),
+                    })
+                    .collect(),
+            }),
+            Payload::SchemaUpdate(update) => Domain::SchemaUpdate(crate::SchemaUpdate {
+                old_version: update.old_version,
+                new_version: update.new_version,
+                migration_sql: update.migration_sql,
+            }),
+            Payload::RouteMessage(route) => Domain::RouteMessage(crate::RouteMessage {
+                target_branch: BranchId::from(route.target_branch),
+                payload: route.payload,
+                sealed: route.sealed.map(TryInto::try_into).transpose()?.map(Box::new),
+            }),
+            Payload::MessageDelivered(delivered) => Domain::MessageDelivered(crate::MessageDelivered {
+                message_id: delivered.message_id,
+                delivered_at: from_timestamp(delivered.delivered_at, "delivered_at")?,
+            }),
+            Payload::MessageFailed(failed) => Domain::MessageFailed(crate::MessageFailed {
+                message_id: failed.message_id,
+                reason: failed.reason,
+            }),
+            Payload::BranchStatus(update) => Domain::BranchStatus(crate::BranchStatusUpdate {
+                status: branch_status(update.status)?,
+                message: update.message,
+                metadata: update.metadata,
+            }),
+            Payload::SystemNotification(notification) => Domain::SystemNotification(crate::SystemNotification {
+                level: notification_level(notification.level)?,
+                message: notification.message,
+                action_required: notification.action_required,
+            }),
+            Payload::Error(error) => Domain::Error(crate::ErrorPayload {
+                code: error.code,
+                message: error.message,
+                details: error
+                    .details_json
+                    .map(|text| from_json_text(&text, "details_json"))
+                    .transpose()?,
+            }),
+            Payload::TokenRenew(request) => Domain::TokenRenew(crate::TokenRenewRequest {
+                refresh_token: request.refresh_token,
+            }),
+            Payload::TokenRenewed(renewal) => Domain::TokenRenewed(crate::TokenRenewal {
+                access_token: renewal.access_token,
+                expires_at: renewal.expires_at,
+                refresh_token: renewal.refresh_token,
+                refresh_expires_at: renewal.refresh_expires_at,
+            }),
+            Payload::PublicKeyRegister(registration) => Domain::PublicKeyRegister(crate::PublicKeyRegistration {
+                public_key: registration.public_key,
+            }),
+            Payload::PublicKeyRequest(query) => Domain::PublicKeyRequest(crate::PublicKeyQuery {
+                branch_ids: from_branch_ids(query.branch_ids),
+            }),
+            Payload::PublicKeys(list) => Domain::PublicKeys(crate::PublicKeyList {
+                keys: list
+                    .keys
+                    .into_iter()
+                    .map(|key| {
+                        Ok(crate::BranchPublicKey {
+                            branch_id: BranchId::from(key.branch_id),
+                            public_key: key.public_key,
+                            fingerprint: key.fingerprint,
+                            registered_at: from_timestamp(key.registered_at, "registered_at")?,
+                        })
+                    })
+                    .collect::<Result<_>>()?,
+            }),
+            Payload::Chunk(chunk) => Domain::Chunk(crate::MessageChunk {
+                message_id: chunk.message_id,
+                index: chunk.index,
+                total: chunk.total,
+                total_size: chunk.total_size,
+                checksum: chunk.checksum,
+                data: chunk.data,
+            }),
+            Payload::ChunkResume(resume) => Domain::ChunkResume(crate::ChunkResume {
+                message_id: resume.message_id,
+                next_index: resume.next_index,
+            }),
+        })
+    }
+}
+
+impl From<&crate::Message> for Message {
+    fn from(message: &crate::Message) -> Self {
+        Self {
+            id: message.id.clone(),
+            timestamp: Some(timestamp(&message.timestamp)),
+            from: message.from.0.clone(),
+            to: message.to.as_ref().map(|to| to.0.clone()),
+            signature: message.signature.clone(),
+            payload: Some((&message.payload).into()),
+        }
+    }
+}
+
+impl TryFrom<Message> for crate::Message {
+    type Error = Error;
+
+    fn try_from(message: Message) -> Result<Self> {
+        // A payload added to the schema after this build decodes to None
+        let payload = message
+            .payload
+            .ok_or_else(|| Error::InvalidMessage(format!("Message {} has no payload this version understands", message.id)))?;
+
+        Ok(Self {
+            id: message.id,
+            timestamp: from_timestamp(message.timestamp, "timestamp")?,
+            from: BranchId::from(message.from),
+            to: message.to.map(BranchId::from),
+            payload: payload.try_into()?,
+            signature: message.signature,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prost::Message as _;
+
+    #[test]
+    fn test_unknown_fields_and_payloads_are_tolerated() {
+        // A newer peer's message: a known payload plus a field this build has never seen
+        let mut encoded = Message::from(&crate::Message::new(
+            BranchId::new("main"),
+            None,
+            crate::MessagePayload::Heartbeat,
+        ))
+        .encode_to_vec();
+        // Field 99, wire type 2 (length-delimited), 3 bytes
+        encoded.extend_from_slice(&[0x9a, 0x06, 0x03, b'n', b'e', b'w']);
+
+        let decoded = crate::Message::try_from(Message::decode(encoded.as_slice()).unwrap()).unwrap();
+        assert!(matches!(decoded.payload, crate::MessagePayload::Heartbeat));
+
+        // A payload variant from a newer schema decodes to no payload and is refused cleanly
+        let mut newer = Message::from(&decoded);
+        newer.payload = None;
+        let encoded = [newer.encode_to_vec(), vec![0xd2, 0x0f, 0x00]].concat(); // field 250, empty message
+        let result = crate::Message::try_from(Message::decode(encoded.as_slice()).unwrap());
+        assert!(matches!(result, Err(Error::InvalidMessage(_))));
+    }
+
+    #[test]
+    fn test_proto_file_declares_every_payload_tag() {
+        let schema = include_str!("../proto/hub_broker.proto");
+        let oneof = schema.split("oneof payload {").nth(1).unwrap().split('}').next().unwrap();
+        let tags: Vec<&str> = oneof
+            .lines()
+            .filter_map(|line| line.trim().strip_suffix(';')?.rsplit("= ").next())
+            .collect();
+        let expected: Vec<String> = (10..=35).map(|tag| tag.to_string()).collect();
+        assert_eq!(tags, expected, "hub_broker.proto payload tags drifted from proto.rs");
+    }
+}
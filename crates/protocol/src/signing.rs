@@ -0,0 +1,140 @@
+//! Per-message HMAC tied to the credential a branch connected with.
+//!
+//! The key is derived from the API key or access token presented at Connect, so
+//! only a holder of that credential can produce messages the hub accepts for the
+//! session. The MAC covers the canonical JSON form of the message (object keys
+//! sorted, `signature` left out), whatever codec carries it on the wire.
+
+use crate::Message;
+use common::{Error, Result};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// Connect capability of branches that sign every message after Connect
+pub const SIGNING_CAPABILITY: &str = "signed_v1";
+
+/// Domain separation, so the derived key is useless for anything but message MACs
+const KEY_CONTEXT: &[u8] = b"hub-broker message signing v1";
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Message MAC key of one session
+#[derive(Clone)]
+pub struct SigningKey([u8; 32]);
+
+impl SigningKey {
+    /// Key for the secret the branch authenticated with (API key or access token)
+    pub fn derive(credential: &str) -> Self {
+        let mut mac = HmacSha256::new_from_slice(KEY_CONTEXT).expect("HMAC accepts any key length");
+        mac.update(credential.as_bytes());
+        Self(mac.finalize().into_bytes().into())
+    }
+
+    /// Set `message.signature` to the MAC of the rest of the message
+    pub fn sign(&self, message: &mut Message) -> Result<()> {
+        message.signature = None;
+        let mac = self.mac(message)?.finalize().into_bytes();
+        message.signature = Some(mac.iter().map(|b| format!("{:02x}", b)).collect());
+        Ok(())
+    }
+
+    /// Check `message.signature` in constant time
+    pub fn verify(&self, message: &Message) -> Result<()> {
+        let signature = message
+            .signature
+            .as_deref()
+            .ok_or_else(|| Error::AuthenticationFailed("Message is not signed".to_string()))?;
+        let signature = decode_hex(signature)
+            .ok_or_else(|| Error::AuthenticationFailed("Malformed message signature".to_string()))?;
+
+        self.mac(message)?
+            .verify_slice(&signature)
+            .map_err(|_| Error::AuthenticationFailed("Invalid message signature".to_string()))
+    }
+
+    fn mac(&self, message: &Message) -> Result<HmacSha256> {
+        let mut mac = HmacSha256::new_from_slice(&self.0).expect("HMAC accepts any key length");
+        mac.update(&signing_input(message)?);
+        Ok(mac)
+    }
+}
+
+impl std::fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SigningKey(..)")
+    }
+}
+
+/// Canonical bytes a signature covers; serde_json maps keep keys sorted, so
+/// `HashMap` fields come out the same on both ends
+fn signing_input(message: &Message) -> Result<Vec<u8>> {
+    let mut value = serde_json::to_value(message)?;
+    if let Some(object) = value.as_object_mut() {
+        object.remove("signature");
+    }
+    Ok(serde_json::to_vec(&value)?)
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [_, _] => u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok(),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BranchStatusUpdate, MessagePayload};
+    use common::BranchId;
+    use std::collections::HashMap;
+
+    fn status_message() -> Message {
+        let metadata: HashMap<String, String> =
+            (0..16).map(|i| (format!("key_{}", i), i.to_string())).collect();
+        Message::new(
+            BranchId::new("main"),
+            None,
+            MessagePayload::BranchStatus(BranchStatusUpdate {
+                status: common::BranchStatus::Online,
+                message: None,
+                metadata,
+            }),
+        )
+    }
+
+    #[test]
+    fn test_signature_survives_the_wire() {
+        let key = SigningKey::derive("key_id.secret");
+        let mut message = status_message();
+        key.sign(&mut message).unwrap();
+
+        // The receiver rebuilds its own HashMap, with its own iteration order
+        let received: Message = serde_json::from_str(&serde_json::to_string(&message).unwrap()).unwrap();
+        assert!(key.verify(&received).is_ok());
+    }
+
+    #[test]
+    fn test_tampered_or_foreign_messages_fail() {
+        let key = SigningKey::derive("key_id.secret");
+        let mut message = status_message();
+        key.sign(&mut message).unwrap();
+
+        let mut tampered = message.clone();
+        tampered.to = Some(BranchId::new("store_2"));
+        assert!(key.verify(&tampered).is_err());
+
+        assert!(SigningKey::derive("other.secret").verify(&message).is_err());
+
+        let mut unsigned = message.clone();
+        unsigned.signature = None;
+        assert!(key.verify(&unsigned).is_err());
+
+        let mut malformed = message;
+        malformed.signature = Some("zz".to_string());
+        assert!(key.verify(&malformed).is_err());
+    }
+}
@@ -0,0 +1,213 @@
+//! Protocol version and capability negotiation.
+//!
+//! A branch sends the newest protocol revision it speaks in `ConnectRequest.version`
+//! and the optional features it supports in `capabilities`. The hub serves any revision
+//! from its configured minimum up to `CURRENT`: it answers in `ConnectAck.assigned_config`
+//! with the revision both sides use and the features it accepted, or refuses the Connect
+//! with `DisconnectReason::UNSUPPORTED_VERSION`. Messages a session could not read are
+//! withheld from it rather than sent in a shape it would fail to decode.
+
+use crate::{ConnectAck, ConnectRequest, DisconnectReason, MessagePayload};
+use common::ProtocolVersion;
+use std::collections::HashMap;
+
+/// Branches from before negotiation send their crate version, 0.1.x, which reads as this
+pub const LEGACY: ProtocolVersion = ProtocolVersion::new(0, 1);
+
+/// Negotiated version and capabilities in `ConnectAck`
+pub const NEGOTIATION: ProtocolVersion = ProtocolVersion::new(1, 0);
+
+/// Chunked transfer of oversized messages
+pub const CHUNKING: ProtocolVersion = ProtocolVersion::new(1, 1);
+
+/// Revision this build speaks: acknowledged delivery of routed messages
+pub const CURRENT: ProtocolVersion = ProtocolVersion::new(1, 2);
+
+/// Connect capability of branches that apply sync batches
+pub const SYNC_CAPABILITY: &str = "sync_v1";
+
+/// Optional features the hub supports, with the revision that introduced each
+pub const CAPABILITIES: &[(&str, ProtocolVersion)] = &[
+    (SYNC_CAPABILITY, LEGACY),
+    (crate::e2e::E2E_CAPABILITY, LEGACY),
+    (crate::signing::SIGNING_CAPABILITY, LEGACY),
+    (crate::chunking::CHUNKED_CAPABILITY, CHUNKING),
+    (crate::delivery::ACK_CAPABILITY, CURRENT),
+];
+
+/// `ConnectAck.assigned_config` key with the negotiated revision
+pub const PROTOCOL_VERSION_CONFIG: &str = "protocol_version";
+
+/// `ConnectAck.assigned_config` key with the accepted capabilities, comma separated
+pub const CAPABILITIES_CONFIG: &str = "capabilities";
+
+/// `ConnectAck.assigned_config` key with the revisions the hub serves, "min-max"
+pub const SUPPORTED_VERSIONS_CONFIG: &str = "supported_protocol_versions";
+
+/// What a session agreed on in its Connect handshake
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Negotiated {
+    pub version: ProtocolVersion,
+    pub capabilities: Vec<String>,
+}
+
+impl Negotiated {
+    /// Hub side: the revision and features to serve a Connect with.
+    /// Refuses branches older than `min_supported` with an upgrade hint.
+    pub fn for_connect(connect: &ConnectRequest, min_supported: ProtocolVersion) -> Result<Self, DisconnectReason> {
+        let unsupported = |offered: &str| {
+            DisconnectReason::new(
+                DisconnectReason::UNSUPPORTED_VERSION,
+                format!(
+                    "Protocol version {} is not supported; upgrade the branch to a build speaking protocol {} to {}",
+                    offered, min_supported, CURRENT
+                ),
+            )
+        };
+
+        let offered: ProtocolVersion = connect.version.parse().map_err(|_| unsupported(&connect.version))?;
+        if offered < min_supported {
+            return Err(unsupported(&connect.version));
+        }
+        let version = offered.min(CURRENT);
+
+        let capabilities = CAPABILITIES
+            .iter()
+            .filter(|(name, since)| *since <= version && connect.capabilities.iter().any(|c| c == name))
+            .map(|(name, _)| name.to_string())
+            .collect();
+
+        Ok(Self { version, capabilities })
+    }
+
+    /// Branch side: what the hub agreed to. Hubs from before negotiation leave it out of
+    /// the ack and honour whatever was offered, at the legacy revision.
+    pub fn from_ack(ack: &ConnectAck, offered: &[String]) -> Self {
+        let version = ack
+            .assigned_config
+            .get(PROTOCOL_VERSION_CONFIG)
+            .and_then(|version| version.parse().ok());
+
+        match version {
+            Some(version) => Self {
+                version,
+                capabilities: ack
+                    .assigned_config
+                    .get(CAPABILITIES_CONFIG)
+                    .map(|list| list.split(',').filter(|c| !c.is_empty()).map(str::to_string).collect())
+                    .unwrap_or_default(),
+            },
+            None => Self {
+                version: LEGACY,
+                capabilities: offered.to_vec(),
+            },
+        }
+    }
+
+    /// Entries to add to `ConnectAck.assigned_config`
+    pub fn assigned_config(&self, min_supported: ProtocolVersion) -> HashMap<String, String> {
+        HashMap::from([
+            (PROTOCOL_VERSION_CONFIG.to_string(), self.version.to_string()),
+            (CAPABILITIES_CONFIG.to_string(), self.capabilities.join(",")),
+            (SUPPORTED_VERSIONS_CONFIG.to_string(), format!("{}-{}", min_supported, CURRENT)),
+        ])
+    }
+
+    pub fn has(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+
+    /// The capability a session would need to read `payload`, if it lacks it
+    pub fn missing_capability(&self, payload: &MessagePayload) -> Option<&'static str> {
+        let needed = match payload {
+            MessagePayload::Chunk(_) | MessagePayload::ChunkResume(_) => crate::chunking::CHUNKED_CAPABILITY,
+            MessagePayload::PublicKeyRegister(_) | MessagePayload::PublicKeyRequest(_) | MessagePayload::PublicKeys(_) => {
+                crate::e2e::E2E_CAPABILITY
+            }
+            _ if crate::e2e::carries_sealed(payload) => crate::e2e::E2E_CAPABILITY,
+            _ => return None,
+        };
+
+        (!self.has(needed)).then_some(needed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connect(version: &str, capabilities: &[&str]) -> ConnectRequest {
+        ConnectRequest {
+            tenant_id: common::TenantId::new("tenant_a"),
+            branch_id: common::BranchId::new("store_1"),
+            api_key: None,
+            version: version.to_string(),
+            capabilities: capabilities.iter().map(|c| c.to_string()).collect(),
+            metadata: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_negotiation_picks_the_older_revision_and_known_capabilities() {
+        let current =
+            Negotiated::for_connect(&connect("1.2", &["sync_v1", "chunked_v1", "ack_v1", "teleport_v9"]), LEGACY).unwrap();
+        assert_eq!(current.version, CURRENT);
+        assert_eq!(current.capabilities, vec!["sync_v1", "chunked_v1", "ack_v1"]);
+
+        // Capabilities newer than the negotiated revision are not accepted
+        let older = Negotiated::for_connect(&connect("1.0", &["e2e_v1", "chunked_v1"]), LEGACY).unwrap();
+        assert_eq!(older.version, NEGOTIATION);
+        assert_eq!(older.capabilities, vec!["e2e_v1"]);
+
+        let chunking = Negotiated::for_connect(&connect("1.1", &["chunked_v1", "ack_v1"]), LEGACY).unwrap();
+        assert_eq!(chunking.version, CHUNKING);
+        assert_eq!(chunking.capabilities, vec!["chunked_v1"]);
+
+        // A pre-negotiation branch sends its crate version
+        let legacy = Negotiated::for_connect(&connect("0.1.0", &["sync_v1"]), LEGACY).unwrap();
+        assert_eq!(legacy.version, LEGACY);
+
+        // A newer branch is served at our revision and has to step down
+        let newer = Negotiated::for_connect(&connect("7.3", &[]), LEGACY).unwrap();
+        assert_eq!(newer.version, CURRENT);
+    }
+
+    #[test]
+    fn test_too_old_or_unparseable_versions_are_refused() {
+        for version in ["0.1.0", "garbage"] {
+            let reason = Negotiated::for_connect(&connect(version, &[]), CURRENT).unwrap_err();
+            assert_eq!(reason.code, DisconnectReason::UNSUPPORTED_VERSION);
+            assert!(reason.reason.contains("upgrade"), "{}", reason.reason);
+        }
+    }
+
+    #[test]
+    fn test_ack_round_trip_and_legacy_hub() {
+        let negotiated = Negotiated::for_connect(&connect("1.1", &["sync_v1", "signed_v1"]), LEGACY).unwrap();
+        let ack = ConnectAck {
+            session_id: "s1".to_string(),
+            server_version: "0.1.0".to_string(),
+            heartbeat_interval_secs: 30,
+            assigned_config: negotiated.assigned_config(LEGACY),
+        };
+        assert_eq!(Negotiated::from_ack(&ack, &[]), negotiated);
+        assert_eq!(ack.assigned_config[SUPPORTED_VERSIONS_CONFIG], "0.1-1.2");
+
+        let offered = vec!["e2e_v1".to_string()];
+        let old_hub = ConnectAck { assigned_config: HashMap::new(), ..ack };
+        let assumed = Negotiated::from_ack(&old_hub, &offered);
+        assert_eq!(assumed.version, LEGACY);
+        assert!(assumed.has("e2e_v1"));
+    }
+
+    #[test]
+    fn test_key_directory_is_withheld_without_e2e() {
+        let plain = Negotiated { version: CURRENT, capabilities: vec![SYNC_CAPABILITY.to_string()] };
+        let keys = MessagePayload::PublicKeys(crate::PublicKeyList { keys: vec![] });
+        assert_eq!(plain.missing_capability(&keys), Some(crate::e2e::E2E_CAPABILITY));
+        assert_eq!(plain.missing_capability(&MessagePayload::Heartbeat), None);
+
+        let e2e = Negotiated { version: CURRENT, capabilities: vec![crate::e2e::E2E_CAPABILITY.to_string()] };
+        assert_eq!(e2e.missing_capability(&keys), None);
+    }
+}
@@ -99,11 +99,11 @@ impl CdcEngine {
         Ok(())
     }
 
-    /// Fetch pending changes
-    pub async fn fetch_pending_changes(&self, schema: &str, limit: i64) -> Result<Vec<DatabaseChange>> {
+    /// Fetch pending changes with their change log IDs, to pass to `mark_synced`
+    pub async fn fetch_pending_changes(&self, schema: &str, limit: i64) -> Result<Vec<(i64, DatabaseChange)>> {
         let query = format!(
             r#"
-            SELECT table_name, operation, primary_key, row_data, changed_at
+            SELECT id, table_name, operation, primary_key, row_data, changed_at
             FROM {}.sync_change_log
             WHERE synced = FALSE
             ORDER BY id
@@ -117,7 +117,7 @@ impl CdcEngine {
             .fetch_all(&self.pool)
             .await?;
 
-        Ok(rows.into_iter().map(|row| row.into()).collect())
+        Ok(rows.into_iter().map(|row| (row.id, row.into())).collect())
     }
 
     /// Mark changes as synced
@@ -142,6 +142,7 @@ impl CdcEngine {
 
 #[derive(sqlx::FromRow)]
 struct ChangeLogRow {
+    id: i64,
     table_name: String,
     operation: String,
     primary_key: sqlx::types::JsonValue,
@@ -159,10 +160,11 @@ impl From<ChangeLogRow> for DatabaseChange {
                 "DELETE" => Operation::Delete,
                 _ => Operation::Insert,
             },
-            primary_key: serde_json::Value::from(row.primary_key),
-            data: serde_json::Value::from(row.row_data),
+            primary_key: row.primary_key,
+            data: row.row_data,
             timestamp: row.changed_at,
             schema_version: 1, // TODO: Track schema versions
+            sealed: None,
         }
     }
 }
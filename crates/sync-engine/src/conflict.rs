@@ -88,8 +88,6 @@ impl ConflictResolver {
 
 #[cfg(test)]
 mod tests {
-    use super::*;
-
     #[test]
     fn test_conflict_detection() {
         // Add comprehensive conflict detection tests
@@ -28,6 +28,11 @@ impl ReplicationEngine {
 
     /// Apply single change
     async fn apply_single_change(&self, schema: &str, change: &DatabaseChange) -> Result<()> {
+        // Sealed rows carry no data until the client opens them
+        if change.sealed.is_some() {
+            return Err(common::Error::Encryption("Change is still sealed".to_string()));
+        }
+
         match change.operation {
             protocol::Operation::Insert => self.apply_insert(schema, change).await,
             protocol::Operation::Update => self.apply_update(schema, change).await,
@@ -51,12 +56,12 @@ impl ReplicationEngine {
         Ok(())
     }
 
-    async fn apply_update(&self, schema: &str, change: &DatabaseChange) -> Result<()> {
+    async fn apply_update(&self, _schema: &str, _change: &DatabaseChange) -> Result<()> {
         // TODO: Implement UPDATE logic
         Ok(())
     }
 
-    async fn apply_delete(&self, schema: &str, change: &DatabaseChange) -> Result<()> {
+    async fn apply_delete(&self, _schema: &str, _change: &DatabaseChange) -> Result<()> {
         // TODO: Implement DELETE logic
         Ok(())
     }